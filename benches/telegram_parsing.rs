@@ -0,0 +1,80 @@
+//! Throughput of the telegram parsers: `cargo bench --bench telegram_parsing`
+//!
+//! One hour of simulated 1 second telegrams, parsed with the line parser
+//! used for live data and with the bulk parser used by `p1_import`.  A year
+//! is 8760 times that, divided by the number of cores for the bulk parser.
+use std::hint::black_box;
+use std::io::Write;
+
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+use flate2::write::GzEncoder;
+use hello_world_lib::p1_bulk::{parse_capture, read_capture};
+use hello_world_lib::p1_meter::{crc16, parse_lines, split_telegrams, validate_crc};
+use hello_world_lib::p1_simulator::{MeterSimulator, SimulatorSettings};
+use hello_world_lib::p1_telegram::encode_telegram;
+use time::{Duration, OffsetDateTime};
+
+const TELEGRAMS: i64 = 3600;
+
+fn simulated_hour() -> String {
+    let mut simulator = MeterSimulator::new(SimulatorSettings::default());
+    let start = OffsetDateTime::from_unix_timestamp(1718964000).unwrap();
+    (0..TELEGRAMS)
+        .map(|i| {
+            encode_telegram(
+                &simulator.step(start + Duration::seconds(i)),
+                "SIM5\\2SIMULATOR-50",
+            )
+        })
+        .collect()
+}
+
+fn parsers(c: &mut Criterion) {
+    let capture = simulated_hour();
+
+    let mut group = c.benchmark_group("telegrams");
+    group.throughput(Throughput::Elements(TELEGRAMS as u64));
+    group.bench_function("line parser", |b| {
+        b.iter(|| {
+            split_telegrams(black_box(&capture))
+                .iter()
+                .filter(|telegram| validate_crc(telegram).is_ok())
+                .filter_map(|telegram| parse_lines(telegram.lines()).ok().flatten())
+                .count()
+        })
+    });
+    group.bench_function("bulk parser", |b| {
+        b.iter(|| {
+            parse_capture(black_box(capture.as_bytes()), None)
+                .measurements
+                .len()
+        })
+    });
+
+    // As archived: one gzip member per minute
+    let path = std::env::temp_dir().join(format!("p1_bench_{}.gz", std::process::id()));
+    let mut file = std::fs::File::create(&path).unwrap();
+    for minute in split_telegrams(&capture).chunks(60) {
+        let mut encoder = GzEncoder::new(Vec::new(), Default::default());
+        encoder.write_all(minute.concat().as_bytes()).unwrap();
+        file.write_all(&encoder.finish().unwrap()).unwrap();
+    }
+    drop(file);
+    group.bench_function("bulk parser, archive file", |b| {
+        b.iter(|| {
+            parse_capture(&read_capture(&path).unwrap(), None)
+                .measurements
+                .len()
+        })
+    });
+    group.finish();
+    std::fs::remove_file(&path).unwrap();
+
+    let mut group = c.benchmark_group("bytes");
+    group.throughput(Throughput::Bytes(capture.len() as u64));
+    group.bench_function("crc16", |b| b.iter(|| crc16(black_box(capture.as_bytes()))));
+    group.finish();
+}
+
+criterion_group!(benches, parsers);
+criterion_main!(benches);
@@ -24,8 +24,56 @@ CREATE TABLE data_202303 (
     gas_m3 FLOAT,
     water_m3 FLOAT
   );
+CREATE TABLE IF NOT EXISTS p1_measurements (
+    timestamp INTEGER PRIMARY KEY ASC,
+    peak_conso_kWh FLOAT NOT NULL,
+    off_conso_kWh FLOAT NOT NULL,
+    peak_inj_kWh FLOAT NOT NULL,
+    off_inj_kWh FLOAT NOT NULL
+  );
+CREATE TABLE IF NOT EXISTS p1_power_failures (
+    end_timestamp INTEGER PRIMARY KEY ASC,
+    duration_s INTEGER NOT NULL
+  );
+CREATE TABLE IF NOT EXISTS p1_power_quality_counters (
+    timestamp INTEGER NOT NULL,
+    counter TEXT NOT NULL,
+    count INTEGER NOT NULL,
+    PRIMARY KEY (counter, count)
+  );
+CREATE TABLE IF NOT EXISTS p1_phase_minutes (
+    timestamp INTEGER NOT NULL,
+    phase INTEGER NOT NULL,
+    max_current_A FLOAT,
+    avg_current_A FLOAT,
+    avg_voltage_V FLOAT,
+    avg_import_kW FLOAT,
+    avg_export_kW FLOAT,
+    PRIMARY KEY (timestamp, phase)
+  );
+CREATE TABLE IF NOT EXISTS p1_appliance_clusters (
+    id INTEGER PRIMARY KEY,
+    phase INTEGER NOT NULL,
+    power_kW FLOAT NOT NULL,
+    events INTEGER NOT NULL,
+    label TEXT
+  );
+CREATE TABLE IF NOT EXISTS p1_appliance_events (
+    timestamp INTEGER NOT NULL,
+    phase INTEGER NOT NULL,
+    delta_kW FLOAT NOT NULL,
+    cluster INTEGER NOT NULL,
+    PRIMARY KEY (timestamp, phase)
+  );
  */
 
+/// How handlers and background tasks reach the database: the shell command
+/// given to `call_sqlite3`, cf `sqlite3_command`.
+#[derive(Clone, Debug)]
+pub struct Database {
+    pub sqlite3: String,
+}
+
 #[derive(Debug, PartialEq)]
 #[allow(non_snake_case)]
 pub struct Data202208 {
@@ -76,7 +124,883 @@ pub fn insert_data_202303(cmd: &str, meas: &Data202303) -> Result<usize, String>
             &some_val_to_sql(meas.off_inj_kWh),
             &some_val_to_sql(meas.gas_m3),
             &some_val_to_sql(meas.water_m3)).as_str());
-    usize::from_str(&sql_output.trim()).map_err(|e| format!("{}", e))
+    usize::from_str(sql_output.trim()).map_err(|e| format!("{}", e))
+}
+
+#[derive(Debug, PartialEq)]
+#[allow(non_snake_case)]
+pub struct P1Measurement {
+    pub timestamp: i64,
+    pub peak_conso_kWh: f64,
+    pub off_conso_kWh: f64,
+    pub peak_inj_kWh: f64,
+    pub off_inj_kWh: f64,
+}
+
+pub const CREATE_P1_MEASUREMENTS: &str = "CREATE TABLE IF NOT EXISTS p1_measurements (timestamp INTEGER PRIMARY KEY ASC, peak_conso_kWh FLOAT NOT NULL, off_conso_kWh FLOAT NOT NULL, peak_inj_kWh FLOAT NOT NULL, off_inj_kWh FLOAT NOT NULL);";
+
+/// Insert all measurements in one transaction, ignoring rows whose
+/// timestamp is already in the table so that an interrupted import can
+/// simply be restarted.  Returns the number of rows actually inserted.
+pub fn insert_p1_measurements(cmd: &str, meas: &[P1Measurement]) -> Result<usize, String> {
+    let mut sql = String::from(".mode list\n");
+    sql.push_str(CREATE_P1_MEASUREMENTS);
+    sql.push_str("\nBEGIN TRANSACTION;\n");
+    for m in meas {
+        sql.push_str(&format!(
+            "insert or ignore into p1_measurements values ({}, {}, {}, {}, {});\n",
+            m.timestamp, m.peak_conso_kWh, m.off_conso_kWh, m.peak_inj_kWh, m.off_inj_kWh
+        ));
+    }
+    sql.push_str("COMMIT;\nselect total_changes();");
+    let sql_output = call_sqlite3(cmd, &sql);
+    usize::from_str(sql_output.trim()).map_err(|e| format!("{}", e))
+}
+
+/// Insert the measurements in one transaction, telling for each whether it
+/// was new (`true`) or its timestamp already in the table (`false`).
+pub fn insert_new_p1_measurements(cmd: &str, meas: &[P1Measurement]) -> Result<Vec<bool>, String> {
+    let mut sql = String::from(".mode list\n");
+    sql.push_str(CREATE_P1_MEASUREMENTS);
+    sql.push_str("\nBEGIN TRANSACTION;\n");
+    for m in meas {
+        sql.push_str(&format!(
+            "insert or ignore into p1_measurements values ({}, {}, {}, {}, {});\nselect changes();\n",
+            m.timestamp, m.peak_conso_kWh, m.off_conso_kWh, m.peak_inj_kWh, m.off_inj_kWh
+        ));
+    }
+    sql.push_str("COMMIT;");
+    let sql_output = call_sqlite3(cmd, &sql);
+    let inserted = sql_output
+        .lines()
+        .map(|line| match line.trim() {
+            "0" => Ok(false),
+            "1" => Ok(true),
+            other => Err(format!("Unexpected change count {}", other)),
+        })
+        .collect::<Result<Vec<bool>, String>>()?;
+    if inserted.len() == meas.len() {
+        Ok(inserted)
+    } else {
+        Err(format!(
+            "{} change counts for {} measurements",
+            inserted.len(),
+            meas.len()
+        ))
+    }
+}
+
+pub const CREATE_P1_POWER_QUALITY: &str = "CREATE TABLE IF NOT EXISTS p1_power_failures (end_timestamp INTEGER PRIMARY KEY ASC, duration_s INTEGER NOT NULL);\nCREATE TABLE IF NOT EXISTS p1_power_quality_counters (timestamp INTEGER NOT NULL, counter TEXT NOT NULL, count INTEGER NOT NULL, PRIMARY KEY (counter, count));";
+
+#[derive(Debug, PartialEq)]
+pub struct PowerFailureRow {
+    pub end_timestamp: i64,
+    pub duration_s: i64,
+}
+
+/// A power quality counter that went up since the previous stored value.
+#[derive(Debug, PartialEq)]
+pub struct PowerQualityEventRow {
+    /// Meter time of the first telegram with the new value
+    pub timestamp: i64,
+    pub counter: String,
+    pub count: i64,
+    pub increment: i64,
+}
+
+/// Store power failures and counter values.  Both are keyed so that the
+/// same failure or counter value is only stored once however often it is
+/// seen.  Returns the number of new rows.
+pub fn insert_power_quality(
+    cmd: &str,
+    failures: &[PowerFailureRow],
+    timestamp: i64,
+    counters: &[(String, u64)],
+) -> Result<usize, String> {
+    let mut sql = String::from(".mode list\n");
+    sql.push_str(CREATE_P1_POWER_QUALITY);
+    sql.push_str("\nBEGIN TRANSACTION;\n");
+    for f in failures {
+        sql.push_str(&format!(
+            "insert or ignore into p1_power_failures values ({}, {});\n",
+            f.end_timestamp, f.duration_s
+        ));
+    }
+    for (counter, count) in counters {
+        sql.push_str(&format!(
+            "insert or ignore into p1_power_quality_counters values ({}, '{}', {});\n",
+            timestamp, counter, count
+        ));
+    }
+    sql.push_str("COMMIT;\nselect total_changes();");
+    let sql_output = call_sqlite3(cmd, &sql);
+    usize::from_str(sql_output.trim()).map_err(|e| format!("{}", e))
+}
+
+fn parse_i64_column(col: Option<&str>, name: &str) -> Result<i64, String> {
+    match col.map(i64::from_str) {
+        Some(Ok(value)) => Ok(value),
+        None => Err(format!("No {}", name)),
+        Some(Err(_)) => Err(format!("Unable to parse {}", name)),
+    }
+}
+
+/// Most recent power failures first
+pub fn select_power_failures(cmd: &str) -> Result<Vec<PowerFailureRow>, String> {
+    let sql_output = call_sqlite3(
+        cmd,
+        &format!(
+            ".mode list\n{}\nselect end_timestamp, duration_s from p1_power_failures order by end_timestamp desc;",
+            CREATE_P1_POWER_QUALITY
+        ),
+    );
+    let mut result = Vec::new();
+    for line in sql_output.lines() {
+        let mut cols = line.split('|');
+        result.push(PowerFailureRow {
+            end_timestamp: parse_i64_column(cols.next(), "end_timestamp")?,
+            duration_s: parse_i64_column(cols.next(), "duration_s")?,
+        })
+    }
+    Ok(result)
+}
+
+/// Most recent counter increments first.  The first value stored for each
+/// counter is only a baseline and is not returned.
+pub fn select_power_quality_events(cmd: &str) -> Result<Vec<PowerQualityEventRow>, String> {
+    let sql_output = call_sqlite3(
+        cmd,
+        &format!(
+            ".mode list\n{}\nselect timestamp, counter, count, increment from (select timestamp, counter, count, count - lag(count) over (partition by counter order by count) as increment from p1_power_quality_counters) where increment is not null order by timestamp desc, counter;",
+            CREATE_P1_POWER_QUALITY
+        ),
+    );
+    let mut result = Vec::new();
+    for line in sql_output.lines() {
+        let mut cols = line.split('|');
+        let timestamp = parse_i64_column(cols.next(), "timestamp")?;
+        let counter = match cols.next() {
+            Some(counter) => counter.to_string(),
+            None => return Err("No counter".to_string()),
+        };
+        result.push(PowerQualityEventRow {
+            timestamp,
+            counter,
+            count: parse_i64_column(cols.next(), "count")?,
+            increment: parse_i64_column(cols.next(), "increment")?,
+        })
+    }
+    Ok(result)
+}
+
+pub const CREATE_P1_PHASE_MINUTES: &str = "CREATE TABLE IF NOT EXISTS p1_phase_minutes (timestamp INTEGER NOT NULL, phase INTEGER NOT NULL, max_current_A FLOAT, avg_current_A FLOAT, avg_voltage_V FLOAT, avg_import_kW FLOAT, avg_export_kW FLOAT, PRIMARY KEY (timestamp, phase));";
+
+/// What `p1_reprocess` derived from the telegrams of an archive section,
+/// to replace what the tables hold for the time they cover.
+#[derive(Debug, Default, PartialEq)]
+pub struct P1Rebuild {
+    /// Time covered, inclusive
+    pub from: i64,
+    pub to: i64,
+    /// End of the phase minutes replaced: the last minute may still be
+    /// aggregated from the telegrams of the next section
+    pub phases_to: i64,
+    pub measurements: Vec<P1Measurement>,
+    pub phase_minutes: Vec<PhaseMinuteRow>,
+    pub power_failures: Vec<PowerFailureRow>,
+    /// `(timestamp, counter, count)`
+    pub counters: Vec<(i64, String, u64)>,
+    pub appliance_events: Vec<ApplianceEventRow>,
+    /// Clusters the appliance events were assigned to, as updated
+    pub appliance_clusters: Vec<ApplianceClusterRow>,
+}
+
+/// Delete what was derived from the telegrams of the time covered by
+/// `rebuild` and insert its rows instead, all in one transaction: on any
+/// error nothing changes.  The event counts of the appliance clusters are
+/// recounted, the deleted events were counted too.  Returns the number of
+/// rows deleted, inserted or updated.
+pub fn replace_p1_range(cmd: &str, rebuild: &P1Rebuild) -> Result<usize, String> {
+    let (from, to, phases_to) = (rebuild.from, rebuild.to, rebuild.phases_to);
+    let mut sql = String::from(".mode list\n.bail on\n");
+    for create in [
+        CREATE_P1_MEASUREMENTS,
+        CREATE_P1_POWER_QUALITY,
+        CREATE_P1_PHASE_MINUTES,
+        CREATE_P1_APPLIANCES,
+    ] {
+        sql.push_str(create);
+        sql.push('\n');
+    }
+    sql.push_str(&format!(
+        "BEGIN TRANSACTION;\ndelete from p1_measurements where timestamp between {from} and {to};\ndelete from p1_phase_minutes where timestamp between {from} and {phases_to};\ndelete from p1_power_failures where end_timestamp between {from} and {to};\ndelete from p1_power_quality_counters where timestamp between {from} and {to};\ndelete from p1_appliance_events where timestamp between {from} and {to};\n"
+    ));
+    for m in &rebuild.measurements {
+        sql.push_str(&format!(
+            "insert or ignore into p1_measurements values ({}, {}, {}, {}, {});\n",
+            m.timestamp, m.peak_conso_kWh, m.off_conso_kWh, m.peak_inj_kWh, m.off_inj_kWh
+        ));
+    }
+    for r in &rebuild.phase_minutes {
+        sql.push_str(&format!(
+            "insert or replace into p1_phase_minutes values ({}, {}, {}, {}, {}, {}, {});\n",
+            r.timestamp,
+            r.phase,
+            &some_val_to_sql(r.max_current_A),
+            &some_val_to_sql(r.avg_current_A),
+            &some_val_to_sql(r.avg_voltage_V),
+            &some_val_to_sql(r.avg_import_kW),
+            &some_val_to_sql(r.avg_export_kW),
+        ));
+    }
+    for f in &rebuild.power_failures {
+        sql.push_str(&format!(
+            "insert or ignore into p1_power_failures values ({}, {});\n",
+            f.end_timestamp, f.duration_s
+        ));
+    }
+    for (timestamp, counter, count) in &rebuild.counters {
+        sql.push_str(&format!(
+            "insert or ignore into p1_power_quality_counters values ({}, '{}', {});\n",
+            timestamp, counter, count
+        ));
+    }
+    for c in &rebuild.appliance_clusters {
+        sql.push_str(&format!(
+            "insert into p1_appliance_clusters (id, phase, power_kW, events) values ({}, {}, {}, {}) on conflict (id) do update set power_kW = excluded.power_kW;\n",
+            c.id, c.phase, c.power_kW, c.events
+        ));
+    }
+    for e in &rebuild.appliance_events {
+        sql.push_str(&format!(
+            "insert or ignore into p1_appliance_events values ({}, {}, {}, {});\n",
+            e.timestamp, e.phase, e.delta_kW, e.cluster
+        ));
+    }
+    sql.push_str("update p1_appliance_clusters set events = (select count(*) from p1_appliance_events where cluster = p1_appliance_clusters.id);\nCOMMIT;\nselect total_changes();");
+    let sql_output = call_sqlite3(cmd, &sql);
+    usize::from_str(sql_output.trim()).map_err(|e| format!("{}", e))
+}
+
+/// Per phase values aggregated over one minute
+#[derive(Debug, PartialEq)]
+#[allow(non_snake_case)]
+pub struct PhaseMinuteRow {
+    /// Start of the minute
+    pub timestamp: i64,
+    /// 1, 2 or 3
+    pub phase: u8,
+    pub max_current_A: Option<f64>,
+    pub avg_current_A: Option<f64>,
+    pub avg_voltage_V: Option<f64>,
+    pub avg_import_kW: Option<f64>,
+    pub avg_export_kW: Option<f64>,
+}
+
+pub fn insert_phase_minutes(cmd: &str, rows: &[PhaseMinuteRow]) -> Result<usize, String> {
+    let mut sql = String::from(".mode list\n");
+    sql.push_str(CREATE_P1_PHASE_MINUTES);
+    sql.push_str("\nBEGIN TRANSACTION;\n");
+    for r in rows {
+        sql.push_str(&format!(
+            "insert or replace into p1_phase_minutes values ({}, {}, {}, {}, {}, {}, {});\n",
+            r.timestamp,
+            r.phase,
+            &some_val_to_sql(r.max_current_A),
+            &some_val_to_sql(r.avg_current_A),
+            &some_val_to_sql(r.avg_voltage_V),
+            &some_val_to_sql(r.avg_import_kW),
+            &some_val_to_sql(r.avg_export_kW)
+        ));
+    }
+    sql.push_str("COMMIT;\nselect total_changes();");
+    let sql_output = call_sqlite3(cmd, &sql);
+    usize::from_str(sql_output.trim()).map_err(|e| format!("{}", e))
+}
+
+#[derive(Debug, PartialEq)]
+#[allow(non_snake_case)]
+pub struct PhaseSummaryRow {
+    pub phase: u8,
+    pub minutes: i64,
+    pub peak_current_A: Option<f64>,
+    /// Minutes with a current at or above the threshold
+    pub minutes_near_breaker: i64,
+    /// Average of import minus export
+    pub avg_net_kW: Option<f64>,
+}
+
+pub fn select_phase_summary(
+    cmd: &str,
+    since: i64,
+    threshold_a: f64,
+) -> Result<Vec<PhaseSummaryRow>, String> {
+    let sql_output = call_sqlite3(
+        cmd,
+        &format!(
+            ".mode list\n{}\nselect phase, count(*), max(max_current_A), sum(max_current_A >= {}), avg(avg_import_kW - avg_export_kW) from p1_phase_minutes where timestamp >= {} group by phase order by phase;",
+            CREATE_P1_PHASE_MINUTES, threshold_a, since
+        ),
+    );
+    let mut result = Vec::new();
+    for line in sql_output.lines() {
+        let mut cols = line.split('|');
+        result.push(PhaseSummaryRow {
+            phase: parse_i64_column(cols.next(), "phase")? as u8,
+            minutes: parse_i64_column(cols.next(), "minutes")?,
+            peak_current_A: some_str_to_result(cols.next(), f64::from_str)?,
+            minutes_near_breaker: some_str_to_result(cols.next(), i64::from_str)?.unwrap_or(0),
+            avg_net_kW: some_str_to_result(cols.next(), f64::from_str)?,
+        })
+    }
+    Ok(result)
+}
+
+#[derive(Debug, PartialEq)]
+#[allow(non_snake_case)]
+pub struct DailyPhaseRow {
+    /// YYYY-MM-DD in the local time of the database host
+    pub day: String,
+    /// Difference between the highest and the lowest phase current
+    pub avg_imbalance_A: Option<f64>,
+    pub max_imbalance_A: Option<f64>,
+    pub avg_net_kW: [Option<f64>; 3],
+}
+
+pub fn select_daily_phase_imbalance(cmd: &str, since: i64) -> Result<Vec<DailyPhaseRow>, String> {
+    let sql_output = call_sqlite3(
+        cmd,
+        &format!(
+            ".mode list\n{}\nselect date(timestamp, 'unixepoch', 'localtime') as day, avg(spread), max(spread), avg(l1), avg(l2), avg(l3) from (select timestamp, max(avg_current_A) - min(avg_current_A) as spread, sum(case when phase = 1 then avg_import_kW - avg_export_kW end) as l1, sum(case when phase = 2 then avg_import_kW - avg_export_kW end) as l2, sum(case when phase = 3 then avg_import_kW - avg_export_kW end) as l3 from p1_phase_minutes where timestamp >= {} group by timestamp having count(*) = 3) group by day order by day desc;",
+            CREATE_P1_PHASE_MINUTES, since
+        ),
+    );
+    let mut result = Vec::new();
+    for line in sql_output.lines() {
+        let mut cols = line.split('|');
+        let day = match cols.next() {
+            Some(day) => day.to_string(),
+            None => return Err("No day".to_string()),
+        };
+        result.push(DailyPhaseRow {
+            day,
+            avg_imbalance_A: some_str_to_result(cols.next(), f64::from_str)?,
+            max_imbalance_A: some_str_to_result(cols.next(), f64::from_str)?,
+            avg_net_kW: [
+                some_str_to_result(cols.next(), f64::from_str)?,
+                some_str_to_result(cols.next(), f64::from_str)?,
+                some_str_to_result(cols.next(), f64::from_str)?,
+            ],
+        })
+    }
+    Ok(result)
+}
+
+/// Average import over a slot of `p1_phase_minutes`
+#[derive(Debug, PartialEq)]
+#[allow(non_snake_case)]
+pub struct PowerSlotRow {
+    pub slot: i64,
+    pub avg_import_kW: f64,
+    pub max_export_kW: f64,
+}
+
+/// Sum of the per phase imports (and exports) per minute, averaged over
+/// slots of `slot_s` seconds.
+pub fn select_power_slots(cmd: &str, since: i64, slot_s: i64) -> Result<Vec<PowerSlotRow>, String> {
+    let sql_output = call_sqlite3(
+        cmd,
+        &format!(
+            ".mode list\n{}\nselect timestamp - timestamp % {} as slot, avg(import), max(export) from (select timestamp, sum(avg_import_kW) as import, sum(avg_export_kW) as export from p1_phase_minutes where timestamp >= {} group by timestamp) where import is not null group by slot order by slot;",
+            CREATE_P1_PHASE_MINUTES, slot_s, since
+        ),
+    );
+    let mut result = Vec::new();
+    for line in sql_output.lines() {
+        let mut cols = line.split('|');
+        result.push(PowerSlotRow {
+            slot: parse_i64_column(cols.next(), "slot")?,
+            avg_import_kW: some_str_to_result(cols.next(), f64::from_str)?.unwrap_or(0.0),
+            max_export_kW: some_str_to_result(cols.next(), f64::from_str)?.unwrap_or(0.0),
+        })
+    }
+    Ok(result)
+}
+
+/// Counter increases between the first and last `p1_measurements` of a slot
+#[derive(Debug, PartialEq)]
+#[allow(non_snake_case)]
+pub struct CounterSlotRow {
+    pub slot: i64,
+    /// Time between the first and the last measurement of the slot
+    pub span_s: i64,
+    pub consumed_kWh: f64,
+    pub injected_kWh: f64,
+}
+
+pub fn select_counter_slots(
+    cmd: &str,
+    since: i64,
+    slot_s: i64,
+) -> Result<Vec<CounterSlotRow>, String> {
+    let sql_output = call_sqlite3(
+        cmd,
+        &format!(
+            ".mode list\n{}\nselect timestamp - timestamp % {} as slot, max(timestamp) - min(timestamp), max(peak_conso_kWh + off_conso_kWh) - min(peak_conso_kWh + off_conso_kWh), max(peak_inj_kWh + off_inj_kWh) - min(peak_inj_kWh + off_inj_kWh) from p1_measurements where timestamp >= {} group by slot order by slot;",
+            CREATE_P1_MEASUREMENTS, slot_s, since
+        ),
+    );
+    let mut result = Vec::new();
+    for line in sql_output.lines() {
+        let mut cols = line.split('|');
+        result.push(CounterSlotRow {
+            slot: parse_i64_column(cols.next(), "slot")?,
+            span_s: parse_i64_column(cols.next(), "span")?,
+            consumed_kWh: some_str_to_result(cols.next(), f64::from_str)?.unwrap_or(0.0),
+            injected_kWh: some_str_to_result(cols.next(), f64::from_str)?.unwrap_or(0.0),
+        })
+    }
+    Ok(result)
+}
+
+pub const CREATE_P1_APPLIANCES: &str = "CREATE TABLE IF NOT EXISTS p1_appliance_clusters (id INTEGER PRIMARY KEY, phase INTEGER NOT NULL, power_kW FLOAT NOT NULL, events INTEGER NOT NULL, label TEXT);\nCREATE TABLE IF NOT EXISTS p1_appliance_events (timestamp INTEGER NOT NULL, phase INTEGER NOT NULL, delta_kW FLOAT NOT NULL, cluster INTEGER NOT NULL, PRIMARY KEY (timestamp, phase));";
+
+/// Signature of the steps of one appliance
+#[derive(Debug, PartialEq, Clone)]
+#[allow(non_snake_case)]
+pub struct ApplianceClusterRow {
+    pub id: i64,
+    pub phase: u8,
+    /// Average size of the steps (on and off)
+    pub power_kW: f64,
+    pub events: i64,
+    pub label: Option<String>,
+}
+
+#[derive(Debug, PartialEq)]
+#[allow(non_snake_case)]
+pub struct ApplianceEventRow {
+    pub timestamp: i64,
+    pub phase: u8,
+    /// Positive when switching on
+    pub delta_kW: f64,
+    pub cluster: i64,
+}
+
+fn sql_string(s: &str) -> String {
+    format!("'{}'", s.replace('\'', "''"))
+}
+
+/// Store an event and the updated signature of its cluster (keeping its label).
+pub fn insert_appliance_event(
+    cmd: &str,
+    event: &ApplianceEventRow,
+    cluster: &ApplianceClusterRow,
+) -> Result<usize, String> {
+    let sql_output = call_sqlite3(
+        cmd,
+        &format!(
+            ".mode list\n{}\nBEGIN TRANSACTION;\ninsert into p1_appliance_clusters (id, phase, power_kW, events) values ({}, {}, {}, {}) on conflict (id) do update set power_kW = excluded.power_kW, events = excluded.events;\ninsert or ignore into p1_appliance_events values ({}, {}, {}, {});\nCOMMIT;\nselect total_changes();",
+            CREATE_P1_APPLIANCES,
+            cluster.id,
+            cluster.phase,
+            cluster.power_kW,
+            cluster.events,
+            event.timestamp,
+            event.phase,
+            event.delta_kW,
+            event.cluster
+        ),
+    );
+    usize::from_str(sql_output.trim()).map_err(|e| format!("{}", e))
+}
+
+pub fn select_appliance_clusters(cmd: &str) -> Result<Vec<ApplianceClusterRow>, String> {
+    let sql_output = call_sqlite3(
+        cmd,
+        &format!(
+            ".mode list\n{}\nselect id, phase, power_kW, events, label from p1_appliance_clusters order by id;",
+            CREATE_P1_APPLIANCES
+        ),
+    );
+    let mut result = Vec::new();
+    for line in sql_output.lines() {
+        let mut cols = line.splitn(5, '|');
+        result.push(ApplianceClusterRow {
+            id: parse_i64_column(cols.next(), "id")?,
+            phase: parse_i64_column(cols.next(), "phase")? as u8,
+            power_kW: some_str_to_result(cols.next(), f64::from_str)?
+                .ok_or_else(|| "No power".to_string())?,
+            events: parse_i64_column(cols.next(), "events")?,
+            label: cols
+                .next()
+                .filter(|label| !label.is_empty())
+                .map(|label| label.to_string()),
+        })
+    }
+    Ok(result)
+}
+
+/// Events since `since`, oldest first
+pub fn select_appliance_events(cmd: &str, since: i64) -> Result<Vec<ApplianceEventRow>, String> {
+    let sql_output = call_sqlite3(
+        cmd,
+        &format!(
+            ".mode list\n{}\nselect timestamp, phase, delta_kW, cluster from p1_appliance_events where timestamp >= {} order by timestamp;",
+            CREATE_P1_APPLIANCES, since
+        ),
+    );
+    let mut result = Vec::new();
+    for line in sql_output.lines() {
+        let mut cols = line.split('|');
+        result.push(ApplianceEventRow {
+            timestamp: parse_i64_column(cols.next(), "timestamp")?,
+            phase: parse_i64_column(cols.next(), "phase")? as u8,
+            delta_kW: some_str_to_result(cols.next(), f64::from_str)?
+                .ok_or_else(|| "No delta".to_string())?,
+            cluster: parse_i64_column(cols.next(), "cluster")?,
+        })
+    }
+    Ok(result)
+}
+
+/// Name a cluster, an empty label removes it.  Returns the number of
+/// clusters updated.
+pub fn update_appliance_label(cmd: &str, id: i64, label: &str) -> Result<usize, String> {
+    let label = label.trim();
+    let sql_output = call_sqlite3(
+        cmd,
+        &format!(
+            ".mode list\n{}\nupdate p1_appliance_clusters set label = {} where id = {};\nselect changes();",
+            CREATE_P1_APPLIANCES,
+            if label.is_empty() {
+                "NULL".to_string()
+            } else {
+                sql_string(label)
+            },
+            id
+        ),
+    );
+    usize::from_str(sql_output.trim()).map_err(|e| format!("{}", e))
+}
+
+pub const CREATE_P1_CLOCK_OFFSETS: &str = "CREATE TABLE IF NOT EXISTS p1_clock_offsets (timestamp INTEGER PRIMARY KEY ASC, samples INTEGER NOT NULL, min_offset_s FLOAT NOT NULL, avg_offset_s FLOAT NOT NULL, max_offset_s FLOAT NOT NULL);";
+
+/// Meter clock minus reception time, over the telegrams of a period
+#[derive(Debug, PartialEq)]
+pub struct ClockOffsetRow {
+    /// Start of the period (reception time)
+    pub timestamp: i64,
+    pub samples: i64,
+    pub min_offset_s: f64,
+    pub avg_offset_s: f64,
+    pub max_offset_s: f64,
+}
+
+pub fn insert_clock_offset(cmd: &str, row: &ClockOffsetRow) -> Result<usize, String> {
+    let sql_output = call_sqlite3(
+        cmd,
+        &format!(
+            ".mode list\n{}\ninsert or replace into p1_clock_offsets values ({}, {}, {}, {}, {});\nselect total_changes();",
+            CREATE_P1_CLOCK_OFFSETS,
+            row.timestamp,
+            row.samples,
+            row.min_offset_s,
+            row.avg_offset_s,
+            row.max_offset_s
+        ),
+    );
+    usize::from_str(sql_output.trim()).map_err(|e| format!("{}", e))
+}
+
+/// Offsets since `since`, combined into periods of `period_s` seconds
+pub fn select_clock_offsets(
+    cmd: &str,
+    since: i64,
+    period_s: i64,
+) -> Result<Vec<ClockOffsetRow>, String> {
+    let sql_output = call_sqlite3(
+        cmd,
+        &format!(
+            ".mode list\n{}\nselect timestamp - timestamp % {period_s} as period, sum(samples), min(min_offset_s), sum(avg_offset_s * samples) / sum(samples), max(max_offset_s) from p1_clock_offsets where timestamp >= {since} group by period order by period;",
+            CREATE_P1_CLOCK_OFFSETS,
+        ),
+    );
+    let mut result = Vec::new();
+    for line in sql_output.lines() {
+        let mut cols = line.split('|');
+        let timestamp = parse_i64_column(cols.next(), "period")?;
+        let samples = parse_i64_column(cols.next(), "samples")?;
+        let mut offset = |name: &str| {
+            some_str_to_result(cols.next(), f64::from_str)?
+                .ok_or_else(|| format!("No {} offset", name))
+        };
+        result.push(ClockOffsetRow {
+            timestamp,
+            samples,
+            min_offset_s: offset("minimum")?,
+            avg_offset_s: offset("average")?,
+            max_offset_s: offset("maximum")?,
+        })
+    }
+    Ok(result)
+}
+
+pub const CREATE_INVERTER_PRODUCTION: &str = "CREATE TABLE IF NOT EXISTS inverter_production (installation TEXT NOT NULL, timestamp INTEGER NOT NULL, total_yield_kWh FLOAT, daily_yield_kWh FLOAT, ac_power_W FLOAT, grid_state TEXT, device_status TEXT, PRIMARY KEY (installation, timestamp));";
+
+/// Values polled from the inverter of an installation
+#[derive(Debug, PartialEq)]
+#[allow(non_snake_case)]
+pub struct ProductionRow {
+    /// Name in `RUST_HELLO_WORLD_PV_INSTALLATIONS`
+    pub installation: String,
+    pub timestamp: i64,
+    pub total_yield_kWh: Option<f64>,
+    pub daily_yield_kWh: Option<f64>,
+    pub ac_power_W: Option<f64>,
+    pub grid_state: Option<String>,
+    pub device_status: Option<String>,
+}
+
+pub fn insert_production(cmd: &str, row: &ProductionRow) -> Result<usize, String> {
+    let text = |s: &Option<String>| s.as_deref().map_or("NULL".to_string(), sql_string);
+    let sql_output = call_sqlite3(
+        cmd,
+        &format!(
+            ".mode list\n{}\ninsert or replace into inverter_production values ({}, {}, {}, {}, {}, {}, {});\nselect total_changes();",
+            CREATE_INVERTER_PRODUCTION,
+            sql_string(&row.installation),
+            row.timestamp,
+            some_val_to_sql(row.total_yield_kWh),
+            some_val_to_sql(row.daily_yield_kWh),
+            some_val_to_sql(row.ac_power_W),
+            text(&row.grid_state),
+            text(&row.device_status)
+        ),
+    );
+    usize::from_str(sql_output.trim()).map_err(|e| format!("{}", e))
+}
+
+pub const CREATE_INVERTER_EVENTS: &str = "CREATE TABLE IF NOT EXISTS inverter_events (installation TEXT NOT NULL, timestamp INTEGER NOT NULL, code INTEGER NOT NULL, severity TEXT NOT NULL, PRIMARY KEY (installation, timestamp, code));";
+
+/// An entry of the event log of the inverter of an installation
+#[derive(Debug, PartialEq, Clone)]
+pub struct InverterEventRow {
+    pub installation: String,
+    pub timestamp: i64,
+    pub code: i64,
+    pub severity: String,
+}
+
+/// An inverter event and the highest average P1 voltage of each phase in the
+/// 10 minutes up to it, if recorded
+#[derive(Debug, PartialEq)]
+#[allow(non_snake_case)]
+pub struct EventVoltageRow {
+    pub event: InverterEventRow,
+    pub max_voltage_V: [Option<f64>; 3],
+}
+
+/// Insert the events in one transaction, keeping those already in the table.
+/// Returns the number of new events.
+pub fn insert_inverter_events(cmd: &str, rows: &[InverterEventRow]) -> Result<usize, String> {
+    let mut sql = String::from(".mode list\n");
+    sql.push_str(CREATE_INVERTER_EVENTS);
+    sql.push_str("\nBEGIN TRANSACTION;\n");
+    for row in rows {
+        sql.push_str(&format!(
+            "insert or ignore into inverter_events values ({}, {}, {}, {});\n",
+            sql_string(&row.installation),
+            row.timestamp,
+            row.code,
+            sql_string(&row.severity)
+        ));
+    }
+    sql.push_str("COMMIT;\nselect total_changes();");
+    let sql_output = call_sqlite3(cmd, &sql);
+    usize::from_str(sql_output.trim()).map_err(|e| format!("{}", e))
+}
+
+/// Events since `since`, most recent first
+pub fn select_inverter_events(cmd: &str, since: i64) -> Result<Vec<EventVoltageRow>, String> {
+    let sql_output = call_sqlite3(
+        cmd,
+        &format!(
+            ".mode list\n{}\n{}\nselect e.installation, e.timestamp, e.code, e.severity, max(case when p.phase = 1 then p.avg_voltage_V end), max(case when p.phase = 2 then p.avg_voltage_V end), max(case when p.phase = 3 then p.avg_voltage_V end) from inverter_events e left join p1_phase_minutes p on p.timestamp between e.timestamp - 600 and e.timestamp where e.timestamp >= {} group by e.installation, e.timestamp, e.code order by e.timestamp desc, e.installation;",
+            CREATE_INVERTER_EVENTS, CREATE_P1_PHASE_MINUTES, since
+        ),
+    );
+    let mut result = Vec::new();
+    for line in sql_output.lines() {
+        let mut cols = line.split('|');
+        let installation = cols.next().unwrap_or_default().to_string();
+        let timestamp = parse_i64_column(cols.next(), "timestamp")?;
+        let code = parse_i64_column(cols.next(), "code")?;
+        let severity = cols.next().unwrap_or_default().to_string();
+        result.push(EventVoltageRow {
+            event: InverterEventRow {
+                installation,
+                timestamp,
+                code,
+                severity,
+            },
+            max_voltage_V: [
+                some_str_to_result(cols.next(), f64::from_str)?,
+                some_str_to_result(cols.next(), f64::from_str)?,
+                some_str_to_result(cols.next(), f64::from_str)?,
+            ],
+        })
+    }
+    Ok(result)
+}
+
+/// Insert the rows in one transaction, keeping those already in the table
+pub fn insert_new_production(cmd: &str, rows: &[ProductionRow]) -> Result<usize, String> {
+    let text = |s: &Option<String>| s.as_deref().map_or("NULL".to_string(), sql_string);
+    let mut sql = String::from(".mode list\n");
+    sql.push_str(CREATE_INVERTER_PRODUCTION);
+    sql.push_str("\nBEGIN TRANSACTION;\n");
+    for row in rows {
+        sql.push_str(&format!(
+            "insert or ignore into inverter_production values ({}, {}, {}, {}, {}, {}, {});\n",
+            sql_string(&row.installation),
+            row.timestamp,
+            some_val_to_sql(row.total_yield_kWh),
+            some_val_to_sql(row.daily_yield_kWh),
+            some_val_to_sql(row.ac_power_W),
+            text(&row.grid_state),
+            text(&row.device_status)
+        ));
+    }
+    sql.push_str("COMMIT;\nselect total_changes();");
+    let sql_output = call_sqlite3(cmd, &sql);
+    usize::from_str(sql_output.trim()).map_err(|e| format!("{}", e))
+}
+
+/// Times of the values of `installation` with a total yield, from `from` to
+/// `to` included, in order
+pub fn select_production_timestamps(
+    cmd: &str,
+    installation: &str,
+    from: i64,
+    to: i64,
+) -> Result<Vec<i64>, String> {
+    let sql_output = call_sqlite3(
+        cmd,
+        &format!(
+            ".mode list\n{}\nselect timestamp from inverter_production where installation = {} and total_yield_kWh is not null and timestamp between {} and {} order by timestamp;",
+            CREATE_INVERTER_PRODUCTION,
+            sql_string(installation),
+            from,
+            to
+        ),
+    );
+    sql_output
+        .lines()
+        .map(|line| parse_i64_column(Some(line), "timestamp"))
+        .collect()
+}
+
+/// Most recent values of `installation` with a total yield, if any
+pub fn select_latest_production(
+    cmd: &str,
+    installation: &str,
+) -> Result<Option<ProductionRow>, String> {
+    let sql_output = call_sqlite3(
+        cmd,
+        &format!(
+            ".mode list\n{}\nselect timestamp, total_yield_kWh, daily_yield_kWh, ac_power_W, grid_state, device_status from inverter_production where installation = {} and total_yield_kWh is not null order by timestamp desc limit 1;",
+            CREATE_INVERTER_PRODUCTION,
+            sql_string(installation)
+        ),
+    );
+    let line = match sql_output.lines().next() {
+        Some(line) => line,
+        None => return Ok(None),
+    };
+    let mut cols = line.split('|');
+    Ok(Some(ProductionRow {
+        installation: installation.to_string(),
+        timestamp: parse_i64_column(cols.next(), "timestamp")?,
+        total_yield_kWh: some_str_to_result(cols.next(), f64::from_str)?,
+        daily_yield_kWh: some_str_to_result(cols.next(), f64::from_str)?,
+        ac_power_W: some_str_to_result(cols.next(), f64::from_str)?,
+        grid_state: cols.next().filter(|s| !s.is_empty()).map(|s| s.to_string()),
+        device_status: cols.next().filter(|s| !s.is_empty()).map(|s| s.to_string()),
+    }))
+}
+
+/// Highest total yield of an installation over an hour
+#[derive(Debug, PartialEq)]
+#[allow(non_snake_case)]
+pub struct HourlyYieldRow {
+    pub installation: String,
+    /// The hour covers the values after `hour_start` up to one hour later
+    /// included: a value at midnight closes the previous day.
+    pub hour_start: i64,
+    pub total_yield_kWh: f64,
+}
+
+fn parse_hourly_yields(sql_output: &str) -> Result<Vec<HourlyYieldRow>, String> {
+    let mut result = Vec::new();
+    for line in sql_output.lines() {
+        let mut cols = line.split('|');
+        let installation = cols.next().unwrap_or_default().to_string();
+        let hour_start = parse_i64_column(cols.next(), "hour")?;
+        let total_yield = some_str_to_result(cols.next(), f64::from_str)?
+            .ok_or_else(|| "No total yield".to_string())?;
+        result.push(HourlyYieldRow {
+            installation,
+            hour_start,
+            total_yield_kWh: total_yield,
+        })
+    }
+    Ok(result)
+}
+
+/// Highest total yield of each installation per hour, after `since`, in
+/// order
+pub fn select_hourly_total_yields(cmd: &str, since: i64) -> Result<Vec<HourlyYieldRow>, String> {
+    let sql_output = call_sqlite3(
+        cmd,
+        &format!(
+            ".mode list\n{}\nselect installation, (timestamp - 1) / 3600 * 3600, max(total_yield_kWh) from inverter_production where total_yield_kWh is not null and timestamp > {} group by 1, 2 order by 1, 2;",
+            CREATE_INVERTER_PRODUCTION, since
+        ),
+    );
+    parse_hourly_yields(&sql_output)
+}
+
+pub const CREATE_DATA_202208: &str = "CREATE TABLE IF NOT EXISTS data_202208 (timestamp INTEGER PRIMARY KEY ASC, pv2012_kWh FLOAT, pv2022_kWh FLOAT, peak_conso_kWh FLOAT, off_conso_kWh FLOAT, gas_m3 FLOAT, water_m3 FLOAT);";
+pub const CREATE_DATA_202303: &str = "CREATE TABLE IF NOT EXISTS data_202303 (timestamp INTEGER PRIMARY KEY ASC, pv2012_kWh FLOAT, pv2022_kWh FLOAT, peak_conso_kWh FLOAT, off_conso_kWh FLOAT, peak_inj_kWh FLOAT, off_inj_kWh FLOAT, gas_m3 FLOAT, water_m3 FLOAT);";
+
+/// Installations read by hand, with a column of their total yield in
+/// `data_202208` and `data_202303`
+pub const MANUAL_INSTALLATIONS: [&str; 2] = ["pv2012", "pv2022"];
+
+/// Highest total yield of each of the `MANUAL_INSTALLATIONS` per hour, as
+/// read by hand, after `since`, in order
+pub fn select_manual_total_yields(cmd: &str, since: i64) -> Result<Vec<HourlyYieldRow>, String> {
+    let select = |installation: &str| {
+        format!(
+            "select '{0}', (timestamp - 1) / 3600 * 3600, max({0}_kWh) from readings where {0}_kWh is not null and timestamp > {1} group by 2",
+            installation, since
+        )
+    };
+    let selects: Vec<String> = MANUAL_INSTALLATIONS
+        .iter()
+        .map(|name| select(name))
+        .collect();
+    let sql_output = call_sqlite3(
+        cmd,
+        &format!(
+            ".mode list\n{}\n{}\nwith readings as (select timestamp, pv2012_kWh, pv2022_kWh from data_202208 union all select timestamp, pv2012_kWh, pv2022_kWh from data_202303) {} order by 1, 2;",
+            CREATE_DATA_202208,
+            CREATE_DATA_202303,
+            selects.join(" union all ")
+        ),
+    );
+    parse_hourly_yields(&sql_output)
 }
 
 fn some_str_to_result<B, C, F>(a: Option<&str>, f: F) -> Result<Option<B>, String>
@@ -87,7 +1011,7 @@ where
     match a {
         None => Ok(None),
         Some(s) => {
-            if s.trim().len() == 0 {
+            if s.trim().is_empty() {
                 Ok(None)
             } else {
                 f(s).map(Some).map_err(|e| format!("{}", e))
@@ -113,7 +1037,7 @@ pub fn select_data_202208(cmd: &str) -> Result<Vec<Data202208>, String> {
     };
     let mut result = Vec::<Data202208>::with_capacity(count);
     for line in info {
-        let mut cols = line.split("|");
+        let mut cols = line.split('|');
         let timestamp = match cols.next().map(i64::from_str) {
             Some(Ok(ts)) => ts,
             None => {
@@ -131,7 +1055,7 @@ pub fn select_data_202208(cmd: &str) -> Result<Vec<Data202208>, String> {
             water_m3: some_str_to_result(cols.next(), f64::from_str)?,
         })
     }
-    return Ok(result);
+    Ok(result)
 }
 
 pub fn select_data_202303(cmd: &str) -> Result<Vec<Data202303>, String> {
@@ -151,7 +1075,7 @@ pub fn select_data_202303(cmd: &str) -> Result<Vec<Data202303>, String> {
     };
     let mut result = Vec::<Data202303>::with_capacity(count);
     for line in info {
-        let mut cols = line.split("|");
+        let mut cols = line.split('|');
         let timestamp = match cols.next().map(i64::from_str) {
             Some(Ok(ts)) => ts,
             None => {
@@ -171,7 +1095,12 @@ pub fn select_data_202303(cmd: &str) -> Result<Vec<Data202303>, String> {
             water_m3: some_str_to_result(cols.next(), f64::from_str)?,
         })
     }
-    return Ok(result);
+    Ok(result)
+}
+
+/// Shell command running the sqlite3 CLI on `database`, for `call_sqlite3`.
+pub fn sqlite3_command(database: &str) -> String {
+    format!("sqlite3 '{}'", database.replace('\'', "'\\''"))
 }
 
 pub fn call_sqlite3(cmd: &str, input: &str) -> String {
@@ -188,9 +1117,8 @@ pub fn call_sqlite3(cmd: &str, input: &str) -> String {
 
     // stdin has type Option<ChildStdin>, but since we know this instance
     // must have one, we can directly unwrap it.
-    match process.stdin.unwrap().write_all(input.as_bytes()) {
-        Err(why) => panic!("couldn't write to sqlite3 stdin: {}", why),
-        Ok(_) => {}
+    if let Err(why) = process.stdin.unwrap().write_all(input.as_bytes()) {
+        panic!("couldn't write to sqlite3 stdin: {}", why)
     }
 
     // Because stdin does not live after the above calls, it is drop-ed,
@@ -201,11 +1129,10 @@ pub fn call_sqlite3(cmd: &str, input: &str) -> String {
 
     // The stdout field also has type Option<ChildStdout> so must be unwrapped.
     let mut s = String::new();
-    match process.stdout.unwrap().read_to_string(&mut s) {
-        Err(why) => panic!("couldn't read sqlite3 stdout: {}", why),
-        Ok(_) => {}
+    if let Err(why) = process.stdout.unwrap().read_to_string(&mut s) {
+        panic!("couldn't read sqlite3 stdout: {}", why)
     }
-    return s;
+    s
 }
 
 #[cfg(test)]
@@ -221,7 +1148,7 @@ mod tests {
     #[test]
     fn count_and_select_data_202208() {
         let result = select_data_202208(
-            "echo '2\n1356994800|487.0|0.0|82313.0|35983.0|9203.0|-393.0\n1359673200|553.0||82564.0|36184.0|9685.0|-385.0'"
+            "cat > /dev/null; echo '2\n1356994800|487.0|0.0|82313.0|35983.0|9203.0|-393.0\n1359673200|553.0||82564.0|36184.0|9685.0|-385.0'"
         ).unwrap();
         assert_eq!(result.len(), 2);
         assert_eq!(
@@ -253,7 +1180,7 @@ mod tests {
     #[test]
     fn count_and_select_data_202303() {
         let result = select_data_202303(
-            "echo '2\n1695485100|50621.3|3579.4|||630.0|1189.4|28973.5|867.5\n1695537420||3579.9||||||'"
+            "cat > /dev/null; echo '2\n1695485100|50621.3|3579.4|||630.0|1189.4|28973.5|867.5\n1695537420||3579.9||||||'"
         ).unwrap();
         assert_eq!(result.len(), 2);
         assert_eq!(
@@ -304,4 +1231,409 @@ mod tests {
         );
         assert_eq!(result.unwrap(), 1234)
     }
+
+    #[test]
+    fn can_insert_p1_measurements() {
+        let result = insert_p1_measurements(
+            "sed -n -e '/^insert or ignore into p1_measurements values (1729814400, 2654\\.919, 2420\\.293, 6254\\.732, 2457\\.202);$/{ s/.*/1/; h }' -e '/^COMMIT;$/{ x; p }'",
+            &[P1Measurement {
+                timestamp: 1729814400,
+                peak_conso_kWh: 2654.919,
+                off_conso_kWh: 2420.293,
+                peak_inj_kWh: 6254.732,
+                off_inj_kWh: 2457.202,
+            }],
+        );
+        assert_eq!(result.unwrap(), 1)
+    }
+
+    #[test]
+    fn can_insert_power_quality() {
+        let result = insert_power_quality(
+            "grep -c -e '^insert or ignore into p1_power_failures values (1291821855, 240);$' -e \"^insert or ignore into p1_power_quality_counters values (1729814400, 'voltage_swell_L2', 3);$\"",
+            &[PowerFailureRow {
+                end_timestamp: 1291821855,
+                duration_s: 240,
+            }],
+            1729814400,
+            &[("voltage_swell_L2".to_string(), 3)],
+        );
+        assert_eq!(result.unwrap(), 2)
+    }
+
+    #[test]
+    fn select_power_quality_events_and_failures() {
+        assert_eq!(
+            select_power_quality_events("cat > /dev/null; echo '1729814400|voltage_swell_L2|3|1'")
+                .unwrap(),
+            vec![PowerQualityEventRow {
+                timestamp: 1729814400,
+                counter: "voltage_swell_L2".to_string(),
+                count: 3,
+                increment: 1
+            }]
+        );
+        assert_eq!(
+            select_power_failures("cat > /dev/null; echo '1291821855|240\n1291821004|301'")
+                .unwrap(),
+            vec![
+                PowerFailureRow {
+                    end_timestamp: 1291821855,
+                    duration_s: 240
+                },
+                PowerFailureRow {
+                    end_timestamp: 1291821004,
+                    duration_s: 301
+                }
+            ]
+        );
+        assert!(select_power_failures("cat > /dev/null; echo 'x|240'").is_err());
+    }
+
+    #[test]
+    fn insert_new_p1_measurements_reports_duplicates() {
+        let meas = || P1Measurement {
+            timestamp: 1729814400,
+            peak_conso_kWh: 1.5,
+            off_conso_kWh: 2.5,
+            peak_inj_kWh: 0.0,
+            off_inj_kWh: 3.0,
+        };
+        assert_eq!(
+            insert_new_p1_measurements(
+                "grep -c '^insert or ignore into p1_measurements values (1729814400, 1.5, 2.5, 0, 3);$' > /dev/null && echo '1\n0'",
+                &[meas(), meas()]
+            ),
+            Ok(vec![true, false])
+        );
+        assert!(insert_new_p1_measurements("cat > /dev/null; echo 1", &[meas(), meas()]).is_err());
+    }
+
+    #[test]
+    fn can_insert_phase_minutes() {
+        let result = insert_phase_minutes(
+            "grep -c '^insert or replace into p1_phase_minutes values (1729814400, 1, 12, 10.5, 231.2, NULL, 2.1);$'",
+            &[PhaseMinuteRow {
+                timestamp: 1729814400,
+                phase: 1,
+                max_current_A: Some(12.0),
+                avg_current_A: Some(10.5),
+                avg_voltage_V: Some(231.2),
+                avg_import_kW: None,
+                avg_export_kW: Some(2.1),
+            }],
+        );
+        assert_eq!(result.unwrap(), 1)
+    }
+
+    #[test]
+    fn select_phase_summary_and_daily_imbalance() {
+        assert_eq!(
+            select_phase_summary(
+                "cat > /dev/null; echo '1|1440|27.0|12|-0.25\n2|1440|3.0||0.3'",
+                0,
+                32.0
+            )
+            .unwrap(),
+            vec![
+                PhaseSummaryRow {
+                    phase: 1,
+                    minutes: 1440,
+                    peak_current_A: Some(27.0),
+                    minutes_near_breaker: 12,
+                    avg_net_kW: Some(-0.25)
+                },
+                PhaseSummaryRow {
+                    phase: 2,
+                    minutes: 1440,
+                    peak_current_A: Some(3.0),
+                    minutes_near_breaker: 0,
+                    avg_net_kW: Some(0.3)
+                }
+            ]
+        );
+        assert_eq!(
+            select_daily_phase_imbalance(
+                "cat > /dev/null; echo '2024-10-25|5.5|20.0|-0.5|0.25|0.125'",
+                0
+            )
+            .unwrap(),
+            vec![DailyPhaseRow {
+                day: "2024-10-25".to_string(),
+                avg_imbalance_A: Some(5.5),
+                max_imbalance_A: Some(20.0),
+                avg_net_kW: [Some(-0.5), Some(0.25), Some(0.125)]
+            }]
+        );
+    }
+
+    #[test]
+    fn select_power_and_counter_slots() {
+        assert_eq!(
+            select_power_slots(
+                "cat > /dev/null; echo '1729814400|0.125|0.0\n1729815300||'",
+                0,
+                900
+            )
+            .unwrap(),
+            vec![
+                PowerSlotRow {
+                    slot: 1729814400,
+                    avg_import_kW: 0.125,
+                    max_export_kW: 0.0
+                },
+                PowerSlotRow {
+                    slot: 1729815300,
+                    avg_import_kW: 0.0,
+                    max_export_kW: 0.0
+                }
+            ]
+        );
+        assert_eq!(
+            select_counter_slots("cat > /dev/null; echo '1729814400|890|0.031|0.0'", 0, 900)
+                .unwrap(),
+            vec![CounterSlotRow {
+                slot: 1729814400,
+                span_s: 890,
+                consumed_kWh: 0.031,
+                injected_kWh: 0.0
+            }]
+        );
+    }
+
+    #[test]
+    fn appliance_clusters_and_labels() {
+        assert_eq!(
+            select_appliance_clusters(
+                "cat > /dev/null; echo '1|2|2.05|14|Kettle|kitchen\n2|1|1.2|3|'"
+            )
+            .unwrap(),
+            vec![
+                ApplianceClusterRow {
+                    id: 1,
+                    phase: 2,
+                    power_kW: 2.05,
+                    events: 14,
+                    label: Some("Kettle|kitchen".to_string())
+                },
+                ApplianceClusterRow {
+                    id: 2,
+                    phase: 1,
+                    power_kW: 1.2,
+                    events: 3,
+                    label: None
+                }
+            ]
+        );
+        assert_eq!(
+            update_appliance_label(
+                "grep -c \"^update p1_appliance_clusters set label = 'Jo''s oven' where id = 3;$\"",
+                3,
+                " Jo's oven "
+            )
+            .unwrap(),
+            1
+        );
+        assert_eq!(
+            update_appliance_label(
+                "grep -c '^update p1_appliance_clusters set label = NULL where id = 3;$'",
+                3,
+                ""
+            )
+            .unwrap(),
+            1
+        );
+    }
+
+    #[test]
+    fn insert_and_select_clock_offsets() {
+        let result = insert_clock_offset(
+            "grep -c '^insert or replace into p1_clock_offsets values (1729814400, 600, -1.5, -0.75, 0.25);$'",
+            &ClockOffsetRow {
+                timestamp: 1729814400,
+                samples: 600,
+                min_offset_s: -1.5,
+                avg_offset_s: -0.75,
+                max_offset_s: 0.25,
+            },
+        );
+        assert_eq!(result.unwrap(), 1);
+        assert_eq!(
+            select_clock_offsets(
+                "cat > /dev/null; echo '1729814400|3600|-1.5|-0.625|0.25'",
+                0,
+                3600
+            )
+            .unwrap(),
+            vec![ClockOffsetRow {
+                timestamp: 1729814400,
+                samples: 3600,
+                min_offset_s: -1.5,
+                avg_offset_s: -0.625,
+                max_offset_s: 0.25,
+            }]
+        );
+        assert!(
+            select_clock_offsets("cat > /dev/null; echo '1729814400|3600|-1.5'", 0, 3600).is_err()
+        );
+    }
+
+    #[test]
+    fn insert_and_select_production() {
+        let result = insert_production(
+            "grep -c \"^insert or replace into inverter_production values ('pv2022', 1729814400, 12345.678, 8.765, NULL, 'Connected', 'Ok');$\"",
+            &ProductionRow {
+                installation: "pv2022".to_string(),
+                timestamp: 1729814400,
+                total_yield_kWh: Some(12345.678),
+                daily_yield_kWh: Some(8.765),
+                ac_power_W: None,
+                grid_state: Some("Connected".to_string()),
+                device_status: Some("Ok".to_string()),
+            },
+        );
+        assert_eq!(result.unwrap(), 1);
+        assert_eq!(
+            select_latest_production(
+                "cat > /dev/null; echo '1729814400|12345.678|8.765||Connected|'",
+                "pv2022"
+            )
+            .unwrap(),
+            Some(ProductionRow {
+                installation: "pv2022".to_string(),
+                timestamp: 1729814400,
+                total_yield_kWh: Some(12345.678),
+                daily_yield_kWh: Some(8.765),
+                ac_power_W: None,
+                grid_state: Some("Connected".to_string()),
+                device_status: None,
+            })
+        );
+        assert_eq!(
+            select_latest_production("cat > /dev/null", "pv2012").unwrap(),
+            None
+        );
+    }
+
+    #[test]
+    fn backfill_production() {
+        let rows = [1729814400, 1729900800].map(|timestamp| ProductionRow {
+            installation: "pv2022".to_string(),
+            timestamp,
+            total_yield_kWh: Some(12345.0),
+            daily_yield_kWh: None,
+            ac_power_W: None,
+            grid_state: None,
+            device_status: None,
+        });
+        let result = insert_new_production(
+            "grep -c \"^insert or ignore into inverter_production values ('pv2022', [0-9]*, 12345, NULL, NULL, NULL, NULL);$\"",
+            &rows,
+        );
+        assert_eq!(result.unwrap(), 2);
+        assert_eq!(
+            select_production_timestamps(
+                "grep -q 'timestamp between 1729800000 and 1729999999 order by timestamp;$' && printf '1729814400\\n1729900800\\n'",
+                "pv2022",
+                1729800000,
+                1729999999
+            ),
+            Ok(vec![1729814400, 1729900800])
+        );
+    }
+
+    #[test]
+    fn hourly_total_yields() {
+        assert_eq!(
+            select_hourly_total_yields(
+                "grep -q 'timestamp > 1729800000 group by 1, 2' && printf 'pv2012|1729810800|487.5\npv2022|1729810800|12345.678\n'",
+                1729800000
+            ),
+            Ok(vec![
+                HourlyYieldRow {
+                    installation: "pv2012".to_string(),
+                    hour_start: 1729810800,
+                    total_yield_kWh: 487.5,
+                },
+                HourlyYieldRow {
+                    installation: "pv2022".to_string(),
+                    hour_start: 1729810800,
+                    total_yield_kWh: 12345.678,
+                },
+            ])
+        );
+    }
+
+    #[test]
+    fn manual_total_yields() {
+        assert_eq!(
+            select_manual_total_yields(
+                "grep -q 'max(pv2022_kWh) from readings where pv2022_kWh is not null and timestamp > 1729800000' && echo 'pv2022|1729810800|1234.5'",
+                1729800000
+            ),
+            Ok(vec![HourlyYieldRow {
+                installation: "pv2022".to_string(),
+                hour_start: 1729810800,
+                total_yield_kWh: 1234.5,
+            }])
+        );
+    }
+
+    #[test]
+    fn insert_and_select_inverter_events() {
+        let result = insert_inverter_events(
+            "grep -c \"^insert or ignore into inverter_events values ('pv2022', 1729850000, 101, 'Error');$\"",
+            &[InverterEventRow {
+                installation: "pv2022".to_string(),
+                timestamp: 1729850000,
+                code: 101,
+                severity: "Error".to_string(),
+            }],
+        );
+        assert_eq!(result.unwrap(), 1);
+        assert_eq!(
+            select_inverter_events(
+                "cat > /dev/null; echo 'pv2022|1729850000|101|Error|253.4||'",
+                1729800000
+            )
+            .unwrap(),
+            vec![EventVoltageRow {
+                event: InverterEventRow {
+                    installation: "pv2022".to_string(),
+                    timestamp: 1729850000,
+                    code: 101,
+                    severity: "Error".to_string(),
+                },
+                max_voltage_V: [Some(253.4), None, None],
+            }]
+        );
+    }
+
+    #[test]
+    fn replace_p1_range_deletes_then_inserts() {
+        let rebuild = P1Rebuild {
+            from: 1729807200,
+            to: 1729893599,
+            phases_to: 1729893539,
+            measurements: vec![P1Measurement {
+                timestamp: 1729807200,
+                peak_conso_kWh: 1.0,
+                off_conso_kWh: 2.0,
+                peak_inj_kWh: 3.0,
+                off_inj_kWh: 4.0,
+            }],
+            ..Default::default()
+        };
+        let result = replace_p1_range(
+            "grep -c ' where [a-z_]*timestamp between 1729807200 and 1729893[0-9]*;$'",
+            &rebuild,
+        );
+        assert_eq!(result.unwrap(), 5);
+        // The deletions come first, in the transaction
+        let result = replace_p1_range(
+            "sed -n '/^BEGIN/,/^COMMIT/p' | grep -n 'insert or ignore into p1_measurements' | cut -d: -f1",
+            &rebuild,
+        );
+        assert_eq!(result.unwrap(), 7);
+    }
 }
@@ -0,0 +1,430 @@
+use std::fmt;
+use std::fs::File;
+use std::io::BufReader;
+use std::sync::Arc;
+use std::time::Duration;
+
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::crypto::{verify_tls12_signature, verify_tls13_signature, CryptoProvider};
+use rustls::pki_types::{CertificateDer, ServerName, UnixTime};
+use rustls::{CertificateError, DigitallySignedStruct, SignatureScheme};
+use serde_json::Value;
+
+use crate::inverter_snapshot::InverterSnapshot;
+use crate::pv_installations::PvInstallation;
+
+/// Time to establish the connection, TLS handshake included
+pub const CONNECT_TIMEOUT: Duration = Duration::from_secs(1);
+/// Time for the whole request, until the body is read
+pub const TOTAL_TIMEOUT: Duration = Duration::from_secs(2);
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum InverterError {
+    /// Missing or unreadable host, path or certificate
+    Config(String),
+    Timeout,
+    /// The inverter presented another certificate than the pinned one
+    TlsMismatch,
+    /// Refused connection, unknown host, ...
+    Connect(String),
+    HttpStatus(u16),
+    BadJson(String),
+    /// Wrong password, or all sessions of the inverter in use
+    Login(String),
+    /// Modbus exception or unexpected registers
+    Modbus(String),
+}
+
+impl fmt::Display for InverterError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            InverterError::Config(e) => write!(f, "Configuration: {}", e),
+            InverterError::Timeout => write!(f, "Timeout"),
+            InverterError::TlsMismatch => {
+                write!(f, "Certificate does not match the pinned one")
+            }
+            InverterError::Connect(e) => write!(f, "Connection failed: {}", e),
+            InverterError::HttpStatus(status) => write!(f, "HTTP status {}", status),
+            InverterError::BadJson(e) => write!(f, "Bad JSON: {}", e),
+            InverterError::Login(e) => write!(f, "Login failed: {}", e),
+            InverterError::Modbus(e) => write!(f, "Modbus: {}", e),
+        }
+    }
+}
+
+/// Accepts exactly one certificate, whatever its name, issuer or validity:
+/// the inverter web UI has a self-signed one, for a name that does not
+/// resolve.
+#[derive(Debug)]
+struct PinnedCertificate {
+    certificate: CertificateDer<'static>,
+    provider: Arc<CryptoProvider>,
+}
+
+impl ServerCertVerifier for PinnedCertificate {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        if end_entity.as_ref() == self.certificate.as_ref() {
+            Ok(ServerCertVerified::assertion())
+        } else {
+            Err(rustls::Error::InvalidCertificate(
+                CertificateError::ApplicationVerificationFailure,
+            ))
+        }
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        certificate: &CertificateDer<'_>,
+        signature: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls12_signature(
+            message,
+            certificate,
+            signature,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        certificate: &CertificateDer<'_>,
+        signature: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls13_signature(
+            message,
+            certificate,
+            signature,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.provider
+            .signature_verification_algorithms
+            .supported_schemes()
+    }
+}
+
+/// First certificate of a PEM file, cf `RUST_HELLO_WORLD_REMOTE_SERVER_CERT`
+pub fn read_certificate(path: &str) -> Result<CertificateDer<'static>, InverterError> {
+    let file = File::open(path).map_err(|e| InverterError::Config(format!("{}: {}", path, e)))?;
+    match rustls_pemfile::certs(&mut BufReader::new(file)).next() {
+        Some(Ok(certificate)) => Ok(certificate),
+        Some(Err(e)) => Err(InverterError::Config(format!("{}: {}", path, e))),
+        None => Err(InverterError::Config(format!("{}: no certificate", path))),
+    }
+}
+
+/// Whether the TLS handshake failed on the certificate.  rustls errors end up
+/// wrapped in `std::io::Error`s, whose `source()` skips the wrapped error.
+fn is_certificate_error(error: &(dyn std::error::Error + 'static)) -> bool {
+    let mut source = Some(error);
+    while let Some(error) = source {
+        if let Some(rustls::Error::InvalidCertificate(_)) = error.downcast_ref::<rustls::Error>() {
+            return true;
+        }
+        if let Some(inner) = error
+            .downcast_ref::<std::io::Error>()
+            .and_then(|e| e.get_ref())
+        {
+            if is_certificate_error(inner) {
+                return true;
+            }
+        }
+        source = error.source();
+    }
+    false
+}
+
+fn request_error(error: reqwest::Error) -> InverterError {
+    if error.is_timeout() {
+        InverterError::Timeout
+    } else if is_certificate_error(&error) {
+        InverterError::TlsMismatch
+    } else if let Some(status) = error.status() {
+        InverterError::HttpStatus(status.as_u16())
+    } else {
+        InverterError::Connect(error.to_string())
+    }
+}
+
+/// HTTPS client for the inverter web UI, trusting only its certificate
+pub struct InverterHttps {
+    client: reqwest::Client,
+    host: String,
+}
+
+impl InverterHttps {
+    pub fn new(
+        host: &str,
+        certificate: CertificateDer<'static>,
+        connect_timeout: Duration,
+        total_timeout: Duration,
+    ) -> Result<Self, InverterError> {
+        let provider = Arc::new(rustls::crypto::ring::default_provider());
+        let tls = rustls::ClientConfig::builder_with_provider(provider.clone())
+            .with_safe_default_protocol_versions()
+            .map_err(|e| InverterError::Config(e.to_string()))?
+            .dangerous()
+            .with_custom_certificate_verifier(Arc::new(PinnedCertificate {
+                certificate,
+                provider,
+            }))
+            .with_no_client_auth();
+        let client = reqwest::Client::builder()
+            .use_preconfigured_tls(tls)
+            .connect_timeout(connect_timeout)
+            .timeout(total_timeout)
+            .build()
+            .map_err(|e| InverterError::Config(e.to_string()))?;
+        Ok(InverterHttps {
+            client,
+            host: host.to_string(),
+        })
+    }
+
+    /// Client for `host` pinning the certificate of the PEM file
+    /// `certificate`, with the default timeouts
+    pub fn pinned(host: &str, certificate: &str) -> Result<Self, InverterError> {
+        InverterHttps::new(
+            host,
+            read_certificate(certificate)?,
+            CONNECT_TIMEOUT,
+            TOTAL_TIMEOUT,
+        )
+    }
+
+    pub fn host(&self) -> &str {
+        &self.host
+    }
+
+    fn url(&self, path: &str) -> String {
+        format!("https://{}/{}", self.host, path.trim_start_matches('/'))
+    }
+
+    pub async fn get_json(&self, path: &str) -> Result<Value, InverterError> {
+        json_body(self.client.get(self.url(path)).send().await).await
+    }
+
+    pub async fn post_json(&self, path: &str, body: &Value) -> Result<Value, InverterError> {
+        let request = self
+            .client
+            .post(self.url(path))
+            .header("content-type", "application/json")
+            .body(body.to_string());
+        json_body(request.send().await).await
+    }
+}
+
+async fn json_body(
+    response: Result<reqwest::Response, reqwest::Error>,
+) -> Result<Value, InverterError> {
+    let response = response
+        .map_err(request_error)?
+        .error_for_status()
+        .map_err(request_error)?;
+    let body = response.bytes().await.map_err(request_error)?;
+    serde_json::from_slice(&body).map_err(|e| InverterError::BadJson(e.to_string()))
+}
+
+/// Values of the dashboard of the web UI of an installation, which needs no
+/// login
+pub async fn fetch_dashboard_snapshot(
+    installation: &PvInstallation,
+) -> Result<InverterSnapshot, InverterError> {
+    let config = &installation.config;
+    let mapping = config.mapping()?;
+    let host = installation.address.resolve()?.address;
+    let json = InverterHttps::pinned(&host, config.certificate()?)?
+        .get_json(&config.dashboard_path)
+        .await?;
+    mapping.snapshot(&json)
+}
+
+/// HTTPS server imitating the inverter web UI, for tests
+#[cfg(test)]
+pub(crate) mod stub {
+    use super::*;
+    use rustls::pki_types::PrivateKeyDer;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+    use tokio_rustls::TlsAcceptor;
+
+    pub struct StubInverter {
+        pub host: String,
+        pub certificate: CertificateDer<'static>,
+    }
+
+    impl StubInverter {
+        /// Client pinning the certificate of the stub, with short timeouts
+        pub fn client(&self) -> InverterHttps {
+            self.client_pinning(self.certificate.clone())
+        }
+
+        pub fn client_pinning(&self, certificate: CertificateDer<'static>) -> InverterHttps {
+            InverterHttps::new(
+                &self.host,
+                certificate,
+                Duration::from_millis(200),
+                Duration::from_millis(500),
+            )
+            .unwrap()
+        }
+    }
+
+    pub fn json_response(body: &str) -> String {
+        format!(
+            "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nConnection: close\r\nContent-Length: {}\r\n\r\n{}",
+            body.len(),
+            body
+        )
+    }
+
+    /// Request line and body of a request
+    async fn read_request<R: AsyncReadExt + Unpin>(stream: &mut R) -> Option<(String, String)> {
+        let mut request = Vec::new();
+        let mut buffer = [0; 4096];
+        loop {
+            let read = stream.read(&mut buffer).await.ok()?;
+            if read == 0 {
+                return None;
+            }
+            request.extend_from_slice(&buffer[..read]);
+            let text = String::from_utf8_lossy(&request);
+            if let Some((head, body)) = text.split_once("\r\n\r\n") {
+                let length = head
+                    .lines()
+                    .filter_map(|line| line.split_once(':'))
+                    .find(|(name, _)| name.eq_ignore_ascii_case("content-length"))
+                    .and_then(|(_, value)| value.trim().parse().ok())
+                    .unwrap_or(0);
+                if body.len() >= length {
+                    let line = head.lines().next().unwrap_or("").to_string();
+                    return Some((line, body.to_string()));
+                }
+            }
+        }
+    }
+
+    /// Server with a fresh self-signed certificate, answering each request
+    /// (request line, body) with the full HTTP response of `respond`, after
+    /// `delay`.
+    pub async fn stub_inverter<F>(respond: F, delay: Duration) -> StubInverter
+    where
+        F: Fn(&str, &str) -> String + Send + Sync + 'static,
+    {
+        let key = rcgen::generate_simple_self_signed(vec!["SMA3000000005".to_string()]).unwrap();
+        let certificate = key.cert.der().clone();
+        let tls = rustls::ServerConfig::builder_with_provider(Arc::new(
+            rustls::crypto::ring::default_provider(),
+        ))
+        .with_safe_default_protocol_versions()
+        .unwrap()
+        .with_no_client_auth()
+        .with_single_cert(
+            vec![certificate.clone()],
+            PrivateKeyDer::Pkcs8(key.key_pair.serialize_der().into()),
+        )
+        .unwrap();
+        let acceptor = TlsAcceptor::from(Arc::new(tls));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let host = listener.local_addr().unwrap().to_string();
+        let respond = Arc::new(respond);
+        actix_rt::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let acceptor = acceptor.clone();
+                let respond = respond.clone();
+                actix_rt::spawn(async move {
+                    tokio::time::sleep(delay).await;
+                    let Ok(mut stream) = acceptor.accept(stream).await else {
+                        return;
+                    };
+                    if let Some((line, body)) = read_request(&mut stream).await {
+                        let _ = stream.write_all(respond(&line, &body).as_bytes()).await;
+                    }
+                    let _ = stream.shutdown().await;
+                });
+            }
+        });
+        StubInverter { host, certificate }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::stub::{json_response, stub_inverter};
+    use super::*;
+
+    const DASH_VALUES: &str =
+        "{\"result\":{\"0199-xxxxx9BD\":{\"6400_00260100\":{\"1\":[{\"val\":42}]}}}}";
+
+    fn dash_values(_: &str, _: &str) -> String {
+        json_response(DASH_VALUES)
+    }
+
+    #[actix_rt::test]
+    async fn get_json_with_pinned_certificate() {
+        let inverter = stub_inverter(dash_values, Duration::ZERO).await;
+        let json = inverter
+            .client()
+            .get_json("dyn/getDashValues.json")
+            .await
+            .unwrap();
+        assert_eq!(
+            json["result"]["0199-xxxxx9BD"]["6400_00260100"]["1"][0]["val"],
+            42
+        );
+    }
+
+    #[actix_rt::test]
+    async fn other_certificate_is_rejected() {
+        let inverter = stub_inverter(dash_values, Duration::ZERO).await;
+        let other = stub_inverter(dash_values, Duration::ZERO).await;
+        assert_eq!(
+            inverter
+                .client_pinning(other.certificate)
+                .get_json("dyn/getDashValues.json")
+                .await,
+            Err(InverterError::TlsMismatch)
+        );
+    }
+
+    #[actix_rt::test]
+    async fn slow_inverter_times_out() {
+        let inverter = stub_inverter(dash_values, Duration::from_secs(5)).await;
+        assert_eq!(
+            inverter.client().get_json("dyn/getDashValues.json").await,
+            Err(InverterError::Timeout)
+        );
+    }
+
+    #[actix_rt::test]
+    async fn http_status_and_bad_json() {
+        let inverter = stub_inverter(
+            |_, _| {
+                "HTTP/1.1 503 Service Unavailable\r\nConnection: close\r\nContent-Length: 0\r\n\r\n"
+                    .to_string()
+            },
+            Duration::ZERO,
+        )
+        .await;
+        assert_eq!(
+            inverter.client().get_json("dyn/getDashValues.json").await,
+            Err(InverterError::HttpStatus(503))
+        );
+        let inverter = stub_inverter(|_, _| json_response("<html>..."), Duration::ZERO).await;
+        assert!(matches!(
+            inverter.client().get_json("dyn/getDashValues.json").await,
+            Err(InverterError::BadJson(_))
+        ));
+    }
+}
@@ -0,0 +1,227 @@
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+use actix_web::{get, web, HttpResponse};
+use serde::Serialize;
+use tera::Tera;
+use time::OffsetDateTime;
+
+use crate::inverter::InverterError;
+use crate::pv_installations::{InverterKind, PvInstallation, PvInstallations};
+use crate::{format_timestamp, get_ip_address};
+
+/// Version of the lease file: it is rewritten by the DHCP server on every
+/// change.
+type LeaseVersion = Option<(SystemTime, u64)>;
+
+fn lease_version(path: &str) -> LeaseVersion {
+    let metadata = std::fs::metadata(path).ok()?;
+    Some((metadata.modified().ok()?, metadata.len()))
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct ResolvedAddress {
+    /// IP address, or the hostname when not in the lease file
+    pub address: String,
+    pub from_lease: bool,
+    pub resolved_at: i64,
+}
+
+/// Address of an inverter, looked up by its hostname in the DHCP lease file
+/// (`RUST_HELLO_WORLD_DHCP_LEASES`), as local DNS may not know the name the
+/// inverter gave the DHCP server.  The lookup is only done again when the
+/// lease file changed.
+pub struct InverterAddress {
+    lease_file: Option<String>,
+    hostname: Option<String>,
+    cached: Mutex<Option<(LeaseVersion, ResolvedAddress)>>,
+}
+
+impl InverterAddress {
+    pub fn new(lease_file: Option<String>, hostname: Option<String>) -> Self {
+        InverterAddress {
+            lease_file,
+            hostname,
+            cached: Mutex::new(None),
+        }
+    }
+
+    pub fn resolve(&self) -> Result<ResolvedAddress, InverterError> {
+        let hostname = self
+            .hostname
+            .as_deref()
+            .ok_or_else(|| InverterError::Config("No inverter host configured".to_string()))?;
+        let version = self.lease_file.as_deref().and_then(lease_version);
+        let mut cached = self.cached.lock().unwrap();
+        if let Some((cached_version, resolved)) = cached.as_ref() {
+            if *cached_version == version {
+                return Ok(resolved.clone());
+            }
+        }
+        let address = match (&self.lease_file, version) {
+            (Some(lease_file), Some(_)) => get_ip_address(lease_file, hostname),
+            _ => hostname.to_string(),
+        };
+        let resolved = ResolvedAddress {
+            from_lease: address != hostname,
+            address,
+            resolved_at: OffsetDateTime::now_utc().unix_timestamp(),
+        };
+        match cached.as_ref() {
+            Some((_, previous)) if previous.address == resolved.address => {}
+            _ => log::info!("Inverter {} is at {}", hostname, resolved.address),
+        }
+        *cached = Some((version, resolved.clone()));
+        Ok(resolved)
+    }
+
+    pub fn hostname(&self) -> Option<&str> {
+        self.hostname.as_deref()
+    }
+}
+
+#[derive(Serialize)]
+struct AddressView {
+    address: String,
+    from_lease: bool,
+    resolved_at: String,
+}
+
+#[derive(Serialize)]
+#[allow(non_snake_case)]
+struct InstallationView {
+    name: String,
+    inverter: InverterKind,
+    kWp: Option<f64>,
+    commissioned: Option<String>,
+    hostname: Option<String>,
+    resolved: Option<AddressView>,
+    error: Option<String>,
+}
+
+fn installation_view(installation: &PvInstallation) -> InstallationView {
+    let (resolved, error) = if installation.config.inverter.is_polled() {
+        match installation.address.resolve() {
+            Ok(resolved) => (
+                Some(AddressView {
+                    address: resolved.address,
+                    from_lease: resolved.from_lease,
+                    resolved_at: format_timestamp(resolved.resolved_at),
+                }),
+                None,
+            ),
+            Err(e) => (None, Some(e.to_string())),
+        }
+    } else {
+        (None, None)
+    };
+    InstallationView {
+        name: installation.config.name.clone(),
+        inverter: installation.config.inverter,
+        kWp: installation.config.kWp,
+        commissioned: installation
+            .config
+            .commissioned
+            .map(|date| date.to_string()),
+        hostname: installation.address.hostname().map(str::to_string),
+        resolved,
+        error,
+    }
+}
+
+#[get("/inverter/status")]
+pub async fn inverter_status_page(
+    tera: web::Data<Tera>,
+    installations: web::Data<PvInstallations>,
+) -> HttpResponse {
+    let mut context = tera::Context::new();
+    context.insert("lease_file", &installations.lease_file);
+    let resolver = installations.clone();
+    match web::block(move || {
+        resolver
+            .installations
+            .iter()
+            .map(|installation| installation_view(installation))
+            .collect::<Vec<_>>()
+    })
+    .await
+    {
+        Ok(views) => context.insert("installations", &views),
+        Err(e) => context.insert("error", &e.to_string()),
+    }
+    if let Some(modified) = installations
+        .lease_file
+        .as_deref()
+        .and_then(lease_version)
+        .and_then(|(modified, _)| modified.duration_since(SystemTime::UNIX_EPOCH).ok())
+    {
+        context.insert(
+            "lease_modified",
+            &format_timestamp(modified.as_secs() as i64),
+        );
+    }
+    context.insert(
+        "now",
+        &format_timestamp(OffsetDateTime::now_utc().unix_timestamp()),
+    );
+    let rendered = tera.render("inverter_status.html", &context).unwrap();
+    HttpResponse::Ok().body(rendered)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn resolves_through_lease_file_until_it_changes() {
+        let path = std::env::temp_dir().join(format!("dhcp_leases_{}", std::process::id()));
+        std::fs::write(
+            &path,
+            "1686243713 9f:a3:00:00:4e:e7 192.168.100.49 SMA30XXXXX5 *\n",
+        )
+        .unwrap();
+        let address = InverterAddress::new(
+            Some(path.to_str().unwrap().to_string()),
+            Some("SMA30XXXXX5".to_string()),
+        );
+        let first = address.resolve().unwrap();
+        assert_eq!(
+            (first.address.as_str(), first.from_lease),
+            ("192.168.100.49", true)
+        );
+
+        // Same lease file: cached
+        *address.cached.lock().unwrap() = Some((
+            lease_version(path.to_str().unwrap()),
+            ResolvedAddress {
+                address: "cached".to_string(),
+                ..first
+            },
+        ));
+        assert_eq!(address.resolve().unwrap().address, "cached");
+
+        std::fs::write(
+            &path,
+            "1686243713 9f:a3:00:00:4e:e7 192.168.100.50 SMA30XXXXX5 *\n\n",
+        )
+        .unwrap();
+        assert_eq!(address.resolve().unwrap().address, "192.168.100.50");
+
+        std::fs::remove_file(&path).unwrap();
+        let fallback = address.resolve().unwrap();
+        assert_eq!(
+            (fallback.address.as_str(), fallback.from_lease),
+            ("SMA30XXXXX5", false)
+        );
+    }
+
+    #[test]
+    fn hostname_without_lease_file_or_nothing() {
+        let address = InverterAddress::new(None, Some("inverter.lan".to_string()));
+        assert_eq!(address.resolve().unwrap().address, "inverter.lan");
+        assert!(matches!(
+            InverterAddress::new(None, None).resolve(),
+            Err(InverterError::Config(_))
+        ));
+    }
+}
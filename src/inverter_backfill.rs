@@ -0,0 +1,291 @@
+use std::collections::BTreeSet;
+use std::fmt;
+
+use crate::data::{insert_new_production, select_production_timestamps, ProductionRow};
+use crate::format_timestamp;
+use crate::inverter::InverterError;
+use crate::inverter_session::{LoggerPoint, WebConnectSession, DAILY_LOGGER, MONTHLY_LOGGER};
+
+/// A logger value closer than that to a stored one is not added [s]
+pub const MIN_SPACING_S: i64 = 12 * 3600;
+
+/// Logger values added between two stored ones
+#[derive(Debug, PartialEq)]
+pub struct FilledGap {
+    /// Stored values around the gap, if any
+    pub after: Option<i64>,
+    pub before: Option<i64>,
+    pub points: Vec<LoggerPoint>,
+}
+
+impl fmt::Display for FilledGap {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let bound = |timestamp: Option<i64>| match timestamp {
+            Some(timestamp) => format_timestamp(timestamp),
+            None => "nothing".to_string(),
+        };
+        write!(
+            f,
+            "{} value(s) from {} to {}, between {} and {} stored",
+            self.points.len(),
+            format_timestamp(self.points[0].timestamp),
+            format_timestamp(self.points[self.points.len() - 1].timestamp),
+            bound(self.after),
+            bound(self.before)
+        )
+    }
+}
+
+/// Daily values of the logger of the inverter from `start` to `end`, then
+/// monthly ones, which go back further
+pub async fn download_history(
+    session: &mut WebConnectSession,
+    start: i64,
+    end: i64,
+) -> Result<Vec<LoggerPoint>, InverterError> {
+    let mut points = session.logger_values(DAILY_LOGGER, start, end).await?;
+    points.extend(session.logger_values(MONTHLY_LOGGER, start, end).await?);
+    Ok(points)
+}
+
+/// The logger `points` not within `MIN_SPACING_S` of a `stored` value nor of
+/// a point taken before them, grouped by gap between stored values.  Points
+/// come first served: daily ones before monthly ones.
+pub fn fill_gaps(stored: &[i64], points: &[LoggerPoint]) -> Vec<FilledGap> {
+    let stored: BTreeSet<i64> = stored.iter().copied().collect();
+    let mut taken = stored.clone();
+    let mut added = Vec::new();
+    for point in points {
+        if point.total_yield_kWh.is_none() {
+            continue;
+        }
+        let near = point.timestamp - MIN_SPACING_S + 1..point.timestamp + MIN_SPACING_S;
+        if taken.range(near).next().is_none() {
+            taken.insert(point.timestamp);
+            added.push(point.clone());
+        }
+    }
+    added.sort_by_key(|point| point.timestamp);
+    let mut gaps: Vec<FilledGap> = Vec::new();
+    for point in added {
+        let after = stored.range(..point.timestamp).next_back().copied();
+        let before = stored.range(point.timestamp..).next().copied();
+        match gaps.last_mut() {
+            Some(gap) if (gap.after, gap.before) == (after, before) => gap.points.push(point),
+            _ => gaps.push(FilledGap {
+                after,
+                before,
+                points: vec![point],
+            }),
+        }
+    }
+    gaps
+}
+
+/// What a backfill read and added
+#[derive(Debug, PartialEq)]
+pub struct Backfill {
+    pub logger_values: usize,
+    pub gaps: Vec<FilledGap>,
+    /// Rows added, none on a dry run
+    pub added: Option<usize>,
+}
+
+/// Add the logger values of `session` from `from` to `to` to the production
+/// of `installation` stored with `sqlite3`, where nothing is stored.  Only
+/// lists the gaps it would fill on a `dry_run`.
+pub async fn backfill(
+    session: &mut WebConnectSession,
+    sqlite3: &str,
+    installation: &str,
+    from: i64,
+    to: i64,
+    dry_run: bool,
+) -> Result<Backfill, String> {
+    let points = download_history(session, from, to)
+        .await
+        .map_err(|e| format!("reading the logger: {}", e))?;
+    // The values just outside of the range count as well
+    let stored = select_production_timestamps(
+        sqlite3,
+        installation,
+        from - MIN_SPACING_S,
+        to + MIN_SPACING_S,
+    )
+    .map_err(|e| format!("Unable to read the stored values: {}", e))?;
+    let points: Vec<_> = points
+        .into_iter()
+        .filter(|point| (from..=to).contains(&point.timestamp))
+        .collect();
+    let gaps = fill_gaps(&stored, &points);
+    let added = if dry_run {
+        None
+    } else {
+        let rows = production_rows(installation, &gaps);
+        Some(
+            insert_new_production(sqlite3, &rows)
+                .map_err(|e| format!("Unable to store the logger values: {}", e))?,
+        )
+    };
+    Ok(Backfill {
+        logger_values: points.len(),
+        gaps,
+        added,
+    })
+}
+
+/// Rows of the logger values of `gaps`, for `installation`
+pub fn production_rows(installation: &str, gaps: &[FilledGap]) -> Vec<ProductionRow> {
+    gaps.iter()
+        .flat_map(|gap| &gap.points)
+        .map(|point| ProductionRow {
+            installation: installation.to_string(),
+            timestamp: point.timestamp,
+            total_yield_kWh: point.total_yield_kWh,
+            daily_yield_kWh: None,
+            ac_power_W: None,
+            grid_state: None,
+            device_status: None,
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::inverter::stub::{json_response, stub_inverter};
+    use crate::inverter_snapshot::ValueMapping;
+    use serde_json::{json, Value};
+    use std::time::Duration;
+
+    const DAY: i64 = 86400;
+
+    fn point(timestamp: i64, total_yield: Option<f64>) -> LoggerPoint {
+        LoggerPoint {
+            timestamp,
+            total_yield_kWh: total_yield,
+        }
+    }
+
+    #[test]
+    fn only_gaps_are_filled() {
+        // Polled on days 3 and 4, then from day 8
+        let stored = [3 * DAY + 36000, 4 * DAY + 36000, 8 * DAY + 300];
+        let mut points: Vec<LoggerPoint> = (0..10)
+            .map(|day| point(day * DAY, Some(1000.0 + day as f64)))
+            .collect();
+        points[6].total_yield_kWh = None;
+        // Monthly values: the first one is a daily one too
+        points.push(point(0, Some(1000.0)));
+        points.push(point(-31 * DAY, Some(900.0)));
+
+        let gaps = fill_gaps(&stored, &points);
+        assert_eq!(
+            gaps,
+            vec![
+                FilledGap {
+                    after: None,
+                    before: Some(3 * DAY + 36000),
+                    points: vec![
+                        point(-31 * DAY, Some(900.0)),
+                        point(0, Some(1000.0)),
+                        point(DAY, Some(1001.0)),
+                        point(2 * DAY, Some(1002.0)),
+                    ],
+                },
+                FilledGap {
+                    after: Some(4 * DAY + 36000),
+                    before: Some(8 * DAY + 300),
+                    points: vec![point(5 * DAY, Some(1005.0)), point(7 * DAY, Some(1007.0))],
+                },
+                FilledGap {
+                    after: Some(8 * DAY + 300),
+                    before: None,
+                    points: vec![point(9 * DAY, Some(1009.0))],
+                },
+            ]
+        );
+        assert_eq!(production_rows("pv2022", &gaps).len(), 7);
+
+        // Nothing left to fill the second time
+        let stored: Vec<i64> = production_rows("pv2022", &gaps)
+            .iter()
+            .map(|row| row.timestamp)
+            .chain(stored)
+            .collect();
+        assert!(fill_gaps(&stored, &points).is_empty());
+    }
+
+    /// Imitates the login and the daily and monthly loggers: a total yield
+    /// at midnight of days 0 to 9, and at the start of the month before
+    async fn logger_inverter() -> WebConnectSession {
+        let inverter = stub_inverter(
+            |line, body| {
+                let body: Value = serde_json::from_str(body).unwrap_or_default();
+                let points: Vec<Value> = match (body["key"].as_u64(), line) {
+                    (_, line) if line.contains("/dyn/login.json") => {
+                        return json_response(&json!({"result": {"sid": "sid1"}}).to_string())
+                    }
+                    (Some(key), _) if key == DAILY_LOGGER as u64 => (0..10)
+                        .map(|day| json!({"t": day * DAY, "v": 1000000 + day * 1000}))
+                        .collect(),
+                    (Some(key), _) if key == MONTHLY_LOGGER as u64 => vec![
+                        json!({"t": -31 * DAY, "v": 900000}),
+                        json!({"t": 0, "v": 1000000}),
+                    ],
+                    _ => return json_response(&json!({"err": 404}).to_string()),
+                };
+                let range = body["tStart"].as_i64()..=body["tEnd"].as_i64();
+                let points: Vec<Value> = points
+                    .into_iter()
+                    .filter(|point| range.contains(&point["t"].as_i64()))
+                    .collect();
+                json_response(&json!({"result": {"0199-xxxxx9BD": points}}).to_string())
+            },
+            Duration::ZERO,
+        )
+        .await;
+        WebConnectSession::new(inverter.client(), "usr", "s3cret", ValueMapping::default())
+    }
+
+    #[actix_rt::test]
+    async fn logger_values_added_where_missing() {
+        // Polled on days 3 and 4, then from day 8; the insert statements
+        // are counted
+        let sqlite3 = "sql=$(cat); case \"$sql\" in *'select timestamp'*) \
+                       printf '%s\\n' 295200 381600 691500;; \
+                       *) printf '%s\\n' \"$sql\" | grep -c 'insert or ignore';; esac";
+        let mut session = logger_inverter().await;
+        let filled = backfill(&mut session, sqlite3, "pv2022", -40 * DAY, 9 * DAY, false)
+            .await
+            .unwrap();
+        assert_eq!(filled.logger_values, 12);
+        let added: Vec<i64> = production_rows("pv2022", &filled.gaps)
+            .iter()
+            .map(|row| row.timestamp)
+            .collect();
+        assert_eq!(
+            added,
+            vec![
+                -31 * DAY,
+                0,
+                DAY,
+                2 * DAY,
+                5 * DAY,
+                6 * DAY,
+                7 * DAY,
+                9 * DAY
+            ]
+        );
+        assert_eq!(filled.added, Some(8));
+        assert_eq!(filled.gaps[1].after, Some(4 * DAY + 36000));
+
+        // Nothing inserted on a dry run
+        let sqlite3 = "grep -q 'select timestamp' && echo 295200";
+        let filled = backfill(&mut session, sqlite3, "pv2022", 0, 9 * DAY, true)
+            .await
+            .unwrap();
+        assert_eq!(filled.added, None);
+        assert_eq!(filled.gaps.len(), 2);
+    }
+}
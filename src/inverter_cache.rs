@@ -0,0 +1,199 @@
+use std::sync::Mutex;
+
+use crate::inverter_snapshot::InverterSnapshot;
+
+/// Consecutive failures after which the inverter is left alone
+const FAILURES_TO_OPEN: u32 = 3;
+/// How long the inverter is left alone, after which one poll is tried [s]
+const OPEN_S: i64 = 900;
+
+/// Stops polling an unreachable inverter for a while: once open, a single
+/// poll is let through after `OPEN_S`, which closes it again on success or
+/// keeps it open another `OPEN_S` on failure.
+#[derive(Debug, Default, PartialEq, Clone)]
+pub struct CircuitBreaker {
+    failures: u32,
+    open_until: Option<i64>,
+}
+
+impl CircuitBreaker {
+    pub fn allows(&self, now: i64) -> bool {
+        self.open_until.is_none_or(|until| now >= until)
+    }
+
+    pub fn is_open(&self) -> bool {
+        self.open_until.is_some()
+    }
+
+    pub fn success(&mut self) {
+        *self = CircuitBreaker::default();
+    }
+
+    /// Returns whether it opened (again)
+    pub fn failure(&mut self, now: i64) -> bool {
+        self.failures += 1;
+        if self.failures >= FAILURES_TO_OPEN {
+            self.open_until = Some(now + OPEN_S);
+            true
+        } else {
+            false
+        }
+    }
+}
+
+/// Last values read from an inverter and when
+#[derive(Debug, PartialEq, Clone)]
+pub struct CachedSnapshot {
+    pub snapshot: InverterSnapshot,
+    pub fetched_at: i64,
+}
+
+#[derive(Default)]
+struct CacheState {
+    value: Option<CachedSnapshot>,
+    /// Of the last poll, when it failed
+    error: Option<String>,
+    breaker: CircuitBreaker,
+    /// A reader is getting fresh values
+    refreshing: bool,
+}
+
+/// Last values of the inverter of an installation, kept up to date by its
+/// poller.  Readers get them at once, however old, and read fresh ones in the
+/// background when too old: stale-while-revalidate.
+#[derive(Default)]
+pub struct InverterCache {
+    state: Mutex<CacheState>,
+}
+
+impl InverterCache {
+    pub fn get(&self) -> Option<CachedSnapshot> {
+        self.state.lock().unwrap().value.clone()
+    }
+
+    /// The last values, and whether the caller is to read fresh ones (and
+    /// `store` them or report it `failed`): when there are none or they are
+    /// older than `max_age_s`, unless the circuit breaker is open or another
+    /// reader is at it already.
+    pub fn get_or_revalidate(&self, now: i64, max_age_s: i64) -> (Option<CachedSnapshot>, bool) {
+        let mut state = self.state.lock().unwrap();
+        let stale = state
+            .value
+            .as_ref()
+            .is_none_or(|value| now - value.fetched_at > max_age_s);
+        let refresh = stale && !state.refreshing && state.breaker.allows(now);
+        state.refreshing |= refresh;
+        (state.value.clone(), refresh)
+    }
+
+    /// Why the last poll failed, unless it succeeded
+    pub fn last_error(&self) -> Option<String> {
+        self.state.lock().unwrap().error.clone()
+    }
+
+    /// Whether polls keep failing
+    pub fn is_unreachable(&self) -> bool {
+        self.state.lock().unwrap().breaker.is_open()
+    }
+
+    /// Whether the inverter may be polled at `now`
+    pub fn allows(&self, now: i64) -> bool {
+        self.state.lock().unwrap().breaker.allows(now)
+    }
+
+    pub fn store(&self, snapshot: InverterSnapshot, fetched_at: i64) {
+        let mut state = self.state.lock().unwrap();
+        state.value = Some(CachedSnapshot {
+            snapshot,
+            fetched_at,
+        });
+        state.error = None;
+        state.breaker.success();
+        state.refreshing = false;
+    }
+
+    /// Returns whether the circuit breaker opened (again)
+    pub fn failed(&self, now: i64, error: String) -> bool {
+        let mut state = self.state.lock().unwrap();
+        state.error = Some(error);
+        state.refreshing = false;
+        state.breaker.failure(now)
+    }
+}
+
+/// How old a value read at `fetched_at` is, e.g. `5 min ago`
+pub fn age(fetched_at: i64, now: i64) -> String {
+    match (now - fetched_at).max(0) {
+        s if s < 60 => "just now".to_string(),
+        s if s < 3600 => format!("{} min ago", s / 60),
+        s if s < 86400 => format!("{} h ago", s / 3600),
+        s => format!("{} days ago", s / 86400),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn snapshot() -> InverterSnapshot {
+        InverterSnapshot {
+            device: "0199-xxxxx9BD".to_string(),
+            total_yield_kWh: Some(12345.678),
+            daily_yield_kWh: None,
+            ac_power_W: None,
+            grid_state: None,
+            device_status: None,
+        }
+    }
+
+    #[test]
+    fn breaker_opens_and_lets_one_poll_through() {
+        let mut breaker = CircuitBreaker::default();
+        assert!(!breaker.failure(1000));
+        assert!(!breaker.failure(1300));
+        assert!(breaker.allows(1600));
+        assert!(breaker.failure(1600));
+        assert!(!breaker.allows(1601));
+        assert!(breaker.allows(1600 + OPEN_S));
+        // Still unreachable
+        assert!(breaker.failure(1600 + OPEN_S));
+        assert!(!breaker.allows(1601 + OPEN_S));
+        breaker.success();
+        assert!(breaker.allows(1601 + OPEN_S));
+        assert!(!breaker.is_open());
+    }
+
+    #[test]
+    fn stale_while_revalidate() {
+        let cache = InverterCache::default();
+        assert_eq!(cache.get_or_revalidate(1000, 300), (None, true));
+        // One reader at a time
+        assert_eq!(cache.get_or_revalidate(1000, 300), (None, false));
+
+        cache.store(snapshot(), 1000);
+        let (cached, refresh) = cache.get_or_revalidate(1300, 300);
+        assert_eq!(cached.as_ref().map(|cached| cached.fetched_at), Some(1000));
+        assert!(!refresh);
+
+        // Stale: still returned, and refreshed
+        assert_eq!(cache.get_or_revalidate(1301, 300), (cached.clone(), true));
+        cache.failed(1301, "Timeout".to_string());
+        assert_eq!(cache.get_or_revalidate(1302, 300), (cached.clone(), true));
+
+        // Not while the inverter is left alone
+        for _ in 1..FAILURES_TO_OPEN {
+            cache.failed(1400, "Timeout".to_string());
+        }
+        assert!(cache.is_unreachable());
+        assert_eq!(cache.last_error(), Some("Timeout".to_string()));
+        assert_eq!(cache.get_or_revalidate(1500, 300), (cached, false));
+    }
+
+    #[test]
+    fn ages() {
+        assert_eq!(age(1000, 1059), "just now");
+        assert_eq!(age(1000, 1300), "5 min ago");
+        assert_eq!(age(1000, 1000 + 7300), "2 h ago");
+        assert_eq!(age(1000, 1000 + 3 * 86400), "3 days ago");
+    }
+}
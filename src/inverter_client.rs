@@ -0,0 +1,281 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use futures_util::future::BoxFuture;
+
+use crate::inverter::{fetch_dashboard_snapshot, InverterError};
+use crate::inverter_events::InverterEvent;
+use crate::inverter_modbus::SunSpecInverter;
+use crate::inverter_session::WebConnectSession;
+use crate::inverter_snapshot::InverterSnapshot;
+use crate::pv_installations::{InverterKind, PvInstallation};
+
+/// Reads the inverters, for the pollers and the meter readings form.  Shared
+/// as `Arc<dyn InverterClient>`, which tests replace with a
+/// `MockInverterClient`.
+pub trait InverterClient: Send + Sync {
+    /// Current values of the inverter of `installation`
+    fn snapshot<'a>(
+        &'a self,
+        installation: &'a PvInstallation,
+    ) -> BoxFuture<'a, Result<InverterSnapshot, InverterError>>;
+
+    /// Event log of the inverter of `installation` from `start` to `end`
+    /// (Unix timestamps), for the kinds that have one
+    fn events<'a>(
+        &'a self,
+        installation: &'a PvInstallation,
+        start: i64,
+        end: i64,
+    ) -> BoxFuture<'a, Result<Vec<InverterEvent>, InverterError>>;
+}
+
+/// Login session of an installation, locked while in use
+type SessionSlot = Arc<tokio::sync::Mutex<Option<WebConnectSession>>>;
+
+/// Reads the inverters over the network, according to their kind.  The
+/// login sessions are kept from one read to the next, each behind its own
+/// lock so that a slow inverter does not hold up the others.
+#[derive(Default)]
+pub struct NetworkInverterClient {
+    sessions: Mutex<HashMap<String, SessionSlot>>,
+}
+
+impl NetworkInverterClient {
+    fn session_slot(&self, installation: &PvInstallation) -> SessionSlot {
+        self.sessions
+            .lock()
+            .unwrap()
+            .entry(installation.config.name.clone())
+            .or_default()
+            .clone()
+    }
+
+    /// The session of `installation`, opened if needed
+    fn webconnect_session<'a>(
+        session: &'a mut Option<WebConnectSession>,
+        installation: &PvInstallation,
+    ) -> Result<&'a mut WebConnectSession, InverterError> {
+        let host = installation.address.resolve()?.address;
+        // A new address (DHCP) needs a new session
+        if session.as_ref().map(|session| session.host()) != Some(host.as_str()) {
+            *session = Some(WebConnectSession::for_installation(
+                &installation.config,
+                &host,
+            )?);
+        }
+        Ok(session.as_mut().unwrap())
+    }
+
+    async fn webconnect_snapshot(
+        &self,
+        installation: &PvInstallation,
+    ) -> Result<InverterSnapshot, InverterError> {
+        let slot = self.session_slot(installation);
+        let mut session = slot.lock().await;
+        Self::webconnect_session(&mut session, installation)?
+            .snapshot()
+            .await
+    }
+
+    async fn read_events(
+        &self,
+        installation: &PvInstallation,
+        start: i64,
+        end: i64,
+    ) -> Result<Vec<InverterEvent>, InverterError> {
+        if !installation.config.inverter.has_event_log() {
+            return Err(InverterError::Config(format!(
+                "{}: the event log is only read with sma-webconnect",
+                installation.config.name
+            )));
+        }
+        let slot = self.session_slot(installation);
+        let mut session = slot.lock().await;
+        Self::webconnect_session(&mut session, installation)?
+            .events(start, end)
+            .await
+    }
+
+    /// Free the sessions on the inverters, which only accept a few: on
+    /// shutdown
+    pub async fn logout(&self) {
+        let slots: Vec<_> = self.sessions.lock().unwrap().drain().collect();
+        for (name, slot) in slots {
+            if let Some(session) = slot.lock().await.as_mut() {
+                if let Err(e) = session.logout().await {
+                    log::warn!("{}: logging out of the inverter: {}", name, e);
+                }
+            }
+        }
+    }
+
+    async fn read(&self, installation: &PvInstallation) -> Result<InverterSnapshot, InverterError> {
+        match installation.config.inverter {
+            InverterKind::SmaDashboard => fetch_dashboard_snapshot(installation).await,
+            InverterKind::SmaWebconnect => self.webconnect_snapshot(installation).await,
+            InverterKind::SunspecModbus => {
+                let host = installation.address.resolve()?.address;
+                SunSpecInverter::for_installation(&installation.config, &host)
+                    .snapshot()
+                    .await
+            }
+            InverterKind::Manual => Err(InverterError::Config("Not polled".to_string())),
+        }
+    }
+}
+
+impl InverterClient for NetworkInverterClient {
+    fn snapshot<'a>(
+        &'a self,
+        installation: &'a PvInstallation,
+    ) -> BoxFuture<'a, Result<InverterSnapshot, InverterError>> {
+        Box::pin(self.read(installation))
+    }
+
+    fn events<'a>(
+        &'a self,
+        installation: &'a PvInstallation,
+        start: i64,
+        end: i64,
+    ) -> BoxFuture<'a, Result<Vec<InverterEvent>, InverterError>> {
+        Box::pin(self.read_events(installation, start, end))
+    }
+}
+
+/// What `MockInverterClient` answers for an installation
+#[derive(Debug, Clone)]
+pub enum MockResponse {
+    Snapshot(InverterSnapshot),
+    Error(InverterError),
+    /// `InverterError::Timeout`, after that long
+    Timeout(Duration),
+}
+
+/// Answers what it is told to, per installation name, and counts the reads.
+/// The event logs are empty unless told otherwise.
+#[derive(Default)]
+pub struct MockInverterClient {
+    responses: Mutex<HashMap<String, MockResponse>>,
+    events: Mutex<HashMap<String, Vec<InverterEvent>>>,
+    reads: Mutex<Vec<String>>,
+}
+
+impl MockInverterClient {
+    pub fn respond(&self, installation: &str, response: MockResponse) {
+        self.responses
+            .lock()
+            .unwrap()
+            .insert(installation.to_string(), response);
+    }
+
+    pub fn respond_events(&self, installation: &str, events: Vec<InverterEvent>) {
+        self.events
+            .lock()
+            .unwrap()
+            .insert(installation.to_string(), events);
+    }
+
+    /// Names of the installations read, in order
+    pub fn reads(&self) -> Vec<String> {
+        self.reads.lock().unwrap().clone()
+    }
+}
+
+impl InverterClient for MockInverterClient {
+    fn snapshot<'a>(
+        &'a self,
+        installation: &'a PvInstallation,
+    ) -> BoxFuture<'a, Result<InverterSnapshot, InverterError>> {
+        let name = installation.config.name.clone();
+        self.reads.lock().unwrap().push(name.clone());
+        let response = self.responses.lock().unwrap().get(&name).cloned();
+        Box::pin(async move {
+            match response {
+                Some(MockResponse::Snapshot(snapshot)) => Ok(snapshot),
+                Some(MockResponse::Error(e)) => Err(e),
+                Some(MockResponse::Timeout(delay)) => {
+                    tokio::time::sleep(delay).await;
+                    Err(InverterError::Timeout)
+                }
+                None => Err(InverterError::Config(format!(
+                    "No mock response for {}",
+                    name
+                ))),
+            }
+        })
+    }
+
+    fn events<'a>(
+        &'a self,
+        installation: &'a PvInstallation,
+        start: i64,
+        end: i64,
+    ) -> BoxFuture<'a, Result<Vec<InverterEvent>, InverterError>> {
+        let events = self
+            .events
+            .lock()
+            .unwrap()
+            .get(&installation.config.name)
+            .map(|events| {
+                events
+                    .iter()
+                    .filter(|event| (start..=end).contains(&event.timestamp))
+                    .cloned()
+                    .collect()
+            })
+            .unwrap_or_default();
+        Box::pin(async move { Ok(events) })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pv_installations::{parse_installations, PvInstallations};
+
+    fn installations() -> PvInstallations {
+        PvInstallations::new(
+            parse_installations(
+                r#"[{"name": "pv2012", "inverter": "manual"},
+                    {"name": "pv2022", "inverter": "sunspec-modbus", "host": "127.0.0.1"}]"#,
+            )
+            .unwrap(),
+            None,
+        )
+    }
+
+    #[actix_rt::test]
+    async fn manual_installations_are_not_read() {
+        let installations = installations();
+        assert_eq!(
+            NetworkInverterClient::default()
+                .snapshot(&installations.installations[0])
+                .await,
+            Err(InverterError::Config("Not polled".to_string()))
+        );
+    }
+
+    #[actix_rt::test]
+    async fn mock_answers_as_told() {
+        let installations = installations();
+        let pv2022 = &installations.installations[1];
+        let mock = MockInverterClient::default();
+        assert!(matches!(
+            mock.snapshot(pv2022).await,
+            Err(InverterError::Config(_))
+        ));
+        mock.respond(
+            "pv2022",
+            MockResponse::Error(InverterError::HttpStatus(503)),
+        );
+        assert_eq!(
+            mock.snapshot(pv2022).await,
+            Err(InverterError::HttpStatus(503))
+        );
+        mock.respond("pv2022", MockResponse::Timeout(Duration::from_millis(10)));
+        assert_eq!(mock.snapshot(pv2022).await, Err(InverterError::Timeout));
+        assert_eq!(mock.reads(), vec!["pv2022"; 3]);
+    }
+}
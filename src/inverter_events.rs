@@ -0,0 +1,247 @@
+use std::fmt;
+use std::str::FromStr;
+
+use actix_web::{get, web, HttpResponse};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tera::Tera;
+use time::OffsetDateTime;
+
+use crate::data::{select_inverter_events, Database, EventVoltageRow};
+use crate::format_timestamp;
+use crate::inverter::InverterError;
+
+/// Events shown by default [days]
+const DEFAULT_DAYS: i64 = 30;
+/// Events shown at most, whatever the query [days]
+const MAX_DAYS: i64 = 3660;
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Severity {
+    Info,
+    Warning,
+    Error,
+}
+
+impl FromStr for Severity {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "info" | "information" => Ok(Severity::Info),
+            "warning" => Ok(Severity::Warning),
+            "error" | "fault" => Ok(Severity::Error),
+            _ => Err(format!("Unknown severity '{}'", s)),
+        }
+    }
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Severity::Info => write!(f, "Info"),
+            Severity::Warning => write!(f, "Warning"),
+            Severity::Error => write!(f, "Error"),
+        }
+    }
+}
+
+/// An entry of the event log of an inverter
+#[derive(Debug, PartialEq, Clone)]
+pub struct InverterEvent {
+    pub timestamp: i64,
+    /// SMA event number
+    pub code: u32,
+    pub severity: Severity,
+}
+
+/// Text of the common SMA event numbers, cf the event lists of the inverter
+/// manuals
+pub fn describe_event(code: u32) -> String {
+    match code {
+        101..=103 => "Grid overvoltage",
+        202..=205 => "Grid undervoltage",
+        301 => "10-minute average grid voltage too high",
+        401..=404 => "Grid disconnection (islanding or frequency change)",
+        501 => "Grid frequency out of range",
+        601 => "DC component in the grid current",
+        701 => "Grid frequency not permitted",
+        1501 => "Reconnection to the grid failed",
+        3301..=3303 => "Unstable operation, not enough PV power",
+        3401..=3407 => "DC overvoltage",
+        3501 => "Insulation failure",
+        3601 => "High leakage current",
+        3701 => "Residual current too high",
+        3801..=3802 => "DC overcurrent",
+        3901..=3902 => "Waiting for DC start conditions",
+        6001..=6499 => "Self-diagnosis: device fault",
+        _ => return format!("Event {}", code),
+    }
+    .to_string()
+}
+
+/// Events of the response of `dyn/getEvents.json`: per device, a list of
+/// entries with their number (`EvtId`), time (`TimeStamp`) and priority
+/// (`Prio`), of `device` or else the first device.  Entries without number
+/// or time are skipped, unknown priorities count as information.
+pub fn parse_events(
+    json: &Value,
+    device: Option<&str>,
+) -> Result<Vec<InverterEvent>, InverterError> {
+    let entries = json["result"]
+        .as_object()
+        .and_then(|devices| match device {
+            Some(device) => devices.get(device),
+            None => devices.values().next(),
+        })
+        .and_then(Value::as_array)
+        .ok_or_else(|| InverterError::BadJson("No events".to_string()))?;
+    Ok(entries
+        .iter()
+        .filter_map(|entry| {
+            Some(InverterEvent {
+                timestamp: entry["TimeStamp"].as_i64()?,
+                code: entry["EvtId"].as_u64()? as u32,
+                severity: entry["Prio"]
+                    .as_str()
+                    .and_then(|prio| Severity::from_str(prio).ok())
+                    .unwrap_or(Severity::Info),
+            })
+        })
+        .collect())
+}
+
+#[derive(Deserialize)]
+pub struct EventsQuery {
+    days: Option<i64>,
+}
+
+#[derive(Debug, PartialEq, Serialize)]
+struct EventView {
+    timestamp: String,
+    installation: String,
+    code: i64,
+    severity: String,
+    description: String,
+    voltages: Vec<String>,
+}
+
+impl From<&EventVoltageRow> for EventView {
+    fn from(row: &EventVoltageRow) -> Self {
+        EventView {
+            timestamp: format_timestamp(row.event.timestamp),
+            installation: row.event.installation.clone(),
+            code: row.event.code,
+            severity: row.event.severity.clone(),
+            description: describe_event(row.event.code as u32),
+            // Blank for the phases the meter does not have or did not record
+            voltages: row
+                .max_voltage_V
+                .iter()
+                .map(|v| v.map(|v| format!("{:.1}", v)).unwrap_or_default())
+                .collect(),
+        }
+    }
+}
+
+/// Stored inverter events, with the highest P1 voltage per phase in the 10
+/// minutes up to each: grid protection trips on the 10-minute average.
+#[get("/inverter/events")]
+pub async fn inverter_events_page(
+    tera: web::Data<Tera>,
+    database: web::Data<Database>,
+    query: web::Query<EventsQuery>,
+) -> HttpResponse {
+    let days = query
+        .days
+        .filter(|&days| days > 0)
+        .unwrap_or(DEFAULT_DAYS)
+        .min(MAX_DAYS);
+    let now = OffsetDateTime::now_utc().unix_timestamp();
+    let sqlite3 = database.sqlite3.clone();
+    let rows = web::block(move || select_inverter_events(&sqlite3, now - days * 86400))
+        .await
+        .map_err(|e| e.to_string())
+        .and_then(|rows| rows);
+    let mut context = tera::Context::new();
+    context.insert("days", &days);
+    match rows {
+        Ok(rows) => {
+            let has_voltage = rows
+                .iter()
+                .any(|row| row.max_voltage_V.iter().any(Option::is_some));
+            let events: Vec<EventView> = rows.iter().map(EventView::from).collect();
+            context.insert("events", &events);
+            context.insert("has_voltage", &has_voltage);
+        }
+        Err(e) => {
+            log::error!("Unable to read inverter events: {}", e);
+            context.insert("error", &e);
+        }
+    }
+    context.insert("now", &format_timestamp(now));
+    let rendered = tera.render("inverter_events.html", &context).unwrap();
+    HttpResponse::Ok().body(rendered)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::data::InverterEventRow;
+    use serde_json::json;
+
+    #[test]
+    fn events_of_the_log() {
+        let json = json!({"result": {"0199-xxxxx9BD": [
+            {"EntryId": 12, "EvtId": 101, "TimeStamp": 1729850000, "Prio": "Error"},
+            {"EntryId": 11, "EvtId": 3901, "TimeStamp": 1729814400, "Prio": "Info"},
+            {"EntryId": 10, "EvtId": 7002, "TimeStamp": 1729810000, "Prio": "?"},
+            {"EntryId": 9, "TimeStamp": 1729800000, "Prio": "Error"},
+        ]}});
+        assert_eq!(
+            parse_events(&json, None).unwrap(),
+            vec![
+                InverterEvent {
+                    timestamp: 1729850000,
+                    code: 101,
+                    severity: Severity::Error,
+                },
+                InverterEvent {
+                    timestamp: 1729814400,
+                    code: 3901,
+                    severity: Severity::Info,
+                },
+                InverterEvent {
+                    timestamp: 1729810000,
+                    code: 7002,
+                    severity: Severity::Info,
+                },
+            ]
+        );
+        assert!(parse_events(&json, Some("0199-other")).is_err());
+    }
+
+    #[test]
+    fn event_voltages() {
+        let row = EventVoltageRow {
+            event: InverterEventRow {
+                installation: "pv2022".to_string(),
+                timestamp: 1729850000,
+                code: 301,
+                severity: "Error".to_string(),
+            },
+            max_voltage_V: [Some(253.44), None, Some(249.0)],
+        };
+        let view = EventView::from(&row);
+        assert_eq!(view.description, "10-minute average grid voltage too high");
+        assert_eq!(view.voltages, vec!["253.4", "", "249.0"]);
+    }
+
+    #[test]
+    fn event_texts() {
+        assert_eq!(describe_event(102), "Grid overvoltage");
+        assert_eq!(describe_event(3501), "Insulation failure");
+        assert_eq!(describe_event(9999), "Event 9999");
+        assert_eq!(Severity::from_str(" warning"), Ok(Severity::Warning));
+    }
+}
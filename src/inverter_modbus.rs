@@ -0,0 +1,441 @@
+use std::time::Duration;
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+use crate::inverter::{InverterError, CONNECT_TIMEOUT, TOTAL_TIMEOUT};
+use crate::inverter_snapshot::{DeviceStatus, InverterSnapshot, WH_PER_KWH};
+use crate::pv_installations::Installation;
+
+pub const DEFAULT_MODBUS_PORT: u16 = 502;
+pub const DEFAULT_UNIT_ID: u8 = 1;
+
+const READ_HOLDING_REGISTERS: u8 = 0x03;
+/// At most 125 registers per request
+const MAX_REGISTERS: u16 = 125;
+
+/// Where devices put the SunSpec registers, most common first
+const SUNSPEC_BASES: [u16; 3] = [40000, 0, 50000];
+/// `SunS`
+const SUNSPEC_MARKER: [u16; 2] = [0x5375, 0x6e53];
+const COMMON_MODEL: u16 = 1;
+/// Integer and scale factor inverter models: single, split and three phase
+const INVERTER_MODELS: [u16; 3] = [101, 102, 103];
+const END_MODEL: u16 = 0xffff;
+/// Stop looking for the inverter model after that many models
+const MAX_MODELS: usize = 64;
+
+/// Offsets in the common model, after its id and length
+const COMMON_SERIAL_NUMBER: usize = 48;
+const COMMON_SERIAL_NUMBER_LEN: usize = 16;
+
+/// Offsets in the inverter models, after their id and length
+const INVERTER_W: usize = 12;
+const INVERTER_W_SF: usize = 13;
+const INVERTER_WH: usize = 22;
+const INVERTER_WH_SF: usize = 24;
+const INVERTER_ST: usize = 36;
+const INVERTER_MODEL_LEN: usize = 50;
+
+/// Values of unimplemented registers
+const NOT_IMPLEMENTED_INT16: u16 = 0x8000;
+const NOT_IMPLEMENTED_ACC32: u32 = 0;
+
+fn io_error(error: std::io::Error) -> InverterError {
+    InverterError::Connect(error.to_string())
+}
+
+/// Modbus TCP connection to one unit, only reading holding registers
+pub struct ModbusTcp {
+    stream: TcpStream,
+    unit_id: u8,
+    transaction: u16,
+}
+
+impl ModbusTcp {
+    pub async fn connect(
+        host: &str,
+        port: u16,
+        unit_id: u8,
+        connect_timeout: Duration,
+    ) -> Result<Self, InverterError> {
+        match tokio::time::timeout(connect_timeout, TcpStream::connect((host, port))).await {
+            Ok(Ok(stream)) => Ok(ModbusTcp {
+                stream,
+                unit_id,
+                transaction: 0,
+            }),
+            Ok(Err(e)) => Err(io_error(e)),
+            Err(_) => Err(InverterError::Timeout),
+        }
+    }
+
+    pub async fn read_holding_registers(
+        &mut self,
+        address: u16,
+        count: u16,
+    ) -> Result<Vec<u16>, InverterError> {
+        if count == 0 || count > MAX_REGISTERS {
+            return Err(InverterError::Modbus(format!(
+                "Cannot read {} registers at once",
+                count
+            )));
+        }
+        self.transaction = self.transaction.wrapping_add(1);
+        let mut request = Vec::with_capacity(12);
+        request.extend(self.transaction.to_be_bytes());
+        // Protocol 0 (Modbus), length of the rest
+        request.extend(0u16.to_be_bytes());
+        request.extend(6u16.to_be_bytes());
+        request.push(self.unit_id);
+        request.push(READ_HOLDING_REGISTERS);
+        request.extend(address.to_be_bytes());
+        request.extend(count.to_be_bytes());
+        self.stream.write_all(&request).await.map_err(io_error)?;
+
+        let mut header = [0u8; 7];
+        self.stream
+            .read_exact(&mut header)
+            .await
+            .map_err(io_error)?;
+        let transaction = u16::from_be_bytes([header[0], header[1]]);
+        let protocol = u16::from_be_bytes([header[2], header[3]]);
+        let length = u16::from_be_bytes([header[4], header[5]]) as usize;
+        if transaction != self.transaction || protocol != 0 || !(3..=254).contains(&length) {
+            return Err(InverterError::Modbus(format!(
+                "Unexpected header {:02x?}",
+                header
+            )));
+        }
+        let mut pdu = vec![0u8; length - 1];
+        self.stream.read_exact(&mut pdu).await.map_err(io_error)?;
+        match pdu[0] {
+            READ_HOLDING_REGISTERS
+                if pdu[1] as usize == 2 * count as usize && pdu.len() == 2 + 2 * count as usize =>
+            {
+                Ok(pdu[2..]
+                    .chunks(2)
+                    .map(|word| u16::from_be_bytes([word[0], word[1]]))
+                    .collect())
+            }
+            function if function == READ_HOLDING_REGISTERS | 0x80 => Err(InverterError::Modbus(
+                format!("Exception {} reading {}", pdu[1], address),
+            )),
+            _ => Err(InverterError::Modbus(format!(
+                "Unexpected response {:02x?}",
+                pdu
+            ))),
+        }
+    }
+}
+
+/// A SunSpec model: its id, where its registers start and how many
+#[derive(Debug, PartialEq)]
+struct Model {
+    id: u16,
+    address: u16,
+    length: u16,
+}
+
+/// Text of registers, two characters each, padded with NULs
+fn registers_to_string(registers: &[u16]) -> String {
+    let bytes: Vec<u8> = registers.iter().flat_map(|r| r.to_be_bytes()).collect();
+    String::from_utf8_lossy(&bytes)
+        .trim_end_matches('\0')
+        .trim()
+        .to_string()
+}
+
+/// `value` × 10^`scale_factor`, unless either is not implemented
+fn scaled(value: Option<f64>, scale_factor: u16) -> Option<f64> {
+    if scale_factor == NOT_IMPLEMENTED_INT16 {
+        return None;
+    }
+    // Dividing by 10^-sf rather than multiplying by 10^sf keeps e.g. 0.1 exact
+    let exponent = scale_factor as i16 as i32;
+    value.map(|value| {
+        if exponent < 0 {
+            value / 10f64.powi(-exponent)
+        } else {
+            value * 10f64.powi(exponent)
+        }
+    })
+}
+
+/// SunSpec operating state (`St`) of the inverter models
+fn operating_state(state: u16) -> DeviceStatus {
+    match state {
+        // MPPT
+        4 => DeviceStatus::Ok,
+        // Throttled
+        5 => DeviceStatus::Warning,
+        7 => DeviceStatus::Fault,
+        // Off, sleeping, shutting down, standby
+        1 | 2 | 6 | 8 => DeviceStatus::Off,
+        state => DeviceStatus::Other(state as u64),
+    }
+}
+
+/// Snapshot of the registers of the common and inverter models
+fn sunspec_snapshot(common: &[u16], inverter: &[u16]) -> Result<InverterSnapshot, InverterError> {
+    if inverter.len() < INVERTER_MODEL_LEN {
+        return Err(InverterError::Modbus(format!(
+            "Inverter model of {} registers",
+            inverter.len()
+        )));
+    }
+    let serial_number = common
+        .get(COMMON_SERIAL_NUMBER..COMMON_SERIAL_NUMBER + COMMON_SERIAL_NUMBER_LEN)
+        .map(registers_to_string)
+        .unwrap_or_default();
+    let power = Some(inverter[INVERTER_W])
+        .filter(|&w| w != NOT_IMPLEMENTED_INT16)
+        .map(|w| w as i16 as f64);
+    let energy = Some((inverter[INVERTER_WH] as u32) << 16 | inverter[INVERTER_WH + 1] as u32)
+        .filter(|&wh| wh != NOT_IMPLEMENTED_ACC32)
+        .map(|wh| wh as f64);
+    Ok(InverterSnapshot {
+        device: serial_number,
+        total_yield_kWh: scaled(energy, inverter[INVERTER_WH_SF]).map(|wh| wh / WH_PER_KWH),
+        // Not in the SunSpec inverter models
+        daily_yield_kWh: None,
+        ac_power_W: scaled(power, inverter[INVERTER_W_SF]),
+        grid_state: None,
+        device_status: Some(operating_state(inverter[INVERTER_ST])),
+    })
+}
+
+/// Inverter read over Modbus TCP, with the SunSpec common (1) and integer
+/// inverter (101 to 103) models
+pub struct SunSpecInverter {
+    host: String,
+    port: u16,
+    unit_id: u8,
+    connect_timeout: Duration,
+    total_timeout: Duration,
+}
+
+impl SunSpecInverter {
+    pub fn new(
+        host: &str,
+        port: u16,
+        unit_id: u8,
+        connect_timeout: Duration,
+        total_timeout: Duration,
+    ) -> Self {
+        SunSpecInverter {
+            host: host.to_string(),
+            port,
+            unit_id,
+            connect_timeout,
+            total_timeout,
+        }
+    }
+
+    /// Inverter of `installation`, at `host`, with the default timeouts
+    pub fn for_installation(installation: &Installation, host: &str) -> Self {
+        SunSpecInverter::new(
+            host,
+            installation.port.unwrap_or(DEFAULT_MODBUS_PORT),
+            installation.unit_id.unwrap_or(DEFAULT_UNIT_ID),
+            CONNECT_TIMEOUT,
+            TOTAL_TIMEOUT,
+        )
+    }
+
+    /// First register after the SunSpec marker
+    async fn find_base(modbus: &mut ModbusTcp) -> Result<u16, InverterError> {
+        for base in SUNSPEC_BASES {
+            match modbus.read_holding_registers(base, 2).await {
+                Ok(marker) if marker == SUNSPEC_MARKER => return Ok(base + 2),
+                // Nothing there
+                Ok(_) | Err(InverterError::Modbus(_)) => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Err(InverterError::Modbus("No SunSpec registers".to_string()))
+    }
+
+    async fn models(modbus: &mut ModbusTcp) -> Result<Vec<Model>, InverterError> {
+        let mut address = SunSpecInverter::find_base(modbus).await?;
+        let mut models = Vec::new();
+        while models.len() < MAX_MODELS {
+            let header = modbus.read_holding_registers(address, 2).await?;
+            if header[0] == END_MODEL {
+                break;
+            }
+            models.push(Model {
+                id: header[0],
+                address: address + 2,
+                length: header[1],
+            });
+            address = address.checked_add(2 + header[1]).ok_or_else(|| {
+                InverterError::Modbus("Models past the last register".to_string())
+            })?;
+        }
+        Ok(models)
+    }
+
+    async fn read_model(modbus: &mut ModbusTcp, model: &Model) -> Result<Vec<u16>, InverterError> {
+        modbus
+            .read_holding_registers(model.address, model.length.min(MAX_REGISTERS))
+            .await
+    }
+
+    async fn read_snapshot(&self) -> Result<InverterSnapshot, InverterError> {
+        let mut modbus =
+            ModbusTcp::connect(&self.host, self.port, self.unit_id, self.connect_timeout).await?;
+        let models = SunSpecInverter::models(&mut modbus).await?;
+        let model = |ids: &[u16]| {
+            models
+                .iter()
+                .find(|model| ids.contains(&model.id))
+                .ok_or_else(|| InverterError::Modbus(format!("No SunSpec model {:?}", ids)))
+        };
+        let common = SunSpecInverter::read_model(&mut modbus, model(&[COMMON_MODEL])?).await?;
+        let inverter = SunSpecInverter::read_model(&mut modbus, model(&INVERTER_MODELS)?).await?;
+        sunspec_snapshot(&common, &inverter)
+    }
+
+    /// Lifetime energy, AC power and operating state
+    pub async fn snapshot(&self) -> Result<InverterSnapshot, InverterError> {
+        tokio::time::timeout(self.total_timeout, self.read_snapshot())
+            .await
+            .unwrap_or(Err(InverterError::Timeout))
+    }
+}
+
+/// Modbus TCP server stand-in holding a register map
+#[cfg(test)]
+pub(crate) mod stub {
+    use std::collections::HashMap;
+    use std::time::Duration;
+
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    /// Registers of a fixture file: lines of the first register (decimal)
+    /// followed by the registers (hex), `#` comments
+    pub fn read_register_map(path: &str) -> HashMap<u16, u16> {
+        let mut registers = HashMap::new();
+        for line in std::fs::read_to_string(path).unwrap().lines() {
+            let mut words = line.split_whitespace();
+            let address = match words.next() {
+                Some(word) if !word.starts_with('#') => word.parse::<u16>().unwrap(),
+                _ => continue,
+            };
+            for (i, word) in words.enumerate() {
+                registers.insert(address + i as u16, u16::from_str_radix(word, 16).unwrap());
+            }
+        }
+        registers
+    }
+
+    /// Answers reads of holding registers from `registers`, with exception 2
+    /// (illegal data address) for registers not in there, after `delay`.
+    /// Returns the port.
+    pub async fn stub_modbus(registers: HashMap<u16, u16>, delay: Duration) -> u16 {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let registers = registers.clone();
+                tokio::spawn(async move {
+                    let mut request = [0u8; 12];
+                    while stream.read_exact(&mut request).await.is_ok() {
+                        tokio::time::sleep(delay).await;
+                        let address = u16::from_be_bytes([request[8], request[9]]);
+                        let count = u16::from_be_bytes([request[10], request[11]]);
+                        let values: Option<Vec<u16>> = (address..address.saturating_add(count))
+                            .map(|register| registers.get(&register).copied())
+                            .collect();
+                        let pdu = match values {
+                            Some(values) if request[7] == 0x03 => {
+                                let mut pdu = vec![0x03, 2 * count as u8];
+                                pdu.extend(values.iter().flat_map(|v| v.to_be_bytes()));
+                                pdu
+                            }
+                            _ => vec![request[7] | 0x80, 2],
+                        };
+                        let mut response = request[..4].to_vec();
+                        response.extend((pdu.len() as u16 + 1).to_be_bytes());
+                        response.push(request[6]);
+                        response.extend(pdu);
+                        if stream.write_all(&response).await.is_err() {
+                            break;
+                        }
+                    }
+                });
+            }
+        });
+        port
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::stub::{read_register_map, stub_modbus};
+    use super::*;
+    use std::collections::HashMap;
+
+    const FIXTURE: &str = "tests/test-data/sunspec_inverter.txt";
+
+    fn inverter(port: u16) -> SunSpecInverter {
+        SunSpecInverter::new(
+            "127.0.0.1",
+            port,
+            DEFAULT_UNIT_ID,
+            Duration::from_millis(200),
+            Duration::from_millis(500),
+        )
+    }
+
+    #[actix_rt::test]
+    async fn snapshot_from_register_map() {
+        let port = stub_modbus(read_register_map(FIXTURE), Duration::ZERO).await;
+        let snapshot = inverter(port).snapshot().await.unwrap();
+        assert_eq!(snapshot.device, "ES3K-2012-0042");
+        assert_eq!(snapshot.total_yield_kWh, Some(12345.678));
+        assert_eq!(snapshot.ac_power_W, Some(2333.0));
+        assert_eq!(snapshot.device_status, Some(DeviceStatus::Ok));
+        assert_eq!(snapshot.daily_yield_kWh, None);
+    }
+
+    #[actix_rt::test]
+    async fn base_zero_and_unimplemented_values() {
+        // The same models at register 0, without power nor energy
+        let mut registers: HashMap<u16, u16> = read_register_map(FIXTURE)
+            .into_iter()
+            .map(|(address, value)| (address - 40000, value))
+            .collect();
+        let inverter_model = 70 + 2;
+        registers.insert(inverter_model + INVERTER_W as u16, NOT_IMPLEMENTED_INT16);
+        registers.insert(inverter_model + INVERTER_WH as u16, 0);
+        registers.insert(inverter_model + INVERTER_WH as u16 + 1, 0);
+        registers.insert(inverter_model + INVERTER_ST as u16, 2);
+        let port = stub_modbus(registers, Duration::ZERO).await;
+        let snapshot = inverter(port).snapshot().await.unwrap();
+        assert_eq!(snapshot.total_yield_kWh, None);
+        assert_eq!(snapshot.ac_power_W, None);
+        assert_eq!(snapshot.device_status, Some(DeviceStatus::Off));
+    }
+
+    #[actix_rt::test]
+    async fn no_sunspec_or_no_answer() {
+        let port = stub_modbus(HashMap::new(), Duration::ZERO).await;
+        assert_eq!(
+            inverter(port).snapshot().await,
+            Err(InverterError::Modbus("No SunSpec registers".to_string()))
+        );
+        let port = stub_modbus(read_register_map(FIXTURE), Duration::from_secs(1)).await;
+        assert_eq!(inverter(port).snapshot().await, Err(InverterError::Timeout));
+    }
+
+    #[test]
+    fn scale_factors_and_strings() {
+        assert!((scaled(Some(5001.0), 0xfffe).unwrap() - 50.01).abs() < 1e-9);
+        assert_eq!(scaled(Some(5001.0), NOT_IMPLEMENTED_INT16), None);
+        assert_eq!(scaled(Some(12.0), 3), Some(12000.0));
+        assert_eq!(registers_to_string(&[0x4553, 0x3300, 0x0000]), "ES3");
+    }
+}
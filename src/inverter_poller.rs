@@ -0,0 +1,450 @@
+use std::collections::BTreeSet;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use actix_web::web;
+use time::OffsetDateTime;
+use tokio::time::Instant;
+
+use crate::data::{
+    insert_inverter_events, insert_production, Database, InverterEventRow, ProductionRow,
+};
+use crate::inverter_client::InverterClient;
+use crate::inverter_events::{describe_event, InverterEvent, Severity};
+use crate::inverter_snapshot::{DeviceStatus, InverterSnapshot};
+use crate::pv_installations::PvInstallation;
+use crate::sun::daylight;
+use crate::{format_timestamp, get_env_var};
+
+const DEFAULT_POLL_S: u64 = 300;
+
+/// Time between reads of the event log, when the device status stays Ok [s]
+const EVENTS_INTERVAL_S: i64 = 3600;
+/// How far back the event log is read first [s]
+const EVENTS_LOOKBACK_S: i64 = 7 * 86400;
+/// Read again on each read, for the events logged late [s]
+const EVENTS_OVERLAP_S: i64 = 3600;
+
+/// The inverter wakes up a bit before sunrise and reports the final daily
+/// yield a bit after sunset [s]
+const TWILIGHT_MARGIN_S: i64 = 1800;
+
+/// Time between polls, cf `RUST_HELLO_WORLD_INVERTER_POLL_S`
+pub fn configured_interval() -> Duration {
+    let seconds = get_env_var("RUST_HELLO_WORLD_INVERTER_POLL_S")
+        .ok()
+        .and_then(|s| u64::from_str(s.trim()).ok())
+        .filter(|&s| s > 0)
+        .unwrap_or(DEFAULT_POLL_S);
+    Duration::from_secs(seconds)
+}
+
+/// Latitude and longitude of the installation, cf
+/// `RUST_HELLO_WORLD_LATITUDE` and `RUST_HELLO_WORLD_LONGITUDE`
+pub fn configured_location() -> Option<(f64, f64)> {
+    let degrees = |name| {
+        get_env_var(name)
+            .ok()
+            .and_then(|s| f64::from_str(s.trim()).ok())
+    };
+    Some((
+        degrees("RUST_HELLO_WORLD_LATITUDE")?,
+        degrees("RUST_HELLO_WORLD_LONGITUDE")?,
+    ))
+}
+
+/// Whether the inverter may be awake at `now`: always without location
+pub fn is_awake(now: OffsetDateTime, location: Option<(f64, f64)>) -> bool {
+    match location {
+        Some((latitude, longitude)) => {
+            daylight(now.date(), latitude, longitude).contains(now, TWILIGHT_MARGIN_S)
+        }
+        None => true,
+    }
+}
+
+fn production_row(
+    installation: &str,
+    timestamp: i64,
+    snapshot: &InverterSnapshot,
+) -> ProductionRow {
+    ProductionRow {
+        installation: installation.to_string(),
+        timestamp,
+        total_yield_kWh: snapshot.total_yield_kWh,
+        daily_yield_kWh: snapshot.daily_yield_kWh,
+        ac_power_W: snapshot.ac_power_W,
+        grid_state: snapshot.grid_state.map(|state| state.to_string()),
+        device_status: snapshot.device_status.map(|status| status.to_string()),
+    }
+}
+
+async fn store(database: &Database, row: ProductionRow) {
+    let sqlite3 = database.sqlite3.clone();
+    match web::block(move || insert_production(&sqlite3, &row)).await {
+        Ok(Ok(_)) => {}
+        Ok(Err(e)) => log::error!("Unable to store inverter values: {}", e),
+        Err(e) => log::error!("Unable to store inverter values: {}", e),
+    }
+}
+
+fn event_rows(installation: &str, events: &[InverterEvent]) -> Vec<InverterEventRow> {
+    events
+        .iter()
+        .map(|event| InverterEventRow {
+            installation: installation.to_string(),
+            timestamp: event.timestamp,
+            code: event.code as i64,
+            severity: event.severity.to_string(),
+        })
+        .collect()
+}
+
+/// What was read of the event log of an inverter, and when to read it next
+struct EventLog {
+    read_until: i64,
+    next_read: i64,
+    /// Device status of the last poll, none when it failed
+    last_status: Option<DeviceStatus>,
+    /// Events read in the overlap of the next read (time, number)
+    seen: BTreeSet<(i64, u32)>,
+}
+
+impl EventLog {
+    fn new(now: i64) -> Self {
+        EventLog {
+            read_until: now - EVENTS_LOOKBACK_S,
+            next_read: now,
+            last_status: Some(DeviceStatus::Ok),
+            seen: BTreeSet::new(),
+        }
+    }
+
+    /// Whether the inverter tripped, going from the last device status to
+    /// `status`: one other than Ok, or none when the poll failed, as a
+    /// faulting inverter may no longer give its values.
+    fn tripped(&mut self, status: Option<DeviceStatus>) -> bool {
+        let tripped = status != self.last_status && status != Some(DeviceStatus::Ok);
+        self.last_status = status;
+        tripped
+    }
+
+    fn is_due(&self, now: i64, tripped: bool) -> bool {
+        tripped || now >= self.next_read
+    }
+
+    /// Read the event log of `installation` from the last read less the
+    /// overlap, store the events not seen yet and log their errors.
+    /// Returns how many were stored, none when the log could not be read.
+    async fn read(
+        &mut self,
+        installation: &PvInstallation,
+        client: &dyn InverterClient,
+        database: &Database,
+        now: i64,
+    ) -> Option<usize> {
+        let name = installation.config.name.as_str();
+        let events = match client
+            .events(installation, self.read_until - EVENTS_OVERLAP_S, now)
+            .await
+        {
+            Ok(events) => events,
+            Err(e) => {
+                log::warn!("{}: reading the event log: {}", name, e);
+                return None;
+            }
+        };
+        let events: Vec<InverterEvent> = events
+            .into_iter()
+            .filter(|event| !self.seen.contains(&(event.timestamp, event.code)))
+            .collect();
+        for event in &events {
+            if event.severity == Severity::Error {
+                log::warn!(
+                    "{}: inverter event {} at {}: {}",
+                    name,
+                    event.code,
+                    format_timestamp(event.timestamp),
+                    describe_event(event.code)
+                );
+            }
+        }
+        self.seen
+            .extend(events.iter().map(|event| (event.timestamp, event.code)));
+        self.seen = self.seen.split_off(&(now - EVENTS_OVERLAP_S, 0));
+        self.read_until = now;
+        self.next_read = now + EVENTS_INTERVAL_S;
+        if events.is_empty() {
+            return Some(0);
+        }
+        let rows = event_rows(name, &events);
+        let sqlite3 = database.sqlite3.clone();
+        match web::block(move || insert_inverter_events(&sqlite3, &rows)).await {
+            Ok(Ok(stored)) => Some(stored),
+            Ok(Err(e)) => {
+                log::error!("Unable to store inverter events: {}", e);
+                Some(0)
+            }
+            Err(e) => {
+                log::error!("Unable to store inverter events: {}", e);
+                Some(0)
+            }
+        }
+    }
+}
+
+/// Read the values of the inverter of `installation` into its cache, for a
+/// reader that found them missing or stale (cf
+/// `InverterCache::get_or_revalidate`).  They are only stored by the poller.
+pub async fn refresh(installation: Arc<PvInstallation>, client: Arc<dyn InverterClient>) {
+    let now = OffsetDateTime::now_utc().unix_timestamp();
+    match client.snapshot(&installation).await {
+        Ok(snapshot) => installation.cache.store(snapshot, now),
+        Err(e) => {
+            if installation.cache.failed(now, e.to_string()) {
+                log::warn!(
+                    "{}: inverter unreachable, polling paused",
+                    installation.config.name
+                );
+            }
+        }
+    }
+}
+
+/// Store the values of the inverter of `installation` every
+/// `RUST_HELLO_WORLD_INVERTER_POLL_S` seconds while the sun is up.  An
+/// unreachable inverter is left alone for a while, cf `CircuitBreaker`.  The event log, where there is one, is read
+/// every `EVENTS_INTERVAL_S` whether the values could be read or not, and as
+/// soon as the device status is no longer Ok or the values can no longer be
+/// read, when the inverter tripped.
+pub async fn run_inverter_poller(
+    installation: Arc<PvInstallation>,
+    client: Arc<dyn InverterClient>,
+    database: Database,
+) {
+    let name = installation.config.name.as_str();
+    let location = configured_location();
+    if location.is_none() {
+        log::info!("{}: no location configured, polling day and night", name);
+    }
+    let interval = configured_interval();
+    let mut next_poll = Instant::now();
+    // Errors are only logged when they change, not every poll
+    let mut last_error: Option<String> = None;
+    let has_event_log = installation.config.inverter.has_event_log();
+    let mut event_log = EventLog::new(OffsetDateTime::now_utc().unix_timestamp());
+    loop {
+        tokio::time::sleep_until(next_poll).await;
+        next_poll = (next_poll + interval).max(Instant::now());
+        let now = OffsetDateTime::now_utc();
+        if !is_awake(now, location) || !installation.cache.allows(now.unix_timestamp()) {
+            continue;
+        }
+        let status = match client.snapshot(&installation).await {
+            Ok(snapshot) => {
+                if last_error.take().is_some() {
+                    log::info!("{}: inverter reachable again", name);
+                }
+                let row = production_row(name, now.unix_timestamp(), &snapshot);
+                let status = snapshot.device_status;
+                installation.cache.store(snapshot, now.unix_timestamp());
+                store(&database, row).await;
+                status
+            }
+            Err(e) => {
+                let e = e.to_string();
+                if last_error.as_ref() != Some(&e) {
+                    log::warn!("{}: polling the inverter: {}", name, e);
+                    last_error = Some(e.clone());
+                }
+                if installation.cache.failed(now.unix_timestamp(), e) {
+                    log::warn!("{}: inverter unreachable, polling paused", name);
+                }
+                None
+            }
+        };
+        // The log tells why the inverter stopped, even when its values are
+        // gone
+        let now = now.unix_timestamp();
+        let tripped = event_log.tripped(status);
+        if has_event_log && event_log.is_due(now, tripped) {
+            event_log
+                .read(&installation, client.as_ref(), &database, now)
+                .await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::inverter::InverterError;
+    use crate::inverter_client::{MockInverterClient, MockResponse};
+    use crate::inverter_snapshot::GridState;
+    use crate::pv_installations::{parse_installations, PvInstallations};
+
+    #[test]
+    fn awake_during_the_day_only() {
+        let brussels = Some((50.85, 4.35));
+        // 2024-06-21
+        let midnight = OffsetDateTime::from_unix_timestamp(1718928000).unwrap();
+        let hour = time::Duration::hours(1);
+        assert!(!is_awake(midnight + 2 * hour, brussels));
+        assert!(is_awake(midnight + 3 * hour, brussels));
+        assert!(is_awake(midnight + 20 * hour, brussels));
+        assert!(!is_awake(midnight + 21 * hour, brussels));
+        assert!(is_awake(midnight + 2 * hour, None));
+    }
+
+    fn event(timestamp: i64, code: u32, severity: Severity) -> InverterEvent {
+        InverterEvent {
+            timestamp,
+            code,
+            severity,
+        }
+    }
+
+    #[test]
+    fn tripped_when_no_longer_ok() {
+        let mut event_log = EventLog::new(1729850000);
+        assert!(!event_log.tripped(Some(DeviceStatus::Ok)));
+        assert!(event_log.tripped(Some(DeviceStatus::Fault)));
+        // Once
+        assert!(!event_log.tripped(Some(DeviceStatus::Fault)));
+        assert!(!event_log.tripped(Some(DeviceStatus::Ok)));
+        // Nor when the values can no longer be read
+        assert!(event_log.tripped(None));
+        assert!(!event_log.tripped(None));
+        assert!(event_log.is_due(1729850000, false));
+        event_log.next_read = 1729853600;
+        assert!(!event_log.is_due(1729850001, false));
+        assert!(event_log.is_due(1729850001, true));
+    }
+
+    #[actix_rt::test]
+    async fn events_stored_once() {
+        let installations = PvInstallations::new(
+            parse_installations(
+                r#"[{"name": "pv2022", "inverter": "sma-webconnect", "host": "127.0.0.1",
+                     "certificate": "inverter.pem",
+                     "password": "s3cret"}]"#,
+            )
+            .unwrap(),
+            None,
+        );
+        let pv2022 = &installations.installations[0];
+        // Counts the events inserted
+        let database = Database {
+            sqlite3: "grep -c '^insert or ignore into inverter_events'".to_string(),
+        };
+        let now = 1729850000;
+        let client = MockInverterClient::default();
+        let mut events = vec![
+            event(now - 86400, 3901, Severity::Info),
+            event(now - 600, 101, Severity::Error),
+        ];
+        client.respond_events("pv2022", events.clone());
+        let mut event_log = EventLog::new(now);
+        assert_eq!(
+            event_log.read(pv2022, &client, &database, now).await,
+            Some(2)
+        );
+        assert!(!event_log.is_due(now + 1, false));
+
+        // Logged late, in the overlap with the previous read
+        events.push(event(now - 300, 102, Severity::Error));
+        client.respond_events("pv2022", events);
+        assert_eq!(
+            event_log.read(pv2022, &client, &database, now + 600).await,
+            Some(1)
+        );
+        assert_eq!(
+            event_log.read(pv2022, &client, &database, now + 1200).await,
+            Some(0)
+        );
+        assert!(event_log.is_due(now + 1200 + EVENTS_INTERVAL_S, false));
+    }
+
+    #[actix_rt::test]
+    async fn refresh_into_the_cache() {
+        let installations = PvInstallations::new(
+            parse_installations(
+                r#"[{"name": "pv2022", "inverter": "sunspec-modbus", "host": "127.0.0.1"}]"#,
+            )
+            .unwrap(),
+            None,
+        );
+        let pv2022 = &installations.installations[0];
+        let client = Arc::new(MockInverterClient::default());
+        client.respond(
+            "pv2022",
+            MockResponse::Error(InverterError::HttpStatus(503)),
+        );
+        refresh(pv2022.clone(), client.clone()).await;
+        assert_eq!(pv2022.cache.get(), None);
+        assert_eq!(
+            pv2022.cache.last_error(),
+            Some("HTTP status 503".to_string())
+        );
+
+        let snapshot = InverterSnapshot {
+            device: "0199-xxxxx9BD".to_string(),
+            total_yield_kWh: Some(12345.678),
+            daily_yield_kWh: None,
+            ac_power_W: None,
+            grid_state: None,
+            device_status: None,
+        };
+        client.respond("pv2022", MockResponse::Snapshot(snapshot.clone()));
+        refresh(pv2022.clone(), client.clone()).await;
+        assert_eq!(
+            pv2022.cache.get().map(|cached| cached.snapshot),
+            Some(snapshot)
+        );
+        assert_eq!(pv2022.cache.last_error(), None);
+        assert_eq!(client.reads(), vec!["pv2022"; 2]);
+    }
+
+    #[test]
+    fn events_to_rows() {
+        let events = [InverterEvent {
+            timestamp: 1729850000,
+            code: 101,
+            severity: Severity::Error,
+        }];
+        assert_eq!(
+            event_rows("pv2022", &events),
+            vec![InverterEventRow {
+                installation: "pv2022".to_string(),
+                timestamp: 1729850000,
+                code: 101,
+                severity: "Error".to_string(),
+            }]
+        );
+    }
+
+    #[test]
+    fn snapshot_to_row() {
+        let snapshot = InverterSnapshot {
+            device: "0199-xxxxx9BD".to_string(),
+            total_yield_kWh: Some(12345.678),
+            daily_yield_kWh: None,
+            ac_power_W: Some(1500.0),
+            grid_state: Some(GridState::Connected),
+            device_status: Some(DeviceStatus::Other(12)),
+        };
+        assert_eq!(
+            production_row("pv2022", 1729814400, &snapshot),
+            ProductionRow {
+                installation: "pv2022".to_string(),
+                timestamp: 1729814400,
+                total_yield_kWh: Some(12345.678),
+                daily_yield_kWh: None,
+                ac_power_W: Some(1500.0),
+                grid_state: Some("Connected".to_string()),
+                device_status: Some("Status 12".to_string()),
+            }
+        );
+    }
+}
@@ -0,0 +1,324 @@
+use serde_json::{json, Value};
+
+use crate::inverter::{InverterError, InverterHttps};
+use crate::inverter_events::{parse_events, InverterEvent};
+use crate::inverter_snapshot::{ExtendedValues, InverterSnapshot, ValueMapping, WH_PER_KWH};
+use crate::pv_installations::Installation;
+
+/// Loggers of the total yield, one value every 5 minutes, day and month
+pub const FIVE_MINUTE_LOGGER: u32 = 28672;
+pub const DAILY_LOGGER: u32 = 28704;
+pub const MONTHLY_LOGGER: u32 = 28736;
+
+/// Total yield at a time of a logger
+#[derive(Debug, PartialEq, Clone)]
+#[allow(non_snake_case)]
+pub struct LoggerPoint {
+    pub timestamp: i64,
+    pub total_yield_kWh: Option<f64>,
+}
+
+/// Logged in session of the SMA WebConnect web UI, for the values not on the
+/// dashboard.  The inverter only allows a few sessions at a time, and keeps
+/// them open for a while unless logged out: call `logout` when done.
+pub struct WebConnectSession {
+    https: InverterHttps,
+    /// `usr` (user) or `istl` (installer)
+    right: String,
+    password: String,
+    mapping: ValueMapping,
+    sid: Option<String>,
+}
+
+impl WebConnectSession {
+    pub fn new(https: InverterHttps, right: &str, password: &str, mapping: ValueMapping) -> Self {
+        WebConnectSession {
+            https,
+            right: right.to_string(),
+            password: password.to_string(),
+            mapping,
+            sid: None,
+        }
+    }
+
+    /// Session on the inverter of `installation`, at `host`
+    pub fn for_installation(
+        installation: &Installation,
+        host: &str,
+    ) -> Result<Self, InverterError> {
+        let password = installation
+            .password
+            .as_deref()
+            .ok_or_else(|| InverterError::Config(format!("{}: no password", installation.name)))?;
+        Ok(WebConnectSession::new(
+            InverterHttps::pinned(host, installation.certificate()?)?,
+            installation.user.as_deref().unwrap_or("usr"),
+            password,
+            installation.mapping()?,
+        ))
+    }
+
+    pub fn host(&self) -> &str {
+        self.https.host()
+    }
+
+    pub fn is_logged_in(&self) -> bool {
+        self.sid.is_some()
+    }
+
+    async fn login(&mut self) -> Result<String, InverterError> {
+        let response = self
+            .https
+            .post_json(
+                "dyn/login.json",
+                &json!({"right": self.right, "pass": self.password}),
+            )
+            .await?;
+        match response["result"]["sid"].as_str() {
+            Some(sid) => {
+                self.sid = Some(sid.to_string());
+                Ok(sid.to_string())
+            }
+            None => Err(InverterError::Login(match response["err"].as_u64() {
+                Some(401) => "wrong password".to_string(),
+                Some(503) => "no session left, try again later".to_string(),
+                _ => response.to_string(),
+            })),
+        }
+    }
+
+    /// Call `path` with the session id, logging in first if needed and again
+    /// once if the session expired.
+    async fn call(&mut self, path: &str, body: &Value) -> Result<Value, InverterError> {
+        let mut renewed = false;
+        loop {
+            let sid = match &self.sid {
+                Some(sid) => sid.clone(),
+                None => {
+                    renewed = true;
+                    self.login().await?
+                }
+            };
+            let response = self
+                .https
+                .post_json(&format!("{}?sid={}", path, sid), body)
+                .await?;
+            match response["err"].as_u64() {
+                None => return Ok(response),
+                Some(401) if !renewed => {
+                    log::info!("Inverter session expired, logging in again");
+                    self.sid = None;
+                }
+                Some(401) => {
+                    self.sid = None;
+                    return Err(InverterError::Login("session refused".to_string()));
+                }
+                Some(err) => return Err(InverterError::BadJson(format!("{}: err {}", path, err))),
+            }
+        }
+    }
+
+    /// The values of the dashboard, from the same keys
+    pub async fn snapshot(&mut self) -> Result<InverterSnapshot, InverterError> {
+        let body = json!({"destDev": [], "keys": self.mapping.keys()});
+        let response = self.call("dyn/getValues.json", &body).await?;
+        self.mapping.snapshot(&response)
+    }
+
+    /// DC input voltages and currents, temperature
+    pub async fn extended_values(&mut self) -> Result<ExtendedValues, InverterError> {
+        let body = json!({"destDev": [], "keys": self.mapping.keys()});
+        let response = self.call("dyn/getValues.json", &body).await?;
+        self.mapping.extended_values(&response)
+    }
+
+    /// Values of the 5 minute logger from `start` to `end` (Unix timestamps)
+    pub async fn logger(
+        &mut self,
+        start: i64,
+        end: i64,
+    ) -> Result<Vec<LoggerPoint>, InverterError> {
+        self.logger_values(FIVE_MINUTE_LOGGER, start, end).await
+    }
+
+    /// Values of the logger `key` from `start` to `end` (Unix timestamps)
+    pub async fn logger_values(
+        &mut self,
+        key: u32,
+        start: i64,
+        end: i64,
+    ) -> Result<Vec<LoggerPoint>, InverterError> {
+        let body = json!({
+            "destDev": [],
+            "key": key,
+            "tStart": start,
+            "tEnd": end,
+        });
+        let response = self.call("dyn/getLogger.json", &body).await?;
+        let points = response["result"]
+            .as_object()
+            .and_then(|devices| match &self.mapping.device {
+                Some(device) => devices.get(device),
+                None => devices.values().next(),
+            })
+            .and_then(Value::as_array)
+            .ok_or_else(|| InverterError::BadJson("No logger values".to_string()))?;
+        points
+            .iter()
+            .map(|point| {
+                Ok(LoggerPoint {
+                    timestamp: point["t"]
+                        .as_i64()
+                        .ok_or_else(|| InverterError::BadJson(format!("No time in {}", point)))?,
+                    total_yield_kWh: point["v"].as_f64().map(|value| value / WH_PER_KWH),
+                })
+            })
+            .collect()
+    }
+
+    /// Entries of the event log from `start` to `end` (Unix timestamps)
+    pub async fn events(
+        &mut self,
+        start: i64,
+        end: i64,
+    ) -> Result<Vec<InverterEvent>, InverterError> {
+        let body = json!({
+            "destDev": [],
+            "lang": "en-US",
+            "tStart": start,
+            "tEnd": end,
+        });
+        let response = self.call("dyn/getEvents.json", &body).await?;
+        parse_events(&response, self.mapping.device.as_deref())
+    }
+
+    /// Free the session on the inverter
+    pub async fn logout(&mut self) -> Result<(), InverterError> {
+        match self.sid.take() {
+            Some(sid) => self
+                .https
+                .post_json(&format!("dyn/logout.json?sid={}", sid), &json!({}))
+                .await
+                .map(|_| ()),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::inverter::stub::{json_response, stub_inverter, StubInverter};
+    use std::collections::HashSet;
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    #[derive(Default)]
+    struct Sessions {
+        open: HashSet<String>,
+        logins: usize,
+    }
+
+    /// Imitates the endpoints of the web UI, with the password `s3cret`
+    async fn web_connect(sessions: Arc<Mutex<Sessions>>) -> StubInverter {
+        stub_inverter(
+            move |line, body| {
+                let mut sessions = sessions.lock().unwrap();
+                let path = line.split_whitespace().nth(1).unwrap_or("");
+                let (path, sid) = path.split_once("?sid=").unwrap_or((path, ""));
+                let body: Value = serde_json::from_str(body).unwrap_or_default();
+                json_response(&match path {
+                    "/dyn/login.json" if body["pass"] == "s3cret" => {
+                        sessions.logins += 1;
+                        let sid = format!("sid{}", sessions.logins);
+                        sessions.open.insert(sid.clone());
+                        json!({"result": {"sid": sid}}).to_string()
+                    }
+                    "/dyn/login.json" => json!({"err": 401}).to_string(),
+                    _ if !sessions.open.contains(sid) => json!({"err": 401}).to_string(),
+                    "/dyn/logout.json" => {
+                        sessions.open.remove(sid);
+                        json!({"result": {"isLogin": false}}).to_string()
+                    }
+                    "/dyn/getValues.json" => json!({"result": {"0199-xxxxx9BD": {
+                        "6400_00260100": {"1": [{"val": 12345678}]},
+                        "6380_40451F00": {"1": [{"val": 35250}, {"val": 34110}]},
+                        "6380_40452100": {"1": [{"val": 5120}, {"val": 4980}]},
+                        "6100_40237700": {"1": [{"val": 415}]},
+                    }}})
+                    .to_string(),
+                    "/dyn/getLogger.json" => json!({"result": {"0199-xxxxx9BD": [
+                        {"t": body["tStart"], "v": 12345000},
+                        {"t": body["tStart"].as_i64().unwrap() + 300, "v": null},
+                    ]}})
+                    .to_string(),
+                    "/dyn/getEvents.json" => json!({"result": {"0199-xxxxx9BD": [
+                        {"EntryId": 2, "EvtId": 101, "TimeStamp": body["tEnd"], "Prio": "Error"},
+                    ]}})
+                    .to_string(),
+                    _ => json!({"err": 404}).to_string(),
+                })
+            },
+            Duration::ZERO,
+        )
+        .await
+    }
+
+    #[actix_rt::test]
+    async fn session_renewed_and_logged_out() {
+        let sessions = Arc::new(Mutex::new(Sessions::default()));
+        let inverter = web_connect(sessions.clone()).await;
+        let mut session =
+            WebConnectSession::new(inverter.client(), "usr", "s3cret", ValueMapping::default());
+
+        let values = session.extended_values().await.unwrap();
+        assert_eq!(values.dc_inputs.len(), 2);
+        assert_eq!(values.dc_inputs[1].current_A, Some(4.98));
+        assert_eq!(values.temperature_C, Some(41.5));
+        assert!(session.is_logged_in());
+
+        // Same session
+        let snapshot = session.snapshot().await.unwrap();
+        assert_eq!(snapshot.total_yield_kWh, Some(12345.678));
+        assert_eq!(sessions.lock().unwrap().logins, 1);
+
+        // Expired on the inverter side
+        sessions.lock().unwrap().open.clear();
+        assert_eq!(
+            session.logger(1729814400, 1729815000).await,
+            Ok(vec![
+                LoggerPoint {
+                    timestamp: 1729814400,
+                    total_yield_kWh: Some(12345.0),
+                },
+                LoggerPoint {
+                    timestamp: 1729814700,
+                    total_yield_kWh: None,
+                },
+            ])
+        );
+        assert_eq!(sessions.lock().unwrap().logins, 2);
+        let events = session.events(1729814400, 1729815000).await.unwrap();
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].timestamp, 1729815000);
+        assert_eq!(events[0].code, 101);
+
+        session.logout().await.unwrap();
+        assert!(!session.is_logged_in());
+        assert!(sessions.lock().unwrap().open.is_empty());
+        session.logout().await.unwrap();
+    }
+
+    #[actix_rt::test]
+    async fn wrong_password() {
+        let sessions = Arc::new(Mutex::new(Sessions::default()));
+        let inverter = web_connect(sessions).await;
+        let mut session =
+            WebConnectSession::new(inverter.client(), "usr", "guess", ValueMapping::default());
+        assert_eq!(
+            session.extended_values().await,
+            Err(InverterError::Login("wrong password".to_string()))
+        );
+        assert!(!session.is_logged_in());
+    }
+}
@@ -0,0 +1,345 @@
+use std::fmt;
+
+use serde_json::Value;
+
+use crate::inverter::InverterError;
+
+/// SMA values are integers in base units (W, Wh) or fractions of them.
+/// They are divided by these, multiplying by the inverse (0.001, ...) would
+/// give e.g. 1000.0020000000001 kWh for 1000002 Wh.
+pub const WH_PER_KWH: f64 = 1000.0;
+pub const W_PER_W: f64 = 1.0;
+pub const CENTIVOLTS_PER_V: f64 = 100.0;
+pub const MILLIAMPERES_PER_A: f64 = 1000.0;
+pub const DECIDEGREES_PER_C: f64 = 10.0;
+
+/// SMA status values are tags: numbers standing for a text of the web UI
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum DeviceStatus {
+    Ok,
+    Warning,
+    Fault,
+    Off,
+    Other(u64),
+}
+
+impl From<u64> for DeviceStatus {
+    fn from(tag: u64) -> Self {
+        match tag {
+            307 => DeviceStatus::Ok,
+            455 => DeviceStatus::Warning,
+            35 => DeviceStatus::Fault,
+            303 => DeviceStatus::Off,
+            tag => DeviceStatus::Other(tag),
+        }
+    }
+}
+
+impl fmt::Display for DeviceStatus {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DeviceStatus::Ok => write!(f, "Ok"),
+            DeviceStatus::Warning => write!(f, "Warning"),
+            DeviceStatus::Fault => write!(f, "Fault"),
+            DeviceStatus::Off => write!(f, "Off"),
+            DeviceStatus::Other(tag) => write!(f, "Status {}", tag),
+        }
+    }
+}
+
+/// State of the grid relay
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum GridState {
+    Connected,
+    Disconnected,
+    Other(u64),
+}
+
+impl From<u64> for GridState {
+    fn from(tag: u64) -> Self {
+        match tag {
+            51 => GridState::Connected,
+            311 => GridState::Disconnected,
+            tag => GridState::Other(tag),
+        }
+    }
+}
+
+impl fmt::Display for GridState {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            GridState::Connected => write!(f, "Connected"),
+            GridState::Disconnected => write!(f, "Disconnected"),
+            GridState::Other(tag) => write!(f, "Relay {}", tag),
+        }
+    }
+}
+
+/// Values of the inverter at one time, in the units of their names.  Missing
+/// values are `None`: the inverter reports `null` for most of them at night.
+#[derive(Debug, PartialEq, Clone)]
+#[allow(non_snake_case)]
+pub struct InverterSnapshot {
+    /// Key of the device in the response (`0199-` + serial number)
+    pub device: String,
+    pub total_yield_kWh: Option<f64>,
+    pub daily_yield_kWh: Option<f64>,
+    pub ac_power_W: Option<f64>,
+    pub grid_state: Option<GridState>,
+    pub device_status: Option<DeviceStatus>,
+}
+
+/// One DC input (string of panels) of the inverter
+#[derive(Debug, PartialEq, Clone)]
+#[allow(non_snake_case)]
+pub struct DcInput {
+    pub voltage_V: Option<f64>,
+    pub current_A: Option<f64>,
+}
+
+/// Values only available after login, cf `inverter_session`
+#[derive(Debug, PartialEq, Clone)]
+#[allow(non_snake_case)]
+pub struct ExtendedValues {
+    pub device: String,
+    pub dc_inputs: Vec<DcInput>,
+    pub temperature_C: Option<f64>,
+}
+
+/// Where the values are in the responses of the web UI.  The device is
+/// discovered unless set, and keys can be overridden, e.g. with
+/// `ac_power=6100_40263F00,daily_yield=6400_00262200`.
+#[derive(Debug, PartialEq, Clone)]
+pub struct ValueMapping {
+    pub device: Option<String>,
+    pub total_yield: String,
+    pub daily_yield: String,
+    pub ac_power: String,
+    pub grid_state: String,
+    pub device_status: String,
+    pub dc_voltage: String,
+    pub dc_current: String,
+    pub temperature: String,
+}
+
+impl Default for ValueMapping {
+    fn default() -> Self {
+        ValueMapping {
+            device: None,
+            total_yield: "6400_00260100".to_string(),
+            daily_yield: "6400_00262200".to_string(),
+            ac_power: "6100_40263F00".to_string(),
+            grid_state: "6180_08416400".to_string(),
+            device_status: "6180_08214800".to_string(),
+            dc_voltage: "6380_40451F00".to_string(),
+            dc_current: "6380_40452100".to_string(),
+            temperature: "6100_40237700".to_string(),
+        }
+    }
+}
+
+impl ValueMapping {
+    /// Apply `name=key` overrides, separated by commas
+    pub fn with_keys(mut self, overrides: &str) -> Result<Self, String> {
+        for pair in overrides
+            .split(',')
+            .map(str::trim)
+            .filter(|p| !p.is_empty())
+        {
+            let (name, key) = pair
+                .split_once('=')
+                .ok_or_else(|| format!("Expected name=key, got '{}'", pair))?;
+            let key = key.trim().to_string();
+            match name.trim() {
+                "total_yield" => self.total_yield = key,
+                "daily_yield" => self.daily_yield = key,
+                "ac_power" => self.ac_power = key,
+                "grid_state" => self.grid_state = key,
+                "device_status" => self.device_status = key,
+                "dc_voltage" => self.dc_voltage = key,
+                "dc_current" => self.dc_current = key,
+                "temperature" => self.temperature = key,
+                name => return Err(format!("Unknown inverter value '{}'", name)),
+            }
+        }
+        Ok(self)
+    }
+
+    /// Keys to ask `getValues.json` for, or to discover the device with
+    pub fn keys(&self) -> [&str; 8] {
+        [
+            &self.total_yield,
+            &self.daily_yield,
+            &self.ac_power,
+            &self.grid_state,
+            &self.device_status,
+            &self.dc_voltage,
+            &self.dc_current,
+            &self.temperature,
+        ]
+    }
+
+    /// The configured device, or the first one with any of the mapped values
+    fn device<'a>(&self, result: &'a Value) -> Result<(&'a str, &'a Value), InverterError> {
+        let devices = result
+            .as_object()
+            .ok_or_else(|| InverterError::BadJson("No result".to_string()))?;
+        match &self.device {
+            Some(device) => devices
+                .get_key_value(device)
+                .map(|(key, values)| (key.as_str(), values))
+                .ok_or_else(|| InverterError::BadJson(format!("No device {}", device))),
+            None => devices
+                .iter()
+                .find(|(_, values)| self.keys().iter().any(|key| values.get(key).is_some()))
+                .map(|(key, values)| (key.as_str(), values))
+                .ok_or_else(|| InverterError::BadJson("No device with known values".to_string())),
+        }
+    }
+
+    pub fn snapshot(&self, json: &Value) -> Result<InverterSnapshot, InverterError> {
+        let (device, values) = self.device(&json["result"])?;
+        // Values of the whole device, as opposed to per phase or string
+        let first = |key: &str| &values[key]["1"][0]["val"];
+        let number = |key: &str, units: f64| first(key).as_f64().map(|value| value / units);
+        let tag = |key: &str| first(key)[0]["tag"].as_u64();
+        Ok(InverterSnapshot {
+            device: device.to_string(),
+            total_yield_kWh: number(&self.total_yield, WH_PER_KWH),
+            daily_yield_kWh: number(&self.daily_yield, WH_PER_KWH),
+            ac_power_W: number(&self.ac_power, W_PER_W),
+            grid_state: tag(&self.grid_state).map(GridState::from),
+            device_status: tag(&self.device_status).map(DeviceStatus::from),
+        })
+    }
+
+    pub fn extended_values(&self, json: &Value) -> Result<ExtendedValues, InverterError> {
+        let (device, values) = self.device(&json["result"])?;
+        // One value per DC input, in order
+        let inputs = |key: &str, units: f64| -> Vec<Option<f64>> {
+            values[key]["1"]
+                .as_array()
+                .map(|inputs| {
+                    inputs
+                        .iter()
+                        .map(|input| input["val"].as_f64().map(|value| value / units))
+                        .collect()
+                })
+                .unwrap_or_default()
+        };
+        let voltages = inputs(&self.dc_voltage, CENTIVOLTS_PER_V);
+        let currents = inputs(&self.dc_current, MILLIAMPERES_PER_A);
+        let dc_inputs = (0..voltages.len().max(currents.len()))
+            .map(|i| DcInput {
+                voltage_V: voltages.get(i).copied().flatten(),
+                current_A: currents.get(i).copied().flatten(),
+            })
+            .collect();
+        Ok(ExtendedValues {
+            device: device.to_string(),
+            dc_inputs,
+            temperature_C: values[self.temperature.as_str()]["1"][0]["val"]
+                .as_f64()
+                .map(|value| value / DECIDEGREES_PER_C),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn dash_values() -> Value {
+        json!({"result": {"0199-xxxxx9BD": {
+            "6400_00260100": {"1": [{"val": 12345678}]},
+            "6400_00262200": {"1": [{"val": 8765}]},
+            "6100_40263F00": {"1": [{"val": null}]},
+            "6180_08416400": {"1": [{"val": [{"tag": 311}]}]},
+            "6180_08214800": {"1": [{"val": [{"tag": 307}]}]},
+        }}})
+    }
+
+    #[test]
+    fn snapshot_of_discovered_device() {
+        assert_eq!(
+            ValueMapping::default().snapshot(&dash_values()),
+            Ok(InverterSnapshot {
+                device: "0199-xxxxx9BD".to_string(),
+                total_yield_kWh: Some(12345.678),
+                daily_yield_kWh: Some(8.765),
+                ac_power_W: None,
+                grid_state: Some(GridState::Disconnected),
+                device_status: Some(DeviceStatus::Ok),
+            })
+        );
+        assert!(ValueMapping::default()
+            .snapshot(&json!({"result": {"0199-1": {"0000_0000": {}}}}))
+            .is_err());
+    }
+
+    #[test]
+    fn configured_device_and_keys() {
+        let mapping = ValueMapping {
+            device: Some("0199-xxxxx9BD".to_string()),
+            ..Default::default()
+        }
+        .with_keys("ac_power = 6400_00262200, ")
+        .unwrap();
+        let snapshot = mapping.snapshot(&dash_values()).unwrap();
+        assert_eq!(snapshot.ac_power_W, Some(8765.0));
+        let mapping = ValueMapping {
+            device: Some("0199-other".to_string()),
+            ..mapping
+        };
+        assert_eq!(
+            mapping.snapshot(&dash_values()),
+            Err(InverterError::BadJson("No device 0199-other".to_string()))
+        );
+        let values = json!({"result": {"0199-xxxxx9BD": {
+            "6400_00260100": {"1": [{"val": 1000002}]},
+        }}});
+        assert_eq!(
+            ValueMapping::default()
+                .snapshot(&values)
+                .unwrap()
+                .total_yield_kWh,
+            Some(1000.002)
+        );
+        assert!(ValueMapping::default().with_keys("power=1").is_err());
+        assert!(ValueMapping::default().with_keys("ac_power").is_err());
+    }
+
+    #[test]
+    fn extended_values_per_dc_input() {
+        let values = json!({"result": {"0199-xxxxx9BD": {
+            "6380_40451F00": {"1": [{"val": 35250}, {"val": 34110}]},
+            "6380_40452100": {"1": [{"val": 5120}, {"val": null}]},
+            "6100_40237700": {"1": [{"val": 415}]},
+        }}});
+        assert_eq!(
+            ValueMapping::default().extended_values(&values),
+            Ok(ExtendedValues {
+                device: "0199-xxxxx9BD".to_string(),
+                dc_inputs: vec![
+                    DcInput {
+                        voltage_V: Some(352.5),
+                        current_A: Some(5.12),
+                    },
+                    DcInput {
+                        voltage_V: Some(341.1),
+                        current_A: None,
+                    },
+                ],
+                temperature_C: Some(41.5),
+            })
+        );
+    }
+
+    #[test]
+    fn status_tags() {
+        assert_eq!(DeviceStatus::from(35), DeviceStatus::Fault);
+        assert_eq!(DeviceStatus::from(1), DeviceStatus::Other(1));
+        assert_eq!(GridState::from(51), GridState::Connected);
+    }
+}
@@ -1,14 +1,45 @@
 use actix_files::NamedFile;
 use actix_web::{get, post, web, App, HttpRequest, HttpResponse};
-use chrono::Utc;
+use chrono::{TimeZone, Utc};
 use chrono_tz::Tz;
 use serde::{Deserialize, Serialize};
-use serde_json::Value;
+use std::collections::HashMap;
 use std::fs::File;
 use std::io::{BufRead, BufReader};
 use std::path::PathBuf;
+use std::sync::Arc;
 use tera::Tera;
-use tokio::process::Command;
+
+use inverter_client::{InverterClient, NetworkInverterClient};
+
+pub mod data;
+pub mod inverter;
+pub mod inverter_address;
+pub mod inverter_backfill;
+pub mod inverter_cache;
+pub mod inverter_client;
+pub mod inverter_events;
+pub mod inverter_modbus;
+pub mod inverter_poller;
+pub mod inverter_session;
+pub mod inverter_snapshot;
+pub mod p1_appliances;
+pub mod p1_archive;
+pub mod p1_base_load;
+pub mod p1_bulk;
+pub mod p1_clock;
+pub mod p1_events;
+pub mod p1_ingest;
+pub mod p1_live;
+pub mod p1_meter;
+pub mod p1_phases;
+pub mod p1_proxy;
+pub mod p1_reader;
+pub mod p1_simulator;
+pub mod p1_telegram;
+pub mod pv_installations;
+pub mod pv_performance;
+pub mod sun;
 
 pub fn empty_string_as_none(
     name: &str,
@@ -18,8 +49,8 @@ pub fn empty_string_as_none(
     match opt_de {
         None => None,
         Some(de) => {
-            let opt = de.trim().replace(",", ".");
-            if opt == "" {
+            let opt = de.trim().replace(',', ".");
+            if opt.is_empty() {
                 None
             } else {
                 opt.parse().map(Some).unwrap_or_else(|e| {
@@ -45,9 +76,9 @@ pub struct MeterReadingsUserInput {
     pub water_m3: Option<String>,
 }
 
-impl ToString for MeterReadingsUserInput {
+impl std::fmt::Display for MeterReadingsUserInput {
     #[allow(non_snake_case)]
-    fn to_string(&self) -> String {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         let timestamp = self.timestamp.to_string();
         let pv_2022_prod_kWh = self.pv_2022_prod_kWh.as_deref().unwrap_or("null");
         let pv_2012_prod_kWh = self.pv_2012_prod_kWh.as_deref().unwrap_or("null");
@@ -58,7 +89,8 @@ impl ToString for MeterReadingsUserInput {
         let gas_m3 = self.gas_m3.as_deref().unwrap_or("null");
         let water_m3 = self.water_m3.as_deref().unwrap_or("null");
 
-        format!(
+        write!(
+            f,
             "MeterReadingsUserInput(timestamp={}, pv_2022_prod_kWh={}, pv_2012_prod_kWh={}, peak_hour_consumption_kWh={}, off_hour_consumption_kWh={}, peak_hour_injection_kWh={}, off_hour_injection_kWh={}, gas_m3={}, water_m3={})",
             timestamp,
             pv_2022_prod_kWh,
@@ -124,7 +156,7 @@ fn parse_meter_values(ui: MeterReadingsUserInput) -> core::result::Result<MeterR
         water_m3: empty_string_as_none("water_m3", ui.water_m3.as_deref(), error_messages),
     };
 
-    if error_messages.len() == 0 {
+    if error_messages.is_empty() {
         Ok(result)
     } else {
         Err(error_messages.join("; "))
@@ -159,8 +191,27 @@ pub async fn static_files(req: HttpRequest) -> actix_web::Result<NamedFile> {
     Ok(NamedFile::open(path)?)
 }
 
-fn get_env_var(name: &str) -> core::result::Result<String, String> {
-    return std::env::var(name).map_err(|_| format!("Set up '{}' with a value.", name));
+pub(crate) fn get_env_var(name: &str) -> core::result::Result<String, String> {
+    std::env::var(name).map_err(|_| format!("Set up '{}' with a value.", name))
+}
+
+/// Timezone used to display timestamps, cf `RUST_HELLO_WORLD_TIMEZONE`.
+pub(crate) fn configured_timezone() -> Tz {
+    get_env_var("RUST_HELLO_WORLD_TIMEZONE")
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .unwrap_or(chrono_tz::UTC)
+}
+
+/// Format a Unix timestamp for display in the configured timezone.
+pub(crate) fn format_timestamp(timestamp: i64) -> String {
+    match Utc.timestamp_opt(timestamp, 0).single() {
+        Some(datetime) => datetime
+            .with_timezone(&configured_timezone())
+            .format("%Y-%m-%d %H:%M:%S")
+            .to_string(),
+        None => format!("@{}", timestamp),
+    }
 }
 
 pub fn get_ip_address(dhcp_lease_file: &str, hostname: &str) -> String {
@@ -182,81 +233,114 @@ pub fn get_ip_address(dhcp_lease_file: &str, hostname: &str) -> String {
         }
     }
 
-    return hostname.to_string();
+    hostname.to_string()
 }
 
-async fn fetch_dashboard_value() -> core::result::Result<f64, String> {
-    let server_host = get_env_var("RUST_HELLO_WORLD_REMOTE_SERVER_HOST")
-        .map_err(|e| format!("Missing environment variable: {}", e))?;
-    let server_path = get_env_var("RUST_HELLO_WORLD_REMOTE_SERVER_PATH")
-        .map_err(|e| format!("Missing environment variable: {}", e))?;
-    let dashboard_url = format!("https://{}/{}", server_host, server_path);
-
-    // Call the external curl command asynchronously
-    let response = Command::new("curl")
-        .arg("--silent") // Silent mode, suppresses progress bar
-        .arg("--insecure") // don't worry about certificates
-        .arg("--connect-timeout")
-        .arg("1")
-        .arg("--max-time")
-        .arg("2")
-        .arg(dashboard_url)
-        .output()
-        .await
-        .map_err(|e| format!("Failed to execute curl: {}", e))?;
-
-    if !response.status.success() {
-        return Err(format!(
-            "Curl request failed with status: {}... {}",
-            response.status,
-            std::str::from_utf8(&response.stderr)
-                .map_err(|e| format!("Failed to decode curl's stderr: {}", e))?
-        ));
+/// `kwh` with one decimal, truncated rather than rounded: formatted to the Wh
+/// first, so 1000.0999999 kWh is not read as 1000.0.  Empty if not a number.
+fn floor_kwh_tenth(kwh: f64) -> String {
+    if !kwh.is_finite() {
+        return String::new();
     }
+    let wh = format!("{:.3}", kwh);
+    wh[..wh.len() - 2].to_string()
+}
 
-    // Convert the output to a UTF-8 string
-    let response_text = std::str::from_utf8(&response.stdout)
-        .map_err(|e| format!("Failed to parse curl response as UTF-8: {}", e))?;
-
-    print!("response_text={}", response_text);
-    let json: Value =
-        serde_json::from_str(response_text).map_err(|e| format!("Unable to parse JSON: {}", e))?;
-    let value = json["result"]["0199-xxxxx9BD"]["6400_00260100"]["1"][0]["val"]
-        .as_f64()
-        .ok_or("Invalid JSON response")?;
-
-    Ok(value)
+/// Total yield of `installation` to pre-fill the form with, and how fresh it
+/// is.  As last read, fresh values being read with `client` in the background
+/// when they are missing or stale, for the next time; or else as last stored.
+/// The inverter itself is never waited for.
+async fn pv_form_value(
+    installation: &Arc<pv_installations::PvInstallation>,
+    client: &Arc<dyn InverterClient>,
+    sqlite3: &str,
+    now: i64,
+    max_age_s: i64,
+) -> (String, Option<String>) {
+    let cache = &installation.cache;
+    // Of the reads until now, not of the one started here
+    let fetch_error = cache.last_error();
+    let latest = if installation.config.inverter.is_polled() {
+        let (cached, refresh) = cache.get_or_revalidate(now, max_age_s);
+        if refresh {
+            actix_web::rt::spawn(inverter_poller::refresh(
+                installation.clone(),
+                client.clone(),
+            ));
+        }
+        cached.and_then(|cached| Some((cached.snapshot.total_yield_kWh?, cached.fetched_at)))
+    } else {
+        None
+    };
+    let latest = match latest {
+        Some(latest) => Ok(Some(latest)),
+        None => {
+            let sqlite3 = sqlite3.to_string();
+            let name = installation.config.name.clone();
+            web::block(move || data::select_latest_production(&sqlite3, &name))
+                .await
+                .map_err(|e| e.to_string())
+                .and_then(|row| row)
+                .map(|row| row.and_then(|row| Some((row.total_yield_kWh?, row.timestamp))))
+        }
+    };
+    match latest {
+        // The form takes one decimal, and a counter is not rounded up
+        Ok(Some((total_yield, fetched_at))) => {
+            let mut note = format!(
+                "as of {}, {}",
+                format_timestamp(fetched_at),
+                inverter_cache::age(fetched_at, now)
+            );
+            if let Some(e) = fetch_error {
+                note.push_str(&format!(", error fetching value: {}", e));
+            } else if cache.is_unreachable() {
+                note.push_str(", inverter unreachable");
+            }
+            (floor_kwh_tenth(total_yield), Some(note))
+        }
+        Ok(None) => (
+            String::new(),
+            fetch_error.map(|e| format!("Error fetching value: {}", e)),
+        ),
+        Err(err) => {
+            log::error!("Failed to read the inverter production: {}", err);
+            (format!("Error reading value: {}", err), None)
+        }
+    }
 }
 
 #[get("/forms/meter-readings")]
-pub async fn get_meter_readings_form(tera: web::Data<Tera>) -> HttpResponse {
+pub async fn get_meter_readings_form(
+    tera: web::Data<Tera>,
+    database: web::Data<data::Database>,
+    installations: web::Data<pv_installations::PvInstallations>,
+    client: web::Data<dyn InverterClient>,
+) -> HttpResponse {
+    let client = client.into_inner();
     let mut context = tera::Context::new();
-    let timezone: Tz = get_env_var("RUST_HELLO_WORLD_TIMEZONE")
-        .ok()
-        .and_then(|s| s.parse().ok())
-        .unwrap_or(chrono_tz::UTC);
     context.insert(
         "timestamp",
         &Utc::now()
-            .with_timezone(&timezone)
+            .with_timezone(&configured_timezone())
             .format("%Y-%m-%d %H:%M:%S")
             .to_string(),
     );
 
-    match fetch_dashboard_value().await {
-        Ok(value) => {
-            context.insert("pv_2022_prod_kWh", &(value / 1000.0).to_string());
-        }
-        Err(err) => {
-            log::error!("Failed to fetch dashboard value: {}", err);
-            let error_message: String = if err.to_string().contains("Request timed out") {
-                "Timeout".to_string()
-            } else {
-                format!("Error fetching value: {}", err)
-            };
-            context.insert("pv_2022_prod_kWh", error_message.as_str());
+    let now = Utc::now().timestamp();
+    let max_age_s = inverter_poller::configured_interval().as_secs() as i64;
+    let mut freshness = HashMap::new();
+    for installation in &installations.installations {
+        if let Some(field) = &installation.config.form_field {
+            let (value, note) =
+                pv_form_value(installation, &client, &database.sqlite3, now, max_age_s).await;
+            context.insert(field, &value);
+            if let Some(note) = note {
+                freshness.insert(field.clone(), note);
+            }
         }
     }
+    context.insert("freshness", &freshness);
 
     let rendered = tera.render("meter_readings_form.html", &context).unwrap();
     HttpResponse::Ok().body(rendered)
@@ -295,6 +379,9 @@ pub async fn submit_meter_readings(
 // https://github.com/actix/actix-web/issues/1147#issuecomment-1509937750.  See
 // also its discussion of `configure' and
 // https://github.com/actix/actix-web/issues/1402
+/// The inverters are read with a `NetworkInverterClient` of its own unless
+/// another `web::Data<dyn InverterClient>` is given: the one of the pollers,
+/// or a `MockInverterClient` in tests.
 pub fn create_app() -> App<
     impl actix_web::dev::ServiceFactory<
         actix_web::dev::ServiceRequest,
@@ -304,14 +391,50 @@ pub fn create_app() -> App<
         InitError = (),
     >,
 > {
+    let client: Arc<dyn InverterClient> = Arc::new(NetworkInverterClient::default());
     App::new()
         .app_data(web::Data::new(Tera::new("templates/**/*").unwrap()))
+        .app_data(web::Data::from(client))
         .service(
             web::scope("/hello-rust")
                 .service(static_files)
                 .service(get_meter_readings_form)
                 .service(submit_meter_readings)
+                .service(p1_live::live_events)
+                .service(p1_live::live_page)
+                .service(p1_events::power_quality_page)
+                .service(p1_phases::phase_report_page)
+                .service(p1_clock::clock_report_page)
+                .service(p1_base_load::base_load_page)
+                .service(p1_appliances::appliances_page)
+                .service(p1_appliances::label_appliance)
+                .service(p1_ingest::ingest_telegrams)
+                .service(inverter_address::inverter_status_page)
+                .service(inverter_events::inverter_events_page)
+                .service(pv_performance::pv_performance_page)
                 .service(greet_user_id_and_name)
                 .service(index),
         )
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn floor_kwh_tenth_truncates_at_the_wh() {
+        assert_eq!(floor_kwh_tenth(1000.0999999), "1000.1");
+        assert_eq!(floor_kwh_tenth(1000.0994), "1000.0");
+        assert_eq!(floor_kwh_tenth(12400.04), "12400.0");
+        assert_eq!(floor_kwh_tenth(12345.678), "12345.6");
+        assert_eq!(floor_kwh_tenth(1000.0), "1000.0");
+        assert_eq!(floor_kwh_tenth(0.0), "0.0");
+    }
+
+    #[test]
+    fn floor_kwh_tenth_not_a_number() {
+        assert_eq!(floor_kwh_tenth(f64::NAN), "");
+        assert_eq!(floor_kwh_tenth(f64::INFINITY), "");
+        assert_eq!(floor_kwh_tenth(f64::NEG_INFINITY), "");
+    }
+}
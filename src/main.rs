@@ -1,6 +1,23 @@
 use std::env;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::Arc;
 
+use actix_web::web;
 use hello_world_lib::create_app;
+use hello_world_lib::data::{sqlite3_command, Database};
+use hello_world_lib::inverter_client::{InverterClient, NetworkInverterClient};
+use hello_world_lib::inverter_poller::run_inverter_poller;
+use hello_world_lib::p1_appliances::run_appliance_recorder;
+use hello_world_lib::p1_archive::run_archive_recorder;
+use hello_world_lib::p1_clock::run_clock_recorder;
+use hello_world_lib::p1_events::run_power_quality_recorder;
+use hello_world_lib::p1_ingest::P1Devices;
+use hello_world_lib::p1_phases::run_phase_recorder;
+use hello_world_lib::p1_proxy::run_p1_proxy;
+use hello_world_lib::p1_reader::{run_p1_reader, P1Hub, P1Source};
+use hello_world_lib::p1_telegram::Dialect;
+use hello_world_lib::pv_installations::PvInstallations;
 
 fn configure_logging() {
     env_logger::Builder::from_env(env_logger::Env::default())
@@ -11,10 +28,137 @@ fn configure_logging() {
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
     configure_logging();
-    let bind_target = env::var("RUST_HELLO_WORLD_BIND_TO").unwrap_or_else(|_| "127.0.0.1:3000".to_string());
+    let bind_target =
+        env::var("RUST_HELLO_WORLD_BIND_TO").unwrap_or_else(|_| "127.0.0.1:3000".to_string());
+    let database = Database {
+        sqlite3: sqlite3_command(
+            &env::var("RUST_HELLO_WORLD_DATABASE")
+                .unwrap_or_else(|_| "hello_world.sqlite3".to_string()),
+        ),
+    };
+    let p1_dialect = match env::var("RUST_HELLO_WORLD_P1_DIALECT").map(|s| Dialect::from_str(&s)) {
+        Ok(Ok(dialect)) => Some(dialect),
+        Ok(Err(e)) => {
+            log::error!("RUST_HELLO_WORLD_P1_DIALECT: {}", e);
+            None
+        }
+        Err(_) => None,
+    };
+    // Attribute the P1 data to the reception time rather than the meter clock
+    let restamp = env::var("RUST_HELLO_WORLD_P1_RESTAMP")
+        .map(|s| matches!(s.trim(), "1" | "true" | "yes"))
+        .unwrap_or(false);
+    let p1_hub = Arc::new(P1Hub::with_restamp(restamp));
+    let p1_source = match env::var("RUST_HELLO_WORLD_P1_SOURCE").map(|s| P1Source::from_str(&s)) {
+        Ok(Ok(source)) => Some(source),
+        Ok(Err(e)) => {
+            log::error!("RUST_HELLO_WORLD_P1_SOURCE: {}", e);
+            None
+        }
+        Err(_) => None,
+    };
+    // Telegrams pushed by network dongles are recorded like those of the port
+    let p1_devices = match env::var("RUST_HELLO_WORLD_P1_DEVICES").map(|p| P1Devices::read(&p)) {
+        Ok(Ok(devices)) => devices,
+        Ok(Err(e)) => {
+            log::error!("RUST_HELLO_WORLD_P1_DEVICES: {}", e);
+            P1Devices::default()
+        }
+        Err(_) => P1Devices::default(),
+    };
+    if p1_source.is_some() || !p1_devices.is_empty() {
+        if let Ok(archive) = env::var("RUST_HELLO_WORLD_P1_ARCHIVE") {
+            let keep_days = env::var("RUST_HELLO_WORLD_P1_ARCHIVE_DAYS")
+                .ok()
+                .and_then(|days| u32::from_str(&days).ok());
+            actix_web::rt::spawn(run_archive_recorder(
+                p1_hub.clone(),
+                PathBuf::from(archive),
+                keep_days,
+            ));
+        }
+        actix_web::rt::spawn(run_power_quality_recorder(
+            p1_hub.clone(),
+            p1_dialect,
+            database.clone(),
+        ));
+        actix_web::rt::spawn(run_clock_recorder(
+            p1_hub.clone(),
+            p1_dialect,
+            database.clone(),
+        ));
+        actix_web::rt::spawn(run_phase_recorder(
+            p1_hub.clone(),
+            p1_dialect,
+            database.clone(),
+        ));
+        actix_web::rt::spawn(run_appliance_recorder(
+            p1_hub.clone(),
+            p1_dialect,
+            database.clone(),
+        ));
+    }
+    match p1_source {
+        Some(source) => {
+            log::info!("Reading P1 telegrams from {:?}", source);
+            if let Ok(address) = env::var("RUST_HELLO_WORLD_P1_PROXY") {
+                let valid_only = env::var("RUST_HELLO_WORLD_P1_PROXY_VALID_ONLY")
+                    .map(|s| matches!(s.trim(), "1" | "true" | "yes"))
+                    .unwrap_or(false);
+                match tokio::net::TcpListener::bind(&address).await {
+                    Ok(listener) => {
+                        log::info!("Serving P1 telegrams on {}", address);
+                        actix_web::rt::spawn(run_p1_proxy(p1_hub.clone(), listener, valid_only));
+                    }
+                    Err(e) => log::error!("RUST_HELLO_WORLD_P1_PROXY {}: {}", address, e),
+                }
+            }
+            actix_web::rt::spawn(run_p1_reader(source, p1_dialect, p1_hub.clone()));
+        }
+        None if !p1_devices.is_empty() => {
+            log::info!("No P1 port, only telegrams pushed by devices")
+        }
+        None => log::info!("RUST_HELLO_WORLD_P1_SOURCE not set, no live P1 data"),
+    }
+    let pv_installations = match PvInstallations::from_env() {
+        Ok(installations) => installations,
+        Err(e) => {
+            log::error!("RUST_HELLO_WORLD_PV_INSTALLATIONS: {}", e);
+            PvInstallations::new(Vec::new(), None)
+        }
+    };
+    let network_client = Arc::new(NetworkInverterClient::default());
+    let inverter_client: Arc<dyn InverterClient> = network_client.clone();
+    for installation in &pv_installations.installations {
+        if installation.config.inverter.is_polled() {
+            actix_web::rt::spawn(run_inverter_poller(
+                installation.clone(),
+                inverter_client.clone(),
+                database.clone(),
+            ));
+        }
+    }
+    let pv_installations = web::Data::new(pv_installations);
+    let p1_devices = web::Data::new(p1_devices);
     log::info!("Starting HttpServer...");
-    actix_web::HttpServer::new(|| create_app())
-        .bind(bind_target)?
-        .run()
-        .await
+    let database = web::Data::new(database);
+    let served = actix_web::HttpServer::new(move || {
+        create_app()
+            .app_data(web::Data::from(p1_hub.clone()))
+            .app_data(database.clone())
+            .app_data(pv_installations.clone())
+            .app_data(web::Data::from(inverter_client.clone()))
+            .app_data(p1_devices.clone())
+            .configure(|config| {
+                // Pushed telegrams, the live view and the clock page use it too
+                if let Some(dialect) = p1_dialect {
+                    config.app_data(web::Data::new(dialect));
+                }
+            })
+    })
+    .bind(bind_target)?
+    .run()
+    .await;
+    network_client.logout().await;
+    served
 }
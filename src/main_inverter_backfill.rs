@@ -0,0 +1,140 @@
+use std::env;
+use std::process::ExitCode;
+
+use hello_world_lib::data::sqlite3_command;
+use hello_world_lib::inverter_backfill::backfill;
+use hello_world_lib::inverter_session::WebConnectSession;
+use hello_world_lib::p1_archive::parse_range_bound;
+use hello_world_lib::pv_installations::PvInstallations;
+
+fn usage() -> ExitCode {
+    eprintln!("Usage: inverter_backfill [--database FILE] [--installations FILE] [--dry-run]");
+    eprintln!("                         --installation NAME --from DATE --to DATE");
+    eprintln!("Defaults are taken from RUST_HELLO_WORLD_DATABASE and");
+    eprintln!("RUST_HELLO_WORLD_PV_INSTALLATIONS.  DATE is YYYY-MM-DD or YYYY-MM-DDThh:mm,");
+    eprintln!("both ends included.  The daily and monthly total yields of the logger of the");
+    eprintln!("inverter are added to the inverter production where nothing is stored, and");
+    eprintln!("the gaps filled are listed.  The logger is read in a logged in session of the");
+    eprintln!("web UI: only sma-webconnect installations, and sma-dashboard ones with a");
+    eprintln!("password (RUST_HELLO_WORLD_INVERTER_PASSWORD for the single installation");
+    eprintln!("pv2022), can be backfilled.");
+    ExitCode::FAILURE
+}
+
+#[actix_web::main]
+async fn main() -> ExitCode {
+    env_logger::Builder::from_env(env_logger::Env::default())
+        .format_timestamp(None)
+        .init();
+    let mut database = env::var("RUST_HELLO_WORLD_DATABASE").ok();
+    let mut installations = env::var("RUST_HELLO_WORLD_PV_INSTALLATIONS").ok();
+    let (mut name, mut from, mut to) = (None, None, None);
+    let mut dry_run = false;
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        if arg == "--dry-run" {
+            dry_run = true;
+            continue;
+        }
+        let value = match arg.as_str() {
+            "--database" | "--installations" | "--installation" | "--from" | "--to" => {
+                match args.next() {
+                    Some(value) => value,
+                    None => return usage(),
+                }
+            }
+            _ => return usage(),
+        };
+        match arg.as_str() {
+            "--database" => database = Some(value),
+            "--installations" => installations = Some(value),
+            "--installation" => name = Some(value),
+            "--from" => from = Some(parse_range_bound(&value, false)),
+            _ => to = Some(parse_range_bound(&value, true)),
+        }
+    }
+    let (database, name, from, to) = match (database, name, from, to) {
+        (Some(database), Some(name), Some(Ok(from)), Some(Ok(to))) if from <= to => {
+            (database, name, from, to)
+        }
+        (_, _, Some(Err(e)), _) | (_, _, _, Some(Err(e))) => {
+            eprintln!("{}", e);
+            return usage();
+        }
+        _ => return usage(),
+    };
+    let lease_file = env::var("RUST_HELLO_WORLD_DHCP_LEASES").ok();
+    let installations = match installations {
+        Some(path) => PvInstallations::from_file(&path, lease_file),
+        None => PvInstallations::from_env(),
+    };
+    let installation = match installations.as_ref().map(|i| i.get(&name)) {
+        Ok(Some(installation))
+            if installation.config.inverter.is_web_ui()
+                && installation.config.password.is_some() =>
+        {
+            installation.clone()
+        }
+        Ok(Some(installation)) if installation.config.inverter.is_web_ui() => {
+            eprintln!(
+                "{}: no password to log into the web UI and read the logger",
+                name
+            );
+            return usage();
+        }
+        Ok(Some(_)) => {
+            eprintln!(
+                "{}: no logger to read, the inverter has no SMA web UI",
+                name
+            );
+            return usage();
+        }
+        Ok(None) => {
+            eprintln!("No installation {}", name);
+            return ExitCode::FAILURE;
+        }
+        Err(e) => {
+            eprintln!("{}", e);
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let session = installation.address.resolve().and_then(|resolved| {
+        WebConnectSession::for_installation(&installation.config, &resolved.address)
+    });
+    let mut session = match session {
+        Ok(session) => session,
+        Err(e) => {
+            eprintln!("{}: {}", name, e);
+            return ExitCode::FAILURE;
+        }
+    };
+    let sqlite3 = sqlite3_command(&database);
+    let filled = backfill(&mut session, &sqlite3, &name, from, to, dry_run).await;
+    if let Err(e) = session.logout().await {
+        log::warn!("{}: logout: {}", name, e);
+    }
+    let filled = match filled {
+        Ok(filled) => filled,
+        Err(e) => {
+            eprintln!("{}: {}", name, e);
+            return ExitCode::FAILURE;
+        }
+    };
+    for gap in &filled.gaps {
+        println!("{}: {}", name, gap);
+    }
+    match filled.added {
+        Some(added) => println!("logger values: {}, added: {}", filled.logger_values, added),
+        None => println!(
+            "logger values: {}, to add: {} (dry run)",
+            filled.logger_values,
+            filled
+                .gaps
+                .iter()
+                .map(|gap| gap.points.len())
+                .sum::<usize>()
+        ),
+    }
+    ExitCode::SUCCESS
+}
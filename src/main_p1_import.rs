@@ -0,0 +1,119 @@
+use std::env;
+use std::path::PathBuf;
+use std::process::ExitCode;
+use std::str::FromStr;
+
+use hello_world_lib::data::{insert_p1_measurements, sqlite3_command, P1Measurement};
+use hello_world_lib::p1_bulk::{parse_captures, CaptureSummary};
+use hello_world_lib::p1_telegram::Dialect;
+
+const BATCH_SIZE: usize = 10000;
+
+#[derive(Default)]
+struct Summary {
+    accepted: usize,
+    duplicates: usize,
+    rejected: usize,
+}
+
+/// Stores what `p1_bulk` parsed; the database tells apart the duplicates.
+struct Importer {
+    sqlite3: String,
+    batch: Vec<P1Measurement>,
+    summary: Summary,
+}
+
+impl Importer {
+    fn capture(&mut self, capture: CaptureSummary) -> Result<(), String> {
+        self.summary.rejected += capture.rejected;
+        for measurement in capture.measurements {
+            self.batch.push(measurement);
+            if self.batch.len() >= BATCH_SIZE {
+                self.flush()?;
+            }
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<(), String> {
+        if self.batch.is_empty() {
+            return Ok(());
+        }
+        let inserted = insert_p1_measurements(&self.sqlite3, &self.batch)?;
+        self.summary.accepted += inserted;
+        self.summary.duplicates += self.batch.len() - inserted;
+        self.batch.clear();
+        Ok(())
+    }
+}
+
+fn usage() -> ExitCode {
+    eprintln!("Usage: p1_import [--database FILE] [--dialect dsmr22|dsmr4|dsmr5] CAPTURE_FILE...");
+    eprintln!("Defaults are taken from RUST_HELLO_WORLD_DATABASE and RUST_HELLO_WORLD_P1_DIALECT,");
+    eprintln!("the dialect is otherwise detected per telegram.  DSMR 2.2 telegrams have no");
+    eprintln!("meter time and are stored at the time of their hourly gas reading.");
+    eprintln!("Capture files ending in .gz are decompressed on the fly, files are parsed");
+    eprintln!("in parallel (cf RAYON_NUM_THREADS).");
+    ExitCode::FAILURE
+}
+
+fn main() -> ExitCode {
+    env_logger::Builder::from_env(env_logger::Env::default())
+        .format_timestamp(None)
+        .init();
+    let mut database = env::var("RUST_HELLO_WORLD_DATABASE").ok();
+    let mut dialect = env::var("RUST_HELLO_WORLD_P1_DIALECT").ok();
+    let mut files = Vec::new();
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--database" => match args.next() {
+                Some(db) => database = Some(db),
+                None => return usage(),
+            },
+            "--dialect" => match args.next() {
+                Some(value) => dialect = Some(value),
+                None => return usage(),
+            },
+            "-h" | "--help" => return usage(),
+            _ => files.push(PathBuf::from(arg)),
+        }
+    }
+    let database = match database {
+        Some(db) if !files.is_empty() => db,
+        _ => return usage(),
+    };
+    let dialect = match dialect.map(|d| Dialect::from_str(&d)).transpose() {
+        Ok(dialect) => dialect,
+        Err(e) => {
+            eprintln!("{}", e);
+            return usage();
+        }
+    };
+
+    let mut importer = Importer {
+        sqlite3: sqlite3_command(&database),
+        batch: Vec::with_capacity(BATCH_SIZE),
+        summary: Summary::default(),
+    };
+    let mut result = Ok(());
+    parse_captures(&files, dialect, |path, capture| {
+        if result.is_ok() {
+            log::info!("Importing {}", path.display());
+            result = capture.and_then(|capture| importer.capture(capture));
+        }
+    });
+    let result = result.and_then(|_| importer.flush());
+    let summary = &importer.summary;
+    println!(
+        "accepted: {}, duplicates: {}, rejected: {}",
+        summary.accepted, summary.duplicates, summary.rejected
+    );
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("Import aborted: {}", e);
+            ExitCode::FAILURE
+        }
+    }
+}
@@ -0,0 +1,325 @@
+use std::env;
+use std::io::{BufRead, BufReader};
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use std::str::FromStr;
+
+use hello_world_lib::data::{
+    replace_p1_range, select_appliance_clusters, sqlite3_command, ApplianceEventRow, P1Rebuild,
+};
+use hello_world_lib::p1_appliances::{phase_net_kw, ApplianceClusters, StepDetector};
+use hello_world_lib::p1_archive::{
+    archive_sections, open_section, parse_range_bound, section_members, ArchiveSection,
+};
+use hello_world_lib::p1_events::{counter_values, failure_rows};
+use hello_world_lib::p1_meter::{validate_crc, PowerQuality, TelegramAssembler};
+use hello_world_lib::p1_phases::PhaseAggregator;
+use hello_world_lib::p1_telegram::{parse_telegram, Dialect};
+
+#[derive(Default)]
+struct Summary {
+    sections: usize,
+    telegrams: usize,
+    measurements: usize,
+    phase_minutes: usize,
+    power_quality: usize,
+    appliance_events: usize,
+    rejected: usize,
+}
+
+/// Reception time of the telegrams of an archive member.  The index only
+/// has the time of the first: the meter time elapsed since is added to it,
+/// the meter clock drifts by seconds a day at most.
+struct MemberClock {
+    first_received: i64,
+    first_meter_time: Option<i64>,
+}
+
+impl MemberClock {
+    fn received(&mut self, meter_time: Option<i64>) -> i64 {
+        match meter_time {
+            Some(meter_time) => {
+                let first = *self.first_meter_time.get_or_insert(meter_time);
+                self.first_received + (meter_time - first).max(0)
+            }
+            None => self.first_received,
+        }
+    }
+}
+
+/// Re-derives the tables from archived telegrams, as the recorders and
+/// `p1_import` would with the current parsers.  Each archive section is read
+/// in full before what its telegrams cover is replaced, so that the tables
+/// keep their rows for the time the archive misses or fails to read.
+struct Reprocessor {
+    sqlite3: String,
+    from: i64,
+    to: i64,
+    dialect: Option<Dialect>,
+    /// Attribute the data to the reception time, cf `P1Hub::data_time`
+    restamp: bool,
+    phases: PhaseAggregator,
+    detector: StepDetector,
+    clusters: ApplianceClusters,
+    quality: Option<PowerQuality>,
+    /// Rows of the section being read ...
+    rows: P1Rebuild,
+    /// ... and the time they cover
+    covered: Option<(i64, i64)>,
+    summary: Summary,
+}
+
+impl Reprocessor {
+    fn cover(&mut self, timestamp: i64) {
+        self.covered = Some(match self.covered {
+            Some((from, to)) => (from.min(timestamp), to.max(timestamp)),
+            None => (timestamp, timestamp),
+        });
+    }
+
+    fn reject(&mut self, error: &str) {
+        log::warn!("Rejecting telegram: {}", error);
+        self.summary.rejected += 1;
+    }
+
+    fn telegram(&mut self, telegram: &str, clock: Option<&mut MemberClock>) {
+        if let Err(e) = validate_crc(telegram) {
+            return self.reject(&e);
+        }
+        let parsed = match parse_telegram(telegram, self.dialect) {
+            Ok(parsed) => parsed,
+            Err(e) => return self.reject(&e),
+        };
+        let meter_time = parsed.timestamp.map(|t| t.unix_timestamp());
+        let received = clock.map(|clock| clock.received(meter_time));
+        let data_time = if self.restamp {
+            received
+        } else {
+            meter_time.or(received)
+        }
+        .or_else(|| parsed.measurement_time());
+        // Chunks at the ends of the range may hold telegrams outside of it
+        let timestamp = match data_time {
+            Some(timestamp) if (self.from..=self.to).contains(&timestamp) => timestamp,
+            Some(_) => return,
+            None => return self.reject("no timestamp"),
+        };
+        self.summary.telegrams += 1;
+        self.cover(timestamp);
+        // Pushed telegrams are stored at the meter time too, cf `p1_ingest`
+        let measurement_time = meter_time.or(received).unwrap_or(timestamp);
+        if let Some(measurement) = parsed.measurement(measurement_time) {
+            self.cover(measurement_time);
+            self.rows.measurements.push(measurement);
+        }
+        for row in self.phases.add(timestamp, &parsed.phases) {
+            self.cover(row.timestamp);
+            self.rows.phase_minutes.push(row);
+        }
+        if let Some(step) =
+            phase_net_kw(&parsed).and_then(|phases| self.detector.push(timestamp, phases))
+        {
+            let cluster = self.clusters.assign(&step).id;
+            self.cover(step.timestamp);
+            self.rows.appliance_events.push(ApplianceEventRow {
+                timestamp: step.timestamp,
+                phase: step.phase,
+                delta_kW: step.delta_kW,
+                cluster,
+            });
+        }
+        if self.quality.as_ref() != Some(&parsed.quality) {
+            self.rows
+                .power_failures
+                .extend(failure_rows(&parsed.quality));
+            self.rows.counters.extend(
+                counter_values(&parsed.quality)
+                    .into_iter()
+                    .map(|(counter, count)| (timestamp, counter, count)),
+            );
+            self.quality = Some(parsed.quality);
+        }
+    }
+
+    /// Read all the telegrams of a section into `rows`.
+    fn section(&mut self, section: &ArchiveSection) -> Result<(), String> {
+        let path = section.path.display().to_string();
+        let members = section_members(section).map_err(|e| format!("{}: {}", path, e))?;
+        let mut assembler = TelegramAssembler::default();
+        let mut buffer = Vec::new();
+        for (first_received, member) in members {
+            if self.restamp && first_received.is_none() {
+                return Err(format!("{}: no index, reception times unknown", path));
+            }
+            let mut clock = first_received.map(|first_received| MemberClock {
+                first_received,
+                first_meter_time: None,
+            });
+            let mut reader =
+                BufReader::new(open_section(&member).map_err(|e| format!("{}: {}", path, e))?);
+            loop {
+                buffer.clear();
+                match reader.read_until(b'\n', &mut buffer) {
+                    Ok(0) => break,
+                    Ok(_) => {}
+                    Err(e) => return Err(format!("{}: {}", path, e)),
+                }
+                if let Some(telegram) = assembler.push_line(&String::from_utf8_lossy(&buffer)) {
+                    self.telegram(&telegram, clock.as_mut());
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Replace what the rows of the section read cover.  The minute being
+    /// aggregated is left to the next section, unless this is the `last`.
+    fn commit(&mut self, last: bool) -> Result<(), String> {
+        if last {
+            for row in self.phases.flush() {
+                self.cover(row.timestamp);
+                self.rows.phase_minutes.push(row);
+            }
+        }
+        let mut rows = std::mem::take(&mut self.rows);
+        let (from, to) = match self.covered.take() {
+            Some(covered) => covered,
+            None => return Ok(()),
+        };
+        rows.from = from;
+        rows.to = to;
+        rows.phases_to = if last { to } else { to - 60 };
+        rows.appliance_clusters = self.clusters.clusters.clone();
+        replace_p1_range(&self.sqlite3, &rows)?;
+        self.summary.sections += 1;
+        self.summary.measurements += rows.measurements.len();
+        self.summary.phase_minutes += rows.phase_minutes.len();
+        self.summary.power_quality += rows.power_failures.len() + rows.counters.len();
+        self.summary.appliance_events += rows.appliance_events.len();
+        Ok(())
+    }
+
+    fn archive(&mut self, dir: &Path) -> Result<(), String> {
+        let sections = archive_sections(dir, self.from, self.to)
+            .map_err(|e| format!("{}: {}", dir.display(), e))?;
+        if sections.is_empty() {
+            return Err(format!("{}: nothing archived in the range", dir.display()));
+        }
+        let count = sections.len();
+        for (i, section) in sections.iter().enumerate() {
+            log::info!("Reprocessing {}", section.path.display());
+            self.section(section)?;
+            self.commit(i + 1 == count)?;
+        }
+        Ok(())
+    }
+}
+
+fn usage() -> ExitCode {
+    eprintln!(
+        "Usage: p1_reprocess [--database FILE] [--archive DIR] [--dialect dsmr22|dsmr4|dsmr5]"
+    );
+    eprintln!("                    [--restamp] --from DATE --to DATE");
+    eprintln!("Defaults are taken from RUST_HELLO_WORLD_DATABASE, RUST_HELLO_WORLD_P1_ARCHIVE,");
+    eprintln!("RUST_HELLO_WORLD_P1_DIALECT and RUST_HELLO_WORLD_P1_RESTAMP.  DATE is YYYY-MM-DD");
+    eprintln!("or YYYY-MM-DDThh:mm in meter time, both ends included and widened to whole");
+    eprintln!("minutes.  For each archive file, the P1 measurements, phase loads, power quality");
+    eprintln!("events and appliance events of the time its telegrams cover are replaced by those");
+    eprintln!("derived from them, once the whole file was read.");
+    ExitCode::FAILURE
+}
+
+fn main() -> ExitCode {
+    env_logger::Builder::from_env(env_logger::Env::default())
+        .format_timestamp(None)
+        .init();
+    let mut database = env::var("RUST_HELLO_WORLD_DATABASE").ok();
+    let mut archive = env::var("RUST_HELLO_WORLD_P1_ARCHIVE").ok();
+    let mut dialect = env::var("RUST_HELLO_WORLD_P1_DIALECT").ok();
+    let mut restamp = env::var("RUST_HELLO_WORLD_P1_RESTAMP")
+        .map(|s| matches!(s.trim(), "1" | "true" | "yes"))
+        .unwrap_or(false);
+    let (mut from, mut to) = (None, None);
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        let value = match arg.as_str() {
+            "--restamp" => {
+                restamp = true;
+                continue;
+            }
+            "--database" | "--archive" | "--dialect" | "--from" | "--to" => match args.next() {
+                Some(value) => value,
+                None => return usage(),
+            },
+            _ => return usage(),
+        };
+        match arg.as_str() {
+            "--database" => database = Some(value),
+            "--archive" => archive = Some(value),
+            "--dialect" => dialect = Some(value),
+            "--from" => from = Some(parse_range_bound(&value, false)),
+            _ => to = Some(parse_range_bound(&value, true)),
+        }
+    }
+    let (database, archive, from, to) = match (database, archive, from, to) {
+        (Some(database), Some(archive), Some(Ok(from)), Some(Ok(to))) if from <= to => {
+            (database, PathBuf::from(archive), from, to)
+        }
+        (_, _, Some(Err(e)), _) | (_, _, _, Some(Err(e))) => {
+            eprintln!("{}", e);
+            return usage();
+        }
+        _ => return usage(),
+    };
+    let dialect = match dialect.map(|d| Dialect::from_str(&d)).transpose() {
+        Ok(dialect) => dialect,
+        Err(e) => {
+            eprintln!("{}", e);
+            return usage();
+        }
+    };
+
+    // Whole minutes, the phase loads are stored per minute
+    let (from, to) = (from - from.rem_euclid(60), to - to.rem_euclid(60) + 59);
+    let sqlite3 = sqlite3_command(&database);
+    let clusters = match select_appliance_clusters(&sqlite3) {
+        Ok(clusters) => ApplianceClusters { clusters },
+        Err(e) => {
+            eprintln!("Unable to read appliance clusters: {}", e);
+            return ExitCode::FAILURE;
+        }
+    };
+    let mut reprocessor = Reprocessor {
+        sqlite3,
+        from,
+        to,
+        dialect,
+        restamp,
+        phases: PhaseAggregator::default(),
+        detector: StepDetector::default(),
+        clusters,
+        quality: None,
+        rows: P1Rebuild::default(),
+        covered: None,
+        summary: Summary::default(),
+    };
+    let result = reprocessor.archive(&archive);
+    let summary = &reprocessor.summary;
+    println!(
+        "files: {}, telegrams: {}, measurements: {}, phase minutes: {}, power quality records: {}, appliance events: {}, rejected: {}",
+        summary.sections,
+        summary.telegrams,
+        summary.measurements,
+        summary.phase_minutes,
+        summary.power_quality,
+        summary.appliance_events,
+        summary.rejected
+    );
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("Reprocessing aborted: {}", e);
+            ExitCode::FAILURE
+        }
+    }
+}
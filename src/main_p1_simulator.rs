@@ -0,0 +1,207 @@
+use std::env;
+use std::fs::File;
+use std::io::{self, Write};
+use std::net::{TcpListener, TcpStream};
+use std::os::fd::FromRawFd;
+use std::process::ExitCode;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use hello_world_lib::p1_simulator::{MeterSimulator, SimulatorSettings};
+use hello_world_lib::p1_telegram::{encode_telegram, Dialect};
+use time::OffsetDateTime;
+
+/// A client that does not take a telegram within this long is dropped
+const WRITE_TIMEOUT: Duration = Duration::from_secs(2);
+
+enum Output {
+    Stdout,
+    Tcp(String),
+    Pty,
+}
+
+/// Where the telegrams go.  TCP clients are accepted in the background and
+/// dropped as soon as writing to them fails or times out.
+enum Sink {
+    Stdout(io::Stdout),
+    Tcp(Arc<Mutex<Vec<TcpStream>>>),
+    // The slave side is kept open so that writing to the master does not
+    // fail while no reader is attached.
+    Pty { master: File, _slave: File },
+}
+
+impl Sink {
+    fn write_telegram(&mut self, telegram: &str) -> io::Result<()> {
+        match self {
+            Sink::Stdout(stdout) => {
+                stdout.write_all(telegram.as_bytes())?;
+                stdout.flush()
+            }
+            Sink::Tcp(clients) => {
+                // Written without the lock, so that a slow client does not
+                // hold up the accepting thread
+                let mut writing = std::mem::take(&mut *clients.lock().unwrap());
+                writing.retain_mut(|client| match client.write_all(telegram.as_bytes()) {
+                    Ok(()) => true,
+                    Err(e) => {
+                        eprintln!("Client dropped: {:?}: {}", client.peer_addr(), e);
+                        false
+                    }
+                });
+                // Clients accepted meanwhile come after the others
+                let mut clients = clients.lock().unwrap();
+                writing.append(&mut clients);
+                *clients = writing;
+                Ok(())
+            }
+            Sink::Pty { master, .. } => master.write_all(telegram.as_bytes()),
+        }
+    }
+}
+
+fn open_pty() -> io::Result<(File, File, String)> {
+    let mut master: libc::c_int = -1;
+    let mut slave: libc::c_int = -1;
+    let mut name = [0 as libc::c_char; 128];
+    // SAFETY: openpty writes two file descriptors and a NUL terminated name
+    // into the buffers provided, which are large enough.
+    let result = unsafe {
+        libc::openpty(
+            &mut master,
+            &mut slave,
+            name.as_mut_ptr(),
+            std::ptr::null(),
+            std::ptr::null(),
+        )
+    };
+    if result != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // SAFETY: name was NUL terminated by openpty, the descriptors are ours.
+    let name = unsafe { std::ffi::CStr::from_ptr(name.as_ptr()) }
+        .to_string_lossy()
+        .to_string();
+    let (master, slave) = unsafe { (File::from_raw_fd(master), File::from_raw_fd(slave)) };
+    Ok((master, slave, name))
+}
+
+fn open_sink(output: &Output) -> io::Result<Sink> {
+    match output {
+        Output::Stdout => Ok(Sink::Stdout(io::stdout())),
+        Output::Tcp(address) => {
+            let listener = TcpListener::bind(address)?;
+            eprintln!("Serving telegrams on {}", listener.local_addr()?);
+            let clients = Arc::new(Mutex::new(Vec::new()));
+            let accepted = clients.clone();
+            thread::spawn(move || {
+                for stream in listener.incoming().flatten() {
+                    eprintln!("Client connected: {:?}", stream.peer_addr());
+                    match stream.set_write_timeout(Some(WRITE_TIMEOUT)) {
+                        Ok(()) => accepted.lock().unwrap().push(stream),
+                        Err(e) => eprintln!("Client refused: {}", e),
+                    }
+                }
+            });
+            Ok(Sink::Tcp(clients))
+        }
+        Output::Pty => {
+            let (master, slave, name) = open_pty()?;
+            eprintln!("Writing telegrams to {}", name);
+            Ok(Sink::Pty {
+                master,
+                _slave: slave,
+            })
+        }
+    }
+}
+
+fn usage() -> ExitCode {
+    eprintln!(
+        "Usage: p1_simulator [--dialect dsmr22|dsmr4|dsmr5] [--pv-kwp KWP] [--base-load-kw KW]"
+    );
+    eprintln!("                    [--seed N] [--interval SECONDS] [--count N] [--fast]");
+    eprintln!("                    [--tcp ADDRESS:PORT | --pty]");
+    eprintln!("Writes a telegram every interval (default: 1s, 10s for DSMR 4/2.2) to stdout,");
+    eprintln!("to the TCP clients connecting to ADDRESS:PORT or to a new pseudo-terminal.");
+    eprintln!("With --fast, simulated time advances without waiting (e.g. to generate captures).");
+    ExitCode::FAILURE
+}
+
+fn parse_arg<T: FromStr>(name: &str, value: Option<String>) -> Result<T, String> {
+    let value = value.ok_or_else(|| format!("Missing value for {}", name))?;
+    T::from_str(&value).map_err(|_| format!("Bad value for {}: {}", name, value))
+}
+
+fn main() -> ExitCode {
+    let mut settings = SimulatorSettings::default();
+    let mut interval: Option<u64> = None;
+    let mut count: Option<u64> = None;
+    let mut fast = false;
+    let mut output = Output::Stdout;
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        let parsed = match arg.as_str() {
+            "--dialect" => args
+                .next()
+                .ok_or_else(|| "Missing value for --dialect".to_string())
+                .and_then(|d| Dialect::from_str(&d))
+                .map(|d| settings.dialect = d),
+            "--pv-kwp" => parse_arg(&arg, args.next()).map(|v| settings.pv_kwp = v),
+            "--base-load-kw" => parse_arg(&arg, args.next()).map(|v| settings.base_load_kw = v),
+            "--seed" => parse_arg(&arg, args.next()).map(|v| settings.seed = v),
+            "--interval" => parse_arg(&arg, args.next()).map(|v| interval = Some(v)),
+            "--count" => parse_arg(&arg, args.next()).map(|v| count = Some(v)),
+            "--fast" => {
+                fast = true;
+                Ok(())
+            }
+            "--tcp" => parse_arg(&arg, args.next()).map(|v| output = Output::Tcp(v)),
+            "--pty" => {
+                output = Output::Pty;
+                Ok(())
+            }
+            _ => return usage(),
+        };
+        if let Err(e) = parsed {
+            eprintln!("{}", e);
+            return usage();
+        }
+    }
+    let interval = interval.unwrap_or(match settings.dialect {
+        Dialect::Dsmr5 => 1,
+        Dialect::Dsmr4 | Dialect::Dsmr22 => 10,
+    });
+    let header = match settings.dialect {
+        Dialect::Dsmr22 => "SIM5\\2SIMULATOR-22",
+        Dialect::Dsmr4 => "SIM5SIMULATOR-42",
+        Dialect::Dsmr5 => "SIM5\\2SIMULATOR-50",
+    };
+
+    let mut sink = match open_sink(&output) {
+        Ok(sink) => sink,
+        Err(e) => {
+            eprintln!("Unable to open output: {}", e);
+            return ExitCode::FAILURE;
+        }
+    };
+    let mut simulator = MeterSimulator::new(settings);
+    let mut now = OffsetDateTime::now_utc();
+    let mut sent = 0;
+    while count.map(|count| sent < count).unwrap_or(true) {
+        let telegram = encode_telegram(&simulator.step(now), header);
+        if let Err(e) = sink.write_telegram(&telegram) {
+            eprintln!("Unable to write telegram: {}", e);
+            return ExitCode::FAILURE;
+        }
+        sent += 1;
+        if fast {
+            now += time::Duration::seconds(interval as i64);
+        } else {
+            thread::sleep(Duration::from_secs(interval));
+            now = OffsetDateTime::now_utc();
+        }
+    }
+    ExitCode::SUCCESS
+}
@@ -0,0 +1,499 @@
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::sync::Arc;
+
+use actix_web::{get, http::header, post, web, HttpResponse};
+use chrono::{NaiveDate, TimeZone, Utc};
+use chrono_tz::Tz;
+use serde::{Deserialize, Serialize};
+use tera::Tera;
+use time::OffsetDateTime;
+use tokio::sync::broadcast::error::RecvError;
+
+use crate::data::{
+    insert_appliance_event, select_appliance_clusters, select_appliance_events,
+    update_appliance_label, ApplianceClusterRow, ApplianceEventRow, Database,
+};
+use crate::p1_reader::P1Hub;
+use crate::p1_telegram::{parse_telegram, Dialect, P1Telegram};
+use crate::{configured_timezone, format_timestamp};
+
+/// Smallest change of the power level reported as an event [kW]
+const MIN_STEP_KW: f64 = 0.5;
+
+/// Consecutive samples needed to consider the power level stable
+const STABLE_SAMPLES: usize = 3;
+
+/// Largest spread of the samples of a stable level [kW]
+const NOISE_KW: f64 = 0.1;
+
+/// Steps join a cluster when within this much of its power [kW] ...
+const CLUSTER_TOLERANCE_KW: f64 = 0.15;
+
+/// ... or within this fraction of its power
+const CLUSTER_TOLERANCE_RATIO: f64 = 0.1;
+
+/// Longer runs are assumed to have missed their off event [s]
+const MAX_RUN_S: i64 = 6 * 3600;
+
+const DEFAULT_REPORT_DAYS: u32 = 7;
+
+/// Change of the power level, attributed to the phase that changed most
+#[derive(Debug, PartialEq, Clone)]
+#[allow(non_snake_case)]
+pub struct StepEvent {
+    pub timestamp: i64,
+    /// 1, 2 or 3
+    pub phase: u8,
+    /// Positive when switching on
+    pub delta_kW: f64,
+}
+
+/// Net power (import minus export) of each phase.  Meters without per
+/// phase values report their total on L1.
+pub fn phase_net_kw(telegram: &P1Telegram) -> Option<[f64; 3]> {
+    let net = |import: Option<f64>, export: Option<f64>| match (import, export) {
+        (None, None) => None,
+        (import, export) => Some(import.unwrap_or(0.0) - export.unwrap_or(0.0)),
+    };
+    let phases: Vec<Option<f64>> = telegram
+        .phases
+        .iter()
+        .map(|phase| net(phase.import_kW, phase.export_kW))
+        .collect();
+    if phases.iter().any(Option::is_some) {
+        Some([
+            phases[0].unwrap_or(0.0),
+            phases[1].unwrap_or(0.0),
+            phases[2].unwrap_or(0.0),
+        ])
+    } else {
+        net(telegram.import_kW, telegram.export_kW).map(|total| [total, 0.0, 0.0])
+    }
+}
+
+/// Finds steps between stable power levels in a series of samples.  Slow
+/// changes (e.g. PV production) move the reference level without events.
+#[derive(Default)]
+pub struct StepDetector {
+    recent: VecDeque<(i64, [f64; 3])>,
+    level: Option<[f64; 3]>,
+}
+
+impl StepDetector {
+    pub fn push(&mut self, timestamp: i64, phases: [f64; 3]) -> Option<StepEvent> {
+        self.recent.push_back((timestamp, phases));
+        if self.recent.len() > STABLE_SAMPLES {
+            self.recent.pop_front();
+        }
+        if self.recent.len() < STABLE_SAMPLES {
+            return None;
+        }
+        let mut candidate = [0.0; 3];
+        for (phase, level) in candidate.iter_mut().enumerate() {
+            let values = self.recent.iter().map(|(_, phases)| phases[phase]);
+            let (min, max) = values
+                .clone()
+                .fold((f64::INFINITY, f64::NEG_INFINITY), |(min, max), v| {
+                    (min.min(v), max.max(v))
+                });
+            if max - min > NOISE_KW {
+                return None;
+            }
+            *level = values.sum::<f64>() / STABLE_SAMPLES as f64;
+        }
+        let previous = self.level.replace(candidate)?;
+        let (phase, delta) = candidate
+            .iter()
+            .zip(previous.iter())
+            .map(|(now, before)| now - before)
+            .enumerate()
+            .max_by(|a, b| a.1.abs().total_cmp(&b.1.abs()))?;
+        if delta.abs() < MIN_STEP_KW {
+            return None;
+        }
+        Some(StepEvent {
+            timestamp: self.recent[0].0,
+            phase: phase as u8 + 1,
+            delta_kW: (delta * 1000.0).round() / 1000.0,
+        })
+    }
+}
+
+fn matches(cluster: &ApplianceClusterRow, event: &StepEvent) -> bool {
+    let tolerance = CLUSTER_TOLERANCE_KW.max(CLUSTER_TOLERANCE_RATIO * cluster.power_kW);
+    cluster.phase == event.phase && (cluster.power_kW - event.delta_kW.abs()).abs() <= tolerance
+}
+
+/// Groups the on and off steps of similar size on the same phase.
+#[derive(Default)]
+pub struct ApplianceClusters {
+    pub clusters: Vec<ApplianceClusterRow>,
+}
+
+impl ApplianceClusters {
+    /// Add the event to the closest matching cluster (or a new one) and
+    /// return the updated cluster.
+    pub fn assign(&mut self, event: &StepEvent) -> &ApplianceClusterRow {
+        let size = event.delta_kW.abs();
+        let closest = self
+            .clusters
+            .iter()
+            .enumerate()
+            .filter(|(_, cluster)| matches(cluster, event))
+            .min_by(|(_, a), (_, b)| {
+                (a.power_kW - size)
+                    .abs()
+                    .total_cmp(&(b.power_kW - size).abs())
+            })
+            .map(|(i, _)| i);
+        let i = match closest {
+            Some(i) => {
+                let cluster = &mut self.clusters[i];
+                cluster.events += 1;
+                cluster.power_kW += (size - cluster.power_kW) / cluster.events as f64;
+                cluster.power_kW = (cluster.power_kW * 1000.0).round() / 1000.0;
+                i
+            }
+            None => {
+                let id = self.clusters.iter().map(|c| c.id).max().unwrap_or(0) + 1;
+                self.clusters.push(ApplianceClusterRow {
+                    id,
+                    phase: event.phase,
+                    power_kW: size,
+                    events: 1,
+                    label: None,
+                });
+                self.clusters.len() - 1
+            }
+        };
+        &self.clusters[i]
+    }
+}
+
+#[derive(Debug, PartialEq, Default)]
+#[allow(non_snake_case)]
+pub struct ApplianceDay {
+    pub runs: u32,
+    pub energy_kWh: f64,
+}
+
+/// Energy per local day and cluster: every on step followed by an off step
+/// of the same cluster is a run at the power of the cluster.
+pub fn daily_energy(
+    events: &[ApplianceEventRow],
+    clusters: &[ApplianceClusterRow],
+    tz: Tz,
+) -> BTreeMap<(NaiveDate, i64), ApplianceDay> {
+    let power: HashMap<i64, f64> = clusters.iter().map(|c| (c.id, c.power_kW)).collect();
+    let mut started: HashMap<i64, i64> = HashMap::new();
+    let mut result: BTreeMap<(NaiveDate, i64), ApplianceDay> = BTreeMap::new();
+    for event in events {
+        if event.delta_kW > 0.0 {
+            started.insert(event.cluster, event.timestamp);
+            continue;
+        }
+        let start = match started.remove(&event.cluster) {
+            Some(start) if event.timestamp - start <= MAX_RUN_S => start,
+            _ => continue,
+        };
+        let (day, kw) = match (
+            Utc.timestamp_opt(start, 0).single(),
+            power.get(&event.cluster),
+        ) {
+            (Some(utc), Some(kw)) => (utc.with_timezone(&tz).date_naive(), kw),
+            _ => continue,
+        };
+        let entry = result.entry((day, event.cluster)).or_default();
+        entry.runs += 1;
+        entry.energy_kWh += kw * (event.timestamp - start) as f64 / 3600.0;
+    }
+    result
+}
+
+/// Detect steps in the telegrams received and store them with their cluster.
+pub async fn run_appliance_recorder(hub: Arc<P1Hub>, dialect: Option<Dialect>, database: Database) {
+    let sqlite3 = database.sqlite3.clone();
+    let mut clusters = match web::block(move || select_appliance_clusters(&sqlite3)).await {
+        Ok(Ok(clusters)) => ApplianceClusters { clusters },
+        Ok(Err(e)) => {
+            log::error!("Unable to read appliance clusters: {}", e);
+            return;
+        }
+        Err(e) => {
+            log::error!("Unable to read appliance clusters: {}", e);
+            return;
+        }
+    };
+    let mut receiver = hub.subscribe();
+    let mut detector = StepDetector::default();
+    loop {
+        let telegram = match receiver.recv().await {
+            Ok(telegram) if telegram.crc_ok => telegram,
+            Ok(_) | Err(RecvError::Lagged(_)) => continue,
+            Err(RecvError::Closed) => return,
+        };
+        let parsed = match parse_telegram(&telegram.raw, dialect) {
+            Ok(parsed) => parsed,
+            Err(e) => {
+                log::warn!("Unable to parse P1 telegram: {}", e);
+                continue;
+            }
+        };
+        let phases = match phase_net_kw(&parsed) {
+            Some(phases) => phases,
+            None => continue,
+        };
+        let timestamp = hub.data_time(&telegram, parsed.timestamp).unix_timestamp();
+        let step = match detector.push(timestamp, phases) {
+            Some(step) => step,
+            None => continue,
+        };
+        let cluster = clusters.assign(&step).clone();
+        let event = ApplianceEventRow {
+            timestamp: step.timestamp,
+            phase: step.phase,
+            delta_kW: step.delta_kW,
+            cluster: cluster.id,
+        };
+        let sqlite3 = database.sqlite3.clone();
+        match web::block(move || insert_appliance_event(&sqlite3, &event, &cluster)).await {
+            Ok(Ok(_)) => {}
+            Ok(Err(e)) => log::error!("Unable to store appliance event: {}", e),
+            Err(e) => log::error!("Unable to store appliance event: {}", e),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct ApplianceReportQuery {
+    days: Option<u32>,
+}
+
+#[derive(Serialize)]
+struct ClusterView {
+    id: i64,
+    phase: u8,
+    watts: String,
+    events: i64,
+    label: String,
+}
+
+#[derive(Serialize)]
+struct ApplianceDayView {
+    day: String,
+    appliance: String,
+    runs: u32,
+    energy: String,
+}
+
+fn cluster_name(cluster: &ApplianceClusterRow) -> String {
+    match &cluster.label {
+        Some(label) => label.clone(),
+        None => format!(
+            "#{} ({:.0}W, L{})",
+            cluster.id,
+            cluster.power_kW * 1000.0,
+            cluster.phase
+        ),
+    }
+}
+
+#[get("/p1/appliances")]
+pub async fn appliances_page(
+    tera: web::Data<Tera>,
+    database: web::Data<Database>,
+    query: web::Query<ApplianceReportQuery>,
+) -> HttpResponse {
+    let days = query.days.unwrap_or(DEFAULT_REPORT_DAYS).max(1);
+    let since = OffsetDateTime::now_utc().unix_timestamp() - days as i64 * 86400;
+    let sqlite3 = database.sqlite3.clone();
+    let rows = web::block(move || {
+        Ok::<_, String>((
+            select_appliance_clusters(&sqlite3)?,
+            select_appliance_events(&sqlite3, since)?,
+        ))
+    })
+    .await
+    .map_err(|e| e.to_string())
+    .and_then(|rows| rows);
+    let mut context = tera::Context::new();
+    match rows {
+        Ok((clusters, events)) => {
+            let names: HashMap<i64, String> =
+                clusters.iter().map(|c| (c.id, cluster_name(c))).collect();
+            let daily: Vec<ApplianceDayView> =
+                daily_energy(&events, &clusters, configured_timezone())
+                    .iter()
+                    .rev()
+                    .map(|((day, cluster), usage)| ApplianceDayView {
+                        day: day.to_string(),
+                        appliance: names.get(cluster).cloned().unwrap_or_default(),
+                        runs: usage.runs,
+                        energy: format!("{:.2}", usage.energy_kWh),
+                    })
+                    .collect();
+            let mut clusters: Vec<ClusterView> = clusters
+                .iter()
+                .map(|c| ClusterView {
+                    id: c.id,
+                    phase: c.phase,
+                    watts: format!("{:.0}", c.power_kW * 1000.0),
+                    events: c.events,
+                    label: c.label.clone().unwrap_or_default(),
+                })
+                .collect();
+            clusters.sort_by_key(|c| std::cmp::Reverse(c.events));
+            context.insert("clusters", &clusters);
+            context.insert("daily", &daily);
+        }
+        Err(e) => {
+            log::error!("Unable to read appliance events: {}", e);
+            context.insert("error", &e);
+        }
+    }
+    context.insert("days", &days);
+    context.insert(
+        "now",
+        &format_timestamp(OffsetDateTime::now_utc().unix_timestamp()),
+    );
+    let rendered = tera.render("p1_appliances.html", &context).unwrap();
+    HttpResponse::Ok().body(rendered)
+}
+
+#[derive(Deserialize)]
+pub struct ApplianceLabelInput {
+    id: i64,
+    label: String,
+}
+
+#[post("/p1/appliances/label")]
+pub async fn label_appliance(
+    database: web::Data<Database>,
+    web::Form(form): web::Form<ApplianceLabelInput>,
+) -> HttpResponse {
+    // The label is shown as is and stored in a `|` separated listing
+    let label: String = form
+        .label
+        .chars()
+        .filter(|c| !c.is_control())
+        .take(64)
+        .collect();
+    let sqlite3 = database.sqlite3.clone();
+    match web::block(move || update_appliance_label(&sqlite3, form.id, &label)).await {
+        Ok(Ok(1)) => HttpResponse::SeeOther()
+            .insert_header((header::LOCATION, "/hello-rust/p1/appliances"))
+            .finish(),
+        Ok(Ok(_)) => HttpResponse::NotFound().body(format!("No appliance #{}", form.id)),
+        Ok(Err(e)) => {
+            log::error!("Unable to label appliance: {}", e);
+            HttpResponse::InternalServerError().body(e)
+        }
+        Err(e) => {
+            log::error!("Unable to label appliance: {}", e);
+            HttpResponse::InternalServerError().body(e.to_string())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn feed(detector: &mut StepDetector, start: i64, samples: &[[f64; 3]]) -> Vec<StepEvent> {
+        samples
+            .iter()
+            .zip(start..)
+            .filter_map(|(phases, timestamp)| detector.push(timestamp, *phases))
+            .collect()
+    }
+
+    #[test]
+    fn step_detector_finds_on_and_off() {
+        let mut detector = StepDetector::default();
+        let idle = [0.1, 0.05, -1.0];
+        let kettle = [0.1, 2.05, -1.0];
+        let mut samples = vec![idle; 5];
+        // Ramp over two samples, then noise
+        samples.extend([[0.1, 1.0, -1.0], kettle, kettle, [0.1, 2.1, -1.02], kettle]);
+        // PV slowly increasing, no event
+        samples.extend((0..5).map(|i| [0.1, 2.05, -1.0 - 0.05 * i as f64]));
+        samples.extend([[0.1, 0.05, -1.2]; 4]);
+        assert_eq!(
+            feed(&mut detector, 1000, &samples),
+            vec![
+                StepEvent {
+                    timestamp: 1006,
+                    phase: 2,
+                    delta_kW: 2.017
+                },
+                StepEvent {
+                    timestamp: 1015,
+                    phase: 2,
+                    delta_kW: -2.0
+                }
+            ]
+        );
+    }
+
+    #[test]
+    fn clusters_group_similar_steps() {
+        let mut clusters = ApplianceClusters::default();
+        #[allow(non_snake_case)]
+        let step = |phase, delta_kW| StepEvent {
+            timestamp: 0,
+            phase,
+            delta_kW,
+        };
+        assert_eq!(clusters.assign(&step(2, 2.0)).id, 1);
+        assert_eq!(clusters.assign(&step(2, -2.1)).id, 1);
+        // Same size on another phase
+        assert_eq!(clusters.assign(&step(1, 2.0)).id, 2);
+        assert_eq!(clusters.assign(&step(2, 0.8)).id, 3);
+        let kettle = clusters.assign(&step(2, 1.9));
+        assert_eq!((kettle.id, kettle.events, kettle.power_kW), (1, 3, 2.0));
+    }
+
+    #[test]
+    fn daily_energy_pairs_on_and_off() {
+        #[allow(non_snake_case)]
+        let cluster = |id, power_kW| ApplianceClusterRow {
+            id,
+            phase: 1,
+            power_kW,
+            events: 2,
+            label: None,
+        };
+        #[allow(non_snake_case)]
+        let event = |timestamp, delta_kW, cluster| ApplianceEventRow {
+            timestamp,
+            phase: 1,
+            delta_kW,
+            cluster,
+        };
+        // 2024-10-25 00:00:00 UTC
+        let day = 1729814400;
+        let events = vec![
+            event(day + 3600, 2.0, 1),
+            event(day + 3600 + 180, -2.0, 1),
+            // Unmatched off, then a run lasting too long
+            event(day + 7200, -2.0, 1),
+            event(day + 7200, 2.0, 1),
+            event(day + 7200 + MAX_RUN_S + 1, -2.0, 1),
+            event(day + 86400 - 600, 1.0, 2),
+            event(day + 86400 + 600, -1.0, 2),
+        ];
+        let energy = daily_energy(&events, &[cluster(1, 2.0), cluster(2, 1.0)], chrono_tz::UTC);
+        let date = NaiveDate::from_ymd_opt(2024, 10, 25).unwrap();
+        assert_eq!(energy.len(), 2);
+        assert_eq!(
+            energy[&(date, 1)],
+            ApplianceDay {
+                runs: 1,
+                energy_kWh: 0.1
+            }
+        );
+        // Run started the day before is counted on that day
+        assert_eq!(energy[&(date, 2)].runs, 1);
+        assert!((energy[&(date, 2)].energy_kWh - 1.0 / 3.0).abs() < 1e-9);
+    }
+}
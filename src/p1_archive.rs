@@ -0,0 +1,418 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use actix_web::web;
+use chrono::{Datelike, NaiveDate, NaiveDateTime, TimeZone};
+use flate2::read::MultiGzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use time::{Date, OffsetDateTime};
+use tokio::sync::broadcast::error::RecvError;
+
+use crate::p1_meter::METER_TIMEZONE;
+use crate::p1_reader::P1Hub;
+
+/// Telegrams are compressed together over this long [s] ...
+const CHUNK_S: i64 = 60;
+
+/// ... or until their text reaches this size
+const MAX_CHUNK_BYTES: usize = 1 << 20;
+
+/// Telegrams received during `CHUNK_S` from `first_received` (UTC day `day`)
+#[derive(Debug, PartialEq)]
+pub struct ArchiveChunk {
+    pub day: Date,
+    pub first_received: i64,
+    pub text: String,
+}
+
+/// Collects telegrams into chunks, each written as one gzip member.
+#[derive(Default)]
+pub struct ArchiveBuffer {
+    chunk: Option<ArchiveChunk>,
+}
+
+impl ArchiveBuffer {
+    /// Add a telegram; returns the previous chunk when this one starts a
+    /// new chunk.
+    pub fn push(&mut self, raw: &str, received_at: OffsetDateTime) -> Option<ArchiveChunk> {
+        let timestamp = received_at.unix_timestamp();
+        let day = received_at.to_offset(time::UtcOffset::UTC).date();
+        let finished = match &self.chunk {
+            Some(chunk)
+                if chunk.day != day
+                    || timestamp - chunk.first_received >= CHUNK_S
+                    || chunk.text.len() >= MAX_CHUNK_BYTES =>
+            {
+                self.chunk.take()
+            }
+            _ => None,
+        };
+        self.chunk
+            .get_or_insert_with(|| ArchiveChunk {
+                day,
+                first_received: timestamp,
+                text: String::new(),
+            })
+            .text
+            .push_str(raw);
+        finished
+    }
+
+    pub fn take(&mut self) -> Option<ArchiveChunk> {
+        self.chunk.take()
+    }
+}
+
+fn archive_path(dir: &Path, day: Date, extension: &str) -> PathBuf {
+    dir.join(format!(
+        "p1-{:04}-{:02}-{:02}.{}",
+        day.year(),
+        day.month() as u8,
+        day.day(),
+        extension
+    ))
+}
+
+fn day_of_file_name(name: &str) -> Option<Date> {
+    let stem = name.strip_prefix("p1-")?;
+    let stem = stem
+        .strip_suffix(".gz")
+        .or_else(|| stem.strip_suffix(".idx"))?;
+    let date = NaiveDate::parse_from_str(stem, "%Y-%m-%d").ok()?;
+    Date::from_ordinal_date(date.year(), date.ordinal() as u16).ok()
+}
+
+/// Append the chunk as a gzip member to the archive of its day, and the
+/// time it starts and its offset to the index of that day.
+pub fn append_chunk(dir: &Path, chunk: &ArchiveChunk) -> io::Result<()> {
+    fs::create_dir_all(dir)?;
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(chunk.text.as_bytes())?;
+    let member = encoder.finish()?;
+    let mut archive = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(archive_path(dir, chunk.day, "gz"))?;
+    let offset = archive.metadata()?.len();
+    archive.write_all(&member)?;
+    // The index only points to complete members
+    let mut index = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(archive_path(dir, chunk.day, "idx"))?;
+    writeln!(index, "{} {}", chunk.first_received, offset)
+}
+
+/// `(first_received, offset)` of every member of an archive file
+pub fn read_index(path: &Path) -> io::Result<Vec<(i64, u64)>> {
+    let mut result = Vec::new();
+    for line in BufReader::new(File::open(path)?).lines() {
+        let line = line?;
+        let parsed = line
+            .split_once(' ')
+            .and_then(|(t, o)| Some((i64::from_str(t).ok()?, u64::from_str(o).ok()?)));
+        match parsed {
+            Some(entry) => result.push(entry),
+            None => log::warn!("{}: bad index line {}", path.display(), line),
+        }
+    }
+    Ok(result)
+}
+
+/// Delete the archives of days older than `keep_days` before `today`.
+pub fn remove_expired(dir: &Path, keep_days: u32, today: Date) -> io::Result<Vec<PathBuf>> {
+    let oldest = today - time::Duration::days(keep_days as i64);
+    let mut removed = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        let expired = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .and_then(day_of_file_name)
+            .map(|day| day < oldest)
+            .unwrap_or(false);
+        if expired {
+            fs::remove_file(&path)?;
+            removed.push(path);
+        }
+    }
+    removed.sort();
+    Ok(removed)
+}
+
+/// Part of an archive file covering a time range: from `start` to `end`
+/// (end of file when `None`).
+#[derive(Debug, PartialEq, Clone)]
+pub struct ArchiveSection {
+    pub path: PathBuf,
+    pub start: u64,
+    pub end: Option<u64>,
+}
+
+/// Sections of the archive files holding the telegrams received between
+/// `from` and `to` (inclusive), to the chunk.
+pub fn archive_sections(dir: &Path, from: i64, to: i64) -> io::Result<Vec<ArchiveSection>> {
+    let day_of = |timestamp| {
+        OffsetDateTime::from_unix_timestamp(timestamp)
+            .map(|t| t.date())
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))
+    };
+    let (mut day, last) = (day_of(from)?, day_of(to)?);
+    let mut result = Vec::new();
+    while day <= last {
+        let path = archive_path(dir, day, "gz");
+        if path.exists() {
+            let index = match read_index(&archive_path(dir, day, "idx")) {
+                Ok(index) => index,
+                Err(e) if e.kind() == io::ErrorKind::NotFound => Vec::new(),
+                Err(e) => return Err(e),
+            };
+            let start = index
+                .iter()
+                .take_while(|(first_received, _)| *first_received <= from)
+                .last()
+                .map(|(_, offset)| *offset)
+                .unwrap_or(0);
+            let end = index
+                .iter()
+                .find(|(first_received, _)| *first_received > to)
+                .map(|(_, offset)| *offset);
+            if end != Some(start) {
+                result.push(ArchiveSection { path, start, end });
+            }
+        }
+        day = match day.next_day() {
+            Some(next) => next,
+            None => break,
+        };
+    }
+    Ok(result)
+}
+
+/// The gzip members of a section, each with the time its first telegram was
+/// received.  Without index, the section is returned whole and without time.
+pub fn section_members(section: &ArchiveSection) -> io::Result<Vec<(Option<i64>, ArchiveSection)>> {
+    let index = match read_index(&section.path.with_extension("idx")) {
+        Ok(index) => index,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Vec::new(),
+        Err(e) => return Err(e),
+    };
+    let offsets: Vec<(i64, u64)> = index
+        .into_iter()
+        .filter(|(_, offset)| {
+            *offset >= section.start && section.end.is_none_or(|end| *offset < end)
+        })
+        .collect();
+    if offsets.first().map(|(_, offset)| *offset) != Some(section.start) {
+        return Ok(vec![(None, section.clone())]);
+    }
+    Ok(offsets
+        .iter()
+        .enumerate()
+        .map(|(i, (first_received, start))| {
+            let end = offsets.get(i + 1).map(|(_, next)| *next).or(section.end);
+            (
+                Some(*first_received),
+                ArchiveSection {
+                    path: section.path.clone(),
+                    start: *start,
+                    end,
+                },
+            )
+        })
+        .collect())
+}
+
+/// Decompressed telegrams of a section
+pub fn open_section(section: &ArchiveSection) -> io::Result<impl Read> {
+    let mut file = File::open(&section.path)?;
+    file.seek(SeekFrom::Start(section.start))?;
+    let limit = section
+        .end
+        .map(|end| end - section.start)
+        .unwrap_or(u64::MAX);
+    Ok(MultiGzDecoder::new(file.take(limit)))
+}
+
+/// `YYYY-MM-DD` or `YYYY-MM-DDThh:mm[:ss]` in meter time; a date alone
+/// stands for its start, or its end when `end_of_day`.
+pub fn parse_range_bound(s: &str, end_of_day: bool) -> Result<i64, String> {
+    let naive = match NaiveDate::parse_from_str(s, "%Y-%m-%d") {
+        Ok(date) if end_of_day => date.and_hms_opt(23, 59, 59),
+        Ok(date) => date.and_hms_opt(0, 0, 0),
+        Err(_) => NaiveDateTime::parse_from_str(s, "%Y-%m-%dT%H:%M:%S")
+            .or_else(|_| NaiveDateTime::parse_from_str(s, "%Y-%m-%dT%H:%M"))
+            .ok(),
+    }
+    .ok_or_else(|| format!("Bad date {}", s))?;
+    METER_TIMEZONE
+        .from_local_datetime(&naive)
+        .earliest()
+        .map(|local| local.timestamp())
+        .ok_or_else(|| format!("No such meter time {}", s))
+}
+
+async fn store_chunk(dir: &Path, chunk: ArchiveChunk) {
+    let dir = dir.to_path_buf();
+    match web::block(move || append_chunk(&dir, &chunk)).await {
+        Ok(Ok(())) => {}
+        Ok(Err(e)) => log::error!("Unable to archive telegrams: {}", e),
+        Err(e) => log::error!("Unable to archive telegrams: {}", e),
+    }
+}
+
+/// Write every telegram received (valid or not) to the daily archives in
+/// `dir`, deleting those older than `keep_days` if set.
+pub async fn run_archive_recorder(hub: Arc<P1Hub>, dir: PathBuf, keep_days: Option<u32>) {
+    let mut receiver = hub.subscribe();
+    let mut buffer = ArchiveBuffer::default();
+    let mut cleaned: Option<Date> = None;
+    loop {
+        // Without telegrams, the pending chunk is written after a while
+        let received = tokio::time::timeout(Duration::from_secs(CHUNK_S as u64), receiver.recv());
+        let telegram = match received.await {
+            Ok(Ok(telegram)) => telegram,
+            Ok(Err(RecvError::Lagged(count))) => {
+                log::warn!("Archive missed {} telegrams", count);
+                continue;
+            }
+            Ok(Err(RecvError::Closed)) => break,
+            Err(_) => {
+                if let Some(chunk) = buffer.take() {
+                    store_chunk(&dir, chunk).await;
+                }
+                continue;
+            }
+        };
+        let chunk = match buffer.push(&telegram.raw, telegram.received_at) {
+            Some(chunk) => chunk,
+            None => continue,
+        };
+        let day = chunk.day;
+        store_chunk(&dir, chunk).await;
+        if let (Some(keep_days), true) = (keep_days, cleaned != Some(day)) {
+            cleaned = Some(day);
+            let dir = dir.clone();
+            match web::block(move || remove_expired(&dir, keep_days, day)).await {
+                Ok(Ok(removed)) => {
+                    for path in removed {
+                        log::info!("Removed {}", path.display());
+                    }
+                }
+                Ok(Err(e)) => log::error!("Unable to remove old archives: {}", e),
+                Err(e) => log::error!("Unable to remove old archives: {}", e),
+            }
+        }
+    }
+    if let Some(chunk) = buffer.take() {
+        store_chunk(&dir, chunk).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("p1_archive_{}_{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    fn at(timestamp: i64) -> OffsetDateTime {
+        OffsetDateTime::from_unix_timestamp(timestamp).unwrap()
+    }
+
+    // 2024-10-25 00:00:00 UTC
+    const DAY: i64 = 1729814400;
+
+    #[test]
+    fn buffer_cuts_chunks_by_time_and_day() {
+        let mut buffer = ArchiveBuffer::default();
+        assert_eq!(buffer.push("/A\r\n!\r\n", at(DAY - 10)), None);
+        // New day
+        let chunk = buffer.push("/B\r\n!\r\n", at(DAY)).unwrap();
+        assert_eq!(
+            (chunk.first_received, chunk.text.as_str()),
+            (DAY - 10, "/A\r\n!\r\n")
+        );
+        assert_eq!(buffer.push("/C\r\n!\r\n", at(DAY + CHUNK_S - 1)), None);
+        let chunk = buffer.push("/D\r\n!\r\n", at(DAY + CHUNK_S)).unwrap();
+        assert_eq!(chunk.text, "/B\r\n!\r\n/C\r\n!\r\n");
+        assert_eq!(buffer.take().unwrap().first_received, DAY + CHUNK_S);
+        assert_eq!(buffer.take(), None);
+    }
+
+    #[test]
+    fn append_and_read_sections() {
+        let dir = test_dir("sections");
+        let day = at(DAY).date();
+        for (i, text) in ["/A\r\n!\r\n", "/B\r\n!\r\n", "/C\r\n!\r\n"]
+            .iter()
+            .enumerate()
+        {
+            let chunk = ArchiveChunk {
+                day,
+                first_received: DAY + 60 * i as i64,
+                text: text.to_string(),
+            };
+            append_chunk(&dir, &chunk).unwrap();
+        }
+        let index = read_index(&dir.join("p1-2024-10-25.idx")).unwrap();
+        assert_eq!(index.len(), 3);
+        assert_eq!(index[0], (DAY, 0));
+
+        let read = |from, to| -> String {
+            let mut text = String::new();
+            for section in archive_sections(&dir, from, to).unwrap() {
+                open_section(&section)
+                    .unwrap()
+                    .read_to_string(&mut text)
+                    .unwrap();
+            }
+            text
+        };
+        assert_eq!(
+            read(DAY - 86400, DAY + 86400),
+            "/A\r\n!\r\n/B\r\n!\r\n/C\r\n!\r\n"
+        );
+        assert_eq!(read(DAY + 70, DAY + 90), "/B\r\n!\r\n");
+        assert_eq!(read(DAY + 60, DAY + 120), "/B\r\n!\r\n/C\r\n!\r\n");
+        assert_eq!(read(DAY - 100, DAY - 10), "");
+
+        let sections = archive_sections(&dir, DAY + 60, DAY + 200).unwrap();
+        let members = section_members(&sections[0]).unwrap();
+        assert_eq!(
+            members.iter().map(|(t, _)| *t).collect::<Vec<_>>(),
+            vec![Some(DAY + 60), Some(DAY + 120)]
+        );
+        let mut text = String::new();
+        open_section(&members[1].1)
+            .unwrap()
+            .read_to_string(&mut text)
+            .unwrap();
+        assert_eq!(text, "/C\r\n!\r\n");
+
+        let removed = remove_expired(&dir, 3, day + time::Duration::days(3)).unwrap();
+        assert!(removed.is_empty());
+        let removed = remove_expired(&dir, 3, day + time::Duration::days(4)).unwrap();
+        assert_eq!(removed.len(), 2);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn range_bounds_in_meter_time() {
+        assert_eq!(parse_range_bound("2024-10-25", false), Ok(DAY - 7200));
+        assert_eq!(
+            parse_range_bound("2024-10-25", true),
+            Ok(DAY + 86400 - 7200 - 1)
+        );
+        assert_eq!(parse_range_bound("2024-12-01T06:30", false), Ok(1733031000));
+        assert!(parse_range_bound("25/10/2024", false).is_err());
+    }
+}
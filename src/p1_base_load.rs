@@ -0,0 +1,379 @@
+use std::collections::BTreeMap;
+
+use actix_web::{get, web, HttpResponse};
+use chrono::{Datelike, NaiveDate, TimeZone, Timelike, Utc};
+use chrono_tz::Tz;
+use serde::{Deserialize, Serialize};
+use tera::Tera;
+use time::OffsetDateTime;
+
+use crate::data::{
+    select_counter_slots, select_power_slots, CounterSlotRow, Database, PowerSlotRow,
+};
+use crate::{configured_timezone, format_timestamp};
+
+/// Length of the periods whose average consumption is compared [s]
+const SLOT_S: i64 = 900;
+
+/// Local hours considered: nothing should run, no PV production
+const NIGHT_HOURS: std::ops::Range<u32> = 0..5;
+
+/// Nights with fewer usable slots have no estimate
+const MIN_SLOTS_PER_NIGHT: usize = 4;
+
+/// Slots of counter data need measurements spanning at least this long [s]
+const MIN_COUNTER_SPAN_S: i64 = 600;
+
+/// Increase over the median of the previous nights flagged as a new load [kW]
+const JUMP_KW: f64 = 0.03;
+
+/// Nights the median is taken over
+const JUMP_REFERENCE_NIGHTS: usize = 7;
+
+const DEFAULT_REPORT_WEEKS: u32 = 12;
+
+#[derive(Debug, PartialEq)]
+#[allow(non_snake_case)]
+pub struct BaseLoadSlot {
+    pub start: i64,
+    pub import_kW: f64,
+    /// Some power was injected: PV production
+    pub exported: bool,
+}
+
+impl From<&PowerSlotRow> for BaseLoadSlot {
+    fn from(row: &PowerSlotRow) -> Self {
+        BaseLoadSlot {
+            start: row.slot,
+            import_kW: row.avg_import_kW,
+            exported: row.max_export_kW > 0.0,
+        }
+    }
+}
+
+/// Average power from the counter increase, if measured over long enough.
+pub fn counter_slot(row: &CounterSlotRow) -> Option<BaseLoadSlot> {
+    if row.span_s < MIN_COUNTER_SPAN_S {
+        return None;
+    }
+    Some(BaseLoadSlot {
+        start: row.slot,
+        import_kW: row.consumed_kWh * 3600.0 / row.span_s as f64,
+        exported: row.injected_kWh > 0.0,
+    })
+}
+
+#[derive(Debug, PartialEq, Clone, Copy, Serialize)]
+pub enum BaseLoadSource {
+    Power,
+    Counters,
+}
+
+#[derive(Debug, PartialEq)]
+#[allow(non_snake_case)]
+pub struct NightlyBaseLoad {
+    /// Date of the morning ending the night
+    pub day: NaiveDate,
+    pub base_load_kW: f64,
+    pub source: BaseLoadSource,
+}
+
+/// Lowest slot average of each night, ignoring slots with PV production.
+fn lowest_per_night(slots: &[BaseLoadSlot], tz: Tz) -> BTreeMap<NaiveDate, f64> {
+    let mut nights: BTreeMap<NaiveDate, Vec<f64>> = BTreeMap::new();
+    for slot in slots.iter().filter(|slot| !slot.exported) {
+        let local = match Utc.timestamp_opt(slot.start, 0).single() {
+            Some(utc) => utc.with_timezone(&tz),
+            None => continue,
+        };
+        if NIGHT_HOURS.contains(&local.hour()) {
+            nights
+                .entry(local.date_naive())
+                .or_default()
+                .push(slot.import_kW);
+        }
+    }
+    nights
+        .into_iter()
+        .filter(|(_, values)| values.len() >= MIN_SLOTS_PER_NIGHT)
+        .map(|(day, values)| (day, values.into_iter().fold(f64::INFINITY, f64::min)))
+        .collect()
+}
+
+/// Base load estimate per night: from the stored P1 power data when
+/// available, from the consumption counter increases otherwise.
+pub fn nightly_base_load(
+    power: &[BaseLoadSlot],
+    counters: &[BaseLoadSlot],
+    tz: Tz,
+) -> Vec<NightlyBaseLoad> {
+    let mut result: BTreeMap<NaiveDate, (f64, BaseLoadSource)> = lowest_per_night(counters, tz)
+        .into_iter()
+        .map(|(day, kw)| (day, (kw, BaseLoadSource::Counters)))
+        .collect();
+    for (day, kw) in lowest_per_night(power, tz) {
+        result.insert(day, (kw, BaseLoadSource::Power));
+    }
+    result
+        .into_iter()
+        .map(|(day, (kw, source))| NightlyBaseLoad {
+            day,
+            base_load_kW: kw,
+            source,
+        })
+        .collect()
+}
+
+fn median(values: &[f64]) -> Option<f64> {
+    let mut sorted = values.to_vec();
+    sorted.sort_by(f64::total_cmp);
+    match sorted.len() {
+        0 => None,
+        n if n % 2 == 1 => Some(sorted[n / 2]),
+        n => Some((sorted[n / 2 - 1] + sorted[n / 2]) / 2.0),
+    }
+}
+
+/// Increase of each night over the median of the previous nights, when
+/// larger than `JUMP_KW`.
+pub fn jumps(nights: &[NightlyBaseLoad]) -> Vec<Option<f64>> {
+    nights
+        .iter()
+        .enumerate()
+        .map(|(i, night)| {
+            let previous: Vec<f64> = nights[i.saturating_sub(JUMP_REFERENCE_NIGHTS)..i]
+                .iter()
+                .map(|night| night.base_load_kW)
+                .collect();
+            median(&previous)
+                .map(|reference| night.base_load_kW - reference)
+                .filter(|increase| *increase > JUMP_KW)
+        })
+        .collect()
+}
+
+#[derive(Debug, PartialEq)]
+#[allow(non_snake_case)]
+pub struct WeeklyBaseLoad {
+    /// ISO week, e.g. 2024-W43
+    pub week: String,
+    pub nights: usize,
+    pub avg_base_load_kW: f64,
+}
+
+pub fn weekly_base_load(nights: &[NightlyBaseLoad]) -> Vec<WeeklyBaseLoad> {
+    let mut weeks: BTreeMap<(i32, u32), Vec<f64>> = BTreeMap::new();
+    for night in nights {
+        let week = night.day.iso_week();
+        weeks
+            .entry((week.year(), week.week()))
+            .or_default()
+            .push(night.base_load_kW);
+    }
+    weeks
+        .into_iter()
+        .map(|((year, week), values)| WeeklyBaseLoad {
+            week: format!("{}-W{:02}", year, week),
+            nights: values.len(),
+            avg_base_load_kW: values.iter().sum::<f64>() / values.len() as f64,
+        })
+        .collect()
+}
+
+#[derive(Deserialize)]
+pub struct BaseLoadQuery {
+    weeks: Option<u32>,
+}
+
+#[derive(Serialize)]
+struct NightView {
+    day: String,
+    watts: String,
+    source: &'static str,
+    jump: Option<String>,
+}
+
+#[derive(Serialize)]
+struct WeekView {
+    week: String,
+    nights: usize,
+    watts: String,
+    change: String,
+    /// Width of the bar [%]
+    bar: u32,
+}
+
+fn watts(kw: f64) -> String {
+    format!("{:.0}", kw * 1000.0)
+}
+
+#[get("/p1/base-load")]
+pub async fn base_load_page(
+    tera: web::Data<Tera>,
+    database: web::Data<Database>,
+    query: web::Query<BaseLoadQuery>,
+) -> HttpResponse {
+    let weeks = query.weeks.unwrap_or(DEFAULT_REPORT_WEEKS).max(1);
+    let since = OffsetDateTime::now_utc().unix_timestamp() - weeks as i64 * 7 * 86400;
+    let sqlite3 = database.sqlite3.clone();
+    let rows = web::block(move || {
+        Ok::<_, String>((
+            select_power_slots(&sqlite3, since, SLOT_S)?,
+            select_counter_slots(&sqlite3, since, SLOT_S)?,
+        ))
+    })
+    .await
+    .map_err(|e| e.to_string())
+    .and_then(|rows| rows);
+    let mut context = tera::Context::new();
+    match rows {
+        Ok((power, counters)) => {
+            let power: Vec<BaseLoadSlot> = power.iter().map(BaseLoadSlot::from).collect();
+            let counters: Vec<BaseLoadSlot> = counters.iter().filter_map(counter_slot).collect();
+            let nights = nightly_base_load(&power, &counters, configured_timezone());
+            let weekly = weekly_base_load(&nights);
+            let highest = weekly
+                .iter()
+                .map(|week| week.avg_base_load_kW)
+                .fold(0.0, f64::max);
+            let week_views: Vec<WeekView> = weekly
+                .iter()
+                .enumerate()
+                .map(|(i, week)| WeekView {
+                    week: week.week.clone(),
+                    nights: week.nights,
+                    watts: watts(week.avg_base_load_kW),
+                    change: match i.checked_sub(1).map(|j| &weekly[j]) {
+                        Some(previous) => format!(
+                            "{:+.0}",
+                            (week.avg_base_load_kW - previous.avg_base_load_kW) * 1000.0
+                        ),
+                        None => String::new(),
+                    },
+                    bar: if highest > 0.0 {
+                        (week.avg_base_load_kW / highest * 100.0).round() as u32
+                    } else {
+                        0
+                    },
+                })
+                .collect();
+            let night_views: Vec<NightView> = nights
+                .iter()
+                .zip(jumps(&nights))
+                .rev()
+                .map(|(night, jump)| NightView {
+                    day: night.day.to_string(),
+                    watts: watts(night.base_load_kW),
+                    source: match night.source {
+                        BaseLoadSource::Power => "power",
+                        BaseLoadSource::Counters => "counters",
+                    },
+                    jump: jump.map(|kw| format!("+{}", watts(kw))),
+                })
+                .collect();
+            context.insert("weekly", &week_views);
+            context.insert("nights", &night_views);
+        }
+        Err(e) => {
+            log::error!("Unable to read base load data: {}", e);
+            context.insert("error", &e);
+        }
+    }
+    context.insert("weeks", &weeks);
+    context.insert(
+        "now",
+        &format_timestamp(OffsetDateTime::now_utc().unix_timestamp()),
+    );
+    let rendered = tera.render("p1_base_load.html", &context).unwrap();
+    HttpResponse::Ok().body(rendered)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 2024-10-25 00:00:00 in Brussels (UTC+2)
+    const MIDNIGHT: i64 = 1729807200;
+
+    #[allow(non_snake_case)]
+    fn slots(start: i64, import_kW: &[f64]) -> Vec<BaseLoadSlot> {
+        import_kW
+            .iter()
+            .enumerate()
+            .map(|(i, kw)| BaseLoadSlot {
+                start: start + i as i64 * SLOT_S,
+                import_kW: *kw,
+                exported: false,
+            })
+            .collect()
+    }
+
+    #[allow(non_snake_case)]
+    fn night(day: u32, base_load_kW: f64) -> NightlyBaseLoad {
+        NightlyBaseLoad {
+            day: NaiveDate::from_ymd_opt(2024, 10, day).unwrap(),
+            base_load_kW,
+            source: BaseLoadSource::Power,
+        }
+    }
+
+    #[test]
+    fn nightly_base_load_prefers_power_data() {
+        let tz = chrono_tz::Europe::Brussels;
+        // Night of the 24th: counters only, the evening before is ignored
+        let mut counters = slots(MIDNIGHT - 86400 - 4 * 3600, &[2.0; 4]);
+        counters.extend(slots(MIDNIGHT - 86400, &[0.3, 0.2, 0.25, 0.4]));
+        // Night of the 25th: both, 0.1 is PV in the morning
+        counters.extend(slots(MIDNIGHT, &[0.5; 4]));
+        let mut power = slots(MIDNIGHT, &[0.15, 0.12, 0.9, 0.14]);
+        power.push(BaseLoadSlot {
+            start: MIDNIGHT + 4 * SLOT_S,
+            import_kW: 0.1,
+            exported: true,
+        });
+        // Too short a night
+        power.extend(slots(MIDNIGHT + 86400, &[0.1; 3]));
+        assert_eq!(
+            nightly_base_load(&power, &counters, tz),
+            vec![
+                NightlyBaseLoad {
+                    day: NaiveDate::from_ymd_opt(2024, 10, 24).unwrap(),
+                    base_load_kW: 0.2,
+                    source: BaseLoadSource::Counters
+                },
+                night(25, 0.12)
+            ]
+        );
+    }
+
+    #[test]
+    fn counter_slot_needs_long_enough_span() {
+        let row = CounterSlotRow {
+            slot: MIDNIGHT,
+            span_s: 900,
+            consumed_kWh: 0.05,
+            injected_kWh: 0.0,
+        };
+        assert_eq!(counter_slot(&row).unwrap().import_kW, 0.2);
+        assert!(counter_slot(&CounterSlotRow { span_s: 300, ..row }).is_none());
+    }
+
+    #[test]
+    fn jumps_and_weekly_trend() {
+        let nights: Vec<NightlyBaseLoad> = [0.1, 0.12, 0.11, 0.2, 0.21]
+            .iter()
+            .zip(25..)
+            .map(|(kw, day)| night(day, *kw))
+            .collect();
+        let jumps = jumps(&nights);
+        assert_eq!(jumps[..3], [None, None, None]);
+        assert!((jumps[3].unwrap() - 0.09).abs() < 1e-9);
+        // Median of 0.1, 0.12, 0.11, 0.2
+        assert!((jumps[4].unwrap() - 0.095).abs() < 1e-9);
+        // 2024-10-27 is a Sunday
+        let weekly = weekly_base_load(&nights);
+        assert_eq!(weekly.len(), 2);
+        assert_eq!((weekly[0].week.as_str(), weekly[0].nights), ("2024-W43", 3));
+        assert!((weekly[1].avg_base_load_kW - 0.205).abs() < 1e-9);
+    }
+}
@@ -0,0 +1,473 @@
+//! Fast path for importing captures and archives of telegrams.
+//!
+//! Works on the raw bytes of whole files: telegrams and lines are slices of
+//! the buffer, numbers are parsed in place (timestamps by
+//! `p1_meter::parse_timestamp`) and errors are plain values, so nothing is
+//! allocated per valid telegram.  The result is the same as
+//! `validate_crc` followed by `parse_telegram` for the telegrams meters send.
+//! DSMR 2.2 telegrams, which lack the meter time this path keys on, go
+//! through `parse_telegram` itself.
+use std::fmt;
+use std::fs::File;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::sync_channel;
+
+use flate2::read::MultiGzDecoder;
+use memchr::{memchr, memchr2_iter, memchr_iter};
+use rayon::prelude::*;
+
+use crate::data::P1Measurement;
+use crate::p1_meter::{self, crc16};
+use crate::p1_telegram::{detect_dialect, parse_telegram, Dialect};
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum BulkError {
+    MalformedCrc,
+    CrcMismatch {
+        expected: u16,
+        actual: u16,
+    },
+    MalformedTimestamp,
+    /// OBIS reference of the malformed value
+    MalformedValue(&'static str),
+    /// Rejected by `parse_telegram`
+    MalformedTelegram,
+    Incomplete,
+}
+
+impl fmt::Display for BulkError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BulkError::MalformedCrc => write!(f, "Malformed CRC"),
+            BulkError::CrcMismatch { expected, actual } => write!(
+                f,
+                "CRC mismatch: telegram says {:04X}, computed {:04X}",
+                expected, actual
+            ),
+            BulkError::MalformedTimestamp => write!(f, "Malformed timestamp"),
+            BulkError::MalformedValue(obis) => write!(f, "Malformed value of {}", obis),
+            BulkError::MalformedTelegram => write!(f, "Malformed telegram"),
+            BulkError::Incomplete => write!(f, "Incomplete telegram"),
+        }
+    }
+}
+
+/// Telegrams of a capture, cf `split_telegrams`
+pub struct Telegrams<'a> {
+    buffer: &'a [u8],
+    position: usize,
+}
+
+pub fn telegrams(buffer: &[u8]) -> Telegrams<'_> {
+    Telegrams {
+        buffer,
+        position: 0,
+    }
+}
+
+impl<'a> Iterator for Telegrams<'a> {
+    type Item = &'a [u8];
+
+    fn next(&mut self) -> Option<&'a [u8]> {
+        let buffer = self.buffer;
+        let from = self.position;
+        let mut header = None;
+        // Both characters only show up at the start of the header and end
+        // lines, or hardly ever elsewhere
+        for offset in memchr2_iter(b'/', b'!', buffer.get(from..)?) {
+            let at = from + offset;
+            if at > from && buffer[at - 1] != b'\n' {
+                continue;
+            }
+            if buffer[at] == b'/' {
+                header = Some(at);
+            } else if let Some(header) = header {
+                self.position = match memchr(b'\n', &buffer[at..]) {
+                    Some(end) => at + end + 1,
+                    None => buffer.len(),
+                };
+                return Some(&buffer[header..self.position]);
+            }
+        }
+        self.position = buffer.len();
+        None
+    }
+}
+
+fn check_crc(telegram: &[u8]) -> Result<(), BulkError> {
+    // Not found means not a telegram: `Telegrams` only returns the `!` line
+    let bang = match telegram.iter().rposition(|b| *b == b'!') {
+        Some(bang) => bang,
+        None => return Err(BulkError::Incomplete),
+    };
+    let expected = telegram[bang + 1..].trim_ascii();
+    if expected.is_empty() {
+        return Ok(());
+    }
+    if expected.len() > 4 {
+        return Err(BulkError::MalformedCrc);
+    }
+    let expected = expected.iter().try_fold(0u16, |crc, digit| {
+        (*digit as char)
+            .to_digit(16)
+            .map(|digit| crc << 4 | digit as u16)
+            .ok_or(BulkError::MalformedCrc)
+    })?;
+    let actual = crc16(&telegram[..=bang]);
+    if actual == expected {
+        Ok(())
+    } else {
+        Err(BulkError::CrcMismatch { expected, actual })
+    }
+}
+
+const POWERS_OF_10: [f64; 16] = [
+    1e0, 1e1, 1e2, 1e3, 1e4, 1e5, 1e6, 1e7, 1e8, 1e9, 1e10, 1e11, 1e12, 1e13, 1e14, 1e15,
+];
+
+/// Parse a decimal number like `001234.567`.  Digits and the divisor are
+/// exact in an f64, so the division rounds like `f64::from_str`, which
+/// handles whatever else shows up.
+fn parse_decimal(text: &[u8]) -> Option<f64> {
+    let from_str = || std::str::from_utf8(text).ok()?.parse().ok();
+    let mut mantissa: u64 = 0;
+    let mut digits = 0;
+    let mut decimals = None;
+    for byte in text {
+        match byte {
+            b'0'..=b'9' if digits < 15 => {
+                mantissa = mantissa * 10 + (byte - b'0') as u64;
+                digits += 1;
+                decimals = decimals.map(|d| d + 1);
+            }
+            b'.' if decimals.is_none() => decimals = Some(0),
+            _ => return from_str(),
+        }
+    }
+    if digits == 0 {
+        return None;
+    }
+    Some(mantissa as f64 / POWERS_OF_10[decimals.unwrap_or(0)])
+}
+
+/// Unix timestamp of `YYMMDDhhmmssX`, cf `p1_meter::parse_timestamp`
+fn parse_timestamp(text: &[u8]) -> Option<i64> {
+    let text = std::str::from_utf8(text).ok()?;
+    p1_meter::parse_timestamp(text)
+        .ok()
+        .flatten()
+        .map(|timestamp| timestamp.unix_timestamp())
+}
+
+/// Meter readings stored in `p1_measurements`, in the order of the columns
+const READINGS: [&str; 4] = ["1-0:1.8.1", "1-0:1.8.2", "1-0:2.8.1", "1-0:2.8.2"];
+
+/// Measurement of a DSMR 2.2 telegram, at the time of its gas reading (cf
+/// `P1Telegram::measurement_time`)
+fn parse_dsmr22_measurement(telegram: &[u8]) -> Result<P1Measurement, BulkError> {
+    let text = std::str::from_utf8(telegram).map_err(|_| BulkError::MalformedTelegram)?;
+    let parsed =
+        parse_telegram(text, Some(Dialect::Dsmr22)).map_err(|_| BulkError::MalformedTelegram)?;
+    parsed
+        .measurement_time()
+        .and_then(|timestamp| parsed.measurement(timestamp))
+        .ok_or(BulkError::Incomplete)
+}
+
+/// Timestamp and meter readings of a telegram, after checking its CRC (if
+/// any).  Only the first occurrence of each object counts.
+///
+/// `dialect` forces the dialect, else telegrams the fast path finds
+/// incomplete are parsed as DSMR 2.2 if `detect_dialect` says so.
+pub fn parse_measurement(
+    telegram: &[u8],
+    dialect: Option<Dialect>,
+) -> Result<P1Measurement, BulkError> {
+    check_crc(telegram)?;
+    if dialect == Some(Dialect::Dsmr22) {
+        return parse_dsmr22_measurement(telegram);
+    }
+    match parse_dsmr4_or_5_measurement(telegram) {
+        Err(BulkError::Incomplete)
+            if dialect.is_none()
+                && std::str::from_utf8(telegram)
+                    .ok()
+                    .and_then(|text| detect_dialect(text).ok())
+                    == Some(Dialect::Dsmr22) =>
+        {
+            parse_dsmr22_measurement(telegram)
+        }
+        result => result,
+    }
+}
+
+fn parse_dsmr4_or_5_measurement(telegram: &[u8]) -> Result<P1Measurement, BulkError> {
+    let mut timestamp = None;
+    let mut readings = [None; 4];
+    let mut start = 0;
+    for end in memchr_iter(b'\n', telegram) {
+        let line = &telegram[start..end];
+        start = end + 1;
+        let line = line.strip_suffix(b"\r").unwrap_or(line);
+        let (prefix, value) = match line.split_at_checked(10) {
+            Some(split) => split,
+            None => continue,
+        };
+        let index = match prefix {
+            b"0-0:1.0.0(" if timestamp.is_none() => {
+                timestamp = Some(
+                    value
+                        .strip_suffix(b")")
+                        .and_then(parse_timestamp)
+                        .ok_or(BulkError::MalformedTimestamp)?,
+                );
+                continue;
+            }
+            b"1-0:1.8.1(" => 0,
+            b"1-0:1.8.2(" => 1,
+            b"1-0:2.8.1(" => 2,
+            b"1-0:2.8.2(" => 3,
+            _ => continue,
+        };
+        if readings[index].is_none() {
+            readings[index] = Some(
+                value
+                    .strip_suffix(b"*kWh)")
+                    .and_then(parse_decimal)
+                    .ok_or(BulkError::MalformedValue(READINGS[index]))?,
+            );
+        }
+        if timestamp.is_some() && readings.iter().all(Option::is_some) {
+            break;
+        }
+    }
+    match (timestamp, readings) {
+        (Some(timestamp), [Some(peak_conso), Some(off_conso), Some(peak_inj), Some(off_inj)]) => {
+            Ok(P1Measurement {
+                timestamp,
+                peak_conso_kWh: peak_conso,
+                off_conso_kWh: off_conso,
+                peak_inj_kWh: peak_inj,
+                off_inj_kWh: off_inj,
+            })
+        }
+        _ => Err(BulkError::Incomplete),
+    }
+}
+
+#[derive(Debug, Default, PartialEq)]
+pub struct CaptureSummary {
+    pub measurements: Vec<P1Measurement>,
+    pub rejected: usize,
+}
+
+/// Measurements of all the telegrams in `buffer`, the others are logged and
+/// counted as rejected.
+pub fn parse_capture(buffer: &[u8], dialect: Option<Dialect>) -> CaptureSummary {
+    let mut summary = CaptureSummary::default();
+    for telegram in telegrams(buffer) {
+        match parse_measurement(telegram, dialect) {
+            Ok(measurement) => summary.measurements.push(measurement),
+            Err(e) => {
+                log::warn!("Rejecting telegram: {}", e);
+                summary.rejected += 1;
+            }
+        }
+    }
+    summary
+}
+
+/// Content of a capture file, decompressed if its name ends in `.gz`
+pub fn read_capture(path: &Path) -> Result<Vec<u8>, String> {
+    let mut file = File::open(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+    let mut buffer = Vec::new();
+    if path.extension().is_some_and(|extension| extension == "gz") {
+        MultiGzDecoder::new(file).read_to_end(&mut buffer)
+    } else {
+        file.read_to_end(&mut buffer)
+    }
+    .map_err(|e| format!("{}: {}", path.display(), e))?;
+    Ok(buffer)
+}
+
+/// Parse capture files on all cores.  `consume` gets the result of each file
+/// (in no particular order) on the calling thread, as they come: parsing
+/// waits while it is busy, which bounds the memory used.
+pub fn parse_captures<F>(paths: &[PathBuf], dialect: Option<Dialect>, mut consume: F)
+where
+    F: FnMut(&Path, Result<CaptureSummary, String>),
+{
+    let (sender, receiver) = sync_channel(rayon::current_num_threads());
+    std::thread::scope(|scope| {
+        scope.spawn(move || {
+            paths.par_iter().for_each_with(sender, |sender, path| {
+                let summary = read_capture(path).map(|buffer| parse_capture(&buffer, dialect));
+                // Only fails when the receiver is gone, i.e. after a panic
+                let _ = sender.send((path.as_path(), summary));
+            })
+        });
+        for (path, summary) in receiver {
+            consume(path, summary);
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::p1_meter::{split_telegrams, validate_crc};
+    use crate::p1_simulator::{MeterSimulator, SimulatorSettings};
+    use crate::p1_telegram::encode_telegram;
+    use time::OffsetDateTime;
+
+    fn simulated_capture(count: i64) -> String {
+        let mut simulator = MeterSimulator::new(SimulatorSettings::default());
+        // Across the end of summer time
+        let start = OffsetDateTime::from_unix_timestamp(1729990800 - count / 2).unwrap();
+        (0..count)
+            .map(|i| {
+                encode_telegram(
+                    &simulator.step(start + time::Duration::seconds(i)),
+                    "SIM5\\2SIMULATOR-50",
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn same_as_line_parser() {
+        let capture = simulated_capture(600);
+        let slow: Vec<P1Measurement> = split_telegrams(&capture)
+            .iter()
+            .map(|telegram| {
+                validate_crc(telegram).unwrap();
+                let parsed = parse_telegram(telegram, None).unwrap();
+                parsed
+                    .measurement(parsed.measurement_time().unwrap())
+                    .unwrap()
+            })
+            .collect();
+        let fast = parse_capture(capture.as_bytes(), None);
+        assert_eq!(fast.rejected, 0);
+        assert_eq!(fast.measurements, slow);
+        assert_eq!(slow.len(), 600);
+    }
+
+    #[test]
+    fn dsmr22_capture() {
+        let capture = read_capture(Path::new("tests/test-data/p1_dsmr22.txt")).unwrap();
+        let summary = parse_capture(&capture, None);
+        assert_eq!(summary.rejected, 1);
+        let measured: Vec<(i64, f64)> = summary
+            .measurements
+            .iter()
+            .map(|m| (m.timestamp, m.peak_conso_kWh))
+            .collect();
+        // Keyed by the hourly gas reading, the database keeps the first
+        assert_eq!(
+            measured,
+            vec![
+                (1351602000, 185.0),
+                (1351602000, 185.002),
+                (1351605600, 185.981)
+            ]
+        );
+        assert_eq!(
+            parse_capture(&capture, Some(Dialect::Dsmr22)).measurements,
+            summary.measurements
+        );
+        assert_eq!(parse_capture(&capture, Some(Dialect::Dsmr4)).rejected, 4);
+    }
+
+    #[test]
+    fn telegrams_of_capture() {
+        let capture = b"junk\r\n!\r\n/A\r\n/B\r\nx\r\n!1234\r\n/C\r\n!";
+        let found: Vec<&[u8]> = telegrams(capture).collect();
+        assert_eq!(found, vec![&b"/B\r\nx\r\n!1234\r\n"[..], &b"/C\r\n!"[..]]);
+    }
+
+    #[test]
+    fn rejects_bad_telegrams() {
+        let body = "/XMX5\r\n\r\n0-0:1.0.0(241025000000S)\r\n1-0:1.8.1(000001.000*kWh)\r\n!";
+        let telegram = format!("{}{:04X}\r\n", body, crc16(body.as_bytes()));
+        assert_eq!(
+            parse_measurement(telegram.as_bytes(), None),
+            Err(BulkError::Incomplete)
+        );
+        assert_eq!(
+            parse_measurement(format!("{}0000\r\n", body).as_bytes(), None),
+            Err(BulkError::CrcMismatch {
+                expected: 0,
+                actual: crc16(body.as_bytes())
+            })
+        );
+        assert_eq!(
+            parse_measurement(format!("{}XYZ\r\n", body).as_bytes(), None),
+            Err(BulkError::MalformedCrc)
+        );
+        assert_eq!(
+            parse_measurement(b"/XMX5\r\n0-0:1.0.0(241325000000S)\r\n!\r\n", None),
+            Err(BulkError::MalformedTimestamp)
+        );
+        assert_eq!(
+            parse_measurement(b"/XMX5\r\n1-0:2.8.1(1.0*kW)\r\n!\r\n", None),
+            Err(BulkError::MalformedValue("1-0:2.8.1"))
+        );
+    }
+
+    #[test]
+    fn decimals_like_from_str() {
+        for text in [
+            "002654.919",
+            "0.1",
+            "12",
+            "000000.000",
+            "123456789.123456789",
+            "1e3",
+        ] {
+            assert_eq!(
+                parse_decimal(text.as_bytes()),
+                Some(text.parse::<f64>().unwrap()),
+                "{}",
+                text
+            );
+        }
+        assert_eq!(parse_decimal(b""), None);
+        assert_eq!(parse_decimal(b"1.2.3"), None);
+    }
+
+    #[test]
+    fn timestamps_with_summer_flag() {
+        assert_eq!(parse_timestamp(b"241027020000S"), Some(1729987200));
+        assert_eq!(parse_timestamp(b"241027020000W"), Some(1729990800));
+        assert_eq!(parse_timestamp(b"241027020000"), None);
+        assert_eq!(parse_timestamp(b"240230020000W"), None);
+    }
+
+    #[test]
+    fn captures_in_parallel() {
+        let dir = std::env::temp_dir().join(format!("p1_bulk_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let plain = dir.join("capture.txt");
+        std::fs::write(&plain, simulated_capture(10)).unwrap();
+        let compressed = dir.join("capture.txt.gz");
+        let mut encoder =
+            flate2::write::GzEncoder::new(File::create(&compressed).unwrap(), Default::default());
+        std::io::Write::write_all(&mut encoder, simulated_capture(20).as_bytes()).unwrap();
+        encoder.finish().unwrap();
+        let paths = vec![plain, compressed, dir.join("missing.txt")];
+        let mut results = Vec::new();
+        parse_captures(&paths, None, |path, summary| {
+            results.push((
+                path.file_name().unwrap().to_string_lossy().to_string(),
+                summary.map(|summary| summary.measurements.len()),
+            ))
+        });
+        std::fs::remove_dir_all(&dir).unwrap();
+        results.sort();
+        assert_eq!(results[0], ("capture.txt".to_string(), Ok(10)));
+        assert_eq!(results[1], ("capture.txt.gz".to_string(), Ok(20)));
+        assert!(results[2].1.is_err());
+    }
+}
@@ -0,0 +1,335 @@
+use std::sync::Arc;
+
+use actix_web::{get, web, HttpResponse};
+use serde::{Deserialize, Serialize};
+use tera::Tera;
+use time::OffsetDateTime;
+use tokio::sync::broadcast::error::RecvError;
+
+use crate::data::{insert_clock_offset, select_clock_offsets, ClockOffsetRow, Database};
+use crate::p1_reader::{P1Hub, ReceivedTelegram};
+use crate::p1_telegram::{parse_telegram, Dialect};
+use crate::{format_timestamp, get_env_var};
+
+/// Length of the periods the offsets are stored for [s]
+const PERIOD_S: i64 = 600;
+
+/// Length of the periods shown on the report [s]
+const REPORT_PERIOD_S: i64 = 3600;
+
+const DEFAULT_WARN_S: f64 = 10.0;
+
+const DEFAULT_REPORT_DAYS: u32 = 7;
+
+/// Shortest time span a drift rate is computed over [s]
+const MIN_DRIFT_SPAN_S: i64 = 6 * 3600;
+
+/// Offset from which the meter clock is reported as wrong, cf
+/// `RUST_HELLO_WORLD_P1_CLOCK_WARN_S`.
+fn configured_warn_s() -> f64 {
+    get_env_var("RUST_HELLO_WORLD_P1_CLOCK_WARN_S")
+        .ok()
+        .and_then(|s| s.trim().parse().ok())
+        .filter(|s: &f64| *s > 0.0)
+        .unwrap_or(DEFAULT_WARN_S)
+}
+
+/// Meter time minus reception time [s].  The meter only sends whole seconds
+/// and the telegram takes a while to arrive, so a correct clock still shows
+/// up to about a second behind.
+pub fn clock_offset(telegram: &ReceivedTelegram, dialect: Option<Dialect>) -> Option<f64> {
+    let meter_time = parse_telegram(&telegram.raw, dialect).ok()?.timestamp?;
+    Some((meter_time - telegram.received_at).as_seconds_f64())
+}
+
+/// Combines the offsets of the telegrams received during a period.
+#[derive(Default)]
+pub struct OffsetAggregator {
+    current: Option<ClockOffsetRow>,
+}
+
+impl OffsetAggregator {
+    /// Add the offset of a telegram received at `timestamp`.  Returns the
+    /// previous period once a telegram of a later one shows up.
+    pub fn add(&mut self, timestamp: i64, offset_s: f64) -> Option<ClockOffsetRow> {
+        let period = timestamp - timestamp.rem_euclid(PERIOD_S);
+        if let Some(current) = self.current.as_mut() {
+            if current.timestamp == period {
+                let samples = current.samples as f64;
+                current.avg_offset_s =
+                    (current.avg_offset_s * samples + offset_s) / (samples + 1.0);
+                current.min_offset_s = current.min_offset_s.min(offset_s);
+                current.max_offset_s = current.max_offset_s.max(offset_s);
+                current.samples += 1;
+                return None;
+            }
+        }
+        self.current.replace(ClockOffsetRow {
+            timestamp: period,
+            samples: 1,
+            min_offset_s: offset_s,
+            avg_offset_s: offset_s,
+            max_offset_s: offset_s,
+        })
+    }
+
+    /// Period being aggregated, which is then reset.
+    pub fn flush(&mut self) -> Option<ClockOffsetRow> {
+        self.current.take()
+    }
+}
+
+/// Warns once when the meter clock goes beyond the threshold, and once when
+/// it comes back.
+struct ClockMonitor {
+    threshold_s: f64,
+    off: bool,
+}
+
+impl ClockMonitor {
+    /// Returns the new state when it changed.
+    fn check(&mut self, row: &ClockOffsetRow) -> Option<bool> {
+        let off = row.avg_offset_s.abs() > self.threshold_s;
+        if off == self.off {
+            return None;
+        }
+        self.off = off;
+        if off {
+            log::warn!(
+                "P1 meter clock is off by {:.1}s (more than {}s)",
+                row.avg_offset_s,
+                self.threshold_s
+            );
+        } else {
+            log::info!(
+                "P1 meter clock is back within {}s ({:.1}s)",
+                self.threshold_s,
+                row.avg_offset_s
+            );
+        }
+        Some(off)
+    }
+}
+
+/// Least squares slope of the average offsets [s/day], None when the rows
+/// span too short a time.
+fn drift_s_per_day(rows: &[ClockOffsetRow]) -> Option<f64> {
+    let first = rows.first()?.timestamp;
+    if rows.last()?.timestamp - first < MIN_DRIFT_SPAN_S {
+        return None;
+    }
+    let count = rows.len() as f64;
+    let days: Vec<f64> = rows
+        .iter()
+        .map(|row| (row.timestamp - first) as f64 / 86400.0)
+        .collect();
+    let mean_day = days.iter().sum::<f64>() / count;
+    let mean_offset = rows.iter().map(|row| row.avg_offset_s).sum::<f64>() / count;
+    let (covariance, variance) =
+        days.iter()
+            .zip(rows.iter())
+            .fold((0.0, 0.0), |(covariance, variance), (day, row)| {
+                (
+                    covariance + (day - mean_day) * (row.avg_offset_s - mean_offset),
+                    variance + (day - mean_day) * (day - mean_day),
+                )
+            });
+    Some(covariance / variance)
+}
+
+async fn store(database: &Database, row: ClockOffsetRow) {
+    let sqlite3 = database.sqlite3.clone();
+    match web::block(move || insert_clock_offset(&sqlite3, &row)).await {
+        Ok(Ok(_)) => {}
+        Ok(Err(e)) => log::error!("Unable to store clock offset: {}", e),
+        Err(e) => log::error!("Unable to store clock offset: {}", e),
+    }
+}
+
+/// Store the offset of the meter clock, warning when it is too far off.
+pub async fn run_clock_recorder(hub: Arc<P1Hub>, dialect: Option<Dialect>, database: Database) {
+    let mut receiver = hub.subscribe();
+    let mut aggregator = OffsetAggregator::default();
+    let mut monitor = ClockMonitor {
+        threshold_s: configured_warn_s(),
+        off: false,
+    };
+    loop {
+        let telegram = match receiver.recv().await {
+            Ok(telegram) if telegram.crc_ok => telegram,
+            Ok(_) | Err(RecvError::Lagged(_)) => continue,
+            Err(RecvError::Closed) => break,
+        };
+        // DSMR 2.2 telegrams have no timestamp
+        let offset_s = match clock_offset(&telegram, dialect) {
+            Some(offset_s) => offset_s,
+            None => continue,
+        };
+        if let Some(row) = aggregator.add(telegram.received_at.unix_timestamp(), offset_s) {
+            monitor.check(&row);
+            store(&database, row).await;
+        }
+    }
+    if let Some(row) = aggregator.flush() {
+        store(&database, row).await;
+    }
+}
+
+#[derive(Deserialize)]
+pub struct ClockReportQuery {
+    days: Option<u32>,
+}
+
+#[derive(Serialize)]
+struct OffsetView {
+    hour: String,
+    samples: i64,
+    min: String,
+    avg: String,
+    max: String,
+    off: bool,
+}
+
+#[get("/p1/clock")]
+pub async fn clock_report_page(
+    tera: web::Data<Tera>,
+    database: web::Data<Database>,
+    hub: web::Data<P1Hub>,
+    dialect: Option<web::Data<Dialect>>,
+    query: web::Query<ClockReportQuery>,
+) -> HttpResponse {
+    let days = query.days.unwrap_or(DEFAULT_REPORT_DAYS).max(1);
+    let since = OffsetDateTime::now_utc().unix_timestamp() - days as i64 * 86400;
+    let threshold_s = configured_warn_s();
+    let sqlite3 = database.sqlite3.clone();
+    let rows = web::block(move || select_clock_offsets(&sqlite3, since, REPORT_PERIOD_S))
+        .await
+        .map_err(|e| e.to_string())
+        .and_then(|rows| rows);
+    let mut context = tera::Context::new();
+    match rows {
+        Ok(rows) => {
+            if let Some(drift) = drift_s_per_day(&rows) {
+                context.insert("drift", &format!("{:+.2}", drift));
+            }
+            let offsets: Vec<OffsetView> = rows
+                .iter()
+                .rev()
+                .map(|row| OffsetView {
+                    hour: format_timestamp(row.timestamp),
+                    samples: row.samples,
+                    min: format!("{:+.1}", row.min_offset_s),
+                    avg: format!("{:+.1}", row.avg_offset_s),
+                    max: format!("{:+.1}", row.max_offset_s),
+                    off: row.avg_offset_s.abs() > threshold_s,
+                })
+                .collect();
+            context.insert("offsets", &offsets);
+        }
+        Err(e) => {
+            log::error!("Unable to read clock offsets: {}", e);
+            context.insert("error", &e);
+        }
+    }
+    let dialect = dialect.map(|dialect| **dialect);
+    if let Some(offset) = hub
+        .latest()
+        .and_then(|telegram| clock_offset(&telegram, dialect))
+    {
+        context.insert("current", &format!("{:+.1}", offset));
+        context.insert("current_off", &(offset.abs() > threshold_s));
+    }
+    context.insert("days", &days);
+    context.insert("threshold", &threshold_s);
+    context.insert("restamp", &hub.restamps());
+    context.insert(
+        "now",
+        &format_timestamp(OffsetDateTime::now_utc().unix_timestamp()),
+    );
+    let rendered = tera.render("p1_clock.html", &context).unwrap();
+    HttpResponse::Ok().body(rendered)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn row(timestamp: i64, avg_offset_s: f64) -> ClockOffsetRow {
+        ClockOffsetRow {
+            timestamp,
+            samples: 600,
+            min_offset_s: avg_offset_s,
+            avg_offset_s,
+            max_offset_s: avg_offset_s,
+        }
+    }
+
+    #[test]
+    fn offset_of_telegram() {
+        let telegram = ReceivedTelegram {
+            raw: "/ISK5\\2M550E-1012\r\n\r\n0-0:1.0.0(241025020000S)\r\n!\r\n".to_string(),
+            // 2024-10-25T00:00:03.5Z
+            received_at: OffsetDateTime::from_unix_timestamp(1729814403).unwrap()
+                + time::Duration::milliseconds(500),
+            crc_ok: true,
+        };
+        assert_eq!(clock_offset(&telegram, None), Some(-3.5));
+        // DSMR 2.2 has no meter time
+        assert_eq!(clock_offset(&telegram, Some(Dialect::Dsmr22)), None);
+        let telegram = ReceivedTelegram {
+            raw: "/ISK5\\2M550E-1012\r\n\r\n1-0:1.8.1(000001.000*kWh)\r\n!\r\n".to_string(),
+            ..telegram
+        };
+        assert_eq!(clock_offset(&telegram, None), None);
+    }
+
+    #[test]
+    fn aggregator_emits_finished_periods() {
+        let mut aggregator = OffsetAggregator::default();
+        assert_eq!(aggregator.add(1729814400, -1.0), None);
+        assert_eq!(aggregator.add(1729814401, 0.5), None);
+        assert_eq!(aggregator.add(1729814999, -0.5), None);
+        assert_eq!(
+            aggregator.add(1729815000, 2.0),
+            Some(ClockOffsetRow {
+                timestamp: 1729814400,
+                samples: 3,
+                min_offset_s: -1.0,
+                avg_offset_s: -1.0 / 3.0,
+                max_offset_s: 0.5,
+            })
+        );
+        assert_eq!(
+            aggregator.flush(),
+            Some(ClockOffsetRow {
+                samples: 1,
+                ..row(1729815000, 2.0)
+            })
+        );
+        assert_eq!(aggregator.flush(), None);
+    }
+
+    #[test]
+    fn monitor_reports_changes_only() {
+        let mut monitor = ClockMonitor {
+            threshold_s: 10.0,
+            off: false,
+        };
+        assert_eq!(monitor.check(&row(0, -9.5)), None);
+        assert_eq!(monitor.check(&row(600, -10.5)), Some(true));
+        assert_eq!(monitor.check(&row(1200, -12.0)), None);
+        assert_eq!(monitor.check(&row(1800, 1.0)), Some(false));
+    }
+
+    #[test]
+    fn drift_rate() {
+        assert_eq!(drift_s_per_day(&[]), None);
+        assert_eq!(drift_s_per_day(&[row(0, 1.0), row(3600, 2.0)]), None);
+        // 2s/day with some noise around it
+        let rows: Vec<ClockOffsetRow> = (0..48)
+            .map(|hour| row(hour * 3600, hour as f64 / 12.0 + 0.1 * (hour % 2) as f64))
+            .collect();
+        let drift = drift_s_per_day(&rows).unwrap();
+        assert!((drift - 2.0).abs() < 0.01, "{}", drift);
+    }
+}
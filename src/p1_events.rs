@@ -0,0 +1,218 @@
+use std::sync::Arc;
+
+use actix_web::{get, web, HttpResponse};
+use serde::Serialize;
+use tera::Tera;
+use time::OffsetDateTime;
+use tokio::sync::broadcast::error::RecvError;
+
+use crate::data::{
+    insert_power_quality, select_power_failures, select_power_quality_events, Database,
+    PowerFailureRow,
+};
+use crate::format_timestamp;
+use crate::p1_meter::PowerQuality;
+use crate::p1_reader::P1Hub;
+use crate::p1_telegram::{parse_telegram, Dialect};
+
+const PHASES: [&str; 3] = ["L1", "L2", "L3"];
+
+/// Counter values under the names used in `p1_power_quality_counters`.
+pub fn counter_values(quality: &PowerQuality) -> Vec<(String, u64)> {
+    let mut result = Vec::new();
+    if let Some(count) = quality.power_failures {
+        result.push(("power_failures".to_string(), count));
+    }
+    if let Some(count) = quality.long_power_failures {
+        result.push(("long_power_failures".to_string(), count));
+    }
+    for (phase, count) in PHASES.iter().zip(quality.voltage_sags.iter()) {
+        if let Some(count) = count {
+            result.push((format!("voltage_sag_{}", phase), *count));
+        }
+    }
+    for (phase, count) in PHASES.iter().zip(quality.voltage_swells.iter()) {
+        if let Some(count) = count {
+            result.push((format!("voltage_swell_{}", phase), *count));
+        }
+    }
+    result
+}
+
+fn describe_counter(counter: &str) -> String {
+    match counter {
+        "power_failures" => "Power failure".to_string(),
+        "long_power_failures" => "Long power failure".to_string(),
+        _ => match counter.rsplit_once('_') {
+            Some(("voltage_sag", phase)) => format!("Voltage sag {}", phase),
+            Some(("voltage_swell", phase)) => format!("Voltage swell {}", phase),
+            _ => counter.to_string(),
+        },
+    }
+}
+
+pub fn failure_rows(quality: &PowerQuality) -> Vec<PowerFailureRow> {
+    quality
+        .long_failure_log
+        .iter()
+        .map(|failure| PowerFailureRow {
+            end_timestamp: failure.end.unix_timestamp(),
+            duration_s: failure.duration_s as i64,
+        })
+        .collect()
+}
+
+/// Store power failures and counter changes as they show up in the telegrams.
+pub async fn run_power_quality_recorder(
+    hub: Arc<P1Hub>,
+    dialect: Option<Dialect>,
+    database: Database,
+) {
+    let mut receiver = hub.subscribe();
+    let mut stored: Option<PowerQuality> = None;
+    loop {
+        let telegram = match receiver.recv().await {
+            Ok(telegram) if telegram.crc_ok => telegram,
+            Ok(_) | Err(RecvError::Lagged(_)) => continue,
+            Err(RecvError::Closed) => return,
+        };
+        let parsed = match parse_telegram(&telegram.raw, dialect) {
+            Ok(parsed) => parsed,
+            Err(e) => {
+                log::warn!("Unable to parse power quality: {}", e);
+                continue;
+            }
+        };
+        let quality = parsed.quality;
+        if stored.as_ref() == Some(&quality) {
+            continue;
+        }
+        let timestamp = hub.data_time(&telegram, parsed.timestamp).unix_timestamp();
+        let sqlite3 = database.sqlite3.clone();
+        let failures = failure_rows(&quality);
+        let counters = counter_values(&quality);
+        match web::block(move || insert_power_quality(&sqlite3, &failures, timestamp, &counters))
+            .await
+        {
+            Ok(Ok(0)) => stored = Some(quality),
+            Ok(Ok(count)) => {
+                log::info!("Stored {} new power quality records", count);
+                stored = Some(quality)
+            }
+            Ok(Err(e)) => log::error!("Unable to store power quality: {}", e),
+            Err(e) => log::error!("Unable to store power quality: {}", e),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct OutageView {
+    start: String,
+    end: String,
+    duration: String,
+}
+
+#[derive(Serialize)]
+struct EventView {
+    timestamp: String,
+    description: String,
+    increment: i64,
+    count: i64,
+}
+
+fn format_duration(seconds: i64) -> String {
+    if seconds < 60 {
+        format!("{}s", seconds)
+    } else if seconds < 3600 {
+        format!("{}min {}s", seconds / 60, seconds % 60)
+    } else {
+        format!("{}h {}min", seconds / 3600, (seconds % 3600) / 60)
+    }
+}
+
+#[get("/p1/events")]
+pub async fn power_quality_page(
+    tera: web::Data<Tera>,
+    database: web::Data<Database>,
+) -> HttpResponse {
+    let sqlite3 = database.sqlite3.clone();
+    let rows = web::block(move || {
+        Ok::<_, String>((
+            select_power_failures(&sqlite3)?,
+            select_power_quality_events(&sqlite3)?,
+        ))
+    })
+    .await
+    .map_err(|e| e.to_string())
+    .and_then(|rows| rows);
+    let mut context = tera::Context::new();
+    match rows {
+        Ok((failures, events)) => {
+            let outages: Vec<OutageView> = failures
+                .iter()
+                .map(|f| OutageView {
+                    start: format_timestamp(f.end_timestamp - f.duration_s),
+                    end: format_timestamp(f.end_timestamp),
+                    duration: format_duration(f.duration_s),
+                })
+                .collect();
+            let events: Vec<EventView> = events
+                .iter()
+                .map(|e| EventView {
+                    timestamp: format_timestamp(e.timestamp),
+                    description: describe_counter(&e.counter),
+                    increment: e.increment,
+                    count: e.count,
+                })
+                .collect();
+            context.insert("outages", &outages);
+            context.insert("events", &events);
+        }
+        Err(e) => {
+            log::error!("Unable to read power quality events: {}", e);
+            context.insert("error", &e);
+        }
+    }
+    context.insert(
+        "now",
+        &format_timestamp(OffsetDateTime::now_utc().unix_timestamp()),
+    );
+    let rendered = tera.render("p1_events.html", &context).unwrap();
+    HttpResponse::Ok().body(rendered)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counter_values_names() {
+        let quality = PowerQuality {
+            power_failures: Some(4),
+            long_power_failures: None,
+            long_failure_log: Vec::new(),
+            voltage_sags: [Some(2), None, None],
+            voltage_swells: [None, None, Some(7)],
+        };
+        assert_eq!(
+            counter_values(&quality),
+            vec![
+                ("power_failures".to_string(), 4),
+                ("voltage_sag_L1".to_string(), 2),
+                ("voltage_swell_L3".to_string(), 7)
+            ]
+        );
+        assert_eq!(describe_counter("voltage_swell_L3"), "Voltage swell L3");
+        assert_eq!(
+            describe_counter("long_power_failures"),
+            "Long power failure"
+        );
+    }
+
+    #[test]
+    fn format_duration_units() {
+        assert_eq!(format_duration(42), "42s");
+        assert_eq!(format_duration(301), "5min 1s");
+        assert_eq!(format_duration(7260), "2h 1min");
+    }
+}
@@ -0,0 +1,272 @@
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+
+use actix_web::{post, web, HttpRequest, HttpResponse};
+use serde::Serialize;
+use time::OffsetDateTime;
+
+use crate::data::{insert_new_p1_measurements, Database, P1Measurement};
+use crate::p1_meter::{split_telegrams, validate_crc};
+use crate::p1_reader::P1Hub;
+use crate::p1_telegram::{parse_telegram, Dialect};
+
+/// Devices allowed to push telegrams, as `(name, token)`, shared as app
+/// data.  None are allowed by default.
+#[derive(Debug, Default, PartialEq)]
+pub struct P1Devices {
+    devices: Vec<(String, String)>,
+}
+
+impl P1Devices {
+    pub fn new(devices: Vec<(String, String)>) -> Self {
+        P1Devices { devices }
+    }
+
+    /// Devices of the file named by `RUST_HELLO_WORLD_P1_DEVICES`, read once
+    /// at startup
+    pub fn read(path: &str) -> Result<Self, String> {
+        read_devices(path).map(P1Devices::new)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.devices.is_empty()
+    }
+}
+
+/// One `name token` pair per line, `#` starts a comment.
+fn read_devices(path: &str) -> Result<Vec<(String, String)>, String> {
+    let file = File::open(path).map_err(|e| format!("{}: {}", path, e))?;
+    let mut devices = Vec::new();
+    for line in BufReader::new(file).lines() {
+        let line = line.map_err(|e| format!("{}: {}", path, e))?;
+        let line = line.split('#').next().unwrap_or("");
+        let mut words = line.split_whitespace();
+        if let (Some(name), Some(token)) = (words.next(), words.next()) {
+            devices.push((name.to_string(), token.to_string()));
+        }
+    }
+    Ok(devices)
+}
+
+/// Compare without stopping at the first difference, so that the response
+/// time does not tell how much of a token was guessed right.
+fn same_token(a: &str, b: &str) -> bool {
+    a.len() == b.len()
+        && a.bytes()
+            .zip(b.bytes())
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+/// Name of the device whose token is in the `Authorization: Bearer` header
+fn authenticate<'a>(devices: &'a [(String, String)], request: &HttpRequest) -> Option<&'a str> {
+    let token = request
+        .headers()
+        .get("authorization")?
+        .to_str()
+        .ok()?
+        .strip_prefix("Bearer ")?
+        .trim();
+    devices
+        .iter()
+        .find(|(_, expected)| same_token(expected, token))
+        .map(|(name, _)| name.as_str())
+}
+
+/// Outcome for each telegram of a request, in order.  Duplicates and errors
+/// need not be sent again, unlike a whole request failing with 5xx.
+#[derive(Serialize, Debug, PartialEq)]
+#[serde(tag = "status", rename_all = "lowercase")]
+enum TelegramResult {
+    Accepted { timestamp: i64 },
+    Duplicate { timestamp: i64 },
+    Error { error: String },
+}
+
+#[derive(Serialize)]
+struct IngestResponse<'a> {
+    device: &'a str,
+    results: Vec<TelegramResult>,
+}
+
+fn error_response(mut response: actix_web::HttpResponseBuilder, error: &str) -> HttpResponse {
+    response.json(serde_json::json!({ "error": error }))
+}
+
+/// Check and parse a pushed telegram into the row to store.  Only DSMR 2.2
+/// telegrams may come without CRC.  They have no meter time either and are
+/// stored at `received`, which the first of them takes: a second one in the
+/// same request would get the same timestamp, so it is rejected instead.
+fn measurement(
+    telegram: &str,
+    dialect: Option<Dialect>,
+    received: &mut Option<i64>,
+) -> Result<P1Measurement, String> {
+    validate_crc(telegram)?;
+    let parsed = parse_telegram(telegram, dialect)?;
+    let has_crc = telegram
+        .rfind('!')
+        .is_some_and(|bang| !telegram[bang + 1..].trim().is_empty());
+    if !has_crc && parsed.dialect != Dialect::Dsmr22 {
+        return Err("Missing CRC".to_string());
+    }
+    let timestamp = match (parsed.timestamp, parsed.dialect) {
+        (Some(timestamp), _) => timestamp.unix_timestamp(),
+        (None, Dialect::Dsmr22) => received
+            .take()
+            .ok_or("Only one DSMR 2.2 telegram per request")?,
+        (None, _) => return Err("Incomplete telegram".to_string()),
+    };
+    parsed
+        .measurement(timestamp)
+        .ok_or_else(|| "Incomplete telegram".to_string())
+}
+
+/// Telegrams pushed by network dongles, as the raw text of one or more
+/// telegrams.  New ones are stored and published like those read from the P1
+/// port, in the dialect of `RUST_HELLO_WORLD_P1_DIALECT` (app data) if set.
+#[post("/api/p1/telegram")]
+pub async fn ingest_telegrams(
+    request: HttpRequest,
+    body: String,
+    database: web::Data<Database>,
+    hub: web::Data<P1Hub>,
+    devices: web::Data<P1Devices>,
+    dialect: Option<web::Data<Dialect>>,
+) -> HttpResponse {
+    let device = match authenticate(&devices.devices, &request) {
+        Some(device) => device,
+        None => return error_response(HttpResponse::Unauthorized(), "Unknown device token"),
+    };
+    let telegrams = split_telegrams(&body);
+    if telegrams.is_empty() {
+        return error_response(HttpResponse::BadRequest(), "No telegram found");
+    }
+    let dialect = dialect.map(|dialect| **dialect);
+    let received = OffsetDateTime::now_utc();
+    let mut reception = Some(received.unix_timestamp());
+    let mut rows = Vec::with_capacity(telegrams.len());
+    let checked: Vec<Result<i64, String>> = telegrams
+        .iter()
+        .map(|telegram| {
+            measurement(telegram, dialect, &mut reception).map(|m| {
+                let timestamp = m.timestamp;
+                rows.push(m);
+                timestamp
+            })
+        })
+        .collect();
+    let sqlite3 = database.sqlite3.clone();
+    let stored = match web::block(move || insert_new_p1_measurements(&sqlite3, &rows))
+        .await
+        .map_err(|e| e.to_string())
+        .and_then(|stored| stored)
+    {
+        Ok(stored) => stored,
+        Err(e) => {
+            log::error!("Unable to store pushed telegrams: {}", e);
+            return error_response(HttpResponse::InternalServerError(), &e);
+        }
+    };
+    let mut stored = stored.into_iter();
+    let mut results = Vec::with_capacity(telegrams.len());
+    for (telegram, checked) in telegrams.iter().zip(checked) {
+        results.push(match checked {
+            Ok(timestamp) if stored.next() == Some(true) => {
+                hub.publish(telegram.to_string(), received);
+                TelegramResult::Accepted { timestamp }
+            }
+            Ok(timestamp) => TelegramResult::Duplicate { timestamp },
+            Err(error) => {
+                log::warn!("Rejecting telegram from {}: {}", device, error);
+                TelegramResult::Error { error }
+            }
+        });
+    }
+    HttpResponse::Ok().json(IngestResponse { device, results })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::p1_meter::crc16;
+
+    #[test]
+    fn devices_file() {
+        let path = std::env::temp_dir().join(format!("p1_devices_{}", std::process::id()));
+        std::fs::write(
+            &path,
+            "# name token\nkitchen s3cret\n\ngarage   t0ken  # ESP32\nincomplete\n",
+        )
+        .unwrap();
+        let devices = read_devices(path.to_str().unwrap());
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(
+            devices,
+            Ok(vec![
+                ("kitchen".to_string(), "s3cret".to_string()),
+                ("garage".to_string(), "t0ken".to_string())
+            ])
+        );
+        assert!(read_devices(path.to_str().unwrap()).is_err());
+    }
+
+    #[test]
+    fn token_comparison() {
+        assert!(same_token("s3cret", "s3cret"));
+        assert!(!same_token("s3cret", "s3creT"));
+        assert!(!same_token("s3cret", "s3cre"));
+        assert!(!same_token("", "s3cret"));
+    }
+
+    #[test]
+    fn measurement_checks_crc_and_completeness() {
+        assert!(measurement(
+            "/ISK5\r\n\r\n0-0:1.0.0(241025020000S)\r\n!0000\r\n",
+            None,
+            &mut Some(0)
+        )
+        .unwrap_err()
+        .starts_with("CRC mismatch"));
+        assert_eq!(
+            measurement(
+                "/ISK5\r\n\r\n0-0:1.0.0(241025020000S)\r\n!\r\n",
+                None,
+                &mut Some(0)
+            ),
+            Err("Missing CRC".to_string())
+        );
+        let body = "/ISK5\r\n\r\n0-0:1.0.0(241025020000S)\r\n!";
+        let telegram = format!("{}{:04X}\r\n", body, crc16(body.as_bytes()));
+        assert_eq!(
+            measurement(&telegram, None, &mut Some(0)),
+            Err("Incomplete telegram".to_string())
+        );
+    }
+
+    #[test]
+    fn measurement_of_dsmr22_at_reception() {
+        let telegram = "/ISk5\\2ME382-1003\r\n\r\n1-0:1.8.1(00185.000*kWh)\r\n1-0:1.8.2(00084.000*kWh)\r\n1-0:2.8.1(00013.000*kWh)\r\n1-0:2.8.2(00019.000*kWh)\r\n!\r\n";
+        let mut received = Some(1729814400);
+        assert_eq!(
+            measurement(telegram, None, &mut received),
+            Ok(P1Measurement {
+                timestamp: 1729814400,
+                peak_conso_kWh: 185.0,
+                off_conso_kWh: 84.0,
+                peak_inj_kWh: 13.0,
+                off_inj_kWh: 19.0,
+            })
+        );
+        // The reception time is taken by the first one
+        assert_eq!(
+            measurement(telegram, None, &mut received),
+            Err("Only one DSMR 2.2 telegram per request".to_string())
+        );
+        // Without CRC in any other dialect
+        assert_eq!(
+            measurement(telegram, Some(Dialect::Dsmr5), &mut Some(1729814400)),
+            Err("Missing CRC".to_string())
+        );
+    }
+}
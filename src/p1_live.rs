@@ -0,0 +1,80 @@
+use std::time::Duration;
+
+use actix_web::{get, web, HttpResponse};
+use futures_util::stream;
+use tera::Tera;
+use tokio::sync::broadcast::error::RecvError;
+
+use crate::p1_reader::{P1Hub, ReceivedTelegram};
+use crate::p1_telegram::{parse_telegram, Dialect};
+
+const KEEPALIVE: Duration = Duration::from_secs(15);
+
+/// One Server-Sent Event with the live values of `telegram`, if it parses.
+fn live_event(telegram: &ReceivedTelegram, dialect: Option<Dialect>) -> Option<String> {
+    if !telegram.crc_ok {
+        return None;
+    }
+    match parse_telegram(&telegram.raw, dialect) {
+        Ok(parsed) => serde_json::to_string(&parsed.live_values())
+            .ok()
+            .map(|json| format!("data: {}\n\n", json)),
+        Err(e) => {
+            log::warn!("Unable to parse live values: {}", e);
+            None
+        }
+    }
+}
+
+/// Live values as Server-Sent Events, in the dialect of
+/// `RUST_HELLO_WORLD_P1_DIALECT` (app data) if set.
+#[get("/p1/live/events")]
+pub async fn live_events(
+    hub: web::Data<P1Hub>,
+    dialect: Option<web::Data<Dialect>>,
+) -> HttpResponse {
+    let dialect = dialect.map(|dialect| **dialect);
+    // Ask the browser to reconnect quickly and send the last known values
+    // right away instead of waiting for the next telegram.
+    let mut first = "retry: 3000\n\n".to_string();
+    if let Some(event) = hub
+        .latest()
+        .and_then(|telegram| live_event(&telegram, dialect))
+    {
+        first.push_str(&event);
+    }
+    let receiver = hub.subscribe();
+    let events = stream::unfold(
+        (Some(first), receiver),
+        move |(pending, mut receiver)| async move {
+            if let Some(pending) = pending {
+                return Some((Ok(web::Bytes::from(pending)), (None, receiver)));
+            }
+            loop {
+                let event = match tokio::time::timeout(KEEPALIVE, receiver.recv()).await {
+                    Err(_) => Some(": keepalive\n\n".to_string()),
+                    Ok(Ok(telegram)) => live_event(&telegram, dialect),
+                    Ok(Err(RecvError::Lagged(_))) => None,
+                    Ok(Err(RecvError::Closed)) => return None,
+                };
+                if let Some(event) = event {
+                    return Some((
+                        Ok::<_, actix_web::Error>(web::Bytes::from(event)),
+                        (None, receiver),
+                    ));
+                }
+            }
+        },
+    );
+    HttpResponse::Ok()
+        .content_type("text/event-stream")
+        .insert_header(("Cache-Control", "no-cache"))
+        .insert_header(("X-Accel-Buffering", "no"))
+        .streaming(events)
+}
+
+#[get("/p1/live")]
+pub async fn live_page(tera: web::Data<Tera>) -> HttpResponse {
+    let rendered = tera.render("p1_live.html", &tera::Context::new()).unwrap();
+    HttpResponse::Ok().body(rendered)
+}
@@ -1,8 +1,11 @@
+use chrono::{NaiveDate, Offset, TimeZone};
+use chrono_tz::Tz;
+use serde::Serialize;
 use std::borrow::Borrow;
 use std::error::Error;
 use std::num::ParseFloatError;
 use std::str::FromStr;
-use time::{Date, Month, OffsetDateTime, UtcOffset};
+use time::{Date, Month, OffsetDateTime, PrimitiveDateTime, UtcOffset};
 
 // 0-0:1.0.0(241025191816S)
 //
@@ -30,34 +33,82 @@ fn parse_kwh(line: &str, prefix: &str) -> Result<Option<f64>, ParseFloatError> {
 }
 
 fn parse_date_time(line: &str) -> Result<Option<OffsetDateTime>, Box<dyn Error>> {
-    const DATA_LEN: usize = 13;
     match strip_prefix_and_suffix(line, "0-0:1.0.0(", ")") {
-        Some(yymmddhhmmssx) => {
-            if yymmddhhmmssx.len() == DATA_LEN
-                && yymmddhhmmssx
-                    .chars()
-                    .nth(DATA_LEN - 1)
-                    .map(|summer_or_winter| summer_or_winter == 'S' || summer_or_winter == 'W')
-                    .unwrap_or(false)
-            {
-                let yy = i32::from_str(&yymmddhhmmssx[0..2])?;
-                let mm = Month::try_from(u8::from_str(&yymmddhhmmssx[2..4])?)?;
-                let dd = u8::from_str(&yymmddhhmmssx[4..6])?;
-                let hours = u8::from_str(&yymmddhhmmssx[6..8])?;
-                let mins = u8::from_str(&yymmddhhmmssx[8..10])?;
-                let secs = u8::from_str(&yymmddhhmmssx[10..12])?;
-                let date = Date::from_calendar_date(2000 + yy, mm, dd)?;
-                let datetime = date.with_hms(hours, mins, secs)?;
-                let datetime = datetime.assume_offset(UtcOffset::UTC);
-                Ok(Some(datetime))
-            } else {
-                Ok(None) // I should (but am not going to) define an error type here
-            }
-        }
+        Some(yymmddhhmmssx) => parse_timestamp(yymmddhhmmssx),
         None => Ok(None),
     }
 }
 
+/// Parse the `YYMMDDhhmmssX` format shared by all timestamps in a telegram.
+pub(crate) fn parse_timestamp(
+    yymmddhhmmssx: &str,
+) -> Result<Option<OffsetDateTime>, Box<dyn Error>> {
+    const DATA_LEN: usize = 13;
+    if yymmddhhmmssx.len() == DATA_LEN
+        && yymmddhhmmssx
+            .chars()
+            .nth(DATA_LEN - 1)
+            .map(|summer_or_winter| summer_or_winter == 'S' || summer_or_winter == 'W')
+            .unwrap_or(false)
+    {
+        let datetime = parse_yymmddhhmmss(&yymmddhhmmssx[0..DATA_LEN - 1])?;
+        // Meters run on Central European Time, the flag tells whether
+        // summer time applies (which also resolves the hour repeated when
+        // going back to winter time)
+        let offset = if yymmddhhmmssx.ends_with('S') {
+            UtcOffset::from_hms(2, 0, 0)?
+        } else {
+            UtcOffset::from_hms(1, 0, 0)?
+        };
+        Ok(Some(datetime.assume_offset(offset)))
+    } else {
+        Ok(None) // I should (but am not going to) define an error type here
+    }
+}
+
+/// Time zone of the meter clocks
+pub const METER_TIMEZONE: Tz = chrono_tz::Europe::Brussels;
+
+/// Meter time without summer/winter flag: ambiguous during the hour repeated
+/// when going back to winter time, the first one is taken.
+pub(crate) fn assume_meter_time(datetime: PrimitiveDateTime) -> Result<OffsetDateTime, String> {
+    let naive = NaiveDate::from_ymd_opt(
+        datetime.year(),
+        datetime.month() as u32,
+        datetime.day() as u32,
+    )
+    .and_then(|date| {
+        date.and_hms_opt(
+            datetime.hour() as u32,
+            datetime.minute() as u32,
+            datetime.second() as u32,
+        )
+    })
+    .ok_or_else(|| format!("Bad meter time {}", datetime))?;
+    let seconds = match METER_TIMEZONE.from_local_datetime(&naive).earliest() {
+        Some(local) => local.offset().fix().local_minus_utc(),
+        // Skipped hour when going to summer time
+        None => 3600,
+    };
+    let offset = UtcOffset::from_whole_seconds(seconds).map_err(|e| e.to_string())?;
+    Ok(datetime.assume_offset(offset))
+}
+
+/// Parse `YYMMDDhhmmss`, without summer/winter flag as in DSMR 2.2.
+pub(crate) fn parse_yymmddhhmmss(yymmddhhmmss: &str) -> Result<PrimitiveDateTime, Box<dyn Error>> {
+    if yymmddhhmmss.len() != 12 || !yymmddhhmmss.is_ascii() {
+        return Err(format!("Malformed timestamp {}", yymmddhhmmss).into());
+    }
+    let yy = i32::from_str(&yymmddhhmmss[0..2])?;
+    let mm = Month::try_from(u8::from_str(&yymmddhhmmss[2..4])?)?;
+    let dd = u8::from_str(&yymmddhhmmss[4..6])?;
+    let hours = u8::from_str(&yymmddhhmmss[6..8])?;
+    let mins = u8::from_str(&yymmddhhmmss[8..10])?;
+    let secs = u8::from_str(&yymmddhhmmss[10..12])?;
+    let date = Date::from_calendar_date(2000 + yy, mm, dd)?;
+    Ok(date.with_hms(hours, mins, secs)?)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -122,7 +173,23 @@ mod tests {
         assert_eq!(datetime.hour(), 19);
         assert_eq!(datetime.minute(), 18);
         assert_eq!(datetime.second(), 16);
-        assert_eq!(datetime.offset(), UtcOffset::UTC);
+        assert_eq!(datetime.offset(), UtcOffset::from_hms(2, 0, 0).unwrap());
+    }
+
+    #[test]
+    fn parse_date_time_repeated_hour_told_apart_by_flag() {
+        // Back to winter time on 2024-10-27 at 3h CEST (1h UTC)
+        let summer = parse_date_time("0-0:1.0.0(241027023000S)").unwrap().unwrap();
+        let winter = parse_date_time("0-0:1.0.0(241027023000W)").unwrap().unwrap();
+        assert_eq!(summer.unix_timestamp(), 1729989000);
+        assert_eq!(winter - summer, time::Duration::hours(1));
+        let without_flag = parse_yymmddhhmmss("241027023000").unwrap();
+        assert_eq!(assume_meter_time(without_flag), Ok(summer));
+        let without_flag = parse_yymmddhhmmss("240115120000").unwrap();
+        assert_eq!(
+            assume_meter_time(without_flag).unwrap().offset(),
+            UtcOffset::from_hms(1, 0, 0).unwrap()
+        );
     }
 
     #[test]
@@ -154,9 +221,64 @@ mod tests {
     fn parse_lines_happy_path() {
         assert_eq!(
             parse_lines("\n0-0:1.0.0(241025000000S)\n\n1-0:1.8.1(002654.919*kWh)\n\n1-0:1.8.2(002420.293*kWh)\n\n1-0:2.8.1(006254.732*kWh)\n\n1-0:2.8.2(002457.202*kWh)".lines()).expect("Ok(some meas) expected here"),
-            Some(CompleteP1Measurement { timestamp: Date::from_calendar_date(2024, Month::October, 25).unwrap().midnight().assume_utc(), peak_hour_consumption: 2654.919, off_hour_consumption: 2420.293, peak_hour_injection: 6254.732, off_hour_injection: 2457.202 }),
+            Some(CompleteP1Measurement { timestamp: Date::from_calendar_date(2024, Month::October, 25).unwrap().midnight().assume_offset(UtcOffset::from_hms(2, 0, 0).unwrap()), peak_hour_consumption: 2654.919, off_hour_consumption: 2420.293, peak_hour_injection: 6254.732, off_hour_injection: 2457.202 }),
         )
     }
+
+    #[test]
+    fn split_cosem_line_multiple_values() {
+        assert_eq!(
+            split_cosem_line("0-1:24.2.1(101209112500W)(12785.123*m3)\r\n"),
+            Some(("0-1:24.2.1", vec!["101209112500W", "12785.123*m3"]))
+        );
+        assert_eq!(split_cosem_line("/ISk5\\2MT382-1000"), None);
+    }
+
+    #[test]
+    fn split_telegrams_drops_incomplete_ones() {
+        let capture = "garbage\r\n1-0:1.8.1(000001.000*kWh)\r\n!ABCD\r\n/first\r\n\r\n0-0:1.0.0(241025000000S)\r\n!1234\r\n/second\r\n1-0:1.8.1(000001.000*kWh)\r\n!\r\n/truncated\r\n1-0:1.8";
+        assert_eq!(
+            split_telegrams(capture),
+            vec![
+                "/first\r\n\r\n0-0:1.0.0(241025000000S)\r\n!1234\r\n",
+                "/second\r\n1-0:1.8.1(000001.000*kWh)\r\n!\r\n"
+            ]
+        );
+    }
+
+    #[test]
+    fn telegram_assembler_agrees_with_split_telegrams() {
+        let capture =
+            "!0000\r\n/first\r\n0-0:1.0.0(241025000000S)\r\n!1234\r\n/second\r\n!\r\n/trunc";
+        let mut assembler = TelegramAssembler::default();
+        let assembled: Vec<String> = capture
+            .split_inclusive('\n')
+            .filter_map(|line| assembler.push_line(line))
+            .collect();
+        assert_eq!(assembled, split_telegrams(capture));
+    }
+
+    #[test]
+    fn crc16_check_value() {
+        assert_eq!(crc16(b"123456789"), 0xBB3D);
+        assert_eq!(crc16(b"12345678"), 0x3C9D);
+        assert_eq!(crc16(b""), 0);
+    }
+
+    #[test]
+    fn validate_crc_accepts_good_and_missing_crc() {
+        let body = "/XMX5\r\n\r\n0-0:1.0.0(241025000000S)\r\n!";
+        let telegram = format!("{}{:04X}\r\n", body, crc16(body.as_bytes()));
+        assert_eq!(validate_crc(&telegram), Ok(()));
+        assert_eq!(validate_crc(&format!("{}\r\n", body)), Ok(()));
+    }
+
+    #[test]
+    fn validate_crc_rejects_bad_crc() {
+        assert!(validate_crc("/XMX5\r\n!0000\r\n").is_err());
+        assert!(validate_crc("/XMX5\r\n!XYZ\r\n").is_err());
+        assert!(validate_crc("/XMX5\r\n").is_err());
+    }
 }
 
 #[derive(PartialEq, Debug)]
@@ -170,11 +292,11 @@ struct PartialP1Measurement {
 
 #[derive(PartialEq, Debug)]
 pub struct CompleteP1Measurement {
-    timestamp: OffsetDateTime,
-    peak_hour_consumption: f64,
-    off_hour_consumption: f64,
-    peak_hour_injection: f64,
-    off_hour_injection: f64,
+    pub timestamp: OffsetDateTime,
+    pub peak_hour_consumption: f64,
+    pub off_hour_consumption: f64,
+    pub peak_hour_injection: f64,
+    pub off_hour_injection: f64,
 }
 
 fn complete_p1_measurement(
@@ -298,5 +420,241 @@ where
             Err(new_partial) => partial = new_partial,
         }
     }
-    return Ok(None);
+    Ok(None)
+}
+
+/// Split a COSEM line like `1-0:21.7.0(01.111*kW)` into its OBIS reference
+/// and the values between parentheses.
+pub fn split_cosem_line(line: &str) -> Option<(&str, Vec<&str>)> {
+    let line = line.trim_end();
+    let open = line.find('(')?;
+    if !line.ends_with(')') {
+        return None;
+    }
+    Some((
+        &line[..open],
+        line[open + 1..line.len() - 1].split(")(").collect(),
+    ))
+}
+
+pub(crate) fn parse_quantity(value: &str, unit: &str) -> Result<f64, String> {
+    match value.strip_suffix(unit).and_then(|v| v.strip_suffix('*')) {
+        Some(number) => f64::from_str(number).map_err(|e| format!("{}: {}", value, e)),
+        None => Err(format!("{}: expected unit {}", value, unit)),
+    }
+}
+
+/// Instantaneous values sent every second, for the live view.
+#[derive(Debug, Default, PartialEq, Serialize)]
+#[allow(non_snake_case)]
+pub struct LiveP1Values {
+    /// Meter time as Unix timestamp
+    pub timestamp: Option<i64>,
+    pub import_kW: Option<f64>,
+    pub export_kW: Option<f64>,
+    pub phase_import_kW: [Option<f64>; 3],
+    pub phase_export_kW: [Option<f64>; 3],
+    /// 1 for peak (day) tariff, 2 for off-peak (night) tariff
+    pub tariff: Option<u8>,
+}
+
+/// One entry of the long power failure event log (1-0:99.97.0)
+#[derive(Debug, PartialEq)]
+pub struct PowerFailure {
+    /// End of the failure (meter time)
+    pub end: OffsetDateTime,
+    pub duration_s: u64,
+}
+
+/// Power failure and voltage quality counters
+#[derive(Debug, Default, PartialEq)]
+pub struct PowerQuality {
+    /// 0-0:96.7.21, any phase
+    pub power_failures: Option<u64>,
+    /// 0-0:96.7.9, any phase
+    pub long_power_failures: Option<u64>,
+    /// 1-0:99.97.0, most meters only keep the last 10 entries
+    pub long_failure_log: Vec<PowerFailure>,
+    /// 1-0:32.32.0, 1-0:52.32.0, 1-0:72.32.0 for L1, L2, L3
+    pub voltage_sags: [Option<u64>; 3],
+    /// 1-0:32.36.0, 1-0:52.36.0, 1-0:72.36.0 for L1, L2, L3
+    pub voltage_swells: [Option<u64>; 3],
+}
+
+impl PowerQuality {
+    /// Counter sent as object `obis`, if it is one.
+    pub(crate) fn counter(&mut self, obis: &str) -> Option<&mut Option<u64>> {
+        Some(match obis {
+            "0-0:96.7.21" => &mut self.power_failures,
+            "0-0:96.7.9" => &mut self.long_power_failures,
+            "1-0:32.32.0" => &mut self.voltage_sags[0],
+            "1-0:52.32.0" => &mut self.voltage_sags[1],
+            "1-0:72.32.0" => &mut self.voltage_sags[2],
+            "1-0:32.36.0" => &mut self.voltage_swells[0],
+            "1-0:52.36.0" => &mut self.voltage_swells[1],
+            "1-0:72.36.0" => &mut self.voltage_swells[2],
+            _ => return None,
+        })
+    }
+}
+
+pub(crate) fn parse_counter(line: &str, value: &str) -> Result<u64, String> {
+    u64::from_str(value).map_err(|e| format!("{}: {}", line, e))
+}
+
+pub(crate) fn parse_failure_log(line: &str, values: &[&str]) -> Result<Vec<PowerFailure>, String> {
+    // 1-0:99.97.0(2)(0-0:96.7.19)(101208152415W)(0000000240*s)(101208151004W)(0000000301*s)
+    let count = match values.first() {
+        None | Some(&"") => return Ok(Vec::new()),
+        Some(count) => parse_counter(line, count)? as usize,
+    };
+    // A corrupt count must not overflow
+    let expected = count.checked_mul(2).and_then(|n| n.checked_add(2));
+    if expected != Some(values.len()) {
+        return Err(format!("{}: expected {} log entries", line, count));
+    }
+    values[2..]
+        .chunks(2)
+        .map(|entry| {
+            let end = parse_timestamp(entry[0])
+                .map_err(|e| format!("{}: {}", line, e))?
+                .ok_or_else(|| format!("{}: bad timestamp {}", line, entry[0]))?;
+            let duration_s = match entry[1].strip_suffix("*s") {
+                Some(duration) => parse_counter(line, duration)?,
+                None => return Err(format!("{}: expected seconds in {}", line, entry[1])),
+            };
+            Ok(PowerFailure { end, duration_s })
+        })
+        .collect()
+}
+
+/// Split a capture of the serial stream into telegrams.
+///
+/// A telegram starts with a `/` header line and ends with the `!` line
+/// (followed by the CRC for DSMR 4 and later).  Line endings are kept
+/// because the CRC is computed over the raw bytes.  Anything before the
+/// first header or after the last complete telegram is dropped.
+pub fn split_telegrams(capture: &str) -> Vec<&str> {
+    let mut result = Vec::new();
+    let mut start: Option<usize> = None;
+    let mut offset = 0;
+    for line in capture.split_inclusive('\n') {
+        if line.starts_with('/') {
+            start = Some(offset);
+        } else if line.starts_with('!') {
+            if let Some(begin) = start.take() {
+                result.push(&capture[begin..offset + line.len()]);
+            }
+        }
+        offset += line.len();
+    }
+    result
+}
+
+/// Incremental version of `split_telegrams` for line-oriented sources.
+#[derive(Default)]
+pub struct TelegramAssembler {
+    current: Option<String>,
+}
+
+impl TelegramAssembler {
+    /// Feed one line (including its line ending); returns the telegram it
+    /// completes, if any.
+    pub fn push_line(&mut self, line: &str) -> Option<String> {
+        if line.starts_with('/') {
+            self.current = Some(line.to_string());
+            None
+        } else if let Some(current) = self.current.as_mut() {
+            current.push_str(line);
+            if line.starts_with('!') {
+                self.current.take()
+            } else {
+                None
+            }
+        } else {
+            None
+        }
+    }
+}
+
+/// `CRC16_TABLES[0]` is the CRC of every byte value, the next ones are those
+/// of the byte followed by 1 to 7 zero bytes, so that `crc16` handles 8 bytes
+/// at a time.
+const CRC16_TABLES: [[u16; 256]; 8] = crc16_tables();
+
+const fn crc16_tables() -> [[u16; 256]; 8] {
+    let mut tables = [[0; 256]; 8];
+    let mut byte = 0;
+    while byte < 256 {
+        let mut crc = byte as u16;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 == 1 {
+                (crc >> 1) ^ 0xA001
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+        tables[0][byte] = crc;
+        byte += 1;
+    }
+    let mut table = 1;
+    while table < 8 {
+        let mut byte = 0;
+        while byte < 256 {
+            let previous = tables[table - 1][byte];
+            tables[table][byte] = (previous >> 8) ^ tables[0][(previous & 0xFF) as usize];
+            byte += 1;
+        }
+        table += 1;
+    }
+    tables
+}
+
+/// CRC-16/ARC (polynomial 0x8005, reflected) as used by DSMR 4 and later.
+pub fn crc16(bytes: &[u8]) -> u16 {
+    let t = &CRC16_TABLES;
+    let mut chunks = bytes.chunks_exact(8);
+    let mut crc = 0;
+    for b in chunks.by_ref() {
+        let low = crc ^ (b[0] as u16 | (b[1] as u16) << 8);
+        crc = t[7][(low & 0xFF) as usize]
+            ^ t[6][(low >> 8) as usize]
+            ^ t[5][b[2] as usize]
+            ^ t[4][b[3] as usize]
+            ^ t[3][b[4] as usize]
+            ^ t[2][b[5] as usize]
+            ^ t[1][b[6] as usize]
+            ^ t[0][b[7] as usize];
+    }
+    chunks.remainder().iter().fold(crc, |crc, byte| {
+        (crc >> 8) ^ t[0][((crc ^ *byte as u16) & 0xFF) as usize]
+    })
+}
+
+/// Check the CRC of a telegram as returned by `split_telegrams`.
+///
+/// Older dialects end with a bare `!` and carry no CRC: they are accepted
+/// as is.
+pub fn validate_crc(telegram: &str) -> Result<(), String> {
+    let bang = match telegram.rfind('!') {
+        Some(bang) => bang,
+        None => return Err("No end of telegram marker".to_string()),
+    };
+    let expected = telegram[bang + 1..].trim();
+    if expected.is_empty() {
+        return Ok(());
+    }
+    let expected = u16::from_str_radix(expected, 16)
+        .map_err(|e| format!("Malformed CRC {}: {}", expected, e))?;
+    let actual = crc16(&telegram.as_bytes()[..=bang]);
+    if actual == expected {
+        Ok(())
+    } else {
+        Err(format!(
+            "CRC mismatch: telegram says {:04X}, computed {:04X}",
+            expected, actual
+        ))
+    }
 }
@@ -0,0 +1,348 @@
+use std::sync::Arc;
+
+use actix_web::{get, web, HttpResponse};
+use serde::{Deserialize, Serialize};
+use tera::Tera;
+use time::OffsetDateTime;
+use tokio::sync::broadcast::error::RecvError;
+
+use crate::data::{
+    insert_phase_minutes, select_daily_phase_imbalance, select_phase_summary, Database,
+    PhaseMinuteRow,
+};
+use crate::p1_reader::P1Hub;
+use crate::p1_telegram::{parse_telegram, Dialect, PhaseValues};
+use crate::{format_timestamp, get_env_var};
+
+/// Length of the aggregation period [s]
+const RESOLUTION_S: i64 = 60;
+
+/// Fraction of the main breaker rating from which a phase is close to tripping
+const NEAR_BREAKER_RATIO: f64 = 0.8;
+
+const DEFAULT_MAIN_BREAKER_A: f64 = 40.0;
+
+const DEFAULT_REPORT_DAYS: u32 = 30;
+
+const PHASES: [&str; 3] = ["L1", "L2", "L3"];
+
+/// Rating of the main breaker, cf `RUST_HELLO_WORLD_MAIN_BREAKER_A`.
+fn configured_main_breaker_a() -> f64 {
+    get_env_var("RUST_HELLO_WORLD_MAIN_BREAKER_A")
+        .ok()
+        .and_then(|s| s.trim().parse().ok())
+        .unwrap_or(DEFAULT_MAIN_BREAKER_A)
+}
+
+#[derive(Default)]
+struct Mean {
+    sum: f64,
+    count: u32,
+}
+
+impl Mean {
+    fn add(&mut self, value: Option<f64>) {
+        if let Some(value) = value {
+            self.sum += value;
+            self.count += 1;
+        }
+    }
+
+    fn value(&self) -> Option<f64> {
+        if self.count == 0 {
+            None
+        } else {
+            Some(self.sum / self.count as f64)
+        }
+    }
+}
+
+#[derive(Default)]
+#[allow(non_snake_case)]
+struct PhaseAccumulator {
+    max_current_A: Option<f64>,
+    current_A: Mean,
+    voltage_V: Mean,
+    import_kW: Mean,
+    export_kW: Mean,
+}
+
+impl PhaseAccumulator {
+    fn add(&mut self, values: &PhaseValues) {
+        if let Some(current) = values.current_A {
+            self.max_current_A = Some(self.max_current_A.map_or(current, |max| max.max(current)));
+        }
+        self.current_A.add(values.current_A);
+        self.voltage_V.add(values.voltage_V);
+        self.import_kW.add(values.import_kW);
+        self.export_kW.add(values.export_kW);
+    }
+
+    fn row(&self, timestamp: i64, phase: u8) -> Option<PhaseMinuteRow> {
+        let row = PhaseMinuteRow {
+            timestamp,
+            phase,
+            max_current_A: self.max_current_A,
+            avg_current_A: self.current_A.value(),
+            avg_voltage_V: self.voltage_V.value(),
+            avg_import_kW: self.import_kW.value(),
+            avg_export_kW: self.export_kW.value(),
+        };
+        // Single phase connections only report L1
+        if row.avg_current_A.is_none()
+            && row.avg_voltage_V.is_none()
+            && row.avg_import_kW.is_none()
+            && row.avg_export_kW.is_none()
+        {
+            None
+        } else {
+            Some(row)
+        }
+    }
+}
+
+/// Aggregates the per phase values of consecutive telegrams by minute.
+#[derive(Default)]
+pub struct PhaseAggregator {
+    minute: Option<i64>,
+    phases: [PhaseAccumulator; 3],
+}
+
+impl PhaseAggregator {
+    /// Add the values of a telegram sent at `timestamp`.  Returns the rows of
+    /// the previous minute once a telegram of a later minute shows up.
+    pub fn add(&mut self, timestamp: i64, phases: &[PhaseValues; 3]) -> Vec<PhaseMinuteRow> {
+        let minute = timestamp - timestamp.rem_euclid(RESOLUTION_S);
+        let finished = match self.minute {
+            Some(current) if current < minute => self.flush(),
+            // Telegrams of a minute already flushed (clock set back) are dropped
+            Some(current) if current > minute => return Vec::new(),
+            _ => Vec::new(),
+        };
+        self.minute = Some(minute);
+        for (accumulator, values) in self.phases.iter_mut().zip(phases.iter()) {
+            accumulator.add(values);
+        }
+        finished
+    }
+
+    /// Rows of the minute being aggregated, which is then reset.
+    pub fn flush(&mut self) -> Vec<PhaseMinuteRow> {
+        let rows = match self.minute {
+            Some(minute) => self
+                .phases
+                .iter()
+                .zip(1..)
+                .filter_map(|(accumulator, phase)| accumulator.row(minute, phase))
+                .collect(),
+            None => Vec::new(),
+        };
+        self.phases = Default::default();
+        rows
+    }
+}
+
+/// Store per minute phase loads of the telegrams received.
+pub async fn run_phase_recorder(hub: Arc<P1Hub>, dialect: Option<Dialect>, database: Database) {
+    let mut receiver = hub.subscribe();
+    let mut aggregator = PhaseAggregator::default();
+    loop {
+        let telegram = match receiver.recv().await {
+            Ok(telegram) if telegram.crc_ok => telegram,
+            Ok(_) | Err(RecvError::Lagged(_)) => continue,
+            Err(RecvError::Closed) => return,
+        };
+        let parsed = match parse_telegram(&telegram.raw, dialect) {
+            Ok(parsed) => parsed,
+            Err(e) => {
+                log::warn!("Unable to parse P1 telegram: {}", e);
+                continue;
+            }
+        };
+        let timestamp = hub.data_time(&telegram, parsed.timestamp).unix_timestamp();
+        let rows = aggregator.add(timestamp, &parsed.phases);
+        if rows.is_empty() {
+            continue;
+        }
+        let sqlite3 = database.sqlite3.clone();
+        match web::block(move || insert_phase_minutes(&sqlite3, &rows)).await {
+            Ok(Ok(_)) => {}
+            Ok(Err(e)) => log::error!("Unable to store phase loads: {}", e),
+            Err(e) => log::error!("Unable to store phase loads: {}", e),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct PhaseReportQuery {
+    days: Option<u32>,
+}
+
+#[derive(Serialize)]
+struct PhaseSummaryView {
+    phase: &'static str,
+    peak_current: String,
+    near_breaker: String,
+    avg_net: String,
+}
+
+#[derive(Serialize)]
+struct DailyPhaseView {
+    day: String,
+    avg_imbalance: String,
+    max_imbalance: String,
+    avg_net: [String; 3],
+}
+
+fn format_value(value: Option<f64>, decimals: usize) -> String {
+    match value {
+        Some(value) => format!("{:.*}", decimals, value),
+        None => "-".to_string(),
+    }
+}
+
+fn format_minutes(minutes: i64) -> String {
+    if minutes < 60 {
+        format!("{}min", minutes)
+    } else {
+        format!("{}h {}min", minutes / 60, minutes % 60)
+    }
+}
+
+#[get("/p1/phases")]
+pub async fn phase_report_page(
+    tera: web::Data<Tera>,
+    database: web::Data<Database>,
+    query: web::Query<PhaseReportQuery>,
+) -> HttpResponse {
+    let days = query.days.unwrap_or(DEFAULT_REPORT_DAYS).max(1);
+    let since = OffsetDateTime::now_utc().unix_timestamp() - days as i64 * 86400;
+    let breaker_a = configured_main_breaker_a();
+    let threshold_a = breaker_a * NEAR_BREAKER_RATIO;
+    let sqlite3 = database.sqlite3.clone();
+    let rows = web::block(move || {
+        Ok::<_, String>((
+            select_phase_summary(&sqlite3, since, threshold_a)?,
+            select_daily_phase_imbalance(&sqlite3, since)?,
+        ))
+    })
+    .await
+    .map_err(|e| e.to_string())
+    .and_then(|rows| rows);
+    let mut context = tera::Context::new();
+    match rows {
+        Ok((summary, daily)) => {
+            // The phase with the lowest peak current has the most room for
+            // new loads
+            let headroom = summary
+                .iter()
+                .filter_map(|row| row.peak_current_A.map(|peak| (row.phase, peak)))
+                .min_by(|a, b| a.1.total_cmp(&b.1))
+                .and_then(|(phase, _)| PHASES.get((phase as usize).checked_sub(1)?));
+            let summary: Vec<PhaseSummaryView> = summary
+                .iter()
+                .filter_map(|row| {
+                    Some(PhaseSummaryView {
+                        phase: PHASES.get((row.phase as usize).checked_sub(1)?)?,
+                        peak_current: format_value(row.peak_current_A, 0),
+                        near_breaker: format_minutes(row.minutes_near_breaker),
+                        avg_net: format_value(row.avg_net_kW, 3),
+                    })
+                })
+                .collect();
+            let daily: Vec<DailyPhaseView> = daily
+                .iter()
+                .map(|row| DailyPhaseView {
+                    day: row.day.clone(),
+                    avg_imbalance: format_value(row.avg_imbalance_A, 1),
+                    max_imbalance: format_value(row.max_imbalance_A, 1),
+                    avg_net: row.avg_net_kW.map(|net| format_value(net, 3)),
+                })
+                .collect();
+            context.insert("summary", &summary);
+            context.insert("daily", &daily);
+            context.insert("headroom", &headroom);
+        }
+        Err(e) => {
+            log::error!("Unable to read phase loads: {}", e);
+            context.insert("error", &e);
+        }
+    }
+    context.insert("days", &days);
+    context.insert("breaker", &format_value(Some(breaker_a), 0));
+    context.insert("threshold", &format_value(Some(threshold_a), 0));
+    context.insert(
+        "now",
+        &format_timestamp(OffsetDateTime::now_utc().unix_timestamp()),
+    );
+    let rendered = tera.render("p1_phases.html", &context).unwrap();
+    HttpResponse::Ok().body(rendered)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[allow(non_snake_case)]
+    fn phase(current_A: f64, import_kW: f64) -> PhaseValues {
+        PhaseValues {
+            voltage_V: Some(230.0),
+            current_A: Some(current_A),
+            import_kW: Some(import_kW),
+            export_kW: Some(0.0),
+        }
+    }
+
+    #[test]
+    fn aggregator_emits_finished_minutes() {
+        let mut aggregator = PhaseAggregator::default();
+        let only_l1 = [
+            phase(2.0, 0.4),
+            PhaseValues::default(),
+            PhaseValues::default(),
+        ];
+        assert!(aggregator.add(1729814400, &only_l1).is_empty());
+        assert!(aggregator
+            .add(
+                1729814459,
+                &[phase(6.0, 1.2), phase(1.0, 0.2), PhaseValues::default()]
+            )
+            .is_empty());
+        let rows = aggregator.add(1729814460, &only_l1);
+        assert_eq!(
+            rows,
+            vec![
+                PhaseMinuteRow {
+                    timestamp: 1729814400,
+                    phase: 1,
+                    max_current_A: Some(6.0),
+                    avg_current_A: Some(4.0),
+                    avg_voltage_V: Some(230.0),
+                    avg_import_kW: Some(0.8),
+                    avg_export_kW: Some(0.0),
+                },
+                PhaseMinuteRow {
+                    timestamp: 1729814400,
+                    phase: 2,
+                    max_current_A: Some(1.0),
+                    avg_current_A: Some(1.0),
+                    avg_voltage_V: Some(230.0),
+                    avg_import_kW: Some(0.2),
+                    avg_export_kW: Some(0.0),
+                }
+            ]
+        );
+        // Late telegram of the flushed minute
+        assert!(aggregator.add(1729814401, &only_l1).is_empty());
+        let rows = aggregator.flush();
+        assert_eq!(rows.len(), 1);
+        assert_eq!(rows[0].timestamp, 1729814460);
+        assert!(aggregator.flush().is_empty());
+    }
+
+    #[test]
+    fn format_minutes_units() {
+        assert_eq!(format_minutes(0), "0min");
+        assert_eq!(format_minutes(135), "2h 15min");
+    }
+}
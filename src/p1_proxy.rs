@@ -0,0 +1,161 @@
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpListener;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::broadcast::Receiver;
+
+use crate::p1_reader::{P1Hub, ReceivedTelegram};
+
+/// Time a client gets to accept a telegram before it is disconnected
+const WRITE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Why a client stopped being served
+#[derive(Debug, PartialEq)]
+enum Disconnect {
+    /// The client fell behind by this many telegrams
+    Lagged(u64),
+    TimedOut,
+    Io(String),
+    /// The hub stopped
+    Closed,
+}
+
+/// Forward the raw telegrams of `receiver` to `client`, optionally only those
+/// with a valid CRC.  A client not accepting a telegram within
+/// `write_timeout` is disconnected.
+///
+/// Every client has its own receiver: one that does not keep up misses
+/// telegrams, which the hub drops when its buffer is full.  It is then
+/// disconnected rather than sent an incomplete stream.
+async fn serve_client<W: AsyncWrite + Unpin>(
+    mut client: W,
+    mut receiver: Receiver<Arc<ReceivedTelegram>>,
+    valid_only: bool,
+    write_timeout: Duration,
+) -> Disconnect {
+    loop {
+        let telegram = match receiver.recv().await {
+            Ok(telegram) if telegram.crc_ok || !valid_only => telegram,
+            Ok(_) => continue,
+            Err(RecvError::Lagged(count)) => return Disconnect::Lagged(count),
+            Err(RecvError::Closed) => return Disconnect::Closed,
+        };
+        match tokio::time::timeout(write_timeout, client.write_all(telegram.raw.as_bytes())).await {
+            Ok(Ok(())) => {}
+            Ok(Err(e)) => return Disconnect::Io(e.to_string()),
+            Err(_) => return Disconnect::TimedOut,
+        }
+    }
+}
+
+/// Serve the telegrams read from the P1 port to whoever connects to
+/// `listener`, like ser2net would (cf `RUST_HELLO_WORLD_P1_PROXY`).  With
+/// `valid_only`, telegrams with a CRC mismatch are not forwarded.
+pub async fn run_p1_proxy(hub: Arc<P1Hub>, listener: TcpListener, valid_only: bool) {
+    loop {
+        let (stream, address) = match listener.accept().await {
+            Ok(client) => client,
+            Err(e) => {
+                log::error!("P1 proxy: {}", e);
+                tokio::time::sleep(Duration::from_secs(1)).await;
+                continue;
+            }
+        };
+        log::info!("P1 proxy: {} connected", address);
+        let receiver = hub.subscribe();
+        actix_web::rt::spawn(async move {
+            let reason = serve_client(stream, receiver, valid_only, WRITE_TIMEOUT).await;
+            log_disconnect(address, reason);
+        });
+    }
+}
+
+fn log_disconnect(address: SocketAddr, reason: Disconnect) {
+    match reason {
+        Disconnect::Lagged(count) => log::warn!(
+            "P1 proxy: disconnecting {}, {} telegrams behind",
+            address,
+            count
+        ),
+        Disconnect::TimedOut => log::warn!(
+            "P1 proxy: disconnecting {}, not reading for {}s",
+            address,
+            WRITE_TIMEOUT.as_secs()
+        ),
+        Disconnect::Io(e) => log::info!("P1 proxy: {} disconnected: {}", address, e),
+        Disconnect::Closed => log::info!("P1 proxy: closing connection of {}", address),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use time::OffsetDateTime;
+    use tokio::io::AsyncReadExt;
+
+    const VALID: &str = "/A\r\n!\r\n";
+    const INVALID: &str = "/B\r\n!0000\r\n";
+
+    #[actix_rt::test]
+    async fn forwards_telegrams() {
+        let hub = P1Hub::new();
+        let (client, mut server) = tokio::io::duplex(1024);
+        let all = serve_client(client, hub.subscribe(), false, WRITE_TIMEOUT);
+        let (valid_client, mut valid_server) = tokio::io::duplex(1024);
+        let valid = serve_client(valid_client, hub.subscribe(), true, WRITE_TIMEOUT);
+        hub.publish(VALID.to_string(), OffsetDateTime::now_utc());
+        hub.publish(INVALID.to_string(), OffsetDateTime::now_utc());
+        hub.publish(VALID.to_string(), OffsetDateTime::now_utc());
+        drop(hub);
+        assert_eq!(all.await, Disconnect::Closed);
+        assert_eq!(valid.await, Disconnect::Closed);
+        let mut received = String::new();
+        server.read_to_string(&mut received).await.unwrap();
+        assert_eq!(received, [VALID, INVALID, VALID].concat());
+        let mut received = String::new();
+        valid_server.read_to_string(&mut received).await.unwrap();
+        assert_eq!(received, [VALID, VALID].concat());
+    }
+
+    #[actix_rt::test]
+    async fn disconnects_lagging_client() {
+        let hub = P1Hub::new();
+        let (client, _server) = tokio::io::duplex(1024);
+        let receiver = hub.subscribe();
+        for _ in 0..100 {
+            hub.publish(VALID.to_string(), OffsetDateTime::now_utc());
+        }
+        assert_eq!(
+            serve_client(client, receiver, false, WRITE_TIMEOUT).await,
+            Disconnect::Lagged(36)
+        );
+    }
+
+    #[actix_rt::test]
+    async fn disconnects_client_not_reading() {
+        let hub = P1Hub::new();
+        let (client, _server) = tokio::io::duplex(4);
+        let receiver = hub.subscribe();
+        hub.publish(VALID.to_string(), OffsetDateTime::now_utc());
+        assert_eq!(
+            serve_client(client, receiver, false, Duration::from_millis(10)).await,
+            Disconnect::TimedOut
+        );
+    }
+
+    #[actix_rt::test]
+    async fn disconnects_closed_client() {
+        let hub = P1Hub::new();
+        let (client, server) = tokio::io::duplex(1024);
+        drop(server);
+        let receiver = hub.subscribe();
+        hub.publish(VALID.to_string(), OffsetDateTime::now_utc());
+        assert!(matches!(
+            serve_client(client, receiver, false, WRITE_TIMEOUT).await,
+            Disconnect::Io(_)
+        ));
+    }
+}
@@ -0,0 +1,242 @@
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use time::OffsetDateTime;
+use tokio::io::{AsyncBufReadExt, AsyncRead, BufReader};
+use tokio::process::Command;
+use tokio::sync::broadcast;
+
+use crate::p1_meter::{validate_crc, TelegramAssembler};
+use crate::p1_telegram::Dialect;
+
+/// Where the telegrams come from, cf `RUST_HELLO_WORLD_P1_SOURCE`:
+/// `tcp://host:port` for a network dongle or ser2net, anything else is the
+/// path of the serial device (or of a file/FIFO when testing).
+#[derive(Debug, PartialEq, Clone)]
+pub enum P1Source {
+    Tcp(String),
+    Serial(String),
+}
+
+impl FromStr for P1Source {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        if s.is_empty() {
+            Err("Empty P1 source".to_string())
+        } else if let Some(address) = s.strip_prefix("tcp://") {
+            Ok(P1Source::Tcp(address.to_string()))
+        } else {
+            Ok(P1Source::Serial(s.to_string()))
+        }
+    }
+}
+
+pub struct ReceivedTelegram {
+    pub raw: String,
+    pub received_at: OffsetDateTime,
+    pub crc_ok: bool,
+}
+
+/// Distributes every telegram read from the P1 port to whoever subscribed
+/// (live view, recorders, ...).
+pub struct P1Hub {
+    sender: broadcast::Sender<Arc<ReceivedTelegram>>,
+    latest: Mutex<Option<Arc<ReceivedTelegram>>>,
+    restamp: bool,
+}
+
+impl Default for P1Hub {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl P1Hub {
+    pub fn new() -> Self {
+        Self::with_restamp(false)
+    }
+
+    /// With `restamp`, the data of the telegrams is attributed to the time
+    /// they were received rather than to the meter clock, cf `data_time`.
+    pub fn with_restamp(restamp: bool) -> Self {
+        let (sender, _) = broadcast::channel(64);
+        P1Hub {
+            sender,
+            latest: Mutex::new(None),
+            restamp,
+        }
+    }
+
+    /// Time the values of `telegram` are stored under: the meter time
+    /// (`meter_time`, as parsed from the telegram) unless re-stamping.
+    pub fn data_time(
+        &self,
+        telegram: &ReceivedTelegram,
+        meter_time: Option<OffsetDateTime>,
+    ) -> OffsetDateTime {
+        match meter_time {
+            Some(meter_time) if !self.restamp => meter_time,
+            _ => telegram.received_at,
+        }
+    }
+
+    pub fn restamps(&self) -> bool {
+        self.restamp
+    }
+
+    pub fn publish(&self, raw: String, received_at: OffsetDateTime) {
+        let crc_ok = match validate_crc(&raw) {
+            Ok(()) => true,
+            Err(e) => {
+                log::warn!("P1 telegram: {}", e);
+                false
+            }
+        };
+        let telegram = Arc::new(ReceivedTelegram {
+            raw,
+            received_at,
+            crc_ok,
+        });
+        if crc_ok {
+            *self.latest.lock().unwrap() = Some(telegram.clone());
+        }
+        // No subscribers is not an error
+        let _ = self.sender.send(telegram);
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<Arc<ReceivedTelegram>> {
+        self.sender.subscribe()
+    }
+
+    /// Most recent telegram with a valid CRC
+    pub fn latest(&self) -> Option<Arc<ReceivedTelegram>> {
+        self.latest.lock().unwrap().clone()
+    }
+}
+
+async fn configure_serial_port(device: &str, dialect: Dialect) {
+    match Command::new("stty")
+        .arg("-F")
+        .arg(device)
+        .args(dialect.serial_settings())
+        .args(["raw", "-echo"])
+        .output()
+        .await
+    {
+        Ok(output) if output.status.success() => {}
+        Ok(output) => log::warn!(
+            "stty {} failed: {}",
+            device,
+            String::from_utf8_lossy(&output.stderr).trim()
+        ),
+        Err(e) => log::warn!("Failed to execute stty: {}", e),
+    }
+}
+
+async fn read_telegrams<R: AsyncRead + Unpin>(reader: R, hub: &P1Hub) -> std::io::Result<()> {
+    let mut reader = BufReader::new(reader);
+    let mut assembler = TelegramAssembler::default();
+    let mut buffer = Vec::new();
+    loop {
+        buffer.clear();
+        if reader.read_until(b'\n', &mut buffer).await? == 0 {
+            return Ok(());
+        }
+        if let Some(telegram) = assembler.push_line(&String::from_utf8_lossy(&buffer)) {
+            hub.publish(telegram, OffsetDateTime::now_utc());
+        }
+    }
+}
+
+async fn read_source(source: &P1Source, dialect: Dialect, hub: &P1Hub) -> std::io::Result<()> {
+    match source {
+        P1Source::Tcp(address) => {
+            read_telegrams(tokio::net::TcpStream::connect(address).await?, hub).await
+        }
+        P1Source::Serial(device) => {
+            configure_serial_port(device, dialect).await;
+            read_telegrams(tokio::fs::File::open(device).await?, hub).await
+        }
+    }
+}
+
+/// Read telegrams forever, reconnecting after errors or end of stream.
+///
+/// The dialect only matters for the serial port framing: without
+/// configuration (`RUST_HELLO_WORLD_P1_DIALECT`), the DSMR 4/5 settings are
+/// used.
+pub async fn run_p1_reader(source: P1Source, dialect: Option<Dialect>, hub: Arc<P1Hub>) {
+    let dialect = dialect.unwrap_or(Dialect::Dsmr5);
+    loop {
+        match read_source(&source, dialect, &hub).await {
+            Ok(()) => log::warn!("End of P1 stream from {:?}", source),
+            Err(e) => log::error!("Reading P1 telegrams from {:?}: {}", source, e),
+        }
+        tokio::time::sleep(Duration::from_secs(5)).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn p1_source_from_str() {
+        assert_eq!(
+            P1Source::from_str("tcp://192.168.1.2:2001"),
+            Ok(P1Source::Tcp("192.168.1.2:2001".to_string()))
+        );
+        assert_eq!(
+            P1Source::from_str("/dev/ttyUSB0"),
+            Ok(P1Source::Serial("/dev/ttyUSB0".to_string()))
+        );
+        assert!(P1Source::from_str(" ").is_err());
+    }
+
+    #[actix_rt::test]
+    async fn read_telegrams_publishes_with_crc_status() {
+        let hub = P1Hub::new();
+        let mut receiver = hub.subscribe();
+        read_telegrams(&b"junk\r\n/A\r\n!\r\n/B\r\n!0000\r\n/C"[..], &hub)
+            .await
+            .unwrap();
+        let first = receiver.recv().await.unwrap();
+        assert_eq!((first.raw.as_str(), first.crc_ok), ("/A\r\n!\r\n", true));
+        let second = receiver.recv().await.unwrap();
+        assert_eq!(
+            (second.raw.as_str(), second.crc_ok),
+            ("/B\r\n!0000\r\n", false)
+        );
+        assert!(receiver.try_recv().is_err());
+        assert_eq!(hub.latest().unwrap().raw, "/A\r\n!\r\n");
+    }
+
+    #[test]
+    fn data_time_restamped_or_not() {
+        let telegram = ReceivedTelegram {
+            raw: String::new(),
+            received_at: OffsetDateTime::from_unix_timestamp(1729814407).unwrap(),
+            crc_ok: true,
+        };
+        let meter_time = OffsetDateTime::from_unix_timestamp(1729814400).ok();
+        assert_eq!(
+            P1Hub::new()
+                .data_time(&telegram, meter_time)
+                .unix_timestamp(),
+            1729814400
+        );
+        assert_eq!(
+            P1Hub::new().data_time(&telegram, None).unix_timestamp(),
+            1729814407
+        );
+        assert_eq!(
+            P1Hub::with_restamp(true)
+                .data_time(&telegram, meter_time)
+                .unix_timestamp(),
+            1729814407
+        );
+    }
+}
@@ -0,0 +1,298 @@
+use std::f64::consts::PI;
+
+use chrono::{Offset, TimeZone, Utc};
+use time::{Duration, OffsetDateTime, UtcOffset, Weekday};
+
+use crate::p1_meter::{PowerQuality, METER_TIMEZONE};
+use crate::p1_telegram::{Dialect, GasReading, P1Telegram, PhaseValues};
+
+/// Appliances switched on at random: power [kW] and how long they run [s].
+const APPLIANCES: [(f64, i64); 4] = [(2.0, 180), (2.2, 1800), (1.8, 3600), (1.2, 5400)];
+
+pub struct SimulatorSettings {
+    pub dialect: Dialect,
+    /// Peak power of the PV installation
+    pub pv_kwp: f64,
+    /// Consumption when nothing is running
+    pub base_load_kw: f64,
+    pub seed: u64,
+}
+
+impl Default for SimulatorSettings {
+    fn default() -> Self {
+        SimulatorSettings {
+            dialect: Dialect::Dsmr5,
+            pv_kwp: 4.0,
+            base_load_kw: 0.15,
+            seed: 42,
+        }
+    }
+}
+
+/// Generates plausible telegrams: a daily solar curve depending on the
+/// season, a household consumption pattern with appliances switching on at
+/// random, peak/off-peak tariffs and a gas meter.  The (single phase) PV
+/// inverter is on L1.
+pub struct MeterSimulator {
+    settings: SimulatorSettings,
+    rng: u64,
+    /// peak consumption, off-peak consumption, peak injection, off-peak injection [kWh]
+    counters: [f64; 4],
+    gas_m3: f64,
+    gas_reading: Option<GasReading>,
+    cloudiness: f64,
+    /// power, phase and end of the running appliance
+    appliance: Option<(f64, usize, OffsetDateTime)>,
+    last: Option<OffsetDateTime>,
+}
+
+/// Offset of the meter clock at `instant`.
+pub fn local_offset(instant: OffsetDateTime) -> UtcOffset {
+    let seconds = match Utc.timestamp_opt(instant.unix_timestamp(), 0).single() {
+        Some(utc) => METER_TIMEZONE
+            .offset_from_utc_datetime(&utc.naive_utc())
+            .fix()
+            .local_minus_utc(),
+        None => 0,
+    };
+    UtcOffset::from_whole_seconds(seconds).unwrap_or(UtcOffset::UTC)
+}
+
+fn hour_of_day(local: OffsetDateTime) -> f64 {
+    local.hour() as f64 + local.minute() as f64 / 60.0 + local.second() as f64 / 3600.0
+}
+
+/// Belgian tariff periods: peak from 7h to 22h on weekdays.
+pub fn tariff(local: OffsetDateTime) -> u8 {
+    let weekend = matches!(local.weekday(), Weekday::Saturday | Weekday::Sunday);
+    if !weekend && (7..22).contains(&local.hour()) {
+        1
+    } else {
+        2
+    }
+}
+
+/// Clear sky PV production in kW, for a given peak power.
+pub fn clear_sky_pv_kw(local: OffsetDateTime, kwp: f64) -> f64 {
+    // Roughly 8h of daylight around the winter solstice, 16h in June
+    let season = (2.0 * PI * (local.ordinal() as f64 - 80.0) / 365.0).sin();
+    let day_length = 12.0 + 4.0 * season;
+    let solar_noon = 12.5 + (local.offset().whole_hours() as f64 - 1.0);
+    let sunrise = solar_noon - day_length / 2.0;
+    let position = (hour_of_day(local) - sunrise) / day_length;
+    if !(0.0..=1.0).contains(&position) {
+        return 0.0;
+    }
+    let elevation = (PI * position).sin();
+    kwp * 0.85 * elevation.powf(1.3) * (0.65 + 0.35 * season)
+}
+
+impl MeterSimulator {
+    pub fn new(settings: SimulatorSettings) -> Self {
+        MeterSimulator {
+            rng: settings.seed.max(1),
+            settings,
+            counters: [2654.919, 2420.293, 6254.732, 2457.202],
+            gas_m3: 4567.89,
+            gas_reading: None,
+            cloudiness: 0.2,
+            appliance: None,
+            last: None,
+        }
+    }
+
+    /// xorshift64*, uniform in [0, 1)
+    fn random(&mut self) -> f64 {
+        self.rng ^= self.rng >> 12;
+        self.rng ^= self.rng << 25;
+        self.rng ^= self.rng >> 27;
+        (self.rng.wrapping_mul(0x2545_F491_4F6C_DD1D) >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    /// Consumption of each phase
+    fn consumption_kw(&mut self, local: OffsetDateTime, seconds: f64) -> [f64; 3] {
+        let hour = hour_of_day(local);
+        let mut kw = [self.settings.base_load_kw / 3.0; 3];
+        if (6.5..8.5).contains(&hour) {
+            kw[1] += 0.4;
+        }
+        if (18.0..21.5).contains(&hour) {
+            kw[1] += 0.3;
+            kw[2] += 0.5;
+        }
+        if (7.0..23.0).contains(&hour) {
+            kw[2] += 0.2 * self.random();
+        }
+        if let Some((_, _, until)) = self.appliance {
+            if until <= local {
+                self.appliance = None;
+            }
+        }
+        // About one appliance start per hour during the day
+        if self.appliance.is_none()
+            && (7.0..22.0).contains(&hour)
+            && self.random() < seconds / 3600.0
+        {
+            let (power, duration) = APPLIANCES[(self.random() * APPLIANCES.len() as f64) as usize];
+            let phase = (self.random() * 3.0) as usize;
+            self.appliance = Some((power, phase, local + Duration::seconds(duration)));
+        }
+        if let Some((power, phase, _)) = self.appliance {
+            kw[phase] += power;
+        }
+        kw
+    }
+
+    fn gas_rate_m3_per_h(&mut self, local: OffsetDateTime) -> f64 {
+        let hour = hour_of_day(local);
+        // Heating from October to April, hot water all year round
+        let heating = match local.month() as u8 {
+            10..=12 | 1..=4 => 1.0,
+            _ => 0.0,
+        };
+        let schedule = if (6.0..9.0).contains(&hour) || (17.0..22.0).contains(&hour) {
+            0.6
+        } else {
+            0.1
+        };
+        heating * schedule + 0.02 * self.random()
+    }
+
+    /// Advance the meter up to `now` and return the telegram it would send.
+    pub fn step(&mut self, now: OffsetDateTime) -> P1Telegram {
+        let local = now.to_offset(local_offset(now));
+        let local = local.replace_nanosecond(0).unwrap_or(local);
+        let seconds = match self.last {
+            Some(last) => (now - last).as_seconds_f64().clamp(0.0, 3600.0),
+            None => 0.0,
+        };
+        self.last = Some(now);
+
+        self.cloudiness = (self.cloudiness + 0.05 * (self.random() - 0.5)).clamp(0.0, 1.0);
+        let solar = clear_sky_pv_kw(local, self.settings.pv_kwp) * (1.0 - 0.7 * self.cloudiness);
+        let phase_consumption = self.consumption_kw(local, seconds);
+        let consumption: f64 = phase_consumption.iter().sum();
+        let import_kw = (consumption - solar).max(0.0);
+        let export_kw = (solar - consumption).max(0.0);
+        let tariff = tariff(local);
+        let (consumption_idx, injection_idx) = if tariff == 1 { (0, 2) } else { (1, 3) };
+        self.counters[consumption_idx] += import_kw * seconds / 3600.0;
+        self.counters[injection_idx] += export_kw * seconds / 3600.0;
+        self.gas_m3 += self.gas_rate_m3_per_h(local) * seconds / 3600.0;
+
+        // The gas meter reports every 5 minutes (DSMR 5) or every hour
+        let period = match self.settings.dialect {
+            Dialect::Dsmr5 => 300,
+            Dialect::Dsmr4 | Dialect::Dsmr22 => 3600,
+        };
+        let gas_time = local - Duration::seconds(local.unix_timestamp().rem_euclid(period));
+        if self.gas_reading.as_ref().map(|gas| gas.timestamp) != Some(gas_time) {
+            self.gas_reading = Some(GasReading {
+                timestamp: gas_time,
+                m3: (self.gas_m3 * 1000.0).round() / 1000.0,
+            });
+        }
+
+        let round = |value: f64| (value * 1000.0).round() / 1000.0;
+        let mut phases: [PhaseValues; 3] = Default::default();
+        for (phase, values) in phases.iter_mut().enumerate() {
+            let net_kw = phase_consumption[phase] - if phase == 0 { solar } else { 0.0 };
+            // Injecting raises the voltage at the connection point
+            let voltage = 230.0 + 4.0 * (self.random() - 0.5) - 2.0 * net_kw.min(0.0);
+            values.voltage_V = Some((voltage * 10.0).round() / 10.0);
+            values.current_A = Some((net_kw.abs() * 1000.0 / voltage).round());
+            values.import_kW = Some(round(net_kw.max(0.0)));
+            values.export_kW = Some(round((-net_kw).max(0.0)));
+        }
+        P1Telegram {
+            dialect: self.settings.dialect,
+            timestamp: Some(local),
+            peak_hour_consumption_kWh: Some(round(self.counters[0])),
+            off_hour_consumption_kWh: Some(round(self.counters[1])),
+            peak_hour_injection_kWh: Some(round(self.counters[2])),
+            off_hour_injection_kWh: Some(round(self.counters[3])),
+            import_kW: Some(round(import_kw)),
+            export_kW: Some(round(export_kw)),
+            tariff: Some(tariff),
+            gas: self.gas_reading.as_ref().map(|gas| GasReading {
+                timestamp: gas.timestamp,
+                m3: gas.m3,
+            }),
+            phases,
+            quality: PowerQuality::default(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use time::{Date, Month};
+
+    fn at(month: Month, day: u8, hour: u8, offset_hours: i8) -> OffsetDateTime {
+        Date::from_calendar_date(2024, month, day)
+            .unwrap()
+            .with_hms(hour, 0, 0)
+            .unwrap()
+            .assume_offset(UtcOffset::from_hms(offset_hours, 0, 0).unwrap())
+    }
+
+    #[test]
+    fn clear_sky_pv_follows_day_and_season() {
+        assert_eq!(clear_sky_pv_kw(at(Month::June, 21, 2, 2), 4.0), 0.0);
+        assert_eq!(clear_sky_pv_kw(at(Month::December, 21, 18, 1), 4.0), 0.0);
+        let june_noon = clear_sky_pv_kw(at(Month::June, 21, 13, 2), 4.0);
+        let december_noon = clear_sky_pv_kw(at(Month::December, 21, 12, 1), 4.0);
+        assert!(june_noon > 3.0 && june_noon <= 4.0, "{}", june_noon);
+        assert!(
+            december_noon > 0.5 && december_noon < june_noon / 2.0,
+            "{}",
+            december_noon
+        );
+    }
+
+    #[test]
+    fn tariff_peak_on_weekdays_only() {
+        // 2024-10-25 is a Friday, 2024-10-26 a Saturday
+        assert_eq!(tariff(at(Month::October, 25, 6, 2)), 2);
+        assert_eq!(tariff(at(Month::October, 25, 7, 2)), 1);
+        assert_eq!(tariff(at(Month::October, 25, 21, 2)), 1);
+        assert_eq!(tariff(at(Month::October, 25, 22, 2)), 2);
+        assert_eq!(tariff(at(Month::October, 26, 12, 2)), 2);
+    }
+
+    #[test]
+    fn step_counters_only_increase() {
+        let mut simulator = MeterSimulator::new(SimulatorSettings::default());
+        let start = at(Month::March, 20, 0, 0);
+        let mut previous = simulator.step(start);
+        for minute in 1..(24 * 60) {
+            let telegram = simulator.step(start + Duration::minutes(minute));
+            for (before, after) in [
+                (
+                    previous.peak_hour_consumption_kWh,
+                    telegram.peak_hour_consumption_kWh,
+                ),
+                (
+                    previous.off_hour_consumption_kWh,
+                    telegram.off_hour_consumption_kWh,
+                ),
+                (
+                    previous.peak_hour_injection_kWh,
+                    telegram.peak_hour_injection_kWh,
+                ),
+                (
+                    previous.off_hour_injection_kWh,
+                    telegram.off_hour_injection_kWh,
+                ),
+            ] {
+                assert!(after >= before);
+            }
+            assert!(telegram.gas.as_ref().unwrap().m3 >= previous.gas.as_ref().unwrap().m3);
+            assert!(telegram.import_kW == Some(0.0) || telegram.export_kW == Some(0.0));
+            previous = telegram;
+        }
+        // Something was injected during the day
+        assert!(previous.peak_hour_injection_kWh > Some(6254.732));
+    }
+}
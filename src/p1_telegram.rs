@@ -0,0 +1,735 @@
+use std::str::FromStr;
+
+use time::OffsetDateTime;
+
+use crate::data::P1Measurement;
+use crate::p1_meter::{
+    assume_meter_time, crc16, parse_counter, parse_failure_log, parse_quantity, parse_timestamp,
+    parse_yymmddhhmmss, split_cosem_line, LiveP1Values, PowerQuality,
+};
+
+/// Supported versions of the DSMR P1 specification.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Dialect {
+    /// DSMR 2.2 (and 3.0): 9600 baud 7E1, no meter timestamp, gas value on
+    /// the line following 0-1:24.3.0, no CRC.
+    Dsmr22,
+    /// DSMR 4.x: 115200 baud 8N1, CRC, hourly gas value in 0-1:24.2.1.
+    Dsmr4,
+    /// DSMR 5.x (and the Belgian e-MUCS variant): like DSMR 4 with more
+    /// precision and gas values every 5 minutes.
+    Dsmr5,
+}
+
+impl FromStr for Dialect {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "dsmr22" | "2.2" | "dsmr3" | "3.0" => Ok(Dialect::Dsmr22),
+            "dsmr4" | "4" | "4.2" => Ok(Dialect::Dsmr4),
+            "dsmr5" | "5" | "5.0" => Ok(Dialect::Dsmr5),
+            _ => Err(format!("Unknown DSMR dialect {}", s)),
+        }
+    }
+}
+
+impl Dialect {
+    /// `stty` settings of the serial port for this dialect.
+    pub fn serial_settings(&self) -> &'static [&'static str] {
+        match self {
+            Dialect::Dsmr22 => &["9600", "cs7", "parenb", "-parodd", "-cstopb"],
+            Dialect::Dsmr4 | Dialect::Dsmr5 => &["115200", "cs8", "-parenb", "-cstopb"],
+        }
+    }
+}
+
+/// Meter models named in the header line (`/XXX5<model>`, `XXX` being the
+/// manufacturer) whose dialect is known, for the telegrams without version
+/// object.
+const HEADER_MODELS: [(&str, Dialect); 8] = [
+    // Iskra ME382, Kamstrup 162/351 and 382, Xemex: DSMR 2.2 and 3.0
+    ("\\2ME382", Dialect::Dsmr22),
+    (" KA6U", Dialect::Dsmr22),
+    (" ZABF", Dialect::Dsmr22),
+    ("XMXABCE", Dialect::Dsmr22),
+    // Iskra AM550, Landis+Gyr E350 and Sagemcom T210-D/XS210 state it,
+    // Belgian meters are based on DSMR 5
+    ("\\2M550", Dialect::Dsmr5),
+    ("ESMR5", Dialect::Dsmr5),
+    ("ESMR 5", Dialect::Dsmr5),
+    ("/FLU5", Dialect::Dsmr5),
+];
+
+/// Guess the dialect of a telegram.
+///
+/// The version object 1-3:0.2.8 (introduced with DSMR 4) settles the
+/// question, else the meter model of the header line (cf `HEADER_MODELS`),
+/// else the meter time 0-0:1.0.0 or the CRC after `!` that DSMR 2.2 meters
+/// do not send.
+pub fn detect_dialect(telegram: &str) -> Result<Dialect, String> {
+    let mut lines = telegram.lines();
+    let header = match lines.next() {
+        Some(header) if header.starts_with('/') => header,
+        _ => return Err("Telegram does not start with a header line".to_string()),
+    };
+    let mut has_crc = false;
+    let mut has_time = false;
+    for line in lines {
+        if let Some(version) = line
+            .strip_prefix("1-3:0.2.8(")
+            .and_then(|v| v.trim_end().strip_suffix(')'))
+        {
+            return if version.starts_with('4') {
+                Ok(Dialect::Dsmr4)
+            } else {
+                Ok(Dialect::Dsmr5)
+            };
+        }
+        if let Some(crc) = line.strip_prefix('!') {
+            has_crc = !crc.trim().is_empty();
+        }
+        has_time |= line.starts_with("0-0:1.0.0(");
+    }
+    if let Some((_, dialect)) = HEADER_MODELS
+        .iter()
+        .find(|(model, _)| header.contains(model))
+    {
+        return Ok(*dialect);
+    }
+    Ok(if has_crc || has_time {
+        Dialect::Dsmr4
+    } else {
+        Dialect::Dsmr22
+    })
+}
+
+#[derive(Debug, PartialEq)]
+pub struct GasReading {
+    /// Time of the last gas meter reading (meter time)
+    pub timestamp: OffsetDateTime,
+    pub m3: f64,
+}
+
+/// Instantaneous values of one phase (L1, L2 or L3)
+#[derive(Debug, Default, PartialEq, Clone)]
+#[allow(non_snake_case)]
+pub struct PhaseValues {
+    /// 1-0:32.7.0, 1-0:52.7.0, 1-0:72.7.0
+    pub voltage_V: Option<f64>,
+    /// 1-0:31.7.0, 1-0:51.7.0, 1-0:71.7.0
+    pub current_A: Option<f64>,
+    /// 1-0:21.7.0, 1-0:41.7.0, 1-0:61.7.0
+    pub import_kW: Option<f64>,
+    /// 1-0:22.7.0, 1-0:42.7.0, 1-0:62.7.0
+    pub export_kW: Option<f64>,
+}
+
+/// Values of a telegram independent of the dialect it was sent in.
+#[derive(Debug, PartialEq)]
+#[allow(non_snake_case)]
+pub struct P1Telegram {
+    pub dialect: Dialect,
+    /// Meter time, absent from DSMR 2.2 telegrams
+    pub timestamp: Option<OffsetDateTime>,
+    pub peak_hour_consumption_kWh: Option<f64>,
+    pub off_hour_consumption_kWh: Option<f64>,
+    pub peak_hour_injection_kWh: Option<f64>,
+    pub off_hour_injection_kWh: Option<f64>,
+    pub import_kW: Option<f64>,
+    pub export_kW: Option<f64>,
+    /// 1 for peak (day) tariff, 2 for off-peak (night) tariff
+    pub tariff: Option<u8>,
+    pub gas: Option<GasReading>,
+    /// L1, L2, L3 (not sent by DSMR 2.2 meters)
+    pub phases: [PhaseValues; 3],
+    /// Power failures and voltage events (not sent by DSMR 2.2 meters)
+    pub quality: PowerQuality,
+}
+
+impl P1Telegram {
+    fn empty(dialect: Dialect) -> Self {
+        P1Telegram {
+            dialect,
+            timestamp: None,
+            peak_hour_consumption_kWh: None,
+            off_hour_consumption_kWh: None,
+            peak_hour_injection_kWh: None,
+            off_hour_injection_kWh: None,
+            import_kW: None,
+            export_kW: None,
+            tariff: None,
+            gas: None,
+            phases: Default::default(),
+            quality: PowerQuality::default(),
+        }
+    }
+
+    /// Instantaneous values, for the live view.
+    pub fn live_values(&self) -> LiveP1Values {
+        LiveP1Values {
+            timestamp: self.timestamp.map(OffsetDateTime::unix_timestamp),
+            import_kW: self.import_kW,
+            export_kW: self.export_kW,
+            phase_import_kW: self.phases.each_ref().map(|phase| phase.import_kW),
+            phase_export_kW: self.phases.each_ref().map(|phase| phase.export_kW),
+            tariff: self.tariff,
+        }
+    }
+
+    /// Time the values of the telegram are stored at: the meter time, or for
+    /// DSMR 2.2 (which has none) the time of the last hourly gas reading.
+    /// Readings keyed by the latter keep the first telegram after each gas
+    /// reading, which is all a capture without reception times can tell.
+    pub fn measurement_time(&self) -> Option<i64> {
+        self.timestamp
+            .or_else(|| self.gas.as_ref().map(|gas| gas.timestamp))
+            .map(OffsetDateTime::unix_timestamp)
+    }
+
+    /// Row of `p1_measurements` at `timestamp`, if the telegram has the four
+    /// meter readings.
+    pub fn measurement(&self, timestamp: i64) -> Option<P1Measurement> {
+        Some(P1Measurement {
+            timestamp,
+            peak_conso_kWh: self.peak_hour_consumption_kWh?,
+            off_conso_kWh: self.off_hour_consumption_kWh?,
+            peak_inj_kWh: self.peak_hour_injection_kWh?,
+            off_inj_kWh: self.off_hour_injection_kWh?,
+        })
+    }
+
+    /// Store the objects all dialects have in common, ignore the others.
+    fn common_object(&mut self, line: &str, obis: &str, value: &str) -> Result<(), String> {
+        match obis {
+            "1-0:1.8.1" => self.peak_hour_consumption_kWh = Some(parse_quantity(value, "kWh")?),
+            "1-0:1.8.2" => self.off_hour_consumption_kWh = Some(parse_quantity(value, "kWh")?),
+            "1-0:2.8.1" => self.peak_hour_injection_kWh = Some(parse_quantity(value, "kWh")?),
+            "1-0:2.8.2" => self.off_hour_injection_kWh = Some(parse_quantity(value, "kWh")?),
+            "1-0:1.7.0" => self.import_kW = Some(parse_quantity(value, "kW")?),
+            "1-0:2.7.0" => self.export_kW = Some(parse_quantity(value, "kW")?),
+            "1-0:32.7.0" => self.phases[0].voltage_V = Some(parse_quantity(value, "V")?),
+            "1-0:52.7.0" => self.phases[1].voltage_V = Some(parse_quantity(value, "V")?),
+            "1-0:72.7.0" => self.phases[2].voltage_V = Some(parse_quantity(value, "V")?),
+            "1-0:31.7.0" => self.phases[0].current_A = Some(parse_quantity(value, "A")?),
+            "1-0:51.7.0" => self.phases[1].current_A = Some(parse_quantity(value, "A")?),
+            "1-0:71.7.0" => self.phases[2].current_A = Some(parse_quantity(value, "A")?),
+            "1-0:21.7.0" => self.phases[0].import_kW = Some(parse_quantity(value, "kW")?),
+            "1-0:41.7.0" => self.phases[1].import_kW = Some(parse_quantity(value, "kW")?),
+            "1-0:61.7.0" => self.phases[2].import_kW = Some(parse_quantity(value, "kW")?),
+            "1-0:22.7.0" => self.phases[0].export_kW = Some(parse_quantity(value, "kW")?),
+            "1-0:42.7.0" => self.phases[1].export_kW = Some(parse_quantity(value, "kW")?),
+            "1-0:62.7.0" => self.phases[2].export_kW = Some(parse_quantity(value, "kW")?),
+            "0-0:96.14.0" => {
+                self.tariff = Some(u8::from_str(value).map_err(|e| format!("{}: {}", line, e))?)
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+}
+
+fn flagged_timestamp(line: &str, value: &str) -> Result<OffsetDateTime, String> {
+    parse_timestamp(value)
+        .map_err(|e| format!("{}: {}", line, e))?
+        .ok_or_else(|| format!("{}: malformed timestamp", line))
+}
+
+/// DSMR 2.2: the gas reading is announced by 0-1:24.3.0 (with a timestamp
+/// lacking the summer/winter flag) and its value follows on the next line:
+///
+/// 0-1:24.3.0(121030140000)(00)(60)(1)(0-1:24.2.1)(m3)
+/// (00610.491)
+pub fn parse_dsmr22(telegram: &str) -> Result<P1Telegram, String> {
+    let mut result = P1Telegram::empty(Dialect::Dsmr22);
+    let mut gas_timestamp: Option<OffsetDateTime> = None;
+    for line in telegram.lines() {
+        let (obis, values) = match split_cosem_line(line) {
+            Some(cosem) => cosem,
+            None => continue,
+        };
+        match (obis, values.as_slice()) {
+            ("", [m3]) => {
+                if let Some(timestamp) = gas_timestamp.take() {
+                    let m3 = f64::from_str(m3).map_err(|e| format!("{}: {}", line, e))?;
+                    result.gas = Some(GasReading { timestamp, m3 });
+                }
+            }
+            ("0-1:24.3.0", [timestamp, ..]) => {
+                gas_timestamp = Some(
+                    parse_yymmddhhmmss(timestamp)
+                        .map_err(|e| format!("{}: {}", line, e))
+                        .and_then(assume_meter_time)?,
+                );
+            }
+            (obis, [value]) => {
+                result.common_object(line, obis, value)?;
+            }
+            _ => {}
+        }
+    }
+    Ok(result)
+}
+
+/// DSMR 4.x and 5.x, the gas reading is a single object:
+///
+/// 0-1:24.2.1(101209110000W)(12785.123*m3)
+///
+/// Belgian meters use 0-1:24.2.3 instead.
+fn parse_dsmr4_or_5(telegram: &str, dialect: Dialect) -> Result<P1Telegram, String> {
+    let mut result = P1Telegram::empty(dialect);
+    for line in telegram.lines() {
+        let (obis, values) = match split_cosem_line(line) {
+            Some(cosem) => cosem,
+            None => continue,
+        };
+        match (obis, values.as_slice()) {
+            ("0-0:1.0.0", [value]) => result.timestamp = Some(flagged_timestamp(line, value)?),
+            (obis, [timestamp, m3])
+                if obis.starts_with("0-")
+                    && (obis.ends_with(":24.2.1") || obis.ends_with(":24.2.3")) =>
+            {
+                result.gas = Some(GasReading {
+                    timestamp: flagged_timestamp(line, timestamp)?,
+                    m3: parse_quantity(m3, "m3")?,
+                });
+            }
+            ("1-0:99.97.0", values) => {
+                result.quality.long_failure_log = parse_failure_log(line, values)?;
+            }
+            (obis, [value]) => match result.quality.counter(obis) {
+                Some(counter) => *counter = Some(parse_counter(line, value)?),
+                None => result.common_object(line, obis, value)?,
+            },
+            _ => {}
+        }
+    }
+    Ok(result)
+}
+
+pub fn parse_dsmr4(telegram: &str) -> Result<P1Telegram, String> {
+    parse_dsmr4_or_5(telegram, Dialect::Dsmr4)
+}
+
+pub fn parse_dsmr5(telegram: &str) -> Result<P1Telegram, String> {
+    parse_dsmr4_or_5(telegram, Dialect::Dsmr5)
+}
+
+/// Parse a telegram in the given dialect, or in the detected one.
+pub fn parse_telegram(telegram: &str, dialect: Option<Dialect>) -> Result<P1Telegram, String> {
+    let dialect = match dialect {
+        Some(dialect) => dialect,
+        None => detect_dialect(telegram)?,
+    };
+    match dialect {
+        Dialect::Dsmr22 => parse_dsmr22(telegram),
+        Dialect::Dsmr4 => parse_dsmr4(telegram),
+        Dialect::Dsmr5 => parse_dsmr5(telegram),
+    }
+}
+
+/// `YYMMDDhhmmssX` in the offset of `timestamp`: summer time (`S`) is
+/// assumed for UTC+2, winter time (`W`) otherwise.
+fn format_flagged_timestamp(timestamp: OffsetDateTime) -> String {
+    format!(
+        "{:02}{:02}{:02}{:02}{:02}{:02}{}",
+        timestamp.year() % 100,
+        timestamp.month() as u8,
+        timestamp.day(),
+        timestamp.hour(),
+        timestamp.minute(),
+        timestamp.second(),
+        if timestamp.offset().whole_hours() == 2 {
+            'S'
+        } else {
+            'W'
+        }
+    )
+}
+
+/// Serialize a telegram in its dialect, the reverse of `parse_telegram`.
+///
+/// `header` is the meter identification without the leading `/`.  Objects
+/// that are `None` are left out and the CRC is appended for DSMR 4 and 5.
+pub fn encode_telegram(telegram: &P1Telegram, header: &str) -> String {
+    let dsmr22 = telegram.dialect == Dialect::Dsmr22;
+    let mut text = format!("/{}\r\n\r\n", header);
+    match telegram.dialect {
+        Dialect::Dsmr22 => {}
+        Dialect::Dsmr4 => text.push_str("1-3:0.2.8(42)\r\n"),
+        Dialect::Dsmr5 => text.push_str("1-3:0.2.8(50)\r\n"),
+    }
+    if let (false, Some(timestamp)) = (dsmr22, telegram.timestamp) {
+        text.push_str(&format!(
+            "0-0:1.0.0({})\r\n",
+            format_flagged_timestamp(timestamp)
+        ));
+    }
+    for (obis, kwh) in [
+        ("1-0:1.8.1", telegram.peak_hour_consumption_kWh),
+        ("1-0:1.8.2", telegram.off_hour_consumption_kWh),
+        ("1-0:2.8.1", telegram.peak_hour_injection_kWh),
+        ("1-0:2.8.2", telegram.off_hour_injection_kWh),
+    ] {
+        if let Some(kwh) = kwh {
+            if dsmr22 {
+                text.push_str(&format!("{}({:09.3}*kWh)\r\n", obis, kwh));
+            } else {
+                text.push_str(&format!("{}({:010.3}*kWh)\r\n", obis, kwh));
+            }
+        }
+    }
+    if let Some(tariff) = telegram.tariff {
+        text.push_str(&format!("0-0:96.14.0({:04})\r\n", tariff));
+    }
+    for (obis, kw) in [
+        ("1-0:1.7.0", telegram.import_kW),
+        ("1-0:2.7.0", telegram.export_kW),
+    ] {
+        if let Some(kw) = kw {
+            if dsmr22 {
+                text.push_str(&format!("{}({:07.2}*kW)\r\n", obis, kw));
+            } else {
+                text.push_str(&format!("{}({:06.3}*kW)\r\n", obis, kw));
+            }
+        }
+    }
+    if !dsmr22 {
+        for (phase, values) in ["32", "52", "72"].iter().zip(telegram.phases.iter()) {
+            if let Some(voltage) = values.voltage_V {
+                text.push_str(&format!("1-0:{}.7.0({:05.1}*V)\r\n", phase, voltage));
+            }
+        }
+        for (phase, values) in ["31", "51", "71"].iter().zip(telegram.phases.iter()) {
+            if let Some(current) = values.current_A {
+                // DSMR 5 sends whole amperes, some meters add decimals
+                if current.fract() == 0.0 {
+                    text.push_str(&format!("1-0:{}.7.0({:03}*A)\r\n", phase, current));
+                } else {
+                    text.push_str(&format!("1-0:{}.7.0({:06.2}*A)\r\n", phase, current));
+                }
+            }
+        }
+        for (phase, values) in ["21", "41", "61"].iter().zip(telegram.phases.iter()) {
+            if let Some(kw) = values.import_kW {
+                text.push_str(&format!("1-0:{}.7.0({:06.3}*kW)\r\n", phase, kw));
+            }
+        }
+        for (phase, values) in ["22", "42", "62"].iter().zip(telegram.phases.iter()) {
+            if let Some(kw) = values.export_kW {
+                text.push_str(&format!("1-0:{}.7.0({:06.3}*kW)\r\n", phase, kw));
+            }
+        }
+    }
+    if let Some(gas) = &telegram.gas {
+        let t = gas.timestamp;
+        if dsmr22 {
+            text.push_str(&format!(
+                "0-1:24.3.0({:02}{:02}{:02}{:02}{:02}{:02})(00)(60)(1)(0-1:24.2.1)(m3)\r\n({:09.3})\r\n",
+                t.year() % 100,
+                t.month() as u8,
+                t.day(),
+                t.hour(),
+                t.minute(),
+                t.second(),
+                gas.m3
+            ));
+        } else {
+            text.push_str(&format!(
+                "0-1:24.2.1({})({:09.3}*m3)\r\n",
+                format_flagged_timestamp(t),
+                gas.m3
+            ));
+        }
+    }
+    text.push('!');
+    if !dsmr22 {
+        text.push_str(&format!("{:04X}", crc16(text.as_bytes())));
+    }
+    text.push_str("\r\n");
+    text
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::p1_meter::PowerFailure;
+    use time::{Date, Month, UtcOffset};
+
+    const DSMR22: &str = "/ISk5\\2ME382-1003\r\n\r\n0-0:96.1.1(4B413650303035303030303030303030)\r\n1-0:1.8.1(00185.000*kWh)\r\n1-0:1.8.2(00084.000*kWh)\r\n1-0:2.8.1(00013.000*kWh)\r\n1-0:2.8.2(00019.000*kWh)\r\n0-0:96.14.0(0001)\r\n1-0:1.7.0(0000.98*kW)\r\n1-0:2.7.0(0000.00*kW)\r\n0-0:17.0.0(999*A)\r\n0-0:96.3.10(1)\r\n0-0:96.13.1()\r\n0-0:96.13.0()\r\n0-1:96.1.0(3238313031453631373038383630303036)\r\n0-1:24.1.0(03)\r\n0-1:24.3.0(121030140000)(00)(60)(1)(0-1:24.2.1)(m3)\r\n(00610.491)\r\n0-1:24.4.0(1)\r\n!\r\n";
+
+    const DSMR42: &str = "/KFM5KAIFA-METER\r\n\r\n1-3:0.2.8(42)\r\n0-0:1.0.0(161113205757W)\r\n0-0:96.1.1(3360316130303239313435363636363132)\r\n1-0:1.8.1(001581.123*kWh)\r\n1-0:1.8.2(001435.706*kWh)\r\n1-0:2.8.1(000000.000*kWh)\r\n1-0:2.8.2(000000.000*kWh)\r\n0-0:96.14.0(0002)\r\n1-0:1.7.0(02.027*kW)\r\n1-0:2.7.0(00.000*kW)\r\n0-1:24.1.0(003)\r\n0-1:96.1.0(3238313031453631373038383630303036)\r\n0-1:24.2.1(161129200000W)(00981.443*m3)\r\n!6796\r\n";
+
+    const DSMR50: &str = "/Ene5\\XS210 ESMR 5.0\r\n\r\n1-3:0.2.8(50)\r\n0-0:1.0.0(241025191816S)\r\n1-0:1.8.1(002654.919*kWh)\r\n1-0:1.8.2(002420.293*kWh)\r\n1-0:2.8.1(006254.732*kWh)\r\n1-0:2.8.2(002457.202*kWh)\r\n0-0:96.14.0(0001)\r\n1-0:1.7.0(00.000*kW)\r\n1-0:2.7.0(02.345*kW)\r\n0-1:24.2.3(241025191500S)(04567.890*m3)\r\n!ABCD\r\n";
+
+    /// Meter time: CET in winter, CEST in summer
+    fn meter_time(year: i32, month: Month, day: u8, h: u8, m: u8, s: u8) -> OffsetDateTime {
+        let summer = (4..=9).contains(&(month as u8)) || (month == Month::October && day < 27);
+        Date::from_calendar_date(year, month, day)
+            .unwrap()
+            .with_hms(h, m, s)
+            .unwrap()
+            .assume_offset(UtcOffset::from_hms(if summer { 2 } else { 1 }, 0, 0).unwrap())
+    }
+
+    #[test]
+    fn detect_dialect_from_version_header_and_crc() {
+        assert_eq!(detect_dialect(DSMR22), Ok(Dialect::Dsmr22));
+        assert_eq!(detect_dialect(DSMR42), Ok(Dialect::Dsmr4));
+        assert_eq!(detect_dialect(DSMR50), Ok(Dialect::Dsmr5));
+        assert_eq!(
+            detect_dialect("/XMX5\r\n1-0:1.8.1(000001.000*kWh)\r\n!1234\r\n"),
+            Ok(Dialect::Dsmr4)
+        );
+        assert_eq!(
+            detect_dialect("/Ene5\\T210-D ESMR5.0\r\n1-0:1.8.1(000001.000*kWh)\r\n!\r\n"),
+            Ok(Dialect::Dsmr5)
+        );
+        assert_eq!(
+            detect_dialect("/FLU5\\253769484_A\r\n1-0:1.8.1(000001.000*kWh)\r\n!1234\r\n"),
+            Ok(Dialect::Dsmr5)
+        );
+        assert_eq!(
+            detect_dialect("/XMX5\r\n0-0:1.0.0(241025020000S)\r\n!\r\n"),
+            Ok(Dialect::Dsmr4)
+        );
+        assert_eq!(
+            detect_dialect("/KMP5 KA6U001585575011\r\n1-0:1.8.1(00001.000*kWh)\r\n!1234\r\n"),
+            Ok(Dialect::Dsmr22)
+        );
+        assert!(detect_dialect("1-0:1.8.1(000001.000*kWh)\r\n!\r\n").is_err());
+    }
+
+    #[test]
+    fn dialect_from_str() {
+        assert_eq!(Dialect::from_str("DSMR22"), Ok(Dialect::Dsmr22));
+        assert_eq!(Dialect::from_str("4.2"), Ok(Dialect::Dsmr4));
+        assert_eq!(Dialect::from_str(" dsmr5 "), Ok(Dialect::Dsmr5));
+        assert!(Dialect::from_str("dsmr6").is_err());
+    }
+
+    #[test]
+    fn parse_dsmr22_gas_on_next_line() {
+        assert_eq!(
+            parse_telegram(DSMR22, None),
+            Ok(P1Telegram {
+                dialect: Dialect::Dsmr22,
+                timestamp: None,
+                peak_hour_consumption_kWh: Some(185.0),
+                off_hour_consumption_kWh: Some(84.0),
+                peak_hour_injection_kWh: Some(13.0),
+                off_hour_injection_kWh: Some(19.0),
+                import_kW: Some(0.98),
+                export_kW: Some(0.0),
+                tariff: Some(1),
+                gas: Some(GasReading {
+                    timestamp: meter_time(2012, Month::October, 30, 14, 0, 0),
+                    m3: 610.491
+                }),
+                phases: Default::default(),
+                quality: PowerQuality::default(),
+            })
+        );
+    }
+
+    #[test]
+    fn dsmr22_measurement_at_gas_reading_time() {
+        let dsmr22 = parse_telegram(DSMR22, None).unwrap();
+        let hour = meter_time(2012, Month::October, 30, 14, 0, 0).unix_timestamp();
+        assert_eq!(dsmr22.measurement_time(), Some(hour));
+        assert_eq!(
+            dsmr22.measurement(hour),
+            Some(P1Measurement {
+                timestamp: hour,
+                peak_conso_kWh: 185.0,
+                off_conso_kWh: 84.0,
+                peak_inj_kWh: 13.0,
+                off_inj_kWh: 19.0,
+            })
+        );
+        let dsmr50 = parse_telegram(DSMR50, None).unwrap();
+        assert_eq!(
+            dsmr50.measurement_time(),
+            Some(meter_time(2024, Month::October, 25, 19, 18, 16).unix_timestamp())
+        );
+    }
+
+    #[test]
+    fn parse_dsmr42_and_dsmr50_same_model() {
+        let dsmr42 = parse_telegram(DSMR42, None).unwrap();
+        assert_eq!(
+            dsmr42.timestamp,
+            Some(meter_time(2016, Month::November, 13, 20, 57, 57))
+        );
+        assert_eq!(dsmr42.peak_hour_consumption_kWh, Some(1581.123));
+        assert_eq!(dsmr42.import_kW, Some(2.027));
+        assert_eq!(dsmr42.tariff, Some(2));
+        assert_eq!(
+            dsmr42.gas,
+            Some(GasReading {
+                timestamp: meter_time(2016, Month::November, 29, 20, 0, 0),
+                m3: 981.443
+            })
+        );
+        let dsmr50 = parse_telegram(DSMR50, None).unwrap();
+        assert_eq!(dsmr50.dialect, Dialect::Dsmr5);
+        assert_eq!(
+            dsmr50.timestamp,
+            Some(meter_time(2024, Month::October, 25, 19, 18, 16))
+        );
+        assert_eq!(dsmr50.off_hour_injection_kWh, Some(2457.202));
+        assert_eq!(dsmr50.export_kW, Some(2.345));
+        assert_eq!(
+            dsmr50.gas,
+            Some(GasReading {
+                timestamp: meter_time(2024, Month::October, 25, 19, 15, 0),
+                m3: 4567.89
+            })
+        );
+    }
+
+    #[test]
+    fn parse_and_encode_phase_values() {
+        let telegram = "/Ene5\\XS210 ESMR 5.0\r\n\r\n1-3:0.2.8(50)\r\n1-0:32.7.0(220.1*V)\r\n1-0:52.7.0(220.2*V)\r\n1-0:72.7.0(220.3*V)\r\n1-0:31.7.0(001*A)\r\n1-0:51.7.0(002*A)\r\n1-0:71.7.0(003.50*A)\r\n1-0:21.7.0(01.111*kW)\r\n1-0:41.7.0(02.222*kW)\r\n1-0:61.7.0(03.333*kW)\r\n1-0:22.7.0(04.444*kW)\r\n1-0:42.7.0(05.555*kW)\r\n1-0:62.7.0(06.666*kW)\r\n!\r\n";
+        let parsed = parse_telegram(telegram, None).unwrap();
+        assert_eq!(
+            parsed.phases,
+            [
+                PhaseValues {
+                    voltage_V: Some(220.1),
+                    current_A: Some(1.0),
+                    import_kW: Some(1.111),
+                    export_kW: Some(4.444)
+                },
+                PhaseValues {
+                    voltage_V: Some(220.2),
+                    current_A: Some(2.0),
+                    import_kW: Some(2.222),
+                    export_kW: Some(5.555)
+                },
+                PhaseValues {
+                    voltage_V: Some(220.3),
+                    current_A: Some(3.5),
+                    import_kW: Some(3.333),
+                    export_kW: Some(6.666)
+                },
+            ]
+        );
+        let encoded = encode_telegram(&parsed, "Ene5\\XS210 ESMR 5.0");
+        assert!(
+            encoded.contains("1-0:31.7.0(001*A)\r\n1-0:51.7.0(002*A)\r\n1-0:71.7.0(003.50*A)\r\n")
+        );
+        assert_eq!(parse_telegram(&encoded, None), Ok(parsed));
+    }
+
+    #[test]
+    fn live_values_of_telegram() {
+        let telegram = "/ISk5\\2MT382-1000\n0-0:1.0.0(241025000000S)\n0-0:96.14.0(0002)\n1-0:1.7.0(01.193*kW)\n1-0:2.7.0(00.000*kW)\n1-0:21.7.0(00.100*kW)\n1-0:41.7.0(01.093*kW)\n1-0:61.7.0(00.000*kW)\n1-0:22.7.0(00.000*kW)\n1-0:42.7.0(00.000*kW)\n1-0:62.7.0(00.000*kW)\n!";
+        assert_eq!(
+            parse_telegram(telegram, None).unwrap().live_values(),
+            LiveP1Values {
+                timestamp: Some(1729807200),
+                import_kW: Some(1.193),
+                export_kW: Some(0.0),
+                phase_import_kW: [Some(0.1), Some(1.093), Some(0.0)],
+                phase_export_kW: [Some(0.0), Some(0.0), Some(0.0)],
+                tariff: Some(2),
+            }
+        );
+    }
+
+    #[test]
+    fn parse_power_quality() {
+        let telegram = "/ISK5\\2M550E-1012\n0-0:96.7.21(00004)\n0-0:96.7.9(00002)\n1-0:99.97.0(2)(0-0:96.7.19)(101208152415W)(0000000240*s)(101208151004W)(0000000301*s)\n1-0:32.32.0(00002)\n1-0:52.32.0(00001)\n1-0:72.32.0(00000)\n1-0:32.36.0(00000)\n1-0:52.36.0(00003)\n1-0:72.36.0(00000)\n!";
+        let at = |hour, minute, second| {
+            Date::from_calendar_date(2010, Month::December, 8)
+                .unwrap()
+                .with_hms(hour, minute, second)
+                .unwrap()
+                .assume_offset(UtcOffset::from_hms(1, 0, 0).unwrap())
+        };
+        assert_eq!(
+            parse_telegram(telegram, None).unwrap().quality,
+            PowerQuality {
+                power_failures: Some(4),
+                long_power_failures: Some(2),
+                long_failure_log: vec![
+                    PowerFailure {
+                        end: at(15, 24, 15),
+                        duration_s: 240
+                    },
+                    PowerFailure {
+                        end: at(15, 10, 4),
+                        duration_s: 301
+                    },
+                ],
+                voltage_sags: [Some(2), Some(1), Some(0)],
+                voltage_swells: [Some(0), Some(3), Some(0)],
+            }
+        );
+    }
+
+    #[test]
+    fn parse_power_quality_empty_and_malformed_log() {
+        let quality = |objects: &str| {
+            parse_telegram(&format!("/ISK5\\2M550E-1012\n{}\n!", objects), None)
+                .map(|telegram| telegram.quality)
+        };
+        assert_eq!(
+            quality("1-0:99.97.0()\n1-0:99.97.0(0)(0-0:96.7.19)"),
+            Ok(PowerQuality::default())
+        );
+        assert!(quality("1-0:99.97.0(2)(0-0:96.7.19)(101208152415W)(0000000240*s)").is_err());
+        assert!(quality("1-0:99.97.0(1)(0-0:96.7.19)(101208152415W)(0000000240)").is_err());
+        assert!(quality("1-0:99.97.0(18446744073709551615)(0-0:96.7.19)").is_err());
+        assert!(quality("0-0:96.7.21(-1)").is_err());
+    }
+
+    #[test]
+    fn parse_telegram_with_overridden_dialect() {
+        let as_dsmr5 = parse_telegram(DSMR42, Some(Dialect::Dsmr5)).unwrap();
+        assert_eq!(as_dsmr5.dialect, Dialect::Dsmr5);
+        assert_eq!(as_dsmr5.gas.map(|gas| gas.m3), Some(981.443));
+        // DSMR 2.2 does not know the 4.x gas object
+        assert_eq!(
+            parse_telegram(DSMR42, Some(Dialect::Dsmr22)).unwrap().gas,
+            None
+        );
+    }
+
+    #[test]
+    fn parse_telegram_bad_values_expect_err() {
+        assert!(parse_telegram(
+            "/ISk5\r\n0-1:24.3.0(129930140000)(00)(60)(1)(0-1:24.2.1)(m3)\r\n!\r\n",
+            None
+        )
+        .is_err());
+        assert!(parse_telegram("/ISk5\r\n1-0:1.7.0(0000.98*W)\r\n!\r\n", None).is_err());
+        assert!(parse_telegram(
+            "/KFM5\r\n1-3:0.2.8(42)\r\n0-1:24.2.1(161129200000W)(00981.443)\r\n!6796\r\n",
+            None
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn encode_telegram_round_trips_in_every_dialect() {
+        for telegram in [DSMR22, DSMR42, DSMR50] {
+            let parsed = parse_telegram(telegram, None).unwrap();
+            let encoded = encode_telegram(&parsed, "XMX5TEST");
+            assert_eq!(crate::p1_meter::validate_crc(&encoded), Ok(()));
+            assert_eq!(parse_telegram(&encoded, None), Ok(parsed));
+        }
+    }
+
+    #[test]
+    fn encode_telegram_dsmr42_layout() {
+        let parsed = parse_telegram(DSMR42, None).unwrap();
+        let encoded = encode_telegram(&parsed, "KFM5KAIFA-METER");
+        let body = "/KFM5KAIFA-METER\r\n\r\n1-3:0.2.8(42)\r\n0-0:1.0.0(161113205757W)\r\n1-0:1.8.1(001581.123*kWh)\r\n1-0:1.8.2(001435.706*kWh)\r\n1-0:2.8.1(000000.000*kWh)\r\n1-0:2.8.2(000000.000*kWh)\r\n0-0:96.14.0(0002)\r\n1-0:1.7.0(02.027*kW)\r\n1-0:2.7.0(00.000*kW)\r\n0-1:24.2.1(161129200000W)(00981.443*m3)\r\n!";
+        assert_eq!(
+            encoded,
+            format!("{}{:04X}\r\n", body, crc16(body.as_bytes()))
+        );
+    }
+}
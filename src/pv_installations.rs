@@ -0,0 +1,288 @@
+use std::collections::HashSet;
+use std::sync::Arc;
+
+use serde::{Deserialize, Deserializer, Serialize};
+use time::{Date, Month};
+
+use crate::get_env_var;
+use crate::inverter::InverterError;
+use crate::inverter_address::InverterAddress;
+use crate::inverter_cache::InverterCache;
+use crate::inverter_snapshot::ValueMapping;
+
+pub const DEFAULT_DASHBOARD_PATH: &str = "dyn/getDashValues.json";
+
+/// How the production of an installation is read
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum InverterKind {
+    /// SMA web UI dashboard, no login
+    SmaDashboard,
+    /// SMA web UI with login, cf `inverter_session`
+    SmaWebconnect,
+    /// Modbus TCP with the SunSpec models, cf `inverter_modbus`
+    SunspecModbus,
+    /// Not polled, typed in the meter readings form
+    Manual,
+}
+
+impl InverterKind {
+    pub fn is_polled(&self) -> bool {
+        !matches!(self, InverterKind::Manual)
+    }
+
+    /// Whether it is read through the SMA web UI, over HTTPS
+    pub fn is_web_ui(&self) -> bool {
+        matches!(
+            self,
+            InverterKind::SmaDashboard | InverterKind::SmaWebconnect
+        )
+    }
+
+    /// Whether its event log is read, cf `inverter_events`
+    pub fn has_event_log(&self) -> bool {
+        matches!(self, InverterKind::SmaWebconnect)
+    }
+}
+
+/// `YYYY-MM-DD`
+fn parse_date(s: &str) -> Result<Date, String> {
+    let mut parts = s.trim().splitn(3, '-').map(str::parse::<u16>);
+    match (parts.next(), parts.next(), parts.next()) {
+        (Some(Ok(year)), Some(Ok(month)), Some(Ok(day))) => Date::from_calendar_date(
+            year as i32,
+            Month::try_from(month as u8).map_err(|e| e.to_string())?,
+            day as u8,
+        )
+        .map_err(|e| e.to_string()),
+        _ => Err(format!("Expected YYYY-MM-DD, got '{}'", s)),
+    }
+}
+
+fn date<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<Date>, D::Error> {
+    match Option::<String>::deserialize(deserializer)? {
+        Some(s) => parse_date(&s).map(Some).map_err(serde::de::Error::custom),
+        None => Ok(None),
+    }
+}
+
+fn default_dashboard_path() -> String {
+    DEFAULT_DASHBOARD_PATH.to_string()
+}
+
+/// One PV installation of `RUST_HELLO_WORLD_PV_INSTALLATIONS`
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+#[allow(non_snake_case)]
+pub struct Installation {
+    /// Name of its series in the `inverter_production` table
+    pub name: String,
+    pub inverter: InverterKind,
+    /// Hostname (looked up in the DHCP leases) or address of the inverter
+    pub host: Option<String>,
+    /// PEM file of the certificate of the inverter web UI
+    pub certificate: Option<String>,
+    /// Modbus TCP port (502) and unit id (1), for `sunspec-modbus`
+    pub port: Option<u16>,
+    pub unit_id: Option<u8>,
+    #[serde(default = "default_dashboard_path")]
+    pub dashboard_path: String,
+    /// `usr` (default) or `istl`, for `sma-webconnect`
+    pub user: Option<String>,
+    pub password: Option<String>,
+    /// Device key and value keys, cf `ValueMapping`
+    pub device: Option<String>,
+    pub keys: Option<String>,
+    pub kWp: Option<f64>,
+    #[serde(default, deserialize_with = "date")]
+    pub commissioned: Option<Date>,
+    /// Field of the meter readings form to pre-fill with the total yield
+    pub form_field: Option<String>,
+}
+
+impl Installation {
+    pub fn mapping(&self) -> Result<ValueMapping, InverterError> {
+        let mapping = ValueMapping {
+            device: self.device.clone(),
+            ..Default::default()
+        };
+        match &self.keys {
+            Some(keys) => mapping.with_keys(keys).map_err(InverterError::Config),
+            None => Ok(mapping),
+        }
+    }
+
+    pub fn certificate(&self) -> Result<&str, InverterError> {
+        self.certificate
+            .as_deref()
+            .ok_or_else(|| InverterError::Config(format!("{}: no certificate", self.name)))
+    }
+
+    fn check(&self) -> Result<(), String> {
+        if self.name.is_empty()
+            || !self
+                .name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+        {
+            return Err(format!("Invalid installation name '{}'", self.name));
+        }
+        if self.inverter.is_polled() && self.host.is_none() {
+            return Err(format!("{}: host needed", self.name));
+        }
+        if self.inverter.is_web_ui() && self.certificate.is_none() {
+            return Err(format!("{}: certificate needed", self.name));
+        }
+        if self.inverter == InverterKind::SmaWebconnect && self.password.is_none() {
+            return Err(format!("{}: password needed", self.name));
+        }
+        self.mapping()
+            .map(|_| ())
+            .map_err(|e| format!("{}: {}", self.name, e))
+    }
+}
+
+/// Parse and check the JSON array of installations
+pub fn parse_installations(json: &str) -> Result<Vec<Installation>, String> {
+    let installations: Vec<Installation> = serde_json::from_str(json).map_err(|e| e.to_string())?;
+    let mut names = HashSet::new();
+    for installation in &installations {
+        installation.check()?;
+        if !names.insert(&installation.name) {
+            return Err(format!("Duplicate installation {}", installation.name));
+        }
+    }
+    Ok(installations)
+}
+
+/// The single installation configured before there was a registry, from
+/// `RUST_HELLO_WORLD_REMOTE_SERVER_*` and `RUST_HELLO_WORLD_INVERTER_*`
+fn legacy_installation() -> Option<Installation> {
+    let host = get_env_var("RUST_HELLO_WORLD_REMOTE_SERVER_HOST").ok()?;
+    Some(Installation {
+        name: "pv2022".to_string(),
+        inverter: InverterKind::SmaDashboard,
+        host: Some(host),
+        certificate: get_env_var("RUST_HELLO_WORLD_REMOTE_SERVER_CERT").ok(),
+        port: None,
+        unit_id: None,
+        dashboard_path: get_env_var("RUST_HELLO_WORLD_REMOTE_SERVER_PATH")
+            .unwrap_or_else(|_| default_dashboard_path()),
+        user: get_env_var("RUST_HELLO_WORLD_INVERTER_USER").ok(),
+        password: get_env_var("RUST_HELLO_WORLD_INVERTER_PASSWORD").ok(),
+        device: get_env_var("RUST_HELLO_WORLD_INVERTER_DEVICE").ok(),
+        keys: get_env_var("RUST_HELLO_WORLD_INVERTER_KEYS").ok(),
+        kWp: None,
+        commissioned: None,
+        form_field: Some("pv_2022_prod_kWh".to_string()),
+    })
+}
+
+/// An installation, where its inverter is and its last values
+pub struct PvInstallation {
+    pub config: Installation,
+    pub address: InverterAddress,
+    pub cache: InverterCache,
+}
+
+/// The installations of `RUST_HELLO_WORLD_PV_INSTALLATIONS` (JSON file)
+pub struct PvInstallations {
+    pub installations: Vec<Arc<PvInstallation>>,
+    pub lease_file: Option<String>,
+}
+
+impl PvInstallations {
+    /// The inverters are looked up in `lease_file`
+    pub fn new(installations: Vec<Installation>, lease_file: Option<String>) -> Self {
+        PvInstallations {
+            installations: installations
+                .into_iter()
+                .map(|config| {
+                    Arc::new(PvInstallation {
+                        address: InverterAddress::new(lease_file.clone(), config.host.clone()),
+                        cache: InverterCache::default(),
+                        config,
+                    })
+                })
+                .collect(),
+            lease_file,
+        }
+    }
+
+    /// The installations of the JSON file `path`
+    pub fn from_file(path: &str, lease_file: Option<String>) -> Result<Self, String> {
+        let json = std::fs::read_to_string(path).map_err(|e| format!("{}: {}", path, e))?;
+        let installations = parse_installations(&json).map_err(|e| format!("{}: {}", path, e))?;
+        Ok(PvInstallations::new(installations, lease_file))
+    }
+
+    pub fn from_env() -> Result<Self, String> {
+        let lease_file = get_env_var("RUST_HELLO_WORLD_DHCP_LEASES").ok();
+        match get_env_var("RUST_HELLO_WORLD_PV_INSTALLATIONS") {
+            Ok(path) => PvInstallations::from_file(&path, lease_file),
+            Err(_) => Ok(PvInstallations::new(
+                legacy_installation().into_iter().collect(),
+                lease_file,
+            )),
+        }
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Arc<PvInstallation>> {
+        self.installations
+            .iter()
+            .find(|installation| installation.config.name == name)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const INSTALLATIONS: &str = r#"[
+      {"name": "pv2012", "inverter": "manual", "kWp": 2.5, "commissioned": "2012-05-31",
+       "form_field": "pv_2012_prod_kWh"},
+      {"name": "pv2022", "inverter": "sma-dashboard", "host": "SMA3000000005",
+       "certificate": "/etc/inverter.pem", "kWp": 6.2, "commissioned": "2022-09-15",
+       "keys": "ac_power=6100_40263F00"},
+      {"name": "garage", "inverter": "sma-webconnect", "host": "192.168.1.30",
+       "certificate": "/etc/garage.pem", "password": "s3cret"},
+      {"name": "shed", "inverter": "sunspec-modbus", "host": "192.168.1.31", "unit_id": 126}
+    ]"#;
+
+    #[test]
+    fn installations_from_json() {
+        let installations = parse_installations(INSTALLATIONS).unwrap();
+        assert_eq!(installations.len(), 4);
+        assert_eq!(installations[0].inverter, InverterKind::Manual);
+        assert_eq!(
+            installations[0].commissioned,
+            Date::from_calendar_date(2012, Month::May, 31).ok()
+        );
+        assert_eq!(installations[1].dashboard_path, DEFAULT_DASHBOARD_PATH);
+        assert_eq!(installations[1].kWp, Some(6.2));
+        assert_eq!(installations[2].password.as_deref(), Some("s3cret"));
+        assert_eq!(installations[2].form_field, None);
+        assert_eq!(installations[3].inverter, InverterKind::SunspecModbus);
+        assert_eq!(
+            (installations[3].port, installations[3].unit_id),
+            (None, Some(126))
+        );
+    }
+
+    #[test]
+    fn invalid_installations() {
+        for json in [
+            r#"[{"name": "a", "inverter": "manual"}, {"name": "a", "inverter": "manual"}]"#,
+            r#"[{"name": "a b", "inverter": "manual"}]"#,
+            r#"[{"name": "a", "inverter": "sma-dashboard", "host": "SMA3000000005"}]"#,
+            r#"[{"name": "a", "inverter": "sma-webconnect", "host": "h", "certificate": "c"}]"#,
+            r#"[{"name": "a", "inverter": "sunspec-modbus"}]"#,
+            r#"[{"name": "a", "inverter": "manual", "keys": "power=1"}]"#,
+            r#"[{"name": "a", "inverter": "manual", "commissioned": "2022-13-01"}]"#,
+            r#"[{"name": "a", "inverter": "fronius"}]"#,
+            r#"[{"name": "a", "inverter": "manual", "kwp": 3}]"#,
+        ] {
+            assert!(parse_installations(json).is_err(), "{}", json);
+        }
+    }
+}
@@ -0,0 +1,541 @@
+use std::collections::BTreeMap;
+use std::fmt;
+
+use actix_web::{get, web, HttpResponse};
+use chrono::{Days, NaiveDate, TimeZone, Utc};
+use chrono_tz::Tz;
+use serde::{Deserialize, Serialize};
+use tera::Tera;
+use time::OffsetDateTime;
+
+use crate::data::{
+    select_hourly_total_yields, select_manual_total_yields, Database, HourlyYieldRow,
+};
+use crate::pv_installations::PvInstallations;
+use crate::{configured_timezone, format_timestamp};
+
+/// Flagged below this share of the yield of the other installations, per kWp
+const PEER_RATIO: f64 = 0.5;
+
+/// Flagged below this share of the clear-sky maximum, whatever the other
+/// installations: lower than most overcast days, and a trip of all of them
+/// shows too
+const CLEAR_SKY_RATIO: f64 = 0.1;
+
+/// Smaller deficits are not flagged: winter days [kWh]
+const MIN_DEFICIT_KWH: f64 = 1.0;
+
+/// The clear-sky maximum is the best day of the previous `RECENT_DAYS`, and
+/// of `SEASON_DAYS` around the same day a year before
+const RECENT_DAYS: u64 = 30;
+const SEASON_DAYS: u64 = 15;
+
+/// Days needed for a clear-sky maximum
+const MIN_REFERENCE_DAYS: usize = 7;
+
+/// Readings by hand further apart are not spread over the days between them
+const MAX_SPREAD_DAYS: i64 = 31;
+
+const DEFAULT_REPORT_DAYS: u32 = 90;
+/// Days reported at most, whatever the query
+const MAX_REPORT_DAYS: u32 = 3660;
+
+/// Daily yields of an installation
+#[derive(Debug)]
+#[allow(non_snake_case)]
+pub struct InstallationYields {
+    pub name: String,
+    pub kWp: Option<f64>,
+    pub commissioned: Option<NaiveDate>,
+    /// [kWh]
+    pub days: BTreeMap<NaiveDate, f64>,
+}
+
+/// Total yield of `installation` at the end of each local day with a value
+fn day_ends(rows: &[HourlyYieldRow], installation: &str, tz: Tz) -> BTreeMap<NaiveDate, f64> {
+    let mut day_ends: BTreeMap<NaiveDate, f64> = BTreeMap::new();
+    for row in rows.iter().filter(|row| row.installation == installation) {
+        let day = match Utc.timestamp_opt(row.hour_start, 0).single() {
+            Some(utc) => utc.with_timezone(&tz).date_naive(),
+            None => continue,
+        };
+        let end = day_ends.entry(day).or_insert(row.total_yield_kWh);
+        *end = end.max(row.total_yield_kWh);
+    }
+    day_ends
+}
+
+/// Increase of the total yield from one day end to the next, spread evenly
+/// over the days after the first up to the second, when at most
+/// `max_days` apart
+fn spread_yields(day_ends: &BTreeMap<NaiveDate, f64>, max_days: i64) -> BTreeMap<NaiveDate, f64> {
+    let mut days = BTreeMap::new();
+    for ((&first, &start), (&last, &end)) in day_ends.iter().zip(day_ends.iter().skip(1)) {
+        let span = (last - first).num_days();
+        // Lower: another inverter, or reset
+        if span > max_days || end < start {
+            continue;
+        }
+        let kwh = (end - start) / span as f64;
+        days.extend(
+            first
+                .iter_days()
+                .skip(1)
+                .take(span as usize)
+                .map(|day| (day, kwh)),
+        );
+    }
+    days
+}
+
+/// Yield of each local day of `installation`: the increase of its total yield
+/// over the day, if the day before has a value too.
+pub fn daily_yields(
+    rows: &[HourlyYieldRow],
+    installation: &str,
+    tz: Tz,
+) -> BTreeMap<NaiveDate, f64> {
+    spread_yields(&day_ends(rows, installation, tz), 1)
+}
+
+/// Yield of each local day of `installation` from its readings by hand, which
+/// are not taken every day: the increase since the previous reading, spread
+/// evenly over the days since, up to `MAX_SPREAD_DAYS`.
+pub fn manual_daily_yields(
+    rows: &[HourlyYieldRow],
+    installation: &str,
+    tz: Tz,
+) -> BTreeMap<NaiveDate, f64> {
+    spread_yields(&day_ends(rows, installation, tz), MAX_SPREAD_DAYS)
+}
+
+/// Best yield of the days before `day`: the previous `RECENT_DAYS`, and
+/// around the same day a year before
+pub fn clear_sky_max(days: &BTreeMap<NaiveDate, f64>, day: NaiveDate) -> Option<f64> {
+    let recent = days.range(day - Days::new(RECENT_DAYS)..day);
+    let year_before = day - Days::new(365);
+    let season =
+        days.range(year_before - Days::new(SEASON_DAYS)..=year_before + Days::new(SEASON_DAYS));
+    let values: Vec<f64> = recent.chain(season).map(|(_, kwh)| *kwh).collect();
+    if values.len() < MIN_REFERENCE_DAYS {
+        return None;
+    }
+    Some(values.into_iter().fold(0.0, f64::max))
+}
+
+fn median(values: &[f64]) -> Option<f64> {
+    let mut sorted = values.to_vec();
+    sorted.sort_by(f64::total_cmp);
+    match sorted.len() {
+        0 => None,
+        n if n % 2 == 1 => Some(sorted[n / 2]),
+        n => Some((sorted[n / 2 - 1] + sorted[n / 2]) / 2.0),
+    }
+}
+
+/// What a day is compared with
+#[derive(Debug, PartialEq, Clone)]
+pub enum Expectation {
+    /// The yield per kWp of the other installations with one that day
+    Peers(Vec<String>),
+    /// Its own `clear_sky_max`
+    ClearSky,
+}
+
+impl fmt::Display for Expectation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Expectation::Peers(names) => write!(f, "{} per kWp", names.join(", ")),
+            Expectation::ClearSky => write!(f, "clear-sky maximum"),
+        }
+    }
+}
+
+/// A comparison a day fell short of
+#[derive(Debug, PartialEq, Clone)]
+#[allow(non_snake_case)]
+pub struct Shortfall {
+    pub expectation: Expectation,
+    pub expected_kWh: f64,
+}
+
+#[derive(Debug, PartialEq)]
+#[allow(non_snake_case)]
+pub struct FlaggedDay {
+    pub installation: String,
+    pub day: NaiveDate,
+    pub yield_kWh: f64,
+    /// The comparisons that flagged it, the peers first
+    pub shortfalls: Vec<Shortfall>,
+}
+
+impl FlaggedDay {
+    /// Of the comparison expecting the most
+    #[allow(non_snake_case)]
+    pub fn expected_kWh(&self) -> f64 {
+        self.shortfalls
+            .iter()
+            .map(|shortfall| shortfall.expected_kWh)
+            .fold(0.0, f64::max)
+    }
+
+    #[allow(non_snake_case)]
+    pub fn deficit_kWh(&self) -> f64 {
+        self.expected_kWh() - self.yield_kWh
+    }
+}
+
+/// Days from `from` on which an installation produced much less than
+/// expected: below `PEER_RATIO` of the other installations normalized by
+/// kWp, or below `CLEAR_SKY_RATIO` of its own clear-sky maximum.  A dark day
+/// lowers all the installations alike, so the comparison between them flags
+/// a tripped one; the clear-sky maximum flags them all tripping at once, or
+/// one without peers.
+pub fn flag_days(installations: &[InstallationYields], from: NaiveDate) -> Vec<FlaggedDay> {
+    let mut flagged = Vec::new();
+    for installation in installations {
+        let commissioned = installation.commissioned.unwrap_or(NaiveDate::MIN);
+        for (&day, &yield_kwh) in installation.days.range(from.max(commissioned)..) {
+            let peers: Vec<(&str, f64)> = installations
+                .iter()
+                .filter(|peer| peer.name != installation.name)
+                .filter_map(|peer| {
+                    let kwp = peer.kWp.filter(|&kwp| kwp > 0.0)?;
+                    Some((peer.name.as_str(), peer.days.get(&day)? / kwp))
+                })
+                .collect();
+            let specific: Vec<f64> = peers.iter().map(|(_, kwh)| *kwh).collect();
+            let mut comparisons = Vec::new();
+            if let (Some(kwp), Some(peer_kwh)) = (installation.kWp, median(&specific)) {
+                let names = peers.iter().map(|(name, _)| name.to_string()).collect();
+                comparisons.push((Expectation::Peers(names), kwp * peer_kwh, PEER_RATIO));
+            }
+            if let Some(max) = clear_sky_max(&installation.days, day) {
+                comparisons.push((Expectation::ClearSky, max, CLEAR_SKY_RATIO));
+            }
+            let shortfalls: Vec<Shortfall> = comparisons
+                .into_iter()
+                .filter(|&(_, expected, ratio)| {
+                    yield_kwh < ratio * expected && expected - yield_kwh >= MIN_DEFICIT_KWH
+                })
+                .map(|(expectation, expected, _)| Shortfall {
+                    expectation,
+                    expected_kWh: expected,
+                })
+                .collect();
+            if !shortfalls.is_empty() {
+                flagged.push(FlaggedDay {
+                    installation: installation.name.clone(),
+                    day,
+                    yield_kWh: yield_kwh,
+                    shortfalls,
+                });
+            }
+        }
+    }
+    flagged.sort_by(|a, b| b.day.cmp(&a.day).then(a.installation.cmp(&b.installation)));
+    flagged
+}
+
+#[derive(Deserialize)]
+pub struct PerformanceQuery {
+    days: Option<u32>,
+}
+
+#[derive(Serialize)]
+struct FlaggedView {
+    day: String,
+    installation: String,
+    yield_kwh: String,
+    expected_kwh: String,
+    deficit_kwh: String,
+    compared_with: String,
+}
+
+#[derive(Serialize)]
+struct InstallationView {
+    name: String,
+    days: usize,
+    flagged: usize,
+    deficit_kwh: String,
+}
+
+/// Days of low production of the installations, from the total yields
+/// stored by the pollers and the backfill, or else read by hand
+#[get("/pv/performance")]
+pub async fn pv_performance_page(
+    tera: web::Data<Tera>,
+    database: web::Data<Database>,
+    installations: web::Data<PvInstallations>,
+    query: web::Query<PerformanceQuery>,
+) -> HttpResponse {
+    let days = query
+        .days
+        .unwrap_or(DEFAULT_REPORT_DAYS)
+        .clamp(1, MAX_REPORT_DAYS) as u64;
+    let now = OffsetDateTime::now_utc().unix_timestamp();
+    // The clear-sky maximum goes back a year
+    let lookback_days = days + 365 + SEASON_DAYS + 1;
+    let since = now - lookback_days as i64 * 86400;
+    let sqlite3 = database.sqlite3.clone();
+    let rows = web::block(move || {
+        Ok::<_, String>((
+            select_hourly_total_yields(&sqlite3, since)?,
+            select_manual_total_yields(&sqlite3, since)?,
+        ))
+    })
+    .await
+    .map_err(|e| e.to_string())
+    .and_then(|rows| rows);
+    let mut context = tera::Context::new();
+    context.insert("days", &days);
+    match rows {
+        Ok((rows, manual_rows)) => {
+            let tz = configured_timezone();
+            let yields: Vec<InstallationYields> = installations
+                .installations
+                .iter()
+                .map(|installation| {
+                    let name = &installation.config.name;
+                    // The inverter values where there are some
+                    let mut days = manual_daily_yields(&manual_rows, name, tz);
+                    days.extend(daily_yields(&rows, name, tz));
+                    InstallationYields {
+                        name: name.clone(),
+                        kWp: installation.config.kWp,
+                        commissioned: installation.config.commissioned.and_then(|date| {
+                            NaiveDate::from_ymd_opt(
+                                date.year(),
+                                date.month() as u32,
+                                date.day() as u32,
+                            )
+                        }),
+                        days,
+                    }
+                })
+                .collect();
+            let today = Utc
+                .timestamp_opt(now, 0)
+                .single()
+                .map(|utc| utc.with_timezone(&tz).date_naive())
+                .unwrap_or_default();
+            // Today is not over
+            let flagged: Vec<FlaggedDay> = flag_days(&yields, today - Days::new(days))
+                .into_iter()
+                .filter(|flagged| flagged.day < today)
+                .collect();
+            let installation_views: Vec<InstallationView> = yields
+                .iter()
+                .map(|installation| {
+                    let own: Vec<&FlaggedDay> = flagged
+                        .iter()
+                        .filter(|flagged| flagged.installation == installation.name)
+                        .collect();
+                    InstallationView {
+                        name: installation.name.clone(),
+                        days: installation
+                            .days
+                            .range(today - Days::new(days)..today)
+                            .count(),
+                        flagged: own.len(),
+                        deficit_kwh: format!(
+                            "{:.1}",
+                            own.iter()
+                                .fold(0.0, |sum, flagged| sum + flagged.deficit_kWh())
+                        ),
+                    }
+                })
+                .collect();
+            let flagged_views: Vec<FlaggedView> = flagged
+                .iter()
+                .map(|flagged| FlaggedView {
+                    day: flagged.day.to_string(),
+                    installation: flagged.installation.clone(),
+                    yield_kwh: format!("{:.1}", flagged.yield_kWh),
+                    expected_kwh: format!("{:.1}", flagged.expected_kWh()),
+                    deficit_kwh: format!("{:.1}", flagged.deficit_kWh()),
+                    compared_with: flagged
+                        .shortfalls
+                        .iter()
+                        .map(|shortfall| shortfall.expectation.to_string())
+                        .collect::<Vec<_>>()
+                        .join(" and "),
+                })
+                .collect();
+            context.insert("installations", &installation_views);
+            context.insert("flagged", &flagged_views);
+        }
+        Err(e) => {
+            log::error!("Unable to read the production: {}", e);
+            context.insert("error", &e);
+        }
+    }
+    context.insert("now", &format_timestamp(now));
+    let rendered = tera.render("pv_performance.html", &context).unwrap();
+    HttpResponse::Ok().body(rendered)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn date(day: u32) -> NaiveDate {
+        NaiveDate::from_ymd_opt(2024, 6, day).unwrap()
+    }
+
+    #[allow(non_snake_case)]
+    fn installation(name: &str, kWp: Option<f64>, days: &[(u32, f64)]) -> InstallationYields {
+        InstallationYields {
+            name: name.to_string(),
+            kWp,
+            commissioned: None,
+            days: days.iter().map(|&(day, kwh)| (date(day), kwh)).collect(),
+        }
+    }
+
+    #[test]
+    fn yields_per_local_day() {
+        let row = |hour_start, total| HourlyYieldRow {
+            installation: "pv2022".to_string(),
+            hour_start,
+            total_yield_kWh: total,
+        };
+        // 2024-06-20 and 21 in Brussels (UTC+2): the logger value at
+        // midnight closes the 20th
+        let rows = [
+            row(1718834400 - 3600, 1000.0),
+            row(1718834400 + 10 * 3600, 1010.0),
+            row(1718834400 + 19 * 3600, 1030.0),
+            row(1718920800 - 3600, 1030.5),
+            row(1718920800 + 18 * 3600, 1060.5),
+        ];
+        assert_eq!(
+            daily_yields(&rows, "pv2022", chrono_tz::Europe::Brussels),
+            BTreeMap::from([(date(20), 30.5), (date(21), 30.0)])
+        );
+        assert!(daily_yields(&rows, "pv2012", chrono_tz::Europe::Brussels).is_empty());
+    }
+
+    #[test]
+    fn tripped_installation_flagged() {
+        let pv2012 = installation(
+            "pv2012",
+            Some(2.5),
+            &[(1, 15.0), (2, 3.0), (3, 0.0), (4, 0.0)],
+        );
+        // Overcast on the 2nd: not flagged
+        let pv2022 = installation(
+            "pv2022",
+            Some(6.2),
+            &[(1, 37.0), (2, 7.0), (3, 38.0), (4, 36.0)],
+        );
+        let flagged = flag_days(&[pv2012, pv2022], date(2));
+        assert_eq!(flagged.len(), 2);
+        assert_eq!(flagged[0].installation, "pv2012");
+        assert_eq!(flagged[0].day, date(4));
+        assert_eq!(
+            flagged[0].shortfalls[0].expectation,
+            Expectation::Peers(vec!["pv2022".to_string()])
+        );
+        assert!((flagged[1].deficit_kWh() - 38.0 * 2.5 / 6.2).abs() < 1e-9);
+    }
+
+    #[test]
+    fn clear_sky_without_peers() {
+        let mut days: Vec<(u32, f64)> = (1..=10).map(|day| (day, 20.0 + day as f64)).collect();
+        days.extend([(11, 8.0), (12, 1.5)]);
+        let pv2022 = installation("pv2022", Some(6.2), &days);
+        assert_eq!(clear_sky_max(&pv2022.days, date(7)), None);
+        assert_eq!(clear_sky_max(&pv2022.days, date(12)), Some(30.0));
+        // A dark day passes, nearly nothing does not
+        assert_eq!(
+            flag_days(&[pv2022], date(1)),
+            vec![FlaggedDay {
+                installation: "pv2022".to_string(),
+                day: date(12),
+                yield_kWh: 1.5,
+                shortfalls: vec![Shortfall {
+                    expectation: Expectation::ClearSky,
+                    expected_kWh: 30.0,
+                }],
+            }]
+        );
+    }
+
+    #[test]
+    fn peers_and_clear_sky() {
+        let sunny: Vec<(u32, f64)> = (1..=10).map(|day| (day, 10.0)).collect();
+        let mut pv2012 = sunny.clone();
+        // Tripped on the 11th, both tripped on the 12th
+        pv2012.extend([(11, 0.0), (12, 0.0)]);
+        let mut pv2022 = sunny
+            .iter()
+            .map(|&(day, kwh)| (day, 2.0 * kwh))
+            .collect::<Vec<_>>();
+        pv2022.extend([(11, 20.0), (12, 0.5)]);
+        let flagged = flag_days(
+            &[
+                installation("pv2012", Some(2.5), &pv2012),
+                installation("pv2022", Some(5.0), &pv2022),
+            ],
+            date(11),
+        );
+        let shortfalls = |i: usize| -> Vec<Expectation> {
+            flagged[i]
+                .shortfalls
+                .iter()
+                .map(|shortfall| shortfall.expectation.clone())
+                .collect()
+        };
+        assert_eq!(flagged.len(), 3);
+        assert_eq!(
+            (flagged[0].day, flagged[0].installation.as_str()),
+            (date(12), "pv2012")
+        );
+        assert_eq!(shortfalls(0), vec![Expectation::ClearSky]);
+        assert_eq!(shortfalls(1), vec![Expectation::ClearSky]);
+        assert_eq!(
+            shortfalls(2),
+            vec![
+                Expectation::Peers(vec!["pv2022".to_string()]),
+                Expectation::ClearSky
+            ]
+        );
+        assert_eq!(flagged[2].expected_kWh(), 10.0);
+        assert_eq!(
+            flagged[2].shortfalls[0].expectation.to_string(),
+            "pv2022 per kWp"
+        );
+    }
+
+    #[test]
+    fn readings_by_hand_spread() {
+        let row = |hour_start, total| HourlyYieldRow {
+            installation: "pv2012".to_string(),
+            hour_start,
+            total_yield_kWh: total,
+        };
+        // Evenings of 2024-06-20, 21 and 24 in Brussels, then 2 months later
+        let evening = 1718834400 + 20 * 3600;
+        let rows = [
+            row(evening, 480.0),
+            row(evening + 86400, 490.0),
+            row(evening + 4 * 86400, 520.0),
+            row(evening + 64 * 86400, 900.0),
+        ];
+        let tz = chrono_tz::Europe::Brussels;
+        assert_eq!(
+            manual_daily_yields(&rows, "pv2012", tz),
+            BTreeMap::from([
+                (date(21), 10.0),
+                (date(22), 10.0),
+                (date(23), 10.0),
+                (date(24), 10.0),
+            ])
+        );
+        assert_eq!(
+            daily_yields(&rows, "pv2012", tz),
+            BTreeMap::from([(date(21), 10.0)])
+        );
+    }
+}
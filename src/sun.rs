@@ -0,0 +1,132 @@
+use time::{Date, OffsetDateTime};
+
+/// Daylight at a place on a day
+#[derive(Debug, PartialEq)]
+pub enum Daylight {
+    /// Sunrise and sunset
+    Between(OffsetDateTime, OffsetDateTime),
+    /// Midnight sun
+    AllDay,
+    /// Polar night
+    AllNight,
+}
+
+impl Daylight {
+    /// Whether the sun is up at `time`, give or take `margin_s` seconds
+    pub fn contains(&self, time: OffsetDateTime, margin_s: i64) -> bool {
+        match self {
+            Daylight::Between(sunrise, sunset) => {
+                let margin = time::Duration::seconds(margin_s);
+                *sunrise - margin <= time && time <= *sunset + margin
+            }
+            Daylight::AllDay => true,
+            Daylight::AllNight => false,
+        }
+    }
+}
+
+const J2000: f64 = 2451545.0;
+const UNIX_EPOCH_JULIAN_DAY: f64 = 2440587.5;
+
+fn from_julian_day(julian_day: f64) -> OffsetDateTime {
+    let unix = ((julian_day - UNIX_EPOCH_JULIAN_DAY) * 86400.0).round() as i64;
+    OffsetDateTime::from_unix_timestamp(unix).unwrap_or(OffsetDateTime::UNIX_EPOCH)
+}
+
+/// Sunrise and sunset of `date` (UTC) at `latitude` (north positive) and
+/// `longitude` (east positive), with the sunrise equation: good to a minute
+/// or two, plenty to know whether the inverter is awake.
+pub fn daylight(date: Date, latitude: f64, longitude: f64) -> Daylight {
+    let noon = date.midnight().assume_utc().unix_timestamp() as f64 + 43200.0;
+    let day = (noon / 86400.0 + UNIX_EPOCH_JULIAN_DAY - J2000).round();
+    let mean_solar_noon = day - longitude / 360.0;
+    let anomaly = (357.5291 + 0.98560028 * mean_solar_noon).rem_euclid(360.0);
+    let m = anomaly.to_radians();
+    let center = 1.9148 * m.sin() + 0.02 * (2.0 * m).sin() + 0.0003 * (3.0 * m).sin();
+    let ecliptic_longitude = (anomaly + center + 180.0 + 102.9372)
+        .rem_euclid(360.0)
+        .to_radians();
+    let transit =
+        J2000 + mean_solar_noon + 0.0053 * m.sin() - 0.0069 * (2.0 * ecliptic_longitude).sin();
+    let declination = (ecliptic_longitude.sin() * 23.4397_f64.to_radians().sin()).asin();
+    let latitude = latitude.to_radians();
+    // Refraction and size of the sun disk: sunrise is when its top shows
+    let cos_hour_angle = ((-0.833_f64).to_radians().sin() - latitude.sin() * declination.sin())
+        / (latitude.cos() * declination.cos());
+    if cos_hour_angle < -1.0 {
+        Daylight::AllDay
+    } else if cos_hour_angle > 1.0 {
+        Daylight::AllNight
+    } else {
+        let half_day = cos_hour_angle.acos().to_degrees() / 360.0;
+        Daylight::Between(
+            from_julian_day(transit - half_day),
+            from_julian_day(transit + half_day),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use time::Month;
+
+    fn date(year: i32, month: Month, day: u8) -> Date {
+        Date::from_calendar_date(year, month, day).unwrap()
+    }
+
+    fn utc(date: Date, hour: u8, minute: u8) -> OffsetDateTime {
+        date.with_hms(hour, minute, 0).unwrap().assume_utc()
+    }
+
+    fn assert_close(actual: OffsetDateTime, expected: OffsetDateTime) {
+        assert!(
+            (actual - expected).abs() < time::Duration::minutes(3),
+            "{} instead of {}",
+            actual,
+            expected
+        );
+    }
+
+    #[test]
+    fn sunrise_and_sunset_in_brussels() {
+        match daylight(date(2024, Month::June, 21), 50.85, 4.35) {
+            Daylight::Between(sunrise, sunset) => {
+                assert_close(sunrise, utc(date(2024, Month::June, 21), 3, 29));
+                assert_close(sunset, utc(date(2024, Month::June, 21), 20, 0));
+            }
+            other => panic!("{:?}", other),
+        }
+        match daylight(date(2024, Month::December, 21), 50.85, 4.35) {
+            Daylight::Between(sunrise, sunset) => {
+                assert_close(sunrise, utc(date(2024, Month::December, 21), 7, 44));
+                assert_close(sunset, utc(date(2024, Month::December, 21), 15, 40));
+            }
+            other => panic!("{:?}", other),
+        }
+    }
+
+    #[test]
+    fn polar_day_and_night() {
+        assert_eq!(
+            daylight(date(2024, Month::June, 21), 78.2, 15.6),
+            Daylight::AllDay
+        );
+        assert_eq!(
+            daylight(date(2024, Month::December, 21), 78.2, 15.6),
+            Daylight::AllNight
+        );
+    }
+
+    #[test]
+    fn contains_with_margin() {
+        let daylight = Daylight::Between(
+            utc(date(2024, Month::June, 21), 3, 29),
+            utc(date(2024, Month::June, 21), 20, 0),
+        );
+        assert!(!daylight.contains(utc(date(2024, Month::June, 21), 2, 0), 1800));
+        assert!(daylight.contains(utc(date(2024, Month::June, 21), 3, 0), 1800));
+        assert!(daylight.contains(utc(date(2024, Month::June, 21), 12, 0), 0));
+        assert!(!daylight.contains(utc(date(2024, Month::June, 21), 20, 1), 0));
+    }
+}
@@ -1,9 +1,23 @@
-use actix_web::{http::StatusCode, test};
+use std::sync::Arc;
+use std::time::Duration;
+
+use actix_web::body::MessageBody;
+use actix_web::{http::StatusCode, test, web};
+use hello_world_lib::data::Database;
+use hello_world_lib::inverter::InverterError;
+use hello_world_lib::inverter_cache::InverterCache;
+use hello_world_lib::inverter_client::{InverterClient, MockInverterClient, MockResponse};
+use hello_world_lib::inverter_snapshot::InverterSnapshot;
+use hello_world_lib::p1_ingest::P1Devices;
+use hello_world_lib::p1_meter::crc16;
+use hello_world_lib::p1_reader::P1Hub;
+use hello_world_lib::pv_installations::{parse_installations, PvInstallations};
 use hello_world_lib::{create_app, MeterReadingsUserInput};
+use time::OffsetDateTime;
 
 #[actix_rt::test]
 async fn test_greet_user_id_and_name() {
-    let mut app = test::init_service(create_app()).await;
+    let app = test::init_service(create_app()).await;
 
     let user_id = 42;
     let name = "John".to_string();
@@ -11,7 +25,7 @@ async fn test_greet_user_id_and_name() {
         .uri(&format!("/hello-rust/{}/{}", user_id, name))
         .to_request();
 
-    let response = test::call_service(&mut app, request).await;
+    let response = test::call_service(&app, request).await;
     assert_eq!(response.status(), StatusCode::OK);
 
     let body = test::read_body(response).await;
@@ -21,19 +35,85 @@ async fn test_greet_user_id_and_name() {
     assert_eq!(body_str, expected_response);
 }
 
-#[actix_rt::test]
-async fn test_get_meter_readings_form() {
-    let mut app = test::init_service(create_app()).await;
+/// The 2012 installation is typed in, the 2022 one polled
+fn pv_installations() -> web::Data<PvInstallations> {
+    web::Data::new(PvInstallations::new(
+        parse_installations(
+            r#"[
+              {"name": "pv2012", "inverter": "manual", "form_field": "pv_2012_prod_kWh"},
+              {"name": "pv2022", "inverter": "sma-dashboard", "host": "SMA30XXXXX5",
+               "certificate": "inverter.pem", "form_field": "pv_2022_prod_kWh"}
+            ]"#,
+        )
+        .unwrap(),
+        None,
+    ))
+}
+
+fn snapshot(total_yield: f64) -> InverterSnapshot {
+    InverterSnapshot {
+        device: "0199-xxxxx9BD".to_string(),
+        total_yield_kWh: Some(total_yield),
+        daily_yield_kWh: None,
+        ac_power_W: None,
+        grid_state: None,
+        device_status: None,
+    }
+}
+
+async fn get_meter_readings_form(
+    database: web::Data<Database>,
+    installations: web::Data<PvInstallations>,
+    client: &Arc<MockInverterClient>,
+) -> String {
+    let client: Arc<dyn InverterClient> = client.clone();
+    let app = test::init_service(
+        create_app()
+            .app_data(database)
+            .app_data(installations)
+            .app_data(web::Data::from(client)),
+    )
+    .await;
 
     let request = test::TestRequest::get()
         .uri("/hello-rust/forms/meter-readings")
         .to_request();
 
-    let response = test::call_service(&mut app, request).await;
+    let response = test::call_service(&app, request).await;
     assert_eq!(response.status(), StatusCode::OK);
 
     let body = test::read_body(response).await;
-    let body_str = String::from_utf8_lossy(&body).to_string();
+    String::from_utf8_lossy(&body).to_string()
+}
+
+/// Wait for the read started by the form to end up in `cache`
+async fn wait_for_read(cache: &InverterCache, read: impl Fn(&InverterCache) -> bool) {
+    for _ in 0..100 {
+        if read(cache) {
+            return;
+        }
+        actix_rt::time::sleep(Duration::from_millis(10)).await;
+    }
+    panic!("The inverter was not read");
+}
+
+fn now() -> i64 {
+    OffsetDateTime::now_utc().unix_timestamp()
+}
+
+#[actix_rt::test]
+async fn test_get_meter_readings_form() {
+    let database = web::Data::new(Database {
+        sqlite3: "grep -q \"installation = 'pv2022'\" && echo '1729814400|12345.678|8.765|1500.0|Connected|Ok'"
+            .to_string(),
+    });
+    let installations = pv_installations();
+    let client = Arc::new(MockInverterClient::default());
+    client.respond(
+        "pv2022",
+        MockResponse::Error(InverterError::HttpStatus(503)),
+    );
+    let body_str = get_meter_readings_form(database.clone(), installations.clone(), &client).await;
 
     for id in [
         "pv_2012_prod_kWh",
@@ -62,11 +142,115 @@ async fn test_get_meter_readings_form() {
             "Response does not contain expected HTML: input",
         );
     }
+    // Nothing read yet: as last stored, the inverter being read meanwhile
+    assert!(body_str.contains("name=\"pv_2022_prod_kWh\" value=\"12345.6\""));
+    assert!(!body_str.contains("error fetching value"));
+    assert!(body_str.contains("name=\"pv_2012_prod_kWh\" value=\"\""));
+    let cache = &installations.installations[1].cache;
+    wait_for_read(cache, |cache| cache.last_error().is_some()).await;
+    assert_eq!(client.reads()[0], "pv2022");
+
+    // The inverter failing
+    let body_str = get_meter_readings_form(database, installations.clone(), &client).await;
+    assert!(body_str.contains("name=\"pv_2022_prod_kWh\" value=\"12345.6\""));
+    assert!(body_str.contains(", error fetching value: HTTP status 503</span>"));
+}
+
+#[actix_rt::test]
+async fn test_get_meter_readings_form_whole_wh() {
+    // Truncated to the tenth once formatted to the Wh, not below it
+    let database = web::Data::new(Database {
+        sqlite3: "cat > /dev/null; echo '1729814400|1000.0999999||||'".to_string(),
+    });
+    let client = Arc::new(MockInverterClient::default());
+    let body_str = get_meter_readings_form(database, pv_installations(), &client).await;
+    assert!(body_str.contains("name=\"pv_2022_prod_kWh\" value=\"1000.1\""));
+}
+
+#[actix_rt::test]
+async fn test_get_meter_readings_form_polled() {
+    let database = web::Data::new(Database {
+        sqlite3: "cat > /dev/null".to_string(),
+    });
+    let installations = pv_installations();
+    let client = Arc::new(MockInverterClient::default());
+    client.respond("pv2022", MockResponse::Snapshot(snapshot(12400.04)));
+    let body_str = get_meter_readings_form(database.clone(), installations.clone(), &client).await;
+    assert!(body_str.contains("name=\"pv_2022_prod_kWh\" value=\"\""));
+    let cache = &installations.installations[1].cache;
+    wait_for_read(cache, |cache| cache.get().is_some()).await;
+
+    let body_str = get_meter_readings_form(database, installations.clone(), &client).await;
+    assert!(body_str.contains("name=\"pv_2022_prod_kWh\" value=\"12400.0\""));
+    assert!(body_str.contains(", just now</span>"));
+    // Fresh: not read again
+    assert_eq!(client.reads(), vec!["pv2022"]);
+}
+
+#[actix_rt::test]
+async fn test_get_meter_readings_form_timeout() {
+    let database = web::Data::new(Database {
+        sqlite3: "cat > /dev/null".to_string(),
+    });
+    let installations = pv_installations();
+    let client = Arc::new(MockInverterClient::default());
+    client.respond("pv2022", MockResponse::Timeout(Duration::from_millis(10)));
+    let body_str = get_meter_readings_form(database.clone(), installations.clone(), &client).await;
+    assert!(body_str.contains("name=\"pv_2022_prod_kWh\" value=\"\""));
+    assert!(!body_str.contains("Error fetching value"));
+    let cache = &installations.installations[1].cache;
+    wait_for_read(cache, |cache| cache.last_error().is_some()).await;
+
+    let body_str = get_meter_readings_form(database, installations, &client).await;
+    assert!(body_str.contains("name=\"pv_2022_prod_kWh\" value=\"\""));
+    assert!(body_str.contains("<span class=\"freshness\">Error fetching value: Timeout</span>"));
+}
+
+#[actix_rt::test]
+async fn test_get_meter_readings_form_does_not_wait() {
+    let database = web::Data::new(Database {
+        sqlite3: "cat > /dev/null".to_string(),
+    });
+    let client = Arc::new(MockInverterClient::default());
+    client.respond("pv2022", MockResponse::Timeout(Duration::from_secs(60)));
+    let body_str = tokio::time::timeout(
+        Duration::from_secs(5),
+        get_meter_readings_form(database, pv_installations(), &client),
+    )
+    .await
+    .expect("The form waited for the inverter");
+    assert!(body_str.contains("name=\"pv_2022_prod_kWh\" value=\"\""));
+}
+
+#[actix_rt::test]
+async fn test_get_meter_readings_form_from_cache() {
+    // Not read from the database when cached, and refreshed when stale
+    let database = web::Data::new(Database {
+        sqlite3: "cat > /dev/null; echo '1729814400|1.0||||'".to_string(),
+    });
+    let installations = pv_installations();
+    let cache = &installations.installations[1].cache;
+    cache.store(snapshot(12400.04), now() - 600);
+    let client = Arc::new(MockInverterClient::default());
+    client.respond("pv2022", MockResponse::Snapshot(snapshot(12500.0)));
+    let body_str = get_meter_readings_form(database.clone(), installations.clone(), &client).await;
+    assert!(body_str.contains("name=\"pv_2022_prod_kWh\" value=\"12400.0\""));
+    assert!(body_str.contains(", 10 min ago</span>"));
+    wait_for_read(cache, |cache| {
+        cache
+            .get()
+            .is_some_and(|cached| cached.fetched_at > now() - 60)
+    })
+    .await;
+
+    let body_str = get_meter_readings_form(database, installations.clone(), &client).await;
+    assert!(body_str.contains("name=\"pv_2022_prod_kWh\" value=\"12500.0\""));
+    assert!(body_str.contains(", just now</span>"));
 }
 
 #[actix_rt::test]
 async fn test_submit_meter_readings() {
-    let mut app = test::init_service(create_app()).await;
+    let app = test::init_service(create_app()).await;
 
     // Create a mock form input
     let form_input = MeterReadingsUserInput {
@@ -86,7 +270,7 @@ async fn test_submit_meter_readings() {
         .uri("/hello-rust/meter-readings")
         .set_form(form_input)
         .to_request();
-    let resp = test::call_service(&mut app, req).await;
+    let resp = test::call_service(&app, req).await;
 
     // Assert that the response is successful
     assert!(resp.status().is_success());
@@ -98,3 +282,489 @@ async fn test_submit_meter_readings() {
     let expected_msg = "Form submitted successfully: Received data for 2023-05-22 20:40: pv_2022_prod_kWh=1848.2, pv_2012_prod_kWh=-99.9, peak_hour_consumption_kWh=10.5, off_hour_consumption_kWh=-99.9, peak_hour_injection_kWh=-99.9, off_hour_injection_kWh=-99.9, gas_m3=5.6, water_m3=6.5";
     assert_eq!(body, expected_msg.as_bytes());
 }
+
+#[actix_rt::test]
+async fn test_p1_live_page() {
+    let app = test::init_service(create_app()).await;
+
+    let request = test::TestRequest::get()
+        .uri("/hello-rust/p1/live")
+        .to_request();
+    let response = test::call_service(&app, request).await;
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let body = test::read_body(response).await;
+    let body_str = String::from_utf8_lossy(&body).to_string();
+    assert!(body_str.contains("new EventSource(\"/hello-rust/p1/live/events\")"));
+}
+
+async fn next_chunk<B: MessageBody>(mut body: std::pin::Pin<&mut B>) -> String {
+    match std::future::poll_fn(|cx| body.as_mut().poll_next(cx)).await {
+        Some(Ok(chunk)) => String::from_utf8(chunk.to_vec()).unwrap(),
+        _ => panic!("Expected another chunk"),
+    }
+}
+
+#[actix_rt::test]
+async fn test_p1_live_events() {
+    let hub = web::Data::new(P1Hub::new());
+    let app = test::init_service(create_app().app_data(hub.clone())).await;
+
+    let request = test::TestRequest::get()
+        .uri("/hello-rust/p1/live/events")
+        .to_request();
+    let response = test::call_service(&app, request).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(
+        response.headers().get("content-type").unwrap(),
+        "text/event-stream"
+    );
+
+    let mut body = std::pin::pin!(response.into_body());
+    assert_eq!(next_chunk(body.as_mut()).await, "retry: 3000\n\n");
+
+    hub.publish(
+        "/XMX5\r\n0-0:96.14.0(0001)\r\n1-0:1.7.0(00.000*kW)\r\n1-0:2.7.0(02.345*kW)\r\n!\r\n"
+            .to_string(),
+        OffsetDateTime::now_utc(),
+    );
+    assert_eq!(
+        next_chunk(body.as_mut()).await,
+        "data: {\"timestamp\":null,\"import_kW\":0.0,\"export_kW\":2.345,\"phase_import_kW\":[null,null,null],\"phase_export_kW\":[null,null,null],\"tariff\":1}\n\n"
+    );
+}
+
+#[actix_rt::test]
+async fn test_p1_events_page() {
+    let database = web::Data::new(Database {
+        sqlite3: "if grep -q 'select end_timestamp'; then echo '1291821855|301'; else echo '1729814400|voltage_swell_L2|3|1'; fi".to_string(),
+    });
+    let app = test::init_service(create_app().app_data(database)).await;
+
+    let request = test::TestRequest::get()
+        .uri("/hello-rust/p1/events")
+        .to_request();
+    let response = test::call_service(&app, request).await;
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let body = test::read_body(response).await;
+    let body_str = String::from_utf8_lossy(&body).to_string();
+    assert!(body_str.contains(
+        "<tr><td>2010-12-08 15:19:14</td><td>2010-12-08 15:24:15</td><td>5min 1s</td></tr>"
+    ));
+    assert!(body_str.contains(
+        "<tr><td>2024-10-25 00:00:00</td><td>Voltage swell L2</td><td>1</td><td>3</td></tr>"
+    ));
+}
+
+#[actix_rt::test]
+async fn test_p1_phases_page() {
+    let database = web::Data::new(Database {
+        sqlite3: "if grep -q 'group by phase'; then echo '1|1440|35.0|12|0.25\n2|1440|9.0|0|0.5'; else echo '2024-10-25|5.5|20.0|-0.5|0.25|0.125'; fi".to_string(),
+    });
+    let app = test::init_service(create_app().app_data(database)).await;
+
+    let request = test::TestRequest::get()
+        .uri("/hello-rust/p1/phases?days=7")
+        .to_request();
+    let response = test::call_service(&app, request).await;
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let body = test::read_body(response).await;
+    let body_str = String::from_utf8_lossy(&body).to_string();
+    assert!(body_str.contains("Last 7 days"));
+    assert!(body_str.contains("<tr><th>L1</th><td>35</td><td>12min</td><td>0.250</td></tr>"));
+    assert!(body_str.contains("Most headroom for new loads: L2."));
+    assert!(body_str.contains(
+        "<tr><th>2024-10-25</th><td>5.5</td><td>20.0</td><td>-0.500</td><td>0.250</td><td>0.125</td></tr>"
+    ));
+}
+
+#[actix_rt::test]
+async fn test_p1_phases_page_bad_phase() {
+    // A stored phase 0 is skipped, not taken for the one with most headroom
+    let database = web::Data::new(Database {
+        sqlite3: "if grep -q 'group by phase'; then echo '0|1440|1.0|0|0.0\n1|1440|35.0|12|0.25'; else cat > /dev/null; fi".to_string(),
+    });
+    let app = test::init_service(create_app().app_data(database)).await;
+
+    let request = test::TestRequest::get()
+        .uri("/hello-rust/p1/phases?days=7")
+        .to_request();
+    let response = test::call_service(&app, request).await;
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let body = test::read_body(response).await;
+    let body_str = String::from_utf8_lossy(&body).to_string();
+    assert!(body_str.contains("<tr><th>L1</th><td>35</td><td>12min</td><td>0.250</td></tr>"));
+    assert!(!body_str.contains("Most headroom"));
+}
+
+#[actix_rt::test]
+async fn test_p1_clock_page() {
+    let database = web::Data::new(Database {
+        sqlite3: "cat > /dev/null; echo '1729807200|3600|-1.0|-0.5|0.0\n1729810800|3600|-12.0|-11.5|-11.0'".to_string(),
+    });
+    let hub = web::Data::new(P1Hub::new());
+    // Meter 2024-10-25T00:00:00Z, received 20s later
+    hub.publish(
+        "/XMX5\r\n0-0:1.0.0(241025020000S)\r\n!\r\n".to_string(),
+        OffsetDateTime::from_unix_timestamp(1729814420).unwrap(),
+    );
+    let app = test::init_service(create_app().app_data(database).app_data(hub)).await;
+
+    let request = test::TestRequest::get()
+        .uri("/hello-rust/p1/clock?days=2")
+        .to_request();
+    let response = test::call_service(&app, request).await;
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let body = test::read_body(response).await;
+    let body_str = String::from_utf8_lossy(&body).to_string();
+    assert!(body_str.contains("last 2 days"));
+    assert!(body_str.contains("Current offset: <span class=\"off\">-20.0s</span>."));
+    assert!(body_str.contains("stored with the meter time"));
+    assert!(body_str.contains(
+        "<tr><th>2024-10-24 23:00:00</th><td>3600</td><td>-12.0</td><td class=\"off\">-11.5</td><td>-11.0</td></tr>"
+    ));
+    assert!(body_str.contains("<td>-1.0</td><td>-0.5</td><td>+0.0</td>"));
+}
+
+#[actix_rt::test]
+async fn test_inverter_status_page() {
+    let installations = web::Data::new(PvInstallations::new(
+        parse_installations(
+            r#"[
+              {"name": "pv2012", "inverter": "manual", "kWp": 2.5},
+              {"name": "pv2022", "inverter": "sma-dashboard", "host": "SMA30XXXXX5",
+               "certificate": "inverter.pem", "kWp": 6.2, "commissioned": "2022-09-15"}
+            ]"#,
+        )
+        .unwrap(),
+        Some("tests/test-data/valid_lease.txt".to_string()),
+    ));
+    let app = test::init_service(create_app().app_data(installations)).await;
+
+    let request = test::TestRequest::get()
+        .uri("/hello-rust/inverter/status")
+        .to_request();
+    let response = test::call_service(&app, request).await;
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let body = test::read_body(response).await;
+    let body_str = String::from_utf8_lossy(&body).to_string();
+    assert!(body_str.contains("<td>SMA30XXXXX5</td>"));
+    assert!(body_str.contains("<td>2022-09-15</td>"));
+    assert!(body_str.contains("<td colspan=\"2\">Not polled</td>"));
+    assert!(body_str.contains("valid_lease.txt, modified "));
+    assert!(body_str.contains("<td>192.168.100.49 (from the DHCP lease)</td>"));
+}
+
+#[actix_rt::test]
+async fn test_inverter_events_page() {
+    let database = web::Data::new(Database {
+        sqlite3: "cat > /dev/null; echo 'pv2022|1729850000|101|Error|253.4||\npv2022|1729814400|3901|Info|||'".to_string(),
+    });
+    let app = test::init_service(create_app().app_data(database)).await;
+
+    let request = test::TestRequest::get()
+        .uri("/hello-rust/inverter/events")
+        .to_request();
+    let response = test::call_service(&app, request).await;
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let body = test::read_body(response).await;
+    let body_str = String::from_utf8_lossy(&body).to_string();
+    assert!(body_str.contains(
+        "<tr class=\"Error\"><td>2024-10-25 09:53:20</td><td>pv2022</td><td>Error</td><td>101</td><td>Grid overvoltage</td><td>253.4</td><td></td><td></td></tr>"
+    ));
+    assert!(body_str.contains("<td>Waiting for DC start conditions</td>"));
+    assert!(body_str.contains("<th>Max L1 (V)</th>"));
+}
+
+#[actix_rt::test]
+async fn test_inverter_events_page_without_meter() {
+    // No voltage column without P1 data around the events
+    let database = web::Data::new(Database {
+        sqlite3: "cat > /dev/null; echo 'pv2022|1729850000|101|Error|||'".to_string(),
+    });
+    let app = test::init_service(create_app().app_data(database)).await;
+
+    let request = test::TestRequest::get()
+        .uri("/hello-rust/inverter/events")
+        .to_request();
+    let response = test::call_service(&app, request).await;
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let body = test::read_body(response).await;
+    let body_str = String::from_utf8_lossy(&body).to_string();
+    assert!(body_str.contains("<td>Grid overvoltage</td></tr>"));
+    assert!(!body_str.contains("Max L1"));
+
+    // Bounded, not overflowing
+    let request = test::TestRequest::get()
+        .uri("/hello-rust/inverter/events?days=9223372036854775807")
+        .to_request();
+    let response = test::call_service(&app, request).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let body = test::read_body(response).await;
+    assert!(String::from_utf8_lossy(&body).contains("<td>Grid overvoltage</td></tr>"));
+}
+
+#[actix_rt::test]
+async fn test_pv_performance_page() {
+    // Evenings of the last 4 days: pv2012 tripped the day before yesterday
+    let midnight = OffsetDateTime::now_utc().unix_timestamp() / 86400 * 86400;
+    let mut rows = String::new();
+    for (days_ago, pv2012, pv2022) in [
+        (4, 500, 1000),
+        (3, 515, 1038),
+        (2, 515, 1076),
+        (1, 530, 1114),
+    ] {
+        let evening = midnight - days_ago * 86400 + 20 * 3600;
+        rows.push_str(&format!(
+            "pv2012|{}|{}\\npv2022|{}|{}\\n",
+            evening, pv2012, evening, pv2022
+        ));
+    }
+    let database = web::Data::new(Database {
+        sqlite3: format!("grep -q 'from inverter_production' && printf '{}'", rows),
+    });
+    let installations = web::Data::new(PvInstallations::new(
+        parse_installations(
+            r#"[{"name": "pv2012", "inverter": "manual", "kWp": 2.5},
+                {"name": "pv2022", "inverter": "manual", "kWp": 6.2}]"#,
+        )
+        .unwrap(),
+        None,
+    ));
+    let app = test::init_service(create_app().app_data(database).app_data(installations)).await;
+
+    let request = test::TestRequest::get()
+        .uri("/hello-rust/pv/performance?days=30")
+        .to_request();
+    let response = test::call_service(&app, request).await;
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let body = test::read_body(response).await;
+    let body_str = String::from_utf8_lossy(&body).to_string();
+    assert!(body_str.contains("Last 30 days"));
+    assert!(body_str.contains("<tr><td>pv2012</td><td>3</td><td>1</td><td>15.3</td></tr>"));
+    assert!(body_str.contains(
+        "<td>pv2012</td><td>0.0</td><td>15.3</td><td class=\"deficit\">15.3</td><td>pv2022 per kWp</td></tr>"
+    ));
+}
+
+#[actix_rt::test]
+async fn test_pv_performance_page_manual_readings() {
+    // pv2012 read by hand every other evening, tripped 4 and 3 days ago;
+    // pv2022 polled every evening
+    let midnight = OffsetDateTime::now_utc().unix_timestamp() / 86400 * 86400;
+    let evening = |days_ago: i64| midnight - days_ago * 86400 + 20 * 3600;
+    let mut readings = String::new();
+    for (days_ago, pv2012) in [(7, 500), (5, 530), (3, 530), (1, 560)] {
+        readings.push_str(&format!("pv2012|{}|{}\\n", evening(days_ago), pv2012));
+    }
+    let mut rows = String::new();
+    for days_ago in 1..=7 {
+        let pv2022 = 1000 + 38 * (7 - days_ago);
+        rows.push_str(&format!("pv2022|{}|{}\\n", evening(days_ago), pv2022));
+    }
+    let database = web::Data::new(Database {
+        sqlite3: format!(
+            "sql=$(cat); case \"$sql\" in *'from readings'*) printf '{}';; *) printf '{}';; esac",
+            readings, rows
+        ),
+    });
+    let installations = web::Data::new(PvInstallations::new(
+        parse_installations(
+            r#"[{"name": "pv2012", "inverter": "manual", "kWp": 2.5},
+                {"name": "pv2022", "inverter": "sma-webconnect", "host": "SMA30XXXXX5",
+                 "certificate": "inverter.pem", "password": "s3cret", "kWp": 6.2}]"#,
+        )
+        .unwrap(),
+        None,
+    ));
+    let app = test::init_service(create_app().app_data(database).app_data(installations)).await;
+
+    let request = test::TestRequest::get()
+        .uri("/hello-rust/pv/performance?days=30")
+        .to_request();
+    let response = test::call_service(&app, request).await;
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let body = test::read_body(response).await;
+    let body_str = String::from_utf8_lossy(&body).to_string();
+    assert!(body_str.contains("<tr><td>pv2012</td><td>6</td><td>2</td><td>30.6</td></tr>"));
+    assert!(body_str.contains(
+        "<td>pv2012</td><td>0.0</td><td>15.3</td><td class=\"deficit\">15.3</td><td>pv2022 per kWp</td></tr>"
+    ));
+    assert!(body_str.contains("<tr><td>pv2022</td><td>6</td><td>0</td><td>0.0</td></tr>"));
+
+    // Bounded, not out of the calendar
+    let request = test::TestRequest::get()
+        .uri("/hello-rust/pv/performance?days=100000000")
+        .to_request();
+    let response = test::call_service(&app, request).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let body = test::read_body(response).await;
+    assert!(String::from_utf8_lossy(&body).contains("Last 3660 days"));
+}
+
+#[actix_rt::test]
+async fn test_p1_base_load_page() {
+    // Four quarters of an hour after midnight UTC, from the per phase data
+    let since_midnight = (OffsetDateTime::now_utc().unix_timestamp() / 86400 - 1) * 86400;
+    let database = web::Data::new(Database {
+        sqlite3: format!(
+            "if grep -q 'from p1_phase_minutes'; then for i in 0 1 2 3; do echo \"$(({} + i * 900))|0.1$i|0\"; done; fi",
+            since_midnight
+        ),
+    });
+    let app = test::init_service(create_app().app_data(database)).await;
+
+    let request = test::TestRequest::get()
+        .uri("/hello-rust/p1/base-load")
+        .to_request();
+    let response = test::call_service(&app, request).await;
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let body = test::read_body(response).await;
+    let body_str = String::from_utf8_lossy(&body).to_string();
+    assert!(body_str.contains("last 12 weeks"));
+    assert!(
+        body_str.contains("<td>100</td><td>power</td>"),
+        "{}",
+        body_str
+    );
+}
+
+#[actix_rt::test]
+async fn test_p1_appliances_page() {
+    let on = OffsetDateTime::now_utc().unix_timestamp() - 3600;
+    let database = web::Data::new(Database {
+        sqlite3: format!(
+            "if grep -q 'from p1_appliance_clusters'; then echo '1|2|2.0|2|Kettle'; else echo '{}|2|2.0|1'; echo '{}|2|-2.0|1'; fi",
+            on,
+            on + 180
+        ),
+    });
+    let app = test::init_service(create_app().app_data(database)).await;
+
+    let request = test::TestRequest::get()
+        .uri("/hello-rust/p1/appliances")
+        .to_request();
+    let response = test::call_service(&app, request).await;
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let body = test::read_body(response).await;
+    let body_str = String::from_utf8_lossy(&body).to_string();
+    assert!(body_str.contains("<input type=\"text\" name=\"label\" value=\"Kettle\""));
+    assert!(body_str.contains("<td>Kettle</td><td>1</td><td>0.10</td>"));
+}
+
+#[actix_rt::test]
+async fn test_p1_appliance_label() {
+    let database = web::Data::new(Database {
+        sqlite3: "grep -c \"set label = 'Kettle' where id = 1;\"".to_string(),
+    });
+    let app = test::init_service(create_app().app_data(database)).await;
+
+    let request = test::TestRequest::post()
+        .uri("/hello-rust/p1/appliances/label")
+        .set_form([("id", "1"), ("label", "Kettle\n")])
+        .to_request();
+    let response = test::call_service(&app, request).await;
+    assert_eq!(response.status(), StatusCode::SEE_OTHER);
+
+    let request = test::TestRequest::post()
+        .uri("/hello-rust/p1/appliances/label")
+        .set_form([("id", "2"), ("label", "Oven")])
+        .to_request();
+    let response = test::call_service(&app, request).await;
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+}
+
+#[actix_rt::test]
+async fn test_p1_ingest_telegrams() {
+    let devices = web::Data::new(P1Devices::new(vec![(
+        "kitchen".to_string(),
+        "s3cret".to_string(),
+    )]));
+    // Only the first of the two complete telegrams is new
+    let database = web::Data::new(Database {
+        sqlite3: "cat > /dev/null; echo '1\n0'".to_string(),
+    });
+    let hub = web::Data::new(P1Hub::new());
+    let mut receiver = hub.subscribe();
+    let app = test::init_service(
+        create_app()
+            .app_data(database)
+            .app_data(hub)
+            .app_data(devices),
+    )
+    .await;
+    let body = "/ISK5\\2M550E-1012\r\n\r\n0-0:1.0.0(241025020000S)\r\n1-0:1.8.1(000100.000*kWh)\r\n1-0:1.8.2(000200.000*kWh)\r\n1-0:2.8.1(000010.000*kWh)\r\n1-0:2.8.2(000020.000*kWh)\r\n!";
+    let telegram = format!("{}{:04X}\r\n", body, crc16(body.as_bytes()));
+
+    let request = test::TestRequest::post()
+        .uri("/hello-rust/api/p1/telegram")
+        .insert_header(("Authorization", "Bearer wrong"))
+        .set_payload(telegram.clone())
+        .to_request();
+    let response = test::call_service(&app, request).await;
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+    let request = test::TestRequest::post()
+        .uri("/hello-rust/api/p1/telegram")
+        .insert_header(("Authorization", "Bearer s3cret"))
+        .set_payload(format!("{}/ISK5\r\n!0000\r\n{}", telegram, telegram))
+        .to_request();
+    let response = test::call_service(&app, request).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let body = test::read_body(response).await;
+    let body_str = String::from_utf8_lossy(&body).to_string();
+    assert!(body_str.starts_with(
+        "{\"device\":\"kitchen\",\"results\":[{\"status\":\"accepted\",\"timestamp\":1729814400},{\"status\":\"error\",\"error\":\"CRC mismatch"
+    ));
+    assert!(body_str.ends_with("{\"status\":\"duplicate\",\"timestamp\":1729814400}]}"));
+    assert_eq!(receiver.recv().await.unwrap().raw, telegram);
+    assert!(receiver.try_recv().is_err());
+
+    let request = test::TestRequest::post()
+        .uri("/hello-rust/api/p1/telegram")
+        .insert_header(("Authorization", "Bearer s3cret"))
+        .set_payload("no telegram")
+        .to_request();
+    let response = test::call_service(&app, request).await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+    // DSMR 2.2 telegrams are stored at reception: only one per request
+    let app = test::init_service(
+        create_app()
+            .app_data(web::Data::new(Database {
+                sqlite3: "cat > /dev/null; echo 1".to_string(),
+            }))
+            .app_data(web::Data::new(P1Hub::new()))
+            .app_data(web::Data::new(P1Devices::new(vec![(
+                "kitchen".to_string(),
+                "s3cret".to_string(),
+            )]))),
+    )
+    .await;
+    let dsmr22 = "/ISk5\\2ME382-1003\r\n\r\n1-0:1.8.1(00185.000*kWh)\r\n1-0:1.8.2(00084.000*kWh)\r\n1-0:2.8.1(00013.000*kWh)\r\n1-0:2.8.2(00019.000*kWh)\r\n!\r\n";
+    let request = test::TestRequest::post()
+        .uri("/hello-rust/api/p1/telegram")
+        .insert_header(("Authorization", "Bearer s3cret"))
+        .set_payload(format!("{}{}", dsmr22, dsmr22))
+        .to_request();
+    let response = test::call_service(&app, request).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let body = test::read_body(response).await;
+    let body_str = String::from_utf8_lossy(&body).to_string();
+    assert!(body_str.contains("[{\"status\":\"accepted\","));
+    assert!(body_str.ends_with(
+        "{\"status\":\"error\",\"error\":\"Only one DSMR 2.2 telegram per request\"}]}"
+    ));
+}